@@ -0,0 +1,75 @@
+//! Benchmarks of the main operations, on generated roots with 1k and 10k packages.
+//!
+//! Run with `cargo bench --features bench`.
+
+mod fixture;
+
+use alpm::{
+    db::{Database, SyncDatabaseOptions},
+    Alpm,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+use crate::fixture::{Fixture, SYNC_DB};
+
+const SIZES: &[usize] = &[1_000, 10_000];
+
+fn open(fixture: &Fixture) -> Alpm {
+    Alpm::new()
+        .with_root_path(fixture.root())
+        .with_architecture("x86_64")
+        .build()
+        .expect("could not open the fixture")
+}
+
+fn open_with_sync(fixture: &Fixture) -> Alpm {
+    let alpm = open(fixture);
+    alpm.register_sync_database(SYNC_DB, SyncDatabaseOptions::new())
+        .expect("could not register the sync database");
+    alpm
+}
+
+fn benchmarks(c: &mut Criterion) {
+    for &size in SIZES {
+        let fixture = Fixture::generate(size).expect("could not generate the fixture");
+
+        c.bench_with_input(BenchmarkId::new("startup", size), &fixture, |b, fixture| {
+            b.iter(|| open(fixture).release().unwrap())
+        });
+
+        c.bench_with_input(
+            BenchmarkId::new("sync_parse", size),
+            &fixture,
+            |b, fixture| {
+                b.iter_batched(
+                    || open_with_sync(fixture),
+                    |alpm| {
+                        // Counting loads every package.
+                        let count = alpm.sync_database(SYNC_DB).unwrap().count();
+                        assert_eq!(count, size);
+                        alpm
+                    },
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+
+        let alpm = open_with_sync(&fixture);
+        alpm.sync_database(SYNC_DB).unwrap().count();
+        c.bench_with_input(BenchmarkId::new("search", size), &alpm, |b, alpm| {
+            b.iter(|| alpm.search("number 99"))
+        });
+        c.bench_with_input(BenchmarkId::new("validate", size), &alpm, |b, alpm| {
+            b.iter(|| alpm.validate_all(4).unwrap())
+        });
+        c.bench_with_input(
+            BenchmarkId::new("broken_dependencies", size),
+            &alpm,
+            |b, alpm| b.iter(|| alpm.local_database().broken_dependencies().unwrap()),
+        );
+        alpm.release().unwrap();
+    }
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,148 @@
+//! Generated roots for the benchmarks.
+//!
+//! A fixture is a root with a local database and a sync database (`bench`) holding the same
+//! number of packages. Each installed package owns one small file, so validation has something to
+//! check.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use libflate::gzip::Encoder;
+use tempfile::TempDir;
+
+/// The name of the generated sync database.
+pub const SYNC_DB: &str = "bench";
+
+/// What every installed file contains.
+const FILE_CONTENTS: &[u8] = b"benchmark\n";
+
+/// A generated root, removed when dropped.
+pub struct Fixture {
+    dir: TempDir,
+}
+
+impl Fixture {
+    /// Generate a root with `packages` packages installed, and in the sync database.
+    pub fn generate(packages: usize) -> io::Result<Fixture> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        let local = root.join("var/lib/pacman/local");
+        let sync = root.join("var/lib/pacman/sync");
+        fs::create_dir_all(&local)?;
+        fs::create_dir_all(&sync)?;
+        fs::create_dir_all(root.join("usr/share/bench"))?;
+        fs::write(local.join("ALPM_DB_VERSION"), "9\n")?;
+
+        let mut archive = tar::Builder::new(Encoder::new(fs::File::create(
+            sync.join(format!("{}.db", SYNC_DB)),
+        )?)?);
+        for idx in 0..packages {
+            let name = name(idx);
+            let file = format!("usr/share/bench/{}", name);
+            fs::write(root.join(&file), FILE_CONTENTS)?;
+            write_local_package(&local, idx, &file)?;
+            let desc = sync_desc(idx);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(desc.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append_data(
+                &mut header,
+                format!("{}-{}/desc", name, VERSION),
+                desc.as_bytes(),
+            )?;
+        }
+        archive.into_inner()?.finish().into_result()?;
+        Ok(Fixture { dir })
+    }
+
+    /// The root path.
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+/// The version of every package.
+const VERSION: &str = "1.0-1";
+
+/// The name of a package.
+pub fn name(idx: usize) -> String {
+    format!("bench-package-{}", idx)
+}
+
+/// Each package depends on the one before, so dependency checks have work to do.
+fn depends(idx: usize) -> String {
+    match idx {
+        0 => String::new(),
+        _ => format!("%DEPENDS%\n{}>=1.0\n\n", name(idx - 1)),
+    }
+}
+
+fn sync_desc(idx: usize) -> String {
+    let name = name(idx);
+    format!(
+        "%FILENAME%\n{name}-{version}-x86_64.pkg.tar.zst\n\n\
+         %NAME%\n{name}\n\n\
+         %VERSION%\n{version}\n\n\
+         %DESC%\nA generated package number {idx} for benchmarking\n\n\
+         %CSIZE%\n1024\n\n\
+         %ISIZE%\n{size}\n\n\
+         %MD5SUM%\n00000000000000000000000000000000\n\n\
+         %SHA256SUM%\n{zeros}\n\n\
+         %PGPSIG%\nAAAA\n\n\
+         %ARCH%\nx86_64\n\n\
+         %BUILDDATE%\n1600000000\n\n\
+         %PACKAGER%\nBenchmarks <bench@example.com>\n\n\
+         %PROVIDES%\nbench-virtual-{idx}\n\n\
+         {depends}",
+        name = name,
+        version = VERSION,
+        idx = idx,
+        size = FILE_CONTENTS.len(),
+        zeros = "0".repeat(64),
+        depends = depends(idx),
+    )
+}
+
+fn write_local_package(local: &Path, idx: usize, file: &str) -> io::Result<()> {
+    let name = name(idx);
+    let dir: PathBuf = local.join(format!("{}-{}", name, VERSION));
+    fs::create_dir(&dir)?;
+    fs::write(
+        dir.join("desc"),
+        format!(
+            "%NAME%\n{name}\n\n\
+             %VERSION%\n{version}\n\n\
+             %DESC%\nA generated package number {idx} for benchmarking\n\n\
+             %ARCH%\nx86_64\n\n\
+             %BUILDDATE%\n1600000000\n\n\
+             %INSTALLDATE%\n1600000001\n\n\
+             %PACKAGER%\nBenchmarks <bench@example.com>\n\n\
+             %SIZE%\n{size}\n\n\
+             %REASON%\n1\n\n\
+             %VALIDATION%\nsha256\n\n\
+             {depends}",
+            name = name,
+            version = VERSION,
+            idx = idx,
+            size = FILE_CONTENTS.len(),
+            depends = depends(idx),
+        ),
+    )?;
+    fs::write(
+        dir.join("files"),
+        format!("%FILES%\nusr/\nusr/share/\nusr/share/bench/\n{}\n\n", file),
+    )?;
+    let mut mtree = Encoder::new(fs::File::create(dir.join("mtree"))?)?;
+    write!(
+        mtree,
+        "#mtree\n/set type=file uid=0 gid=0 mode=644\n./{} time=1600000000.0 size={}\n",
+        file,
+        FILE_CONTENTS.len()
+    )?;
+    mtree.finish().into_result()?;
+    Ok(())
+}
@@ -102,7 +102,7 @@ fn run(opts: Opts) -> Result<(), Error> {
             let mut bar = PackageProgress::new(local_db.count());
             local_db.packages(|pkg| -> Result<(), Error> {
                 bar.update(pkg.name());
-                let mut pkg_errors = pkg.validate()?;
+                let mut pkg_errors = pkg.validate()?.errors;
                 if ignore_etc {
                     pkg_errors = pkg_errors
                         .into_iter()
@@ -124,15 +124,9 @@ fn run(opts: Opts) -> Result<(), Error> {
             println!("Total errors: {}", total_errors_cnt);
         }
         Cmd::Search { name } => {
-            alpm.sync_databases(|db| {
-                db.packages(|pkg| -> Result<(), alpm::Error> {
-                    if pkg.name().contains(&name) {
-                        println!("[{}] {}:  {}", db.name(), pkg.name(), pkg.description());
-                    }
-                    Ok(())
-                })
-                .unwrap();
-            });
+            for pkg in alpm.search(&name).into_flat() {
+                println!("[{}] {}:  {}", pkg.db_name, pkg.name(), pkg.description());
+            }
         }
     }
 
@@ -152,7 +146,7 @@ fn run(opts: Opts) -> Result<(), Error> {
     multilib.add_server(&server_url("multilib", "x86_64"))?;
     */
 
-    Ok(())
+    alpm.release()
 }
 
 /// Print all packages, and their disk usage, where packages have no reason field.
@@ -1,10 +1,14 @@
-//! Serde (de)serializers for the alpm database format.
+//! Serde (de)serializers for the alpm database format, and `Document` for working with desc
+//! files without a schema.
 
 pub mod de;
 mod de_error;
+mod document;
 pub mod ser;
 mod ser_error;
 
+pub use self::document::Document;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -36,4 +40,94 @@ mod tests {
             best_friend: ("Arthur".into(), 20),
         });
     }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Entry {
+        name: String,
+        description: Option<String>,
+        #[serde(default)]
+        depends: Vec<String>,
+    }
+
+    #[test]
+    fn sections() {
+        let entries = vec![
+            Entry {
+                name: "foo".into(),
+                description: None,
+                depends: vec!["bar".into(), "baz".into()],
+            },
+            Entry {
+                name: "bar".into(),
+                description: Some("A bar".into()),
+                depends: vec![],
+            },
+        ];
+        let serialized = ser::to_string(&entries).unwrap();
+        assert!(serialized.starts_with("%NAME%\nfoo\n\n%DESCRIPTION%\n\n%DEPENDS%\nbar\nbaz\n\n"));
+        assert!(serialized.contains("baz\n\n\n%NAME%\nbar\n\n"));
+        let deserialized: Vec<Entry> = de::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, entries);
+
+        // Without blank lines between sections, a repeated key starts the next one.
+        let raw = "%NAME%\nfoo\n\n%DEPENDS%\nbar\n\n%NAME%\nbar\n\n%DESCRIPTION%\nA bar\n\n";
+        let deserialized: Vec<Entry> = de::from_str(raw).unwrap();
+        assert_eq!(deserialized.len(), 2);
+        assert_eq!(deserialized[0].depends, vec!["bar".to_owned()]);
+        assert_eq!(deserialized[1].description.as_ref().unwrap(), "A bar");
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        use de::{Deserializer, DuplicateKeyPolicy};
+        use serde::Deserialize;
+
+        let raw = "%NAME%\nfoo\n\n%DEPENDS%\nbar\n\n%NAME%\nbaz\n\n%DEPENDS%\nqux\n\n";
+        let parse = |policy| {
+            let mut deserializer = Deserializer::from_str(raw).with_duplicate_key_policy(policy);
+            Entry::deserialize(&mut deserializer)
+                .map(|entry| (entry, deserializer.duplicate_keys().to_vec()))
+        };
+        match parse(DuplicateKeyPolicy::Error) {
+            Err(e) => assert_eq!(e.kind(), &de::ErrorKind::DuplicateKey("NAME".into())),
+            Ok(_) => panic!("expected an error"),
+        }
+        let (entry, duplicates) = parse(DuplicateKeyPolicy::FirstWins).unwrap();
+        assert_eq!(
+            (entry.name.as_str(), &entry.depends[..]),
+            ("foo", &["bar".to_owned()][..])
+        );
+        assert_eq!(duplicates, vec!["NAME".to_owned(), "DEPENDS".to_owned()]);
+        let (entry, _) = parse(DuplicateKeyPolicy::LastWins).unwrap();
+        assert_eq!(
+            (entry.name.as_str(), &entry.depends[..]),
+            ("baz", &["qux".to_owned()][..])
+        );
+        let (entry, _) = parse(DuplicateKeyPolicy::MergeLists).unwrap();
+        assert_eq!(entry.name, "foo");
+        assert_eq!(entry.depends, vec!["bar".to_owned(), "qux".to_owned()]);
+    }
+
+    #[test]
+    fn aliases() {
+        use de::{Deserializer, DuplicateKeyPolicy};
+        use serde::Deserialize;
+
+        let raw = "%PKGNAME%\nfoo\n\n%DEPS%\nbar\n\n";
+        let mut deserializer = Deserializer::from_str(raw)
+            .with_alias("pkgname", "name")
+            .with_aliases(vec![("DEPS", "DEPENDS")]);
+        let entry = Entry::deserialize(&mut deserializer).unwrap();
+        assert_eq!(entry.name, "foo");
+        assert_eq!(entry.depends, vec!["bar".to_owned()]);
+
+        // An alias and its field together are a repeated key.
+        let raw = "%NAME%\nfoo\n\n%PKGNAME%\nbar\n\n";
+        let mut deserializer = Deserializer::from_str(raw)
+            .with_alias("PKGNAME", "NAME")
+            .with_duplicate_key_policy(DuplicateKeyPolicy::LastWins);
+        let entry = Entry::deserialize(&mut deserializer).unwrap();
+        assert_eq!(entry.name, "bar");
+        assert_eq!(deserializer.duplicate_keys(), ["NAME".to_owned()]);
+    }
 }
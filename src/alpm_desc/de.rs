@@ -11,6 +11,14 @@
 //!
 //! ...
 //! ```
+//!
+//! A sequence of structs or maps is read as one section per element. A section ends at a blank
+//! line, or when a key it already has is repeated.
+//!
+//! Otherwise, what happens when a key is repeated is chosen with a `DuplicateKeyPolicy`.
+//!
+//! Keys are matched to struct fields ignoring ASCII case. Keys with other names can be read as a
+//! field by giving an alias (see `Deserializer::with_alias`).
 
 pub use super::de_error::{Error, ErrorKind, Result};
 
@@ -19,13 +27,41 @@ use serde::de::{
 };
 
 use std::fmt;
+use std::mem;
 use std::str::FromStr;
 
+/// What to do when a key appears more than once in a document.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum DuplicateKeyPolicy {
+    /// Fail with `ErrorKind::DuplicateKey`.
+    Error,
+    /// Use the first value, ignoring the rest.
+    FirstWins,
+    /// Use the last value, ignoring the rest.
+    LastWins,
+    /// Join the values of lists. Other values use the first.
+    MergeLists,
+}
+
+impl Default for DuplicateKeyPolicy {
+    fn default() -> Self {
+        DuplicateKeyPolicy::Error
+    }
+}
+
 /// A deserializer for the alpm db format.
 pub struct Deserializer<'de> {
     input: &'de str,
     line_ending: &'static str,
     double_line_ending: &'static str,
+    /// Whether we are reading one section of a sequence.
+    in_section: bool,
+    /// What to do when a key is repeated.
+    duplicate_key_policy: DuplicateKeyPolicy,
+    /// The keys that were repeated.
+    duplicate_keys: Vec<String>,
+    /// Keys to read as other keys or fields.
+    aliases: Vec<(String, &'static str)>,
 }
 
 impl<'de> Deserializer<'de> {
@@ -36,6 +72,10 @@ impl<'de> Deserializer<'de> {
             input,
             line_ending: "\r\n",
             double_line_ending: "\r\n\r\n", // concat! doesn't work
+            in_section: false,
+            duplicate_key_policy: DuplicateKeyPolicy::default(),
+            duplicate_keys: Vec::new(),
+            aliases: Vec::new(),
         }
     }
 
@@ -46,6 +86,10 @@ impl<'de> Deserializer<'de> {
             input,
             line_ending: "\n",
             double_line_ending: "\n\n",
+            in_section: false,
+            duplicate_key_policy: DuplicateKeyPolicy::default(),
+            duplicate_keys: Vec::new(),
+            aliases: Vec::new(),
         }
     }
 
@@ -59,9 +103,48 @@ impl<'de> Deserializer<'de> {
             input,
             line_ending,
             double_line_ending,
+            in_section: false,
+            duplicate_key_policy: DuplicateKeyPolicy::default(),
+            duplicate_keys: Vec::new(),
+            aliases: Vec::new(),
         }
     }
 
+    /// Choose what happens when a key appears more than once (the default is to fail).
+    ///
+    /// This doesn't apply to sequences of sections, where a repeated key starts the next section.
+    pub fn with_duplicate_key_policy(mut self, policy: DuplicateKeyPolicy) -> Self {
+        self.duplicate_key_policy = policy;
+        self
+    }
+
+    /// The keys that appeared more than once, in the order the repeats were found, so they can be
+    /// reported.
+    pub fn duplicate_keys(&self) -> &[String] {
+        &self.duplicate_keys
+    }
+
+    /// Read `key` as if it were `field`, for example to accept a key that has been renamed.
+    ///
+    /// `field` can be a struct field, or the key it is normally read from. Like fields, aliases are
+    /// matched ignoring ASCII case. A document with both the alias and the field has a repeated
+    /// key.
+    pub fn with_alias(mut self, key: impl Into<String>, field: &'static str) -> Self {
+        self.aliases.push((key.into(), field));
+        self
+    }
+
+    /// Add several aliases (see `with_alias`).
+    pub fn with_aliases<I, K>(mut self, aliases: I) -> Self
+    where
+        I: IntoIterator<Item = (K, &'static str)>,
+        K: Into<String>,
+    {
+        self.aliases
+            .extend(aliases.into_iter().map(|(key, field)| (key.into(), field)));
+        self
+    }
+
     // TODO implement `from_reader`
 
     /// Returns the next key, and consumes it.
@@ -77,6 +160,11 @@ impl<'de> Deserializer<'de> {
 
     /// Returns the next value, consuming it and the delimiter.
     fn parse_value(&mut self) -> Result<&'de str> {
+        // An empty value is just the blank line after the key.
+        if self.input.starts_with(self.line_ending) {
+            self.input = &self.input[self.line_ending.len()..];
+            return Ok("");
+        }
         match self.split_next_double_newline() {
             (ref line, Some(ref rest)) => {
                 self.input = rest;
@@ -89,6 +177,62 @@ impl<'de> Deserializer<'de> {
         }
     }
 
+    /// Read the entries of a map: the rest of the input, or the rest of the section.
+    ///
+    /// Repeated keys are handled according to the duplicate key policy.
+    fn read_entries(&mut self) -> Result<Vec<(&'de str, Vec<&'de str>)>> {
+        let mut entries: Vec<(&'de str, Vec<&'de str>)> = Vec::new();
+        while self.input.trim().len() != 0 {
+            // a section ends at a blank line, or a key it already has
+            if self.in_section && self.input.starts_with(self.line_ending) {
+                break;
+            }
+            let before = self.input;
+            let key = self.parse_key()?;
+            let key = self.resolve_alias(key);
+            let existing = entries
+                .iter()
+                .position(|(seen, _)| seen.eq_ignore_ascii_case(key));
+            if self.in_section && existing.is_some() {
+                self.input = before;
+                break;
+            }
+            let value = self.parse_value()?;
+            let idx = match existing {
+                Some(idx) => idx,
+                None => {
+                    entries.push((key, vec![value]));
+                    continue;
+                }
+            };
+            match self.duplicate_key_policy {
+                DuplicateKeyPolicy::Error => {
+                    return Err(ErrorKind::DuplicateKey(key.to_owned()).into())
+                }
+                DuplicateKeyPolicy::FirstWins => (),
+                DuplicateKeyPolicy::LastWins => entries[idx].1 = vec![value],
+                DuplicateKeyPolicy::MergeLists => entries[idx].1.push(value),
+            }
+            self.duplicate_keys.push(key.to_owned());
+        }
+        Ok(entries)
+    }
+
+    /// The key or field `key` is read as.
+    fn resolve_alias(&self, key: &'de str) -> &'de str {
+        self.aliases
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(key))
+            .map_or(key, |&(_, field)| field)
+    }
+
+    /// Consume any blank lines.
+    fn skip_blank_lines(&mut self) {
+        while self.input.starts_with(self.line_ending) {
+            self.input = &self.input[self.line_ending.len()..];
+        }
+    }
+
     /// Returns all the input up to the next newline
     ///
     /// Returns `(<current line without newline>, Some(<everything after the newline>))` if a
@@ -262,12 +406,15 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         visitor.visit_newtype_struct(self)
     }
 
-    fn deserialize_seq<V>(self, _visitor: V) -> Result<V::Value>
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        // can only deserialize structures at root
-        Err(ErrorKind::Unsupported("seq").into())
+        // sections can't be nested
+        if self.in_section {
+            return Err(ErrorKind::Unsupported("seq").into());
+        }
+        visitor.visit_seq(AlpmSections::new(self))
     }
 
     fn deserialize_tuple<V>(self, _len: usize, _visitor: V) -> Result<V::Value>
@@ -291,15 +438,15 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         Err(ErrorKind::Unsupported("TupleStruct(..)").into())
     }
 
-    fn deserialize_map<V>(mut self, visitor: V) -> Result<V::Value>
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_map(AlpmMap::new(&mut self, &[]))
+        visitor.visit_map(AlpmMap::new(self, &[])?)
     }
 
     fn deserialize_struct<V>(
-        mut self,
+        self,
         _name: &'static str,
         fields: &'static [&'static str],
         visitor: V,
@@ -315,7 +462,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
                 }
             }
         }
-        visitor.visit_map(AlpmMap::new(&mut self, fields))
+        visitor.visit_map(AlpmMap::new(self, fields)?)
     }
 
     fn deserialize_enum<V>(
@@ -346,30 +493,41 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     }
 }
 
-struct AlpmMap<'a, 'de: 'a> {
-    de: &'a mut Deserializer<'de>,
+struct AlpmMap<'de> {
+    line_ending: &'static str,
     fields: &'static [&'static str],
+    /// The entries, with repeated keys already dealt with.
+    entries: std::vec::IntoIter<(&'de str, Vec<&'de str>)>,
+    /// The values for the key just returned.
+    values: Vec<&'de str>,
 }
 
-impl<'a, 'de> AlpmMap<'a, 'de> {
-    fn new(de: &'a mut Deserializer<'de>, fields: &'static [&'static str]) -> Self {
-        AlpmMap { de, fields }
+impl<'de> AlpmMap<'de> {
+    fn new(de: &mut Deserializer<'de>, fields: &'static [&'static str]) -> Result<Self> {
+        Ok(AlpmMap {
+            line_ending: de.line_ending,
+            fields,
+            entries: de.read_entries()?.into_iter(),
+            values: Vec::new(),
+        })
     }
 }
 
-impl<'a, 'de> MapAccess<'de> for AlpmMap<'a, 'de> {
+impl<'de> MapAccess<'de> for AlpmMap<'de> {
     type Error = Error;
 
     fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
     where
         K: DeserializeSeed<'de>,
     {
-        // if we're at the end of input we're done
-        if self.de.input.trim().len() == 0 {
-            return Ok(None);
-        }
+        let mut key = match self.entries.next() {
+            Some((key, values)) => {
+                self.values = values;
+                key
+            }
+            None => return Ok(None),
+        };
         // if there is a struct field that matches case-insensitively, use that instead.
-        let mut key = self.de.parse_key()?;
         for field in self.fields {
             if field.eq_ignore_ascii_case(key) {
                 key = &field;
@@ -379,7 +537,8 @@ impl<'a, 'de> MapAccess<'de> for AlpmMap<'a, 'de> {
         seed.deserialize(DeserializerInner {
             input: &key,
             allow_list: false,
-            line_ending: self.de.line_ending,
+            line_ending: self.line_ending,
+            merged: Vec::new(),
         })
         .map(Some)
     }
@@ -388,24 +547,60 @@ impl<'a, 'de> MapAccess<'de> for AlpmMap<'a, 'de> {
     where
         V: DeserializeSeed<'de>,
     {
-        let value = self.de.parse_value()?;
+        let mut values = mem::replace(&mut self.values, Vec::new()).into_iter();
         seed.deserialize(DeserializerInner {
-            input: value,
+            input: values.next().unwrap_or(""),
             allow_list: true,
-            line_ending: self.de.line_ending,
+            line_ending: self.line_ending,
+            merged: values.collect(),
         })
     }
 }
 
+/// The sections of a document, each deserialized as a struct or map.
+struct AlpmSections<'a, 'de: 'a> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'a, 'de> AlpmSections<'a, 'de> {
+    fn new(de: &'a mut Deserializer<'de>) -> Self {
+        AlpmSections { de }
+    }
+}
+
+impl<'a, 'de> SeqAccess<'de> for AlpmSections<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        self.de.skip_blank_lines();
+        if self.de.input.trim().len() == 0 {
+            return Ok(None);
+        }
+        self.de.in_section = true;
+        let element = seed.deserialize(&mut *self.de);
+        self.de.in_section = false;
+        element.map(Some)
+    }
+}
+
 struct DeserializerInner<'de> {
     input: &'de str,
     allow_list: bool,
     line_ending: &'static str,
+    /// The values of repeated keys, which lists carry on into (see `DuplicateKeyPolicy`).
+    merged: Vec<&'de str>,
 }
 
 impl<'de> DeserializerInner<'de> {
     /// Returns the next element in a sequence
     fn parse_seq_element(&mut self) -> &'de str {
+        // carry on into the next merged value when this one is used up
+        while self.input.is_empty() && !self.merged.is_empty() {
+            self.input = self.merged.remove(0);
+        }
         match self.input.find(self.line_ending) {
             Some(newline_pos) => {
                 let value = &self.input[..newline_pos];
@@ -745,6 +940,7 @@ impl<'a, 'de> SeqAccess<'de> for AlpmSeq<'a, 'de> {
                 input: element,
                 allow_list: false,
                 line_ending: self.de.line_ending,
+                merged: Vec::new(),
             })?)
         })
     }
@@ -26,6 +26,8 @@ pub enum ErrorKind {
     ExpectedKey,
     /// The deserializer expected an empty string
     ExpectedEmpty,
+    /// A key appeared more than once (see `DuplicateKeyPolicy`).
+    DuplicateKey(String),
     /// A Serialize method returned a custom error.
     Custom(String),
 }
@@ -46,6 +48,7 @@ impl fmt::Display for Error {
             ErrorKind::ExpectedChar => write!(f, "expected a char"),
             ErrorKind::ExpectedKey => write!(f, "expected a key (e.g. `%NAME%`)"),
             ErrorKind::ExpectedEmpty => write!(f, "expected an empty string"),
+            ErrorKind::DuplicateKey(key) => write!(f, "the key `%{}%` appears more than once", key),
             ErrorKind::Custom(msg) => {
                 write!(f, "the type being deserialized reported an error: {}", msg)
             }
@@ -0,0 +1,271 @@
+//! A desc file as an ordered list of keys and values, for reading and patching files without
+//! defining a type for their schema.
+//!
+//! Each entry is a key, and the lines following it up to the next blank line:
+//!
+//! ```text
+//! %NAME%
+//! foo
+//!
+//! %DEPENDS%
+//! bar
+//! baz>=2
+//!
+//! ```
+//!
+//! Keys are looked up ignoring ASCII case, as the deserializer does, but written as they were
+//! given. Entries keep their order, so parsing and writing a file only changes what was changed.
+
+use std::{fmt, str::FromStr};
+
+use super::de::{Error, ErrorKind, Result};
+
+/// The keys and values of a desc file, in order.
+///
+/// # Examples
+///
+/// ```
+/// # use alpm::alpm_desc::Document;
+/// let mut doc: Document = "%NAME%\nfoo\n\n%SIZE%\n1024\n\n".parse().unwrap();
+/// assert_eq!(doc.get_str("name"), Some("foo"));
+/// assert_eq!(doc.get_parsed::<u64>("SIZE").unwrap(), Some(1024));
+/// doc.push("DEPENDS", "bar");
+/// assert_eq!(doc.to_string(), "%NAME%\nfoo\n\n%SIZE%\n1024\n\n%DEPENDS%\nbar\n\n");
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Document {
+    entries: Vec<(String, Vec<String>)>,
+}
+
+impl Document {
+    /// An empty document.
+    pub fn new() -> Document {
+        Document::default()
+    }
+
+    /// Parse a document.
+    ///
+    /// Fails with `ErrorKind::ExpectedKey` if a line that should be a key (the first line, and
+    /// each line after a blank line) isn't one. Extra blank lines between entries are allowed, and
+    /// `\r\n` line endings are accepted.
+    pub fn parse(input: &str) -> Result<Document> {
+        let mut doc = Document::new();
+        let mut current: Option<(String, Vec<String>)> = None;
+        for line in input.lines() {
+            if line.is_empty() {
+                doc.entries.extend(current.take());
+            } else if let Some((_, values)) = current.as_mut() {
+                values.push(line.to_owned());
+            } else {
+                current = Some((parse_key(line)?.to_owned(), Vec::new()));
+            }
+        }
+        doc.entries.extend(current);
+        Ok(doc)
+    }
+
+    /// The number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The keys, in order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(key, _)| key.as_str())
+    }
+
+    /// The entries, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.entries
+            .iter()
+            .map(|(key, values)| (key.as_str(), values.as_slice()))
+    }
+
+    /// Whether there is an entry for `key`.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.position(key).is_some()
+    }
+
+    /// The values for `key`.
+    ///
+    /// If the key appears more than once, this is the first entry.
+    pub fn get(&self, key: &str) -> Option<&[String]> {
+        self.position(key).map(|idx| self.entries[idx].1.as_slice())
+    }
+
+    /// The first value for `key`.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+
+    /// The first value for `key`, parsed.
+    pub fn get_parsed<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.get_str(key) {
+            Some(value) => parse_value(key, value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Every value for `key`, parsed, or an empty list if there is no entry.
+    pub fn get_list_parsed<T>(&self, key: &str) -> Result<Vec<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get(key)
+            .unwrap_or(&[])
+            .iter()
+            .map(|value| parse_value(key, value))
+            .collect()
+    }
+
+    /// Set the values for `key`, replacing the existing entry in place or adding one at the end.
+    ///
+    /// Empty values are dropped, as they can't be written.
+    pub fn set<I>(&mut self, key: &str, values: I)
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let values = values
+            .into_iter()
+            .map(Into::into)
+            .filter(|value: &String| !value.is_empty())
+            .collect();
+        match self.position(key) {
+            Some(idx) => self.entries[idx].1 = values,
+            None => self.entries.push((key.to_owned(), values)),
+        }
+    }
+
+    /// Set `key` to a single value, formatted with `Display`.
+    pub fn set_value(&mut self, key: &str, value: impl fmt::Display) {
+        self.set(key, Some(value.to_string()))
+    }
+
+    /// Add a value to the end of the entry for `key`, adding the entry if there isn't one.
+    ///
+    /// An empty value is dropped, as it can't be written.
+    pub fn push(&mut self, key: &str, value: impl Into<String>) {
+        let value = value.into();
+        match self.position(key) {
+            Some(idx) if !value.is_empty() => self.entries[idx].1.push(value),
+            Some(_) => (),
+            None => self.set(key, Some(value)),
+        }
+    }
+
+    /// Remove the entry for `key`, returning its values.
+    pub fn remove(&mut self, key: &str) -> Option<Vec<String>> {
+        self.position(key).map(|idx| self.entries.remove(idx).1)
+    }
+
+    /// The index of the first entry for `key`.
+    fn position(&self, key: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(key))
+    }
+}
+
+impl FromStr for Document {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Document> {
+        Document::parse(input)
+    }
+}
+
+/// Writes the document in the desc format, each entry followed by a blank line.
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (key, values) in &self.entries {
+            writeln!(f, "%{}%", key)?;
+            for value in values {
+                writeln!(f, "{}", value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The name in a key line, `%NAME%`.
+fn parse_key(line: &str) -> Result<&str> {
+    let line = line.trim_end();
+    if line.len() > 2 && line.starts_with('%') && line.ends_with('%') {
+        let key = &line[1..line.len() - 1];
+        if !key.contains('%') {
+            return Ok(key);
+        }
+    }
+    Err(ErrorKind::ExpectedKey.into())
+}
+
+/// Parse a value, saying which key it was for if it is invalid.
+fn parse_value<T>(key: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| ErrorKind::Custom(format!("%{}%: \"{}\": {}", key, value, e)).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_write() {
+        let raw = "%NAME%\nfoo\n\n%DEPENDS%\nbar\nbaz>=2\n\n%EMPTY%\n\n";
+        let doc = Document::parse(raw).unwrap();
+        assert_eq!(doc.keys().collect::<Vec<_>>(), ["NAME", "DEPENDS", "EMPTY"]);
+        assert_eq!(doc.get("depends").unwrap(), ["bar", "baz>=2"]);
+        assert_eq!(doc.get("EMPTY").unwrap().len(), 0);
+        assert_eq!(doc.to_string(), raw);
+
+        let crlf = Document::parse("%NAME%\r\nfoo\r\n\r\n\r\n%SIZE%\r\n1\r\n").unwrap();
+        assert_eq!(crlf.get_str("NAME"), Some("foo"));
+        assert_eq!(crlf.get_parsed::<u8>("SIZE").unwrap(), Some(1));
+
+        assert!(Document::parse("foo\n").is_err());
+        assert!(Document::parse("%NAME%\nfoo\n\nbar\n").is_err());
+    }
+
+    #[test]
+    fn typed_values() {
+        let doc = Document::parse("%SIZE%\n12x\n\n%NUMS%\n1\n2\n\n").unwrap();
+        assert!(doc.get_parsed::<u64>("SIZE").is_err());
+        assert_eq!(doc.get_parsed::<u64>("MISSING").unwrap(), None);
+        assert_eq!(doc.get_list_parsed::<u8>("NUMS").unwrap(), [1, 2]);
+        assert!(doc.get_list_parsed::<u8>("MISSING").unwrap().is_empty());
+    }
+
+    #[test]
+    fn patch() {
+        let mut doc = Document::parse("%NAME%\nfoo\n\n%VERSION%\n1-1\n\n").unwrap();
+        doc.set_value("name", "bar");
+        doc.push("GROUPS", "base");
+        doc.push("GROUPS", "");
+        doc.set("VERSION", vec!["2-1", ""]);
+        assert_eq!(doc.remove("groups"), Some(vec!["base".to_owned()]));
+        doc.set("REPLACES", Vec::<String>::new());
+        assert_eq!(
+            doc.to_string(),
+            "%NAME%\nbar\n\n%VERSION%\n2-1\n\n%REPLACES%\n\n"
+        );
+    }
+}
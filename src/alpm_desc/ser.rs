@@ -11,6 +11,9 @@
 //!
 //! ...
 //! ```
+//!
+//! A sequence of structs or maps is written as one section per element, with a blank line
+//! between sections.
 use std::io::Write;
 
 use serde::ser::{self, Serialize};
@@ -22,6 +25,21 @@ pub use super::ser_error::{Error, ErrorKind, Result};
 pub struct Serializer<W: Write> {
     /// The writer we will serialize to.
     writer: W,
+    /// Whether we are serializing a sequence of sections (they can't be nested).
+    in_sections: bool,
+    /// The number of sections written so far.
+    sections: usize,
+}
+
+impl<W: Write> Serializer<W> {
+    /// Create a serializer writing to `writer`.
+    fn new(writer: W) -> Self {
+        Serializer {
+            writer,
+            in_sections: false,
+            sections: 0,
+        }
+    }
 }
 
 /// Serialize the given value to a string in the alpm db format.
@@ -31,13 +49,11 @@ where
 {
     let mut output: Vec<u8> = Vec::new();
     {
-        let mut serializer = Serializer {
-            writer: &mut output,
-        };
+        let mut serializer = Serializer::new(&mut output);
         value.serialize(&mut serializer)?;
     }
     // Our format is all valid utf8 - so we could probably use _unchecked, but safety first :)
-    Ok(String::from_utf8(output).unwrap())
+    String::from_utf8(output).map_err(ser::Error::custom)
 }
 
 /// Serialize the given value to the given writer in the alpm db format.
@@ -46,7 +62,7 @@ where
     W: Write,
     T: Serialize,
 {
-    let mut serializer = Serializer { writer };
+    let mut serializer = Serializer::new(writer);
     value.serialize(&mut serializer)
 }
 
@@ -81,7 +97,7 @@ where
     type Ok = ();
     type Error = Error;
 
-    type SerializeSeq = ser::Impossible<(), Error>;
+    type SerializeSeq = Self;
     type SerializeTuple = ser::Impossible<(), Error>;
     type SerializeTupleStruct = ser::Impossible<(), Error>;
     type SerializeTupleVariant = ser::Impossible<(), Error>;
@@ -214,9 +230,14 @@ where
         Err(ErrorKind::Unsupported.into())
     }
 
-    // only keyed maps are supported at root
+    // a section per element, which must be keyed maps
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        Err(ErrorKind::Unsupported.into())
+        if self.in_sections {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        self.in_sections = true;
+        self.sections = 0;
+        Ok(self)
     }
 
     // only keyed maps are supported at root
@@ -265,6 +286,30 @@ where
     }
 }
 
+impl<'a, W> ser::SerializeSeq for &'a mut Serializer<W>
+where
+    W: Write,
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+    {
+        if self.sections > 0 {
+            write!(self.writer, "\n")?;
+        }
+        self.sections += 1;
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.in_sections = false;
+        Ok(())
+    }
+}
+
 impl<'a, W> ser::SerializeMap for &'a mut Serializer<W>
 where
     W: Write,
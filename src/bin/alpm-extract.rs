@@ -0,0 +1,8 @@
+//! Extracts a package archive beneath a root, in a sandbox that can only write beneath the root.
+//!
+//! Run by the library when `AlpmBuilder::with_extraction_sandbox` is set; not meant to be run by
+//! hand.
+
+fn main() {
+    std::process::exit(alpm::extraction_helper_main());
+}
@@ -0,0 +1,49 @@
+//! What this build of the library can do, like `alpm_capabilities` in libalpm.
+
+use crate::db::{Compression, LOCAL_DB_CURRENT_VERSION};
+
+/// Every compression a sync database can be found with.
+const COMPRESSIONS: &[Compression] = &[
+    Compression::None,
+    Compression::Gzip,
+    Compression::Zstd,
+    Compression::Xz,
+    Compression::Bzip2,
+];
+
+/// The features of this build of the library, from `capabilities`.
+///
+/// Frontends can use this to hide options that won't work, rather than finding out when they
+/// fail.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Capabilities {
+    /// The version of this crate.
+    pub version: &'static str,
+    /// The compressions that sync databases can be read with.
+    ///
+    /// Zstd and xz support are enabled with the `zstd` and `xz2` crate features.
+    pub compressions: Vec<Compression>,
+    /// Whether package extraction can be sandboxed (the `sandbox` crate feature).
+    pub sandbox: bool,
+    /// Whether package and database signatures are checked.
+    ///
+    /// This is not implemented yet, so it is always `false`.
+    pub signature_verification: bool,
+    /// The version of the local database layout that is read and written.
+    pub local_database_version: u64,
+}
+
+/// Report what this build of the library supports.
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        compressions: COMPRESSIONS
+            .iter()
+            .cloned()
+            .filter(|compression| compression.is_supported())
+            .collect(),
+        sandbox: cfg!(feature = "sandbox"),
+        signature_verification: false,
+        local_database_version: LOCAL_DB_CURRENT_VERSION,
+    }
+}
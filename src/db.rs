@@ -1,15 +1,33 @@
 //! Functionality relating to alpm databases (local and sync).
 
-use crate::error::Error;
-use std::path::PathBuf;
+use crate::{
+    alpm_desc::{
+        de::{self, DuplicateKeyPolicy},
+        Document,
+    },
+    diagnostics::{self, Warning},
+    error::Error,
+    Handle,
+};
+use serde::Deserialize;
+use std::{cell::RefCell, fmt, ops::Deref, path::PathBuf, rc::Weak};
 
+mod dynamic;
 mod local;
 mod sync;
 
-pub(crate) use self::local::LocalDatabaseInner;
-pub use self::local::{InstallReason, LocalDatabase, LocalPackage, ValidationError};
-pub use self::sync::{SyncDatabase, SyncPackage};
-pub(crate) use self::sync::{SyncDatabaseInner, SyncDbName};
+pub use self::dynamic::DynDatabase;
+pub(crate) use self::local::{validate_all, LocalDatabaseInner, LOCAL_DB_CURRENT_VERSION};
+pub use self::local::{
+    BackupFile, BrokenEntry, DiskUsageAccounting, DiskUsageReport, DuplicatePolicy, EntryProblem,
+    FileEntry, FileType, InstallReason, LocalDatabase, LocalPackage, PermissionDrift, Provenance,
+    ValidationCounts, ValidationError, ValidationExclusions, ValidationReport, ValidationSummary,
+};
+pub(crate) use self::sync::{is_valid_db_extension, open_archive, SyncDatabaseInner, SyncDbName};
+pub use self::sync::{
+    Compression, DbFormat, Delta, FileMatch, SyncAttempt, SyncDatabase, SyncDatabaseOptions,
+    SyncDatabaseRef, SyncGroup, SyncMetadata, SyncOutcome, SyncPackage,
+};
 
 /// The name of the directory for sync databases.
 pub(crate) const SYNC_DB_DIR: &str = "sync";
@@ -44,22 +62,121 @@ pub trait Database {
         Str: AsRef<str>;
 
     /// Run a callback on all packages in the database.
+    ///
+    /// Packages are visited in order of name (and then version), so the order is the same between
+    /// runs.
     fn packages<E, F>(&self, f: F) -> Result<(), E>
     where
         F: FnMut(Self::Pkg) -> Result<(), E>,
         E: From<Error>;
 }
 
+/// A package from a sync database, together with the database it was found in.
+///
+/// Operations that look through several databases return these, so callers don't have to search
+/// again to find where a package came from.
+#[derive(Debug, Clone)]
+pub struct Located<P> {
+    /// The name of the database the package was found in.
+    pub db_name: String,
+    /// The database the package was found in.
+    pub database: SyncDatabase,
+    /// The package.
+    pub package: P,
+}
+
+impl<P> Located<P> {
+    pub(crate) fn new(database: SyncDatabase, package: P) -> Self {
+        Located {
+            db_name: database.name().to_owned(),
+            database,
+            package,
+        }
+    }
+}
+
+impl<P> Deref for Located<P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        &self.package
+    }
+}
+
 /// The response from checking the status of a database.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum DbStatus {
     /// The database is not present.
     Missing,
     /// The database is present but invalid.
-    Invalid,
+    Invalid {
+        /// Everything that was found to be wrong with the database.
+        reasons: Vec<InvalidReason>,
+    },
     /// The database is present and valid.
     Valid,
 }
+
+impl DbStatus {
+    /// Create a status from a list of problems, valid if the list is empty.
+    pub(crate) fn from_reasons(reasons: Vec<InvalidReason>) -> DbStatus {
+        if reasons.is_empty() {
+            DbStatus::Valid
+        } else {
+            DbStatus::Invalid { reasons }
+        }
+    }
+
+    /// Is the database present and valid.
+    pub fn is_valid(&self) -> bool {
+        *self == DbStatus::Valid
+    }
+}
+
+/// The reasons a database can be invalid.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum InvalidReason {
+    /// The database should be a directory, but it isn't (local database).
+    NotADirectory,
+    /// The database should be a file, but it isn't (sync databases).
+    NotAFile,
+    /// The database is a different version to the one this library supports.
+    WrongVersion { found: u64, expected: u64 },
+    /// The database has no version file, and it could not be created.
+    MissingVersionFile,
+    /// The version file could not be read or understood.
+    BadVersionFile(String),
+    /// The database archive could not be read.
+    BadArchive(String),
+    /// The database signature could not be verified.
+    BadSignature(String),
+}
+
+impl fmt::Display for InvalidReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidReason::NotADirectory => f.write_str("the database is not a directory"),
+            InvalidReason::NotAFile => f.write_str("the database is not a file"),
+            InvalidReason::WrongVersion { found, expected } => write!(
+                f,
+                "the database is version {}, but version {} is required",
+                found, expected
+            ),
+            InvalidReason::MissingVersionFile => {
+                f.write_str("the database version file is missing and could not be created")
+            }
+            InvalidReason::BadVersionFile(msg) => {
+                write!(f, "the database version file is not valid: {}", msg)
+            }
+            InvalidReason::BadArchive(msg) => {
+                write!(f, "the database archive could not be read: {}", msg)
+            }
+            InvalidReason::BadSignature(msg) => {
+                write!(f, "the database signature is not valid: {}", msg)
+            }
+        }
+    }
+}
 /*
 bitflags! {
     pub struct DbStatus: u32 {
@@ -110,6 +227,96 @@ impl Default for SignatureLevel {
     }
 }
 
+/// A deserializer for a desc-format file, dealing with repeated keys and aliases as configured.
+fn desc_deserializer<'a>(raw: &'a str, handle: &Weak<RefCell<Handle>>) -> de::Deserializer<'a> {
+    let deserializer = de::Deserializer::from_str(raw);
+    match handle.upgrade() {
+        Some(handle) => {
+            let handle = handle.borrow();
+            deserializer
+                .with_duplicate_key_policy(handle.duplicate_key_policy)
+                .with_aliases(handle.desc_aliases.iter().cloned())
+        }
+        None => deserializer.with_duplicate_key_policy(DuplicateKeyPolicy::MergeLists),
+    }
+}
+
+/// Parse a desc-format file of `package`, dealing with repeated keys and aliases as configured,
+/// and warning about repeated keys.
+pub(crate) fn parse_desc<'a, T>(
+    raw: &'a str,
+    package: &str,
+    handle: &Weak<RefCell<Handle>>,
+) -> Result<T, de::Error>
+where
+    T: Deserialize<'a>,
+{
+    let (parsed, duplicate_keys) = parse_desc_quietly(raw, handle)?;
+    for key in duplicate_keys {
+        diagnostics::warn(
+            handle,
+            Warning::DuplicateDescKey {
+                package: package.to_owned(),
+                key,
+            },
+        );
+    }
+    Ok(parsed)
+}
+
+/// Like `parse_desc`, but return the repeated keys rather than warning about them, for files that
+/// have been warned about already.
+pub(crate) fn parse_desc_quietly<'a, T>(
+    raw: &'a str,
+    handle: &Weak<RefCell<Handle>>,
+) -> Result<(T, Vec<String>), de::Error>
+where
+    T: Deserialize<'a>,
+{
+    let mut deserializer = desc_deserializer(raw, handle);
+    let parsed = T::deserialize(&mut deserializer)?;
+    Ok((parsed, deserializer.duplicate_keys().to_vec()))
+}
+
+/// The values of `key` in a desc-format file as written, for `Package::raw_field`.
+///
+/// Aliases and repeated keys are handled as configured, as `desc_deserializer` does, except that
+/// `DuplicateKeyPolicy::MergeLists` joins the values of every repeat, since which keys are lists
+/// isn't known here. Files that can't be parsed have no fields.
+pub(crate) fn raw_desc_field(
+    raw: &str,
+    key: &str,
+    handle: &Weak<RefCell<Handle>>,
+) -> Option<Vec<String>> {
+    let doc = Document::parse(raw).ok()?;
+    let handle = handle.upgrade();
+    let handle = handle.as_ref().map(|handle| handle.borrow());
+    let (policy, aliases) = match &handle {
+        Some(handle) => (handle.duplicate_key_policy, &handle.desc_aliases[..]),
+        None => (DuplicateKeyPolicy::MergeLists, &[][..]),
+    };
+    let mut found: Option<Vec<String>> = None;
+    for (entry_key, values) in doc.iter() {
+        let read_as = aliases
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(entry_key))
+            .map_or(entry_key, |&(_, as_key)| as_key);
+        if !read_as.eq_ignore_ascii_case(key) {
+            continue;
+        }
+        match found {
+            None => found = Some(values.to_vec()),
+            Some(ref mut found) => match policy {
+                // The package couldn't have been read with a repeated key.
+                DuplicateKeyPolicy::Error | DuplicateKeyPolicy::FirstWins => (),
+                DuplicateKeyPolicy::LastWins => *found = values.to_vec(),
+                DuplicateKeyPolicy::MergeLists => found.extend_from_slice(values),
+            },
+        }
+    }
+    found
+}
+
 /// If the name has at least 2 hyphens ('-'), split at the second from last
 fn split_package_dirname(input: &str) -> Option<(&str, &str)> {
     let idx = input.rmatch_indices('-').skip(1).next()?.0;
@@ -136,16 +343,45 @@ mod tests {
 
     #[test(ignore)]
     fn db_path() {
-        let base_path = "/var/lib/pacman/";
-        let base_path2 = "/var/lib/pacman";
+        let base_path = "/var/lib/pacman/sync/";
+        let base_path2 = "/var/lib/pacman/sync";
         let ext = "db";
 
         let tests = vec![("sync1", "/var/lib/pacman/sync/sync1.db")];
         for (db_name, target) in tests {
             let db_name = SyncDbName::new(db_name).unwrap();
             let target = Path::new(target);
-            assert_eq!(db_name.path(&base_path), target);
-            assert_eq!(db_name.path(&base_path2), target);
+            assert_eq!(db_name.path(&base_path, ext), target);
+            assert_eq!(db_name.path(&base_path2, ext), target);
         }
     }
+
+    #[test]
+    fn raw_fields() {
+        let raw = "%NAME%\nfoo\n\n%DEPENDS%\nbar\n\n%OLDDEPENDS%\nbaz\n\n%DEPENDS%\nqux\n\n";
+        assert_eq!(
+            raw_desc_field(raw, "depends", &Weak::new()),
+            Some(vec!["bar".to_owned(), "qux".to_owned()])
+        );
+        assert_eq!(raw_desc_field(raw, "MISSING", &Weak::new()), None);
+
+        let root = tempfile::tempdir().unwrap();
+        let db_path = root.path().join("db");
+        std::fs::create_dir_all(db_path.join(SYNC_DB_DIR)).unwrap();
+        let alpm = crate::Alpm::new()
+            .with_root_path(root.path())
+            .with_database_path(&db_path)
+            .with_architecture("x86_64")
+            .with_read_only(true)
+            .with_duplicate_key_policy(DuplicateKeyPolicy::LastWins)
+            .with_desc_alias("OLDDEPENDS", "DEPENDS")
+            .build()
+            .unwrap();
+        let handle = std::rc::Rc::downgrade(&alpm.handle);
+        assert_eq!(
+            raw_desc_field(raw, "DEPENDS", &handle),
+            Some(vec!["qux".to_owned()])
+        );
+        assert_eq!(raw_desc_field(raw, "OLDDEPENDS", &handle), None);
+    }
 }
@@ -0,0 +1,182 @@
+//! A wrapper that hides what kind of database it holds.
+//!
+//! `Database` has generic methods and an associated package type, so it can't be used as a trait
+//! object. `DynDatabase` can, at the cost of returning packages as `Rc<dyn Package>`.
+
+use std::{fmt, path::PathBuf, rc::Rc};
+
+use crate::{
+    db::{Database, DbStatus, LocalDatabase, SyncDatabase},
+    error::Error,
+    package::Package,
+};
+
+/// Any database, local or sync, with the package type erased.
+///
+/// This lets databases of different kinds be handled together, for example in a
+/// `Vec<DynDatabase>` (see `Alpm::databases`). It implements `Database` itself, with packages of
+/// type `Rc<dyn Package>`.
+///
+/// Clones are shallow, like the databases themselves.
+#[derive(Clone)]
+pub struct DynDatabase {
+    inner: Rc<dyn ErasedDatabase>,
+}
+
+impl DynDatabase {
+    /// Wrap a database.
+    pub fn new<D, P>(database: D) -> DynDatabase
+    where
+        D: Database<Pkg = Rc<P>> + 'static,
+        P: Package + 'static,
+    {
+        DynDatabase {
+            inner: Rc::new(database),
+        }
+    }
+}
+
+impl From<LocalDatabase> for DynDatabase {
+    fn from(database: LocalDatabase) -> DynDatabase {
+        DynDatabase::new(database)
+    }
+}
+
+impl From<SyncDatabase> for DynDatabase {
+    fn from(database: SyncDatabase) -> DynDatabase {
+        DynDatabase::new(database)
+    }
+}
+
+impl fmt::Debug for DynDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DynDatabase")
+            .field("name", &self.inner.name())
+            .finish()
+    }
+}
+
+impl Database for DynDatabase {
+    type Pkg = Rc<dyn Package>;
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn path(&self) -> PathBuf {
+        self.inner.path()
+    }
+
+    fn status(&self) -> Result<DbStatus, Error> {
+        self.inner.status()
+    }
+
+    fn count(&self) -> usize {
+        self.inner.count()
+    }
+
+    fn package(&self, name: impl AsRef<str>, version: impl AsRef<str>) -> Result<Self::Pkg, Error> {
+        self.inner.package(name.as_ref(), version.as_ref())
+    }
+
+    fn package_latest<Str>(&self, name: Str) -> Result<Self::Pkg, Error>
+    where
+        Str: AsRef<str>,
+    {
+        self.inner.package_latest(name.as_ref())
+    }
+
+    fn packages<E, F>(&self, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(Self::Pkg) -> Result<(), E>,
+        E: From<Error>,
+    {
+        // The callback's error type can't cross the trait object, so it is carried around it.
+        let mut callback_error = None;
+        self.inner
+            .packages(&mut |package: Rc<dyn Package>| match f(package) {
+                Ok(()) => true,
+                Err(e) => {
+                    callback_error = Some(e);
+                    false
+                }
+            })?;
+        match callback_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The object-safe version of `Database`.
+trait ErasedDatabase {
+    fn name(&self) -> &str;
+    fn path(&self) -> PathBuf;
+    fn status(&self) -> Result<DbStatus, Error>;
+    fn count(&self) -> usize;
+    fn package(&self, name: &str, version: &str) -> Result<Rc<dyn Package>, Error>;
+    fn package_latest(&self, name: &str) -> Result<Rc<dyn Package>, Error>;
+    /// Visit packages until the callback returns `false`.
+    fn packages(&self, f: &mut dyn FnMut(Rc<dyn Package>) -> bool) -> Result<(), Error>;
+}
+
+/// Why `ErasedDatabase::packages` stopped before the end.
+enum Stop {
+    /// The callback asked to stop.
+    Done,
+    /// The database couldn't be read.
+    Failed(Error),
+}
+
+impl From<Error> for Stop {
+    fn from(e: Error) -> Stop {
+        Stop::Failed(e)
+    }
+}
+
+impl<D, P> ErasedDatabase for D
+where
+    D: Database<Pkg = Rc<P>>,
+    P: Package + 'static,
+{
+    fn name(&self) -> &str {
+        Database::name(self)
+    }
+
+    fn path(&self) -> PathBuf {
+        Database::path(self)
+    }
+
+    fn status(&self) -> Result<DbStatus, Error> {
+        Database::status(self)
+    }
+
+    fn count(&self) -> usize {
+        Database::count(self)
+    }
+
+    fn package(&self, name: &str, version: &str) -> Result<Rc<dyn Package>, Error> {
+        let package: Rc<dyn Package> = Database::package(self, name, version)?;
+        Ok(package)
+    }
+
+    fn package_latest(&self, name: &str) -> Result<Rc<dyn Package>, Error> {
+        let package: Rc<dyn Package> = Database::package_latest(self, name)?;
+        Ok(package)
+    }
+
+    fn packages(&self, f: &mut dyn FnMut(Rc<dyn Package>) -> bool) -> Result<(), Error> {
+        let result = Database::packages(self, |package: Rc<P>| {
+            let package: Rc<dyn Package> = package;
+            if f(package) {
+                Ok(())
+            } else {
+                Err(Stop::Done)
+            }
+        });
+        match result {
+            Ok(()) | Err(Stop::Done) => Ok(()),
+            Err(Stop::Failed(e)) => Err(e),
+        }
+    }
+}
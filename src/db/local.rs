@@ -1,28 +1,66 @@
 use std::{
     borrow::Cow,
     cell::RefCell,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     ffi::OsStr,
     fs,
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     rc::{Rc, Weak},
+    time::SystemTime,
 };
 
 use atoi::atoi;
 
 use crate::{
-    db::{Database, DbStatus, DbUsage, SignatureLevel, LOCAL_DB_NAME},
-    error::{Error, ErrorKind},
-    package::PackageKey,
+    db::{Database, DbStatus, DbUsage, InvalidReason, SignatureLevel, LOCAL_DB_NAME},
+    dependency::Dependency,
+    diagnostics::{self, Warning},
+    error::{Error, ErrorContext, ErrorKind},
+    package::{Package, PackageKey},
+    version::Version,
     Handle,
 };
 
+mod disk_usage;
 mod package;
-pub use self::package::{InstallReason, LocalPackage, ValidationError};
+pub use self::disk_usage::{DiskUsageAccounting, DiskUsageReport};
+pub(crate) use self::package::validate_all;
+use self::package::{convert_file_list, write_provenance, LocalPackageDescription};
+pub use self::package::{
+    BackupFile, FileEntry, FileType, InstallReason, LocalPackage, PermissionDrift, Provenance,
+    ValidationCounts, ValidationError, ValidationExclusions, ValidationReport, ValidationSummary,
+};
 
 const LOCAL_DB_VERSION_FILE: &str = "ALPM_DB_VERSION";
-const LOCAL_DB_CURRENT_VERSION: u64 = 9;
+
+/// What to do when the local database has more than one version of a package installed.
+///
+/// This can happen when a transaction is interrupted, or the database is edited by hand. The
+/// latest version is used by `Database::package_latest`, and `LocalDatabase::duplicates` and
+/// `LocalDatabase::remove_stale_duplicates` find and repair them. Two entries whose versions are
+/// equal but written differently (like `1.0-1` and `0:1.0-1`) can't both be loaded, and are an
+/// `ErrorKind::DuplicateLocalPackage` unless they are removed with `RemoveStale`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum DuplicatePolicy {
+    /// Keep all the versions.
+    Allow,
+    /// Keep all the versions, raising a `Warning::DuplicateLocalPackage` for each package.
+    Warn,
+    /// Fail to load the local database with `ErrorKind::DuplicateLocalPackage`.
+    Error,
+    /// Remove the entries of all but the latest version before loading the local database, as
+    /// `LocalDatabase::remove_stale_duplicates` does. A read-only instance can't, so it warns as
+    /// with `Warn`.
+    RemoveStale,
+}
+
+impl Default for DuplicatePolicy {
+    fn default() -> Self {
+        DuplicatePolicy::Warn
+    }
+}
+pub(crate) const LOCAL_DB_CURRENT_VERSION: u64 = 9;
 
 /// The package database of installed packages.
 ///
@@ -39,6 +77,353 @@ impl LocalDatabase {
     pub(crate) fn new(inner: Rc<RefCell<LocalDatabaseInner>>) -> LocalDatabase {
         LocalDatabase { inner }
     }
+
+    /// Get the installed package with the given name, or `None` if it isn't installed.
+    ///
+    /// If more than one version is installed, this is the latest, like `version_of`.
+    pub(crate) fn find(&self, name: &str) -> Result<Option<Rc<LocalPackage>>, Error> {
+        let inner = self.inner.borrow();
+        match inner.latest(name) {
+            Some(pkg) => pkg.borrow_mut().load(inner.handle.clone()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether a package with the given name is installed.
+    ///
+    /// Unlike `find`, this doesn't load the package, so it is cheap enough for hot paths. It
+    /// returns a `Result` like `SyncDatabase::contains`, so code can use either database.
+    pub fn contains(&self, name: &str) -> Result<bool, Error> {
+        Ok(self.inner.borrow().latest(name).is_some())
+    }
+
+    /// The installed version of the package with the given name, or `None` if it isn't
+    /// installed.
+    ///
+    /// This is taken from the name of the package's directory, so the package isn't loaded. If
+    /// more than one version is installed, this is the latest.
+    pub fn version_of(&self, name: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .inner
+            .borrow()
+            .latest(name)
+            .map(|package| package.borrow().version().to_owned()))
+    }
+
+    /// How much disk space each installed package uses.
+    ///
+    /// The first call stats every installed file once; the result is then cached until
+    /// `invalidate_disk_usage` is called, or the database is reloaded. While a
+    /// `DiskUsageAccounting::PerPackage` report is cached, `LocalPackage::size_on_disk` uses it
+    /// too.
+    pub fn disk_usage_report(
+        &self,
+        accounting: DiskUsageAccounting,
+    ) -> Result<Rc<DiskUsageReport>, Error> {
+        if let Some(report) = self.inner.borrow().disk_usage.get(&accounting) {
+            return Ok(report.clone());
+        }
+        let mut packages = Vec::new();
+        self.packages(|package| -> Result<(), Error> {
+            packages.push(package);
+            Ok(())
+        })?;
+        let handle = self
+            .inner
+            .borrow()
+            .handle
+            .upgrade()
+            .ok_or(ErrorKind::UseAfterDrop)?;
+        let root_path = handle.borrow().root_path.clone();
+        let report = Rc::new(DiskUsageReport::compute(&packages, &root_path, accounting)?);
+        self.inner
+            .borrow_mut()
+            .disk_usage
+            .insert(accounting, report.clone());
+        Ok(report)
+    }
+
+    /// The dependencies of each installed package that no installed package satisfies, by
+    /// package name, like `pacman -Dk`.
+    ///
+    /// Packages with no missing dependencies are left out.
+    pub fn broken_dependencies(&self) -> Result<BTreeMap<String, Vec<Dependency>>, Error> {
+        let providers = self.providers()?;
+        let mut broken = BTreeMap::new();
+        self.packages(|package| -> Result<(), Error> {
+            let missing = package.missing_from(&providers)?;
+            if !missing.is_empty() {
+                broken.insert(package.name().to_owned(), missing);
+            }
+            Ok(())
+        })?;
+        Ok(broken)
+    }
+
+    /// The packages installed or updated at or after `since`, most recent first, for example to
+    /// audit what changed after an upgrade.
+    ///
+    /// Packages with a missing or invalid install date are left out.
+    pub fn installed_since(&self, since: SystemTime) -> Result<Vec<Rc<LocalPackage>>, Error> {
+        let mut packages = Vec::new();
+        self.packages(|package| -> Result<(), Error> {
+            if package.install_date().map_or(false, |date| date >= since) {
+                packages.push(package);
+            }
+            Ok(())
+        })?;
+        packages.sort_by(|a, b| {
+            b.install_date()
+                .cmp(&a.install_date())
+                .then_with(|| a.name().cmp(b.name()))
+        });
+        Ok(packages)
+    }
+
+    /// Check the installed packages for problems besides missing dependencies (see
+    /// `broken_dependencies`).
+    ///
+    /// For now this finds packages built for an architecture that isn't configured (see
+    /// `AlpmBuilder::with_architecture`), which have usually been installed by force. Each problem
+    /// is also raised as a warning.
+    pub fn check(&self) -> Result<Vec<Warning>, Error> {
+        let handle = self
+            .inner
+            .borrow()
+            .handle
+            .upgrade()
+            .ok_or(ErrorKind::UseAfterDrop)?;
+        let mut problems = Vec::new();
+        self.packages(|package| -> Result<(), Error> {
+            if !handle.borrow().architecture_allowed(&*package) {
+                problems.push(Warning::ForeignArchitecture {
+                    package: package.name().to_owned(),
+                    arch: package.arch().to_owned(),
+                });
+            }
+            Ok(())
+        })?;
+        for problem in &problems {
+            handle.borrow().warn(problem.clone());
+        }
+        Ok(problems)
+    }
+
+    /// The installed packages, indexed by every name they can be depended on by (their own name
+    /// and anything they provide).
+    pub(crate) fn providers(&self) -> Result<HashMap<String, Vec<Rc<LocalPackage>>>, Error> {
+        let mut providers: HashMap<String, Vec<Rc<LocalPackage>>> = HashMap::new();
+        self.packages(|package| -> Result<(), Error> {
+            providers
+                .entry(package.name().to_owned())
+                .or_default()
+                .push(package.clone());
+            for provision in package.provides() {
+                let name = Dependency::parse(provision)?.name;
+                providers.entry(name).or_default().push(package.clone());
+            }
+            Ok(())
+        })?;
+        Ok(providers)
+    }
+
+    /// The packages with more than one version installed, with their versions from oldest to
+    /// latest.
+    ///
+    /// This only looks at the names of the entries, so no packages are loaded.
+    pub fn duplicates(&self) -> BTreeMap<String, Vec<String>> {
+        self.inner.borrow().duplicates()
+    }
+
+    /// Record where a package came from in the entry for exactly this version, returning whether
+    /// there is such an entry.
+    pub(crate) fn record_provenance(
+        &self,
+        name: &str,
+        version: &str,
+        provenance: &Provenance,
+    ) -> Result<bool, Error> {
+        let inner = self.inner.borrow();
+        inner.check_writable()?;
+        let entry = inner.path.join(format!("{}-{}", name, version));
+        if !entry.join("desc").is_file() {
+            return Ok(false);
+        }
+        write_provenance(&entry, provenance)?;
+        Ok(true)
+    }
+
+    /// Remove the entries of all but the latest version of each package with more than one
+    /// version installed, returning the versions removed by package name.
+    ///
+    /// Only the database entries are removed: files on disk are left alone, as they may belong to
+    /// the latest version too. Of entries whose versions are equal but written differently, the
+    /// one changed last is kept. If anything is removed, the database is reloaded.
+    pub fn remove_stale_duplicates(&self) -> Result<BTreeMap<String, Vec<String>>, Error> {
+        let mut inner = self.inner.borrow_mut();
+        inner.check_writable()?;
+        let removed = inner.remove_stale_entries()?;
+        if !removed.is_empty() {
+            inner.package_cache.clear();
+            inner.populate_package_cache()?;
+        }
+        Ok(removed)
+    }
+
+    /// Store each package's list of files gzip compressed (as `files.gz`), or uncompressed (as
+    /// `files`), returning the number of packages changed.
+    ///
+    /// Compressed lists are read transparently, and take a fraction of the disk space on systems
+    /// with many packages (the `mtree` files are already compressed). Pacman and other libalpm
+    /// users can only read uncompressed lists though, so convert them back before using those.
+    pub fn compress_file_lists(&self, compress: bool) -> Result<usize, Error> {
+        let inner = self.inner.borrow();
+        inner.check_writable()?;
+        let mut changed = 0;
+        for package in inner.package_cache.values() {
+            let path = package.borrow().path().to_owned();
+            if convert_file_list(&path, compress)? {
+                log::debug!("converted file list in {}", path.display());
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Copy the database into `dir`, which must not exist yet, for backups or to compare with
+    /// later (see `open_snapshot`).
+    ///
+    /// Files are hard linked where possible, except each package's `desc`, which is always copied
+    /// because pacman rewrites it in place (for example when the install reason changes).
+    pub fn export_snapshot(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        let inner = self.inner.borrow();
+        fs::create_dir(dir)?;
+        let version_file = inner.version_file();
+        if version_file.is_file() {
+            fs::copy(&version_file, dir.join(LOCAL_DB_VERSION_FILE))?;
+        }
+        for package in inner.package_cache.values() {
+            let from = package.borrow().path().to_owned();
+            let to = match from.file_name() {
+                Some(name) => dir.join(name),
+                None => {
+                    return Err(ErrorKind::InvalidLocalPackage(from.display().to_string()).into())
+                }
+            };
+            fs::create_dir(&to)?;
+            for entry in fs::read_dir(&from)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_file() {
+                    log::debug!("not copying {} to snapshot", entry.path().display());
+                    continue;
+                }
+                let target = to.join(entry.file_name());
+                if entry.file_name() == OsStr::new("desc")
+                    || fs::hard_link(entry.path(), &target).is_err()
+                {
+                    fs::copy(entry.path(), &target)?;
+                }
+            }
+        }
+        log::info!(
+            "exported {} local packages to {}",
+            inner.package_cache.len(),
+            dir.display()
+        );
+        Ok(())
+    }
+
+    /// Open a snapshot made by `export_snapshot`, to read it like the live database.
+    ///
+    /// The snapshot is not locked, and isn't part of the system: plans still use this database.
+    /// Warnings from loading it go to the same callback, and paths in it are relative to the same
+    /// root. Fails with `ErrorKind::BadDatabasePath` if `dir` isn't a directory.
+    pub fn open_snapshot(&self, dir: impl AsRef<Path>) -> Result<LocalDatabase, Error> {
+        // Unlike the live database, a missing snapshot isn't just an empty one.
+        if !dir.as_ref().is_dir() {
+            return Err(ErrorKind::BadDatabasePath(dir.as_ref().to_owned()).into());
+        }
+        let mut snapshot = {
+            let inner = self.inner.borrow();
+            LocalDatabaseInner::at(
+                inner.handle.clone(),
+                inner.sig_level,
+                dir.as_ref().to_owned(),
+            )
+        };
+        snapshot.populate_package_cache()?;
+        Ok(LocalDatabase::new(Rc::new(RefCell::new(snapshot))))
+    }
+
+    /// Create the database directory and its version file if they don't exist, so a fresh
+    /// database can be started in an empty root.
+    ///
+    /// An existing database is left alone (`Database::status` says whether it is usable). A
+    /// directory that has entries but no version file may be from an older version of pacman, so
+    /// it isn't marked as current, and this fails with `ErrorKind::DatabaseVersion`.
+    pub fn initialize(&self) -> Result<(), Error> {
+        let inner = self.inner.borrow();
+        inner.check_writable()?;
+        let cannot_create = || ErrorKind::CannotCreateDatabase(LOCAL_DB_NAME.to_owned());
+        fs::create_dir_all(&inner.path).context(cannot_create())?;
+        if inner.version_file().is_file() {
+            return Ok(());
+        }
+        if fs::read_dir(&inner.path)?.next().is_some() {
+            return Err(ErrorKind::DatabaseVersion(LOCAL_DB_NAME.to_owned()).into());
+        }
+        log::info!("initializing local database at {}", inner.path.display());
+        inner.create_version_file().context(cannot_create())?;
+        Ok(())
+    }
+
+    /// Remove the broken entries that `confirm` agrees to, returning them.
+    ///
+    /// An entry is broken if its directory name isn't a package name and version, it has no
+    /// `desc` file, or its `desc` file can't be understood or is for a different package or
+    /// version. These are left behind by tools that were interrupted while writing to the
+    /// database. If anything is removed, the database is reloaded.
+    pub fn gc(
+        &self,
+        mut confirm: impl FnMut(&BrokenEntry) -> bool,
+    ) -> Result<Vec<BrokenEntry>, Error> {
+        self.inner.borrow().check_writable()?;
+        let mut entries = fs::read_dir(self.path())?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        let mut removed = Vec::new();
+        for entry in entries {
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            let problem = match entry_problem(&path, &self.inner.borrow().handle)? {
+                Some(problem) => problem,
+                None => continue,
+            };
+            let broken = BrokenEntry { path, problem };
+            if !confirm(&broken) {
+                continue;
+            }
+            log::info!(
+                "removing broken local database entry {}",
+                broken.path.display()
+            );
+            fs::remove_dir_all(&broken.path)?;
+            removed.push(broken);
+        }
+        if !removed.is_empty() {
+            let mut inner = self.inner.borrow_mut();
+            inner.package_cache.clear();
+            inner.populate_package_cache()?;
+        }
+        Ok(removed)
+    }
+
+    /// Forget the cached disk usage, because installed files have changed.
+    pub fn invalidate_disk_usage(&self) {
+        self.inner.borrow_mut().disk_usage.clear();
+    }
 }
 
 impl Database for LocalDatabase {
@@ -97,6 +482,55 @@ impl Database for LocalDatabase {
     }
 }
 
+/// A broken entry in the local database, found by `LocalDatabase::gc`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BrokenEntry {
+    /// The entry's directory.
+    pub path: PathBuf,
+    /// What is wrong with it.
+    pub problem: EntryProblem,
+}
+
+/// What is wrong with a `BrokenEntry`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EntryProblem {
+    /// The directory name isn't of the form `name-version-release`.
+    InvalidName,
+    /// There is no `desc` file.
+    MissingDesc,
+    /// The `desc` file could not be understood.
+    InvalidDesc(String),
+    /// The `desc` file is for a different package or version than the directory name.
+    Mismatch { name: String, version: String },
+}
+
+/// What is wrong with the local database entry in `path`, if anything.
+fn entry_problem(path: &Path, handle: &Weak<RefCell<Handle>>) -> io::Result<Option<EntryProblem>> {
+    let dirname = path.file_name().and_then(OsStr::to_str);
+    let (name, version) = match dirname.and_then(super::split_package_dirname) {
+        Some(split) => split,
+        None => return Ok(Some(EntryProblem::InvalidName)),
+    };
+    let raw = match fs::read_to_string(path.join("desc")) {
+        Ok(raw) => raw,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Some(EntryProblem::MissingDesc))
+        }
+        Err(e) => return Err(e),
+    };
+    let desc: LocalPackageDescription = match super::parse_desc(&raw, name, handle) {
+        Ok(desc) => desc,
+        Err(e) => return Ok(Some(EntryProblem::InvalidDesc(e.to_string()))),
+    };
+    if desc.name != name || desc.version != version {
+        return Ok(Some(EntryProblem::Mismatch {
+            name: desc.name,
+            version: desc.version,
+        }));
+    }
+    Ok(None)
+}
+
 /// A package database.
 #[derive(Debug)]
 pub struct LocalDatabaseInner {
@@ -107,11 +541,15 @@ pub struct LocalDatabaseInner {
     usage: DbUsage,
     /// The database path.
     path: PathBuf,
-    /// The package cache (HashMap of package name to package version to package, which lazily
-    /// gets info from disk)
-    package_cache: HashMap<PackageKey<'static>, RefCell<MaybePackage>>,
+    /// The package cache (map of package name and version to package, which lazily gets info from
+    /// disk)
+    ///
+    /// This is ordered so that iteration is deterministic.
+    package_cache: BTreeMap<PackageKey<'static>, RefCell<MaybePackage>>,
     /// Count of the number of packages (cached)
     package_count: usize,
+    /// The last disk usage report for each kind of accounting, while they are still valid.
+    disk_usage: HashMap<DiskUsageAccounting, Rc<DiskUsageReport>>,
 }
 
 impl LocalDatabaseInner {
@@ -125,22 +563,43 @@ impl LocalDatabaseInner {
         handle: &Rc<RefCell<Handle>>,
         sig_level: SignatureLevel,
     ) -> LocalDatabaseInner {
-        //  path is `$db_path SEP $local_db_name` for local
-        let path = handle.borrow().database_path.join(LOCAL_DB_NAME);
+        let path = handle.borrow().local_database_path.clone();
+        LocalDatabaseInner::at(Rc::downgrade(handle), sig_level, path)
+    }
+
+    /// Create a database for the package directories in `path`.
+    fn at(
+        handle: Weak<RefCell<Handle>>,
+        sig_level: SignatureLevel,
+        path: PathBuf,
+    ) -> LocalDatabaseInner {
         LocalDatabaseInner {
-            handle: Rc::downgrade(handle),
+            handle,
             sig_level,
             usage: DbUsage::default(),
             path,
-            package_cache: HashMap::new(),
+            package_cache: BTreeMap::new(),
             package_count: 0,
+            disk_usage: HashMap::new(),
         }
     }
 
+    /// Fail with `ErrorKind::ReadOnly` if the instance may not write.
+    fn check_writable(&self) -> Result<(), Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        let handle = handle.borrow();
+        handle.check_writable()
+    }
+
+    /// The path of the file holding the database version.
+    fn version_file(&self) -> PathBuf {
+        self.path.join(LOCAL_DB_VERSION_FILE)
+    }
+
     /// Helper to create a new version file for the local database.
     #[inline]
     fn create_version_file(&self) -> io::Result<()> {
-        let mut version_file = fs::File::create(&self.path)?;
+        let mut version_file = fs::File::create(self.version_file())?;
         // Format is number followed by single newline
         writeln!(version_file, "{}", LOCAL_DB_CURRENT_VERSION)?;
         Ok(())
@@ -166,7 +625,7 @@ impl LocalDatabaseInner {
     ///
     /// There should only be one version of a package installed at any time,
     /// so this function is kinda useless, and it's also expensive as it has to traverse the
-    /// hashtable.
+    /// whole cache.
     fn package_latest(&self, name: impl AsRef<str>) -> Result<Rc<LocalPackage>, Error> {
         let name = name.as_ref();
 
@@ -208,30 +667,43 @@ impl LocalDatabaseInner {
         };
 
         if !md.is_dir() {
-            return Ok(DbStatus::Invalid);
+            return Ok(DbStatus::Invalid {
+                reasons: vec![InvalidReason::NotADirectory],
+            });
         }
 
         log::debug!("checking local database version");
-        let valid = match fs::read(self.path.join(&LOCAL_DB_VERSION_FILE)) {
+        let mut reasons = Vec::new();
+        match fs::read(self.version_file()) {
             Ok(version_raw) => {
                 // Check version is up to date.
                 if let Some(version) = atoi::<u64>(&version_raw) {
-                    if version == LOCAL_DB_CURRENT_VERSION {
-                        true
-                    } else {
-                        log::warn!(
-                            r#"local database version is "{}" which is not the latest ("{}")"#,
-                            version,
-                            LOCAL_DB_CURRENT_VERSION
+                    if version != LOCAL_DB_CURRENT_VERSION {
+                        diagnostics::warn(
+                            &self.handle,
+                            Warning::OutdatedDatabaseVersion {
+                                found: version,
+                                expected: LOCAL_DB_CURRENT_VERSION,
+                            },
                         );
-                        false
+                        reasons.push(InvalidReason::WrongVersion {
+                            found: version,
+                            expected: LOCAL_DB_CURRENT_VERSION,
+                        });
                     }
                 } else {
-                    log::error!(
+                    let contents = String::from_utf8_lossy(&version_raw).into_owned();
+                    reasons.push(InvalidReason::BadVersionFile(format!(
                         r#""{}" is not a valid version"#,
-                        String::from_utf8_lossy(&version_raw)
+                        contents
+                    )));
+                    diagnostics::warn(
+                        &self.handle,
+                        Warning::InvalidVersionFile {
+                            path: self.version_file(),
+                            contents,
+                        },
                     );
-                    false
                 }
             }
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
@@ -239,18 +711,17 @@ impl LocalDatabaseInner {
                 log::debug!("local database version file not found - creating");
                 match fs::read_dir(&self.path) {
                     Ok(ref mut d) => match d.next() {
-                        Some(_) => false,
-                        None => match self.create_version_file() {
-                            Ok(_) => true,
-                            Err(e) => {
+                        Some(_) => reasons.push(InvalidReason::MissingVersionFile),
+                        None => {
+                            if let Err(e) = self.create_version_file() {
                                 log::error!(
                                     "could not create version file for local database at {}",
                                     self.path.display()
                                 );
                                 log::error!("caused by {}", e);
-                                false
+                                reasons.push(InvalidReason::MissingVersionFile);
                             }
-                        },
+                        }
                     },
                     Err(e) => {
                         log::error!(
@@ -258,7 +729,7 @@ impl LocalDatabaseInner {
                             self.path.display()
                         );
                         log::error!("caused by {}", e);
-                        false
+                        reasons.push(InvalidReason::MissingVersionFile);
                     }
                 }
             }
@@ -268,34 +739,135 @@ impl LocalDatabaseInner {
                     self.path.display()
                 );
                 log::error!("caused by {}", e);
-                false
+                reasons.push(InvalidReason::BadVersionFile(e.to_string()));
             }
         };
-        Ok(if valid {
-            DbStatus::Valid
-        } else {
-            DbStatus::Invalid
-        })
+        Ok(DbStatus::from_reasons(reasons))
+    }
+
+    /// The names with more than one version in the cache, with their versions from oldest to
+    /// latest.
+    fn duplicates(&self) -> BTreeMap<String, Vec<String>> {
+        let mut versions: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        // The cache is ordered by name and then version.
+        for (key, package) in &self.package_cache {
+            versions
+                .entry(key.name.clone().into_owned())
+                .or_default()
+                .push(package.borrow().version().to_owned());
+        }
+        versions.retain(|_, versions| versions.len() > 1);
+        versions
+    }
+
+    /// The entry of the latest installed version of the package with the given name.
+    ///
+    /// The cache is ordered by name and then version, and package names can't contain a NUL, so
+    /// this is the last entry before `<name>\0`.
+    fn latest(&self, name: &str) -> Option<&RefCell<MaybePackage>> {
+        let end = PackageKey::from_owned(format!("{}\0", name), "0");
+        self.package_cache
+            .range(..end)
+            .next_back()
+            .filter(|(key, _)| key.name == name)
+            .map(|(_, package)| package)
+    }
+
+    /// Remove the entries of all but the latest version of each package, returning the versions
+    /// removed by package name.
+    ///
+    /// The entries are read from the directory rather than the cache, so this also repairs
+    /// entries that couldn't be loaded: those whose versions are equal but written differently.
+    /// Of those, the entry changed last is kept, as the last transaction most likely wrote it.
+    fn remove_stale_entries(&self) -> Result<BTreeMap<String, Vec<String>>, Error> {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut by_name: BTreeMap<String, Vec<(String, PathBuf, Option<SystemTime>)>> =
+            BTreeMap::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let file_name = match entry.file_name().into_string() {
+                Ok(file_name) => file_name,
+                Err(_) => continue,
+            };
+            let (name, version) = match super::split_package_dirname(&file_name) {
+                Some(split) => split,
+                None => continue,
+            };
+            let modified = entry.metadata()?.modified().ok();
+            by_name.entry(name.to_owned()).or_default().push((
+                version.to_owned(),
+                entry.path(),
+                modified,
+            ));
+        }
+        let mut removed = BTreeMap::new();
+        for (name, mut versions) in by_name {
+            if versions.len() < 2 {
+                continue;
+            }
+            versions.sort_by(|a, b| {
+                Version::parse(&a.0)
+                    .cmp(&Version::parse(&b.0))
+                    .then(a.2.cmp(&b.2))
+            });
+            // The last version is the latest.
+            versions.pop();
+            for (_, path, _) in &versions {
+                log::info!("removing stale local database entry {}", path.display());
+                fs::remove_dir_all(path)?;
+            }
+            removed.insert(
+                name,
+                versions
+                    .into_iter()
+                    .map(|(version, _, _)| version)
+                    .collect(),
+            );
+        }
+        Ok(removed)
     }
 
     /// Load all package names into the cache, and validate the database
     // The syscalls for this function are a single readdir and a stat per subentry
     pub(crate) fn populate_package_cache(&mut self) -> Result<(), Error> {
+        self.disk_usage.clear();
+        let policy = self
+            .handle
+            .upgrade()
+            .map_or(DuplicatePolicy::default(), |handle| {
+                handle.borrow().duplicate_policy
+            });
+        if policy == DuplicatePolicy::RemoveStale && self.check_writable().is_ok() {
+            self.remove_stale_entries()?;
+        }
         log::debug!(
             r#"searching for local packages in "{}""#,
             self.path.display()
         );
-        for entry in fs::read_dir(&self.path)? {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            // A missing database has no packages; see `LocalDatabase::initialize`.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                log::debug!("local database not found");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
             let entry = entry?;
             if !entry.metadata()?.is_dir() {
                 // Check ALPM_DB_VERSION
                 if entry.file_name() == OsStr::new(LOCAL_DB_VERSION_FILE) {
                 } else {
                     // ignore extra files for now (should probably error)
-                    log::warn!(
-                        "Unexpected file {} found in local db directory",
-                        entry.path().display()
-                    );
+                    diagnostics::warn(&self.handle, Warning::UnexpectedFile(entry.path()));
                 }
                 continue;
             }
@@ -304,20 +876,34 @@ impl LocalDatabaseInner {
             let file_name = entry
                 .file_name()
                 .into_string()
-                .expect("non-utf8 package names not yet supported");
+                .map_err(|_| ErrorKind::NonUtf8PackageName(path.clone()))?;
             let (name, version) = super::split_package_dirname(&file_name)
                 .ok_or(ErrorKind::InvalidLocalPackage(file_name.to_owned()))?;
             log::debug!(r#"found "{}", version: "{}""#, name, version);
-            if self
-                .package_cache
-                .insert(
-                    PackageKey::from_owned(name.to_owned(), version),
-                    RefCell::new(MaybePackage::new(path, name, version)),
-                )
-                .is_some()
-            {
-                // This should not be possible (since name comes from unique filename)
-                panic!("Found package in localdb with duplicate name/version");
+            let previous = self.package_cache.insert(
+                PackageKey::from_owned(name.to_owned(), version),
+                RefCell::new(MaybePackage::new(path, name, version)),
+            );
+            // Directory names are unique, but versions can be equal without being written the
+            // same way.
+            if let Some(previous) = previous {
+                return Err(ErrorKind::DuplicateLocalPackage {
+                    name: name.to_owned(),
+                    versions: vec![previous.borrow().version().to_owned(), version.to_owned()],
+                }
+                .into());
+            }
+        }
+        for (name, versions) in self.duplicates() {
+            match policy {
+                DuplicatePolicy::Allow => (),
+                DuplicatePolicy::Warn | DuplicatePolicy::RemoveStale => diagnostics::warn(
+                    &self.handle,
+                    Warning::DuplicateLocalPackage { name, versions },
+                ),
+                DuplicatePolicy::Error => {
+                    return Err(ErrorKind::DuplicateLocalPackage { name, versions }.into())
+                }
             }
         }
         Ok(())
@@ -351,6 +937,22 @@ impl MaybePackage {
         }
     }
 
+    /// The version of the package, from its directory name if it isn't loaded.
+    fn version(&self) -> &str {
+        match self {
+            MaybePackage::Unloaded { version, .. } => version,
+            MaybePackage::Loaded(pkg) => pkg.version(),
+        }
+    }
+
+    /// The path of the package's directory.
+    fn path(&self) -> &Path {
+        match self {
+            MaybePackage::Unloaded { path, .. } => path,
+            MaybePackage::Loaded(pkg) => &pkg.path,
+        }
+    }
+
     /// Load the package if necessary and return it
     fn load(&mut self, handle: Weak<RefCell<Handle>>) -> Result<Rc<LocalPackage>, Error> {
         match self {
@@ -0,0 +1,150 @@
+//! Working out how much disk space installed packages use.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{db::LocalPackage, package::Package};
+
+/// How files owned by more than one package are counted in a `DiskUsageReport`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum DiskUsageAccounting {
+    /// Every package is charged for all of its files, as in `LocalPackage::size_on_disk`.
+    ///
+    /// Directories like `usr/` are owned by many packages, so the package sizes add up to more
+    /// than the space actually used.
+    PerPackage,
+    /// Each file is charged to one package only (the first owner by name), so the package sizes
+    /// add up to the total.
+    ///
+    /// Files other than directories that are owned by more than one package are listed in
+    /// `DiskUsageReport::shared_files`, since the database should never contain them.
+    Shared,
+}
+
+impl Default for DiskUsageAccounting {
+    fn default() -> Self {
+        DiskUsageAccounting::PerPackage
+    }
+}
+
+/// How much disk space installed packages use.
+///
+/// This is built by statting every installed file once, rather than once per package that owns
+/// it. See `LocalDatabase::disk_usage_report`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DiskUsageReport {
+    /// How shared files were counted.
+    accounting: DiskUsageAccounting,
+    /// The bytes used by each package's files, by package name.
+    packages: BTreeMap<String, u64>,
+    /// The bytes used by all installed files, counting each file once.
+    total: u64,
+    /// The number of files listed by packages that weren't on disk.
+    missing: usize,
+    /// Files (not directories) owned by more than one package, with their owners.
+    shared_files: BTreeMap<PathBuf, Vec<String>>,
+}
+
+impl DiskUsageReport {
+    /// Stat every file of the given packages, and add up the sizes.
+    ///
+    /// The packages should be sorted by name, which decides who is charged for shared files.
+    pub(crate) fn compute(
+        packages: &[Rc<LocalPackage>],
+        root: &Path,
+        accounting: DiskUsageAccounting,
+    ) -> io::Result<Self> {
+        // The file index: each file and the packages that own it, in order.
+        let mut owners: HashMap<&Path, Vec<usize>> = HashMap::new();
+        for (idx, package) in packages.iter().enumerate() {
+            for file in package.file_names() {
+                owners.entry(file).or_default().push(idx);
+            }
+        }
+
+        let mut sizes = vec![0; packages.len()];
+        let mut report = DiskUsageReport {
+            accounting,
+            ..DiskUsageReport::default()
+        };
+        for (file, indices) in owners {
+            let md = match root.join(file).metadata() {
+                Ok(md) => md,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    report.missing += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let size = md.len();
+            report.total += size;
+            if indices.len() > 1 && !md.is_dir() {
+                let names = indices
+                    .iter()
+                    .map(|&idx| packages[idx].name().to_owned())
+                    .collect();
+                report.shared_files.insert(file.to_owned(), names);
+            }
+            match accounting {
+                DiskUsageAccounting::PerPackage => {
+                    for idx in indices {
+                        sizes[idx] += size;
+                    }
+                }
+                DiskUsageAccounting::Shared => sizes[indices[0]] += size,
+            }
+        }
+        report.packages = packages
+            .iter()
+            .zip(sizes)
+            .map(|(package, size)| (package.name().to_owned(), size))
+            .collect();
+        Ok(report)
+    }
+
+    /// How shared files were counted.
+    pub fn accounting(&self) -> DiskUsageAccounting {
+        self.accounting
+    }
+
+    /// The bytes charged to the named package, or `None` if it isn't installed.
+    ///
+    /// With `DiskUsageAccounting::PerPackage`, this is the same as `LocalPackage::size_on_disk`.
+    pub fn package(&self, name: &str) -> Option<u64> {
+        self.packages.get(name).cloned()
+    }
+
+    /// The bytes used by each package, sorted by package name.
+    pub fn packages(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.packages
+            .iter()
+            .map(|(name, size)| (name.as_str(), *size))
+    }
+
+    /// The bytes used by all installed files.
+    ///
+    /// Files owned by more than one package are only counted once, so this can be less than the
+    /// sum of the package sizes.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// The number of files listed by packages that weren't found on disk.
+    pub fn missing(&self) -> usize {
+        self.missing
+    }
+
+    /// Files other than directories that more than one package claims to own, with the names of
+    /// the packages.
+    ///
+    /// Each of these is an inconsistency in the local database.
+    pub fn shared_files(&self) -> impl Iterator<Item = (&Path, &[String])> {
+        self.shared_files
+            .iter()
+            .map(|(file, owners)| (file.as_path(), owners.as_slice()))
+    }
+}
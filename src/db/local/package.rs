@@ -1,18 +1,28 @@
 use std::{
     cell::RefCell,
-    collections::HashSet,
+    collections::{BTreeSet, HashMap, HashSet},
     error::Error as StdError,
-    fmt, fs, io,
+    fmt, fs,
+    io::{self, Read, Write},
+    ops,
     path::{Path, PathBuf},
-    rc::Weak,
+    rc::{Rc, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use derivative::Derivative;
-use libflate::gzip::Decoder;
+use libflate::gzip::{Decoder, Encoder};
 use mtree::{self, Entry, MTree};
 use serde_derive::{Deserialize, Serialize};
 
-use crate::{alpm_desc::de, error::Error, package::Package, Handle};
+use crate::{
+    db::{self, DiskUsageAccounting, LocalDatabase},
+    dependency::{Depend, Dependency},
+    error::{Error, ErrorKind},
+    hash, hook,
+    package::Package,
+    Handle,
+};
 
 /// A package from the local database - the database of installed packages.
 #[derive(Debug, Clone, Derivative)]
@@ -20,7 +30,10 @@ use crate::{alpm_desc::de, error::Error, package::Package, Handle};
 pub struct LocalPackage {
     pub path: PathBuf,
     desc: LocalPackageDescription,
-    files: Vec<Entry>,
+    /// The desc file, for `Package::raw_field`.
+    desc_raw: String,
+    files: Vec<FileEntry>,
+    backup: Vec<BackupFile>,
     #[derivative(PartialEq = "ignore", Hash = "ignore")]
     handle: Weak<RefCell<Handle>>,
 }
@@ -37,8 +50,8 @@ impl LocalPackage {
 
         // get package description
         let desc_raw = fs::read_to_string(path.join("desc"))?;
-        let desc: LocalPackageDescription =
-            de::from_str(&desc_raw).map_err(|err| Error::invalid_local_package(name, err))?;
+        let desc: LocalPackageDescription = db::parse_desc(&desc_raw, name, &handle)
+            .map_err(|err| Error::invalid_local_package(name, err))?;
 
         // check package name/version with path
         if desc.name != name {
@@ -66,10 +79,16 @@ impl LocalPackage {
         // FIXME for now, we use the fact we are on unix to convert paths to byte arrays for faster
         // comparing. It was too slow using std::path::Path. This is something I'd have to fix to
         // get the lib working on windows.
-        let files_raw = fs::read_to_string(path.join("files"))?;
-        let files: HashSet<Vec<u8>> = de::from_str(&files_raw)
-            .map(|f: Files| f.files)
-            .map_err(|err| Error::invalid_local_package(name, err))?
+        let files_raw = read_file_list(&path)?;
+        let files_desc: Files = db::parse_desc(&files_raw, name, &handle)
+            .map_err(|err| Error::invalid_local_package(name, err))?;
+        let backup = files_desc
+            .backup
+            .iter()
+            .map(|raw| BackupFile::parse(raw))
+            .collect();
+        let files: HashSet<Vec<u8>> = files_desc
+            .files
             .into_iter()
             .map(|file| {
                 use std::ffi::OsString;
@@ -82,6 +101,7 @@ impl LocalPackage {
         let mtree = MTree::from_reader(Decoder::new(io::BufReader::new(fs::File::open(
             path.join("mtree"),
         )?))?)
+        .map(|entry| entry.map(|entry| FileEntry { entry }))
         .filter(|entry| match entry {
             // we have to do the `ends_with` hack because the mtree representation has a
             // leading `./`. Also means this is O(n) rather than O(log n) which we could do
@@ -102,11 +122,24 @@ impl LocalPackage {
         Ok(LocalPackage {
             path,
             desc,
+            desc_raw,
             files: mtree,
+            backup,
             handle,
         })
     }
 
+    /// When this package was installed, or last updated.
+    ///
+    /// This is `None` if the desc file doesn't have a valid install date.
+    pub fn install_date(&self) -> Option<SystemTime> {
+        self.desc
+            .install_date
+            .parse()
+            .ok()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
     /// The reason this package was installed, if given.
     pub fn reason(&self) -> Option<InstallReason> {
         self.desc.reason
@@ -117,13 +150,33 @@ impl LocalPackage {
         &self.desc.validation
     }
 
+    /// The extra `key=value` data recorded for this package (`%XDATA%` in its `desc` file).
+    pub fn xdata(&self) -> &[String] {
+        &self.desc.xdata
+    }
+
+    /// Where this package came from, if that was recorded when it was installed.
+    pub fn provenance(&self) -> Option<Provenance> {
+        Provenance::from_xdata(&self.desc.xdata)
+    }
+
+    /// Record where this package came from in its `desc` file, replacing any previous record.
+    ///
+    /// Other extra data is kept. The change is seen when the local database is next loaded. This
+    /// fails with `ErrorKind::ReadOnly` on a read-only instance.
+    pub fn record_provenance(&self, provenance: &Provenance) -> Result<(), Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        handle.borrow().check_writable()?;
+        write_provenance(&self.path, provenance)
+    }
+
     /// An iterator over the paths of all files in this package.
     pub fn file_names(&self) -> impl Iterator<Item = &Path> {
         self.files().map(|v| v.path())
     }
 
     /// An iterator over metadata for all files in this package.
-    pub fn files(&self) -> impl Iterator<Item = &Entry> {
+    pub fn files(&self) -> impl Iterator<Item = &FileEntry> {
         self.files.iter()
     }
 
@@ -132,11 +185,41 @@ impl LocalPackage {
         self.files.len()
     }
 
+    /// The files that are kept if they have been changed, like configuration files.
+    pub fn backup_files(&self) -> &[BackupFile] {
+        &self.backup
+    }
+
+    /// The alpm instance, for methods that return `io::Error`s.
+    fn io_handle(&self) -> io::Result<Rc<RefCell<Handle>>> {
+        self.handle.upgrade().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                Error::from(ErrorKind::UseAfterDrop).to_string(),
+            )
+        })
+    }
+
     /// The amount of disk space that this package takes up on disk
+    ///
+    /// If a disk usage report is cached (see `LocalDatabase::disk_usage_report`), the size is
+    /// taken from that instead of statting every file.
     pub fn size_on_disk(&self) -> Result<u64, io::Error> {
         let mut acc = 0;
-        let handle = self.handle.upgrade().unwrap();
-        let root = &handle.borrow().root_path;
+        let handle = self.io_handle()?;
+        let handle = handle.borrow();
+        if let Some(local) = &handle.local_database {
+            if let Ok(local) = local.try_borrow() {
+                let cached = local
+                    .disk_usage
+                    .get(&DiskUsageAccounting::PerPackage)
+                    .and_then(|report| report.package(self.name()));
+                if let Some(size) = cached {
+                    return Ok(size);
+                }
+            }
+        }
+        let root = &handle.root_path;
         for file in self.files() {
             let md = match root.join(file.path()).metadata() {
                 Ok(md) => md,
@@ -148,72 +231,207 @@ impl LocalPackage {
         Ok(acc)
     }
 
-    /// Make sure a package matches its metadata.
+    /// The dependencies of this package that no installed package satisfies, taking versions and
+    /// provisions into account.
+    pub fn missing_dependencies(&self, db: &LocalDatabase) -> Result<Vec<Dependency>, Error> {
+        self.missing_from(&db.providers()?)
+    }
+
+    /// The dependencies of this package that none of the given providers satisfy.
+    pub(crate) fn missing_from(
+        &self,
+        providers: &HashMap<String, Vec<Rc<LocalPackage>>>,
+    ) -> Result<Vec<Dependency>, Error> {
+        let mut missing = Vec::new();
+        for raw in self.depends() {
+            // Only missing dependencies are copied.
+            let dependency =
+                Depend::parse(raw).ok_or_else(|| ErrorKind::InvalidDependency(raw.to_owned()))?;
+            let satisfied = providers.get(dependency.name).map_or(false, |candidates| {
+                candidates
+                    .iter()
+                    .any(|candidate| dependency.satisfied_by(&**candidate))
+            });
+            if !satisfied {
+                missing.push(dependency.to_dependency());
+            }
+        }
+        Ok(missing)
+    }
+
+    /// The files whose owner, group or permissions on disk differ from the mtree, for finding
+    /// files whose permissions were changed after they were installed.
     ///
-    /// There a few different sources of truth for a package. This method (aspires to) make sure
-    /// they are all consistent.
-    pub fn validate(&self) -> io::Result<Vec<ValidationError>> {
-        log::info!("validating package {}", self.name());
-        let mut errors = Vec::new();
-        let handle = self
-            .handle
-            .upgrade()
-            .expect("the alpm instance no longer exists");
-        let root_path = &handle.borrow().root_path;
+    /// This is separate from `validate`, which checks the files themselves. Missing files are
+    /// skipped, and the permissions of symbolic links aren't compared.
+    pub fn permission_drift(&self) -> io::Result<Vec<PermissionDrift>> {
+        use std::os::unix::fs::MetadataExt;
+        let handle = self.io_handle()?;
+        let root_path = handle.borrow().root_path.clone();
+        let changed = |expected: Option<u32>, actual: u32| {
+            expected
+                .filter(|&expected| expected != actual)
+                .map(|expected| (expected, actual))
+        };
+        let mut drift = Vec::new();
         for file in self.files() {
-            let path = root_path.join(file.path());
-            // Check
-            let md = match path.symlink_metadata() {
+            let md = match root_path.join(file.path()).symlink_metadata() {
                 Ok(md) => md,
-                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-                    errors.push(ValidationError::FileNotFound(format!("{}", path.display())));
-                    continue;
-                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
                 Err(e) => return Err(e),
             };
-            // Check file type
-            if let Some(ty) = file.file_type() {
-                match (FileType::from(ty), FileType::from(md.file_type())) {
-                    (FileType::File, FileType::File) => (),
-                    (FileType::File, ty) => {
-                        errors.push(ValidationError::wrong_type(
-                            format!("{}", file.path().display()),
-                            FileType::File,
-                            ty,
-                        ));
-                    }
-                    (FileType::Directory, FileType::Directory) => (),
-                    (FileType::Directory, ty) => {
-                        errors.push(ValidationError::wrong_type(
-                            format!("{}", file.path().display()),
-                            FileType::Directory,
-                            ty,
-                        ));
-                    }
-                    (FileType::SymbolicLink, FileType::SymbolicLink) => (),
-                    (FileType::SymbolicLink, ty) => {
-                        errors.push(ValidationError::wrong_type(
-                            format!("{}", file.path().display()),
-                            FileType::SymbolicLink,
-                            ty,
-                        ));
-                    }
-                    _ => (),
-                }
+            let entry = PermissionDrift {
+                path: file.path().to_owned(),
+                uid: changed(file.uid(), md.uid()),
+                gid: changed(file.gid(), md.gid()),
+                mode: if md.file_type().is_symlink() {
+                    None
+                } else {
+                    changed(file.mode(), md.mode() & 0o7777)
+                },
+            };
+            if entry.uid.is_some() || entry.gid.is_some() || entry.mode.is_some() {
+                drift.push(entry);
             }
-            // Check size
-            if let Some(size) = file.size() {
-                if md.len() != size {
-                    errors.push(ValidationError::wrong_size(
-                        format!("{}", file.path().display()),
-                        size,
-                        md.len(),
-                    ));
-                }
+        }
+        Ok(drift)
+    }
+
+    /// Make sure a package matches its metadata.
+    ///
+    /// There a few different sources of truth for a package. This method (aspires to) make sure
+    /// they are all consistent.
+    pub fn validate(&self) -> io::Result<ValidationReport> {
+        log::info!("validating package {}", self.name());
+        let handle = self.io_handle()?;
+        let handle = handle.borrow();
+        validate_files(
+            self.name().to_owned(),
+            self.version().to_owned(),
+            &handle.root_path,
+            &self.files,
+            &handle.validation_exclusions,
+        )
+    }
+}
+
+/// A file whose owner, group or permissions differ from its mtree entry, from
+/// `LocalPackage::permission_drift`.
+///
+/// Each field that differs holds the expected value followed by the value on disk.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
+pub struct PermissionDrift {
+    /// The file, as in the mtree.
+    pub path: PathBuf,
+    /// The expected and actual owner, if they differ.
+    pub uid: Option<(u32, u32)>,
+    /// The expected and actual group, if they differ.
+    pub gid: Option<(u32, u32)>,
+    /// The expected and actual permission bits, including the setuid, setgid and sticky bits, if
+    /// they differ.
+    pub mode: Option<(u32, u32)>,
+}
+
+/// Check files on disk against their mtree entries.
+///
+/// This doesn't need the handle, so it can run on another thread.
+fn validate_files(
+    package: String,
+    version: String,
+    root_path: &Path,
+    files: &[FileEntry],
+    exclusions: &ValidationExclusions,
+) -> io::Result<ValidationReport> {
+    let start = Instant::now();
+    let mut counts = ValidationCounts::default();
+    let mut errors = Vec::new();
+    for file in files {
+        if exclusions.is_excluded(file.path(), file.file_type()) {
+            counts.excluded += 1;
+            continue;
+        }
+        let path = root_path.join(file.path());
+        counts.files_checked += 1;
+        // Check
+        let md = match path.symlink_metadata() {
+            Ok(md) => md,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                counts.missing += 1;
+                errors.push(ValidationError::FileNotFound(format!("{}", path.display())));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        // Check file type
+        if let Some(expected) = file.file_type() {
+            counts.types_checked += 1;
+            let actual = FileType::from(md.file_type());
+            if expected != FileType::Other && expected != actual {
+                counts.wrong_type += 1;
+                errors.push(ValidationError::wrong_type(
+                    format!("{}", file.path().display()),
+                    expected,
+                    actual,
+                ));
+            }
+        }
+        // Check size
+        if let Some(size) = file.size() {
+            counts.sizes_checked += 1;
+            if md.len() != size {
+                counts.wrong_size += 1;
+                errors.push(ValidationError::wrong_size(
+                    format!("{}", file.path().display()),
+                    size,
+                    md.len(),
+                ));
             }
         }
-        Ok(errors)
     }
+    Ok(ValidationReport {
+        package,
+        version,
+        counts,
+        errors,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Validate many packages, spreading the work over `parallelism` threads.
+///
+/// Reports are returned sorted by package name. A worker that panics is not turned into an
+/// error: the panic is resumed on this thread with its original payload (see `hash::par_map`).
+pub(crate) fn validate_all(
+    packages: &[Rc<LocalPackage>],
+    root_path: &Path,
+    exclusions: &ValidationExclusions,
+    parallelism: usize,
+) -> io::Result<ValidationSummary> {
+    let start = Instant::now();
+    // The packages can't leave this thread, so give each worker owned copies of what it needs.
+    let jobs = packages
+        .iter()
+        .map(|package| {
+            (
+                package.name().to_owned(),
+                package.version().to_owned(),
+                package.files.clone(),
+            )
+        })
+        .collect();
+    let root_path = root_path.to_owned();
+    let exclusions = exclusions.clone();
+    let mut reports = hash::par_map(jobs, parallelism, move |(name, version, files)| {
+        log::info!("validating package {}", name);
+        validate_files(name, version, &root_path, &files, &exclusions)
+    })
+    .into_iter()
+    .collect::<io::Result<Vec<_>>>()?;
+    reports.sort_by(|a, b| a.package.cmp(&b.package));
+    Ok(ValidationSummary {
+        reports,
+        elapsed: start.elapsed(),
+    })
 }
 
 impl Package for LocalPackage {
@@ -302,6 +520,11 @@ impl Package for LocalPackage {
     fn provides(&self) -> &[String] {
         &self.desc.provides
     }
+
+    /// The values of any field of the desc file.
+    fn raw_field(&self, key: &str) -> Option<Vec<String>> {
+        db::raw_desc_field(&self.desc_raw, key, &self.handle)
+    }
 }
 
 /// Struct to help deserializing `files` file.
@@ -311,6 +534,239 @@ impl Package for LocalPackage {
 struct Files {
     #[serde(default)]
     files: Vec<PathBuf>,
+    #[serde(default)]
+    backup: Vec<String>,
+}
+
+/// A file that is kept if it has been changed since it was installed (the `backup` array of a
+/// PKGBUILD), like a configuration file.
+///
+/// When a package is upgraded and its version of a changed file is different, the new version is
+/// written next to it with a `.pacnew` extension.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct BackupFile {
+    /// The path of the file, relative to the root.
+    pub path: PathBuf,
+    /// The md5 checksum of the file as it was installed, in hex.
+    pub md5sum: String,
+}
+
+impl BackupFile {
+    /// Parse an entry of the form `path\tmd5sum`.
+    fn parse(raw: &str) -> Self {
+        let mut parts = raw.rsplitn(2, '\t');
+        match (parts.next(), parts.next()) {
+            (Some(md5sum), Some(path)) => BackupFile {
+                path: path.into(),
+                md5sum: md5sum.to_owned(),
+            },
+            _ => BackupFile {
+                path: raw.into(),
+                md5sum: String::new(),
+            },
+        }
+    }
+
+    /// Whether the file under `root` has changed since it was installed.
+    ///
+    /// A file that is missing, or has no recorded checksum, has not changed.
+    pub fn is_modified(&self, root: &Path) -> io::Result<bool> {
+        if self.md5sum.is_empty() {
+            return Ok(false);
+        }
+        let md5sum = match hash::md5_file(&root.join(&self.path)) {
+            Ok(md5sum) => md5sum,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        Ok(!md5sum.eq_ignore_ascii_case(&self.md5sum))
+    }
+}
+
+/// The prefix of the extra data entries holding provenance.
+const PROVENANCE_PREFIX: &str = "provenance.";
+
+/// Where an installed package came from, for audits and reproducing a system.
+///
+/// This is kept as `provenance.<field>=<value>` entries in the `%XDATA%` section of the package's
+/// `desc` file, which pacman keeps but ignores.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Provenance {
+    /// The sync database the package was installed from.
+    pub repository: Option<String>,
+    /// The URL the package archive was downloaded from.
+    pub mirror: Option<String>,
+    /// The sha256 checksum of the package archive, in hex.
+    pub sha256: Option<String>,
+    /// The transaction that installed the package.
+    pub transaction: Option<String>,
+}
+
+impl Provenance {
+    /// Read provenance from extra data entries, or `None` if there isn't any.
+    fn from_xdata(xdata: &[String]) -> Option<Provenance> {
+        let mut provenance = Provenance::default();
+        let mut found = false;
+        for entry in xdata {
+            let mut parts = entry.splitn(2, '=');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key.starts_with(PROVENANCE_PREFIX) => {
+                    (&key[PROVENANCE_PREFIX.len()..], value.to_owned())
+                }
+                _ => continue,
+            };
+            let field = match key {
+                "repository" => &mut provenance.repository,
+                "mirror" => &mut provenance.mirror,
+                "sha256" => &mut provenance.sha256,
+                "transaction" => &mut provenance.transaction,
+                _ => continue,
+            };
+            *field = Some(value);
+            found = true;
+        }
+        if found {
+            Some(provenance)
+        } else {
+            None
+        }
+    }
+
+    /// The extra data entries for this provenance.
+    fn to_xdata(&self) -> Vec<String> {
+        let fields = [
+            ("repository", &self.repository),
+            ("mirror", &self.mirror),
+            ("sha256", &self.sha256),
+            ("transaction", &self.transaction),
+        ];
+        fields
+            .iter()
+            .filter_map(|(key, value)| {
+                value
+                    .as_ref()
+                    .map(|value| format!("{}{}={}", PROVENANCE_PREFIX, key, value))
+            })
+            .collect()
+    }
+}
+
+/// Record provenance in the `desc` file of the local database entry in `entry`, replacing any
+/// previous record and keeping other extra data.
+///
+/// Callers check the instance is writable.
+pub(crate) fn write_provenance(entry: &Path, provenance: &Provenance) -> Result<(), Error> {
+    let desc_path = entry.join("desc");
+    let desc = fs::read_to_string(&desc_path)?;
+    let mut xdata: Vec<String> = section_values(&desc, "XDATA")
+        .into_iter()
+        .filter(|value| !value.starts_with(PROVENANCE_PREFIX))
+        .collect();
+    xdata.extend(provenance.to_xdata());
+    let desc = replace_section(&desc, "XDATA", &xdata);
+    let mut out = tempfile::NamedTempFile::new_in(entry)?;
+    out.write_all(desc.as_bytes())?;
+    out.persist(&desc_path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// The values of the section with the given key in a `desc` file.
+fn section_values(desc: &str, key: &str) -> Vec<String> {
+    let header = format!("%{}%", key);
+    desc.lines()
+        .skip_while(|line| *line != header)
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Replace the section with the given key in a `desc` file, or remove it if there are no values.
+fn replace_section(desc: &str, key: &str, values: &[String]) -> String {
+    let header = format!("%{}%", key);
+    let mut out = String::new();
+    let mut skipping = false;
+    for line in desc.lines() {
+        if line == header {
+            skipping = true;
+        } else if skipping {
+            // Sections end with a blank line.
+            skipping = !line.is_empty();
+        } else {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    if !values.is_empty() {
+        if !out.is_empty() && !out.ends_with("\n\n") {
+            out.push('\n');
+        }
+        out.push_str(&header);
+        out.push('\n');
+        for value in values {
+            out.push_str(value);
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The name of the file in a local database entry listing the package's files.
+const FILES_FILE: &str = "files";
+/// The name of the file list when it is gzip compressed (see `LocalDatabase::compress_file_lists`).
+const COMPRESSED_FILES_FILE: &str = "files.gz";
+
+/// Read the file list of the entry in `path`, whether or not it is compressed.
+fn read_file_list(path: &Path) -> io::Result<String> {
+    match read_file_list_from(path, true) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => read_file_list_from(path, false),
+        result => result,
+    }
+}
+
+/// Read the compressed or uncompressed file list of the entry in `path`.
+fn read_file_list_from(path: &Path, compressed: bool) -> io::Result<String> {
+    let mut raw = String::new();
+    if compressed {
+        let file = fs::File::open(path.join(COMPRESSED_FILES_FILE))?;
+        Decoder::new(io::BufReader::new(file))?.read_to_string(&mut raw)?;
+    } else {
+        fs::File::open(path.join(FILES_FILE))?.read_to_string(&mut raw)?;
+    }
+    Ok(raw)
+}
+
+/// Store the file list of the entry in `path` compressed or uncompressed, returning whether it
+/// had to be changed.
+///
+/// The new file replaces the old atomically, so an interrupted conversion leaves a readable
+/// list.
+pub(crate) fn convert_file_list(path: &Path, compress: bool) -> io::Result<bool> {
+    let (from, to) = if compress {
+        (FILES_FILE, COMPRESSED_FILES_FILE)
+    } else {
+        (COMPRESSED_FILES_FILE, FILES_FILE)
+    };
+    let from = path.join(from);
+    let permissions = match fs::metadata(&from) {
+        Ok(md) => md.permissions(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let raw = read_file_list_from(path, !compress)?;
+    let mut out = tempfile::NamedTempFile::new_in(path)?;
+    if compress {
+        let mut encoder = Encoder::new(out.as_file_mut())?;
+        encoder.write_all(raw.as_bytes())?;
+        encoder.finish().into_result()?;
+    } else {
+        out.write_all(raw.as_bytes())?;
+    }
+    fs::set_permissions(out.path(), permissions)?;
+    out.persist(path.join(to)).map_err(|e| e.error)?;
+    fs::remove_file(&from)?;
+    Ok(true)
 }
 
 /// Struct to help deserializing `desc` file
@@ -353,6 +809,8 @@ pub(crate) struct LocalPackageDescription {
     pub(crate) conflicts: Vec<String>,
     #[serde(default)]
     pub(crate) provides: Vec<String>,
+    #[serde(default)]
+    pub(crate) xdata: Vec<String>,
 }
 
 /// Different possible validation methods
@@ -378,7 +836,75 @@ pub enum InstallReason {
     #[serde(rename = "1")]
     Depend,
 }
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+
+/// Metadata about a file in a package, from the package's mtree.
+///
+/// Everything but the path is optional, because an mtree only has what it was asked to record.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct FileEntry {
+    entry: Entry,
+}
+
+impl FileEntry {
+    /// The path of the file, relative to the root (as in the mtree, like `./usr/bin/ls`).
+    pub fn path(&self) -> &Path {
+        self.entry.path()
+    }
+
+    /// What kind of file this is.
+    pub fn file_type(&self) -> Option<FileType> {
+        self.entry.file_type().map(FileType::from)
+    }
+
+    /// The permission bits, including the setuid, setgid and sticky bits, like `0o4755`.
+    pub fn mode(&self) -> Option<u32> {
+        self.entry.mode().map(|mode| {
+            u32::from(mode.setuid) << 11
+                | u32::from(mode.setgid) << 10
+                | u32::from(mode.sticky) << 9
+                | u32::from(mode.owner.bits()) << 6
+                | u32::from(mode.group.bits()) << 3
+                | u32::from(mode.other.bits())
+        })
+    }
+
+    /// The id of the user owning the file.
+    pub fn uid(&self) -> Option<u32> {
+        self.entry.uid()
+    }
+
+    /// The id of the group owning the file.
+    pub fn gid(&self) -> Option<u32> {
+        self.entry.gid()
+    }
+
+    /// The size of the file in bytes.
+    pub fn size(&self) -> Option<u64> {
+        self.entry.size()
+    }
+
+    /// The md5 digest of the file's contents.
+    pub fn md5(&self) -> Option<[u8; 16]> {
+        self.entry.md5().map(u128::to_be_bytes)
+    }
+
+    /// The sha256 digest of the file's contents.
+    pub fn sha256(&self) -> Option<[u8; 32]> {
+        self.entry.sha256().cloned()
+    }
+
+    /// What the file links to, if it is a symbolic link.
+    pub fn link_target(&self) -> Option<&Path> {
+        self.entry.link()
+    }
+
+    /// When the file was last modified.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.entry.time()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
 pub enum FileType {
     File,
     Directory,
@@ -422,8 +948,188 @@ impl From<fs::FileType> for FileType {
     }
 }
 
+/// How many checks `LocalPackage::validate` made, and how many failed.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash, Serialize)]
+pub struct ValidationCounts {
+    /// The number of files looked for.
+    pub files_checked: usize,
+    /// The number of files whose type was compared.
+    pub types_checked: usize,
+    /// The number of files whose size was compared.
+    pub sizes_checked: usize,
+    /// The number of files that were missing.
+    pub missing: usize,
+    /// The number of files that were the wrong type.
+    pub wrong_type: usize,
+    /// The number of files that were the wrong size.
+    pub wrong_size: usize,
+    /// The number of files skipped because they are excluded (see `ValidationExclusions`).
+    pub excluded: usize,
+}
+
+impl ops::AddAssign for ValidationCounts {
+    fn add_assign(&mut self, other: ValidationCounts) {
+        self.files_checked += other.files_checked;
+        self.types_checked += other.types_checked;
+        self.sizes_checked += other.sizes_checked;
+        self.missing += other.missing;
+        self.wrong_type += other.wrong_type;
+        self.wrong_size += other.wrong_size;
+        self.excluded += other.excluded;
+    }
+}
+
+/// Files that validation skips, because they are expected to change after they are installed.
+///
+/// The default skips nothing. `ValidationExclusions::volatile` is a built-in set of volatile
+/// paths, like `etc/mtab`, font and icon caches and python bytecode. Use
+/// `AlpmBuilder::with_validation_exclusions` to choose one.
+///
+/// Patterns are globs matched against the whole path, where `*` doesn't match `/`, and a `**`
+/// component matches any number of directories.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ValidationExclusions {
+    patterns: Vec<(String, Option<FileType>)>,
+}
+
+/// The built-in volatile paths, and the type of file they apply to.
+const VOLATILE_PATHS: &[(&str, Option<FileType>)] = &[
+    ("etc/mtab", None),
+    ("etc/ld.so.cache", Some(FileType::File)),
+    ("usr/share/info/dir", Some(FileType::File)),
+    ("usr/share/fonts/*/fonts.dir", Some(FileType::File)),
+    ("usr/share/fonts/*/fonts.scale", Some(FileType::File)),
+    ("usr/share/icons/*/icon-theme.cache", Some(FileType::File)),
+    (
+        "usr/share/glib-2.0/schemas/gschemas.compiled",
+        Some(FileType::File),
+    ),
+    ("usr/share/mime/*", Some(FileType::File)),
+    ("var/cache/fontconfig/*", Some(FileType::File)),
+    ("usr/lib/python*/**/__pycache__/*.pyc", Some(FileType::File)),
+    ("usr/lib/python*/**/__pycache__", Some(FileType::Directory)),
+];
+
+impl ValidationExclusions {
+    /// Skip nothing.
+    pub fn none() -> ValidationExclusions {
+        ValidationExclusions {
+            patterns: Vec::new(),
+        }
+    }
+
+    /// The built-in set of volatile paths.
+    pub fn volatile() -> ValidationExclusions {
+        ValidationExclusions {
+            patterns: VOLATILE_PATHS
+                .iter()
+                .map(|&(pattern, file_type)| (pattern.to_owned(), file_type))
+                .collect(),
+        }
+    }
+
+    /// Also skip the files matching a glob relative to the root (like `var/lib/foo/*`), either
+    /// of any type or only of the given type.
+    ///
+    /// `*` doesn't match `/`, so `var/lib/foo/*` doesn't skip `var/lib/foo/bar/baz`; use
+    /// `var/lib/foo/**/*` for that.
+    pub fn with_pattern(mut self, pattern: impl Into<String>, file_type: Option<FileType>) -> Self {
+        self.patterns.push((pattern.into(), file_type));
+        self
+    }
+
+    /// The globs skipped, with the type of file each applies to.
+    pub fn patterns(&self) -> impl Iterator<Item = (&str, Option<FileType>)> {
+        self.patterns
+            .iter()
+            .map(|(pattern, file_type)| (pattern.as_str(), *file_type))
+    }
+
+    /// Whether a file (as in an mtree, relative to the root) is skipped.
+    pub fn is_excluded(&self, path: &Path, file_type: Option<FileType>) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+        let path = path.strip_prefix(".").unwrap_or(path);
+        let path: Vec<&[u8]> = path.as_os_str().as_bytes().split(|&c| c == b'/').collect();
+        self.patterns.iter().any(|(pattern, only)| {
+            let pattern: Vec<&[u8]> = pattern.as_bytes().split(|&c| c == b'/').collect();
+            only.map_or(true, |only| file_type == Some(only)) && path_matches(&pattern, &path)
+        })
+    }
+}
+
+impl Default for ValidationExclusions {
+    fn default() -> Self {
+        ValidationExclusions::none()
+    }
+}
+
+/// Match a path against a glob a component at a time, so `*` stays within a component, and a `**`
+/// component matches any number of components.
+fn path_matches(pattern: &[&[u8]], path: &[&[u8]]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&b"**", rest)) => (0..=path.len()).any(|skip| path_matches(rest, &path[skip..])),
+        Some((component, rest)) => match path.split_first() {
+            Some((first, path)) => hook::fnmatch(component, first) && path_matches(rest, path),
+            None => false,
+        },
+    }
+}
+
+/// The result of validating a single package.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ValidationReport {
+    /// The name of the package.
+    pub package: String,
+    /// The version of the package the files were validated against.
+    pub version: String,
+    /// How many checks were made, and how many failed.
+    pub counts: ValidationCounts,
+    /// Every problem found.
+    pub errors: Vec<ValidationError>,
+    /// How long validation took.
+    pub elapsed: Duration,
+}
+
+impl ValidationReport {
+    /// Did every check pass.
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// The files that differ from the package metadata, without duplicates.
+    pub fn differing_files(&self) -> BTreeSet<&str> {
+        self.errors.iter().map(ValidationError::filename).collect()
+    }
+}
+
+/// The result of validating every installed package (see `Alpm::validate_all`).
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ValidationSummary {
+    /// The report for each package, sorted by name.
+    pub reports: Vec<ValidationReport>,
+    /// How long validation took overall.
+    pub elapsed: Duration,
+}
+
+impl ValidationSummary {
+    /// The counts for all packages added together.
+    pub fn counts(&self) -> ValidationCounts {
+        let mut counts = ValidationCounts::default();
+        for report in &self.reports {
+            counts += report.counts;
+        }
+        counts
+    }
+
+    /// The reports of packages that had problems.
+    pub fn invalid(&self) -> impl Iterator<Item = &ValidationReport> {
+        self.reports.iter().filter(|report| !report.is_valid())
+    }
+}
+
 /// Possible problems with a package.
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
 pub enum ValidationError {
     /// A file in the package is not present on disk.
     FileNotFound(String),
@@ -473,6 +1179,15 @@ impl fmt::Display for ValidationError {
 impl StdError for ValidationError {}
 
 impl ValidationError {
+    /// The file the problem is with.
+    pub fn filename(&self) -> &str {
+        match self {
+            ValidationError::FileNotFound(filename)
+            | ValidationError::WrongType { filename, .. }
+            | ValidationError::WrongSize { filename, .. } => filename,
+        }
+    }
+
     /// Constructor for FileNotFound variant
     #[inline]
     fn file_not_found(s: impl Into<String>) -> ValidationError {
@@ -507,3 +1222,123 @@ impl ValidationError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volatile_paths() {
+        let exclusions = ValidationExclusions::volatile();
+        let file = Some(FileType::File);
+        assert!(exclusions.is_excluded(Path::new("./etc/mtab"), Some(FileType::SymbolicLink)));
+        assert!(exclusions.is_excluded(
+            Path::new("./usr/lib/python3.8/__pycache__/os.cpython-38.pyc"),
+            file
+        ));
+        assert!(!exclusions.is_excluded(Path::new("./usr/bin/python"), file));
+        // The type matters where it is given.
+        assert!(!exclusions.is_excluded(Path::new("./usr/share/info/dir"), None));
+        // Patterns are anchored, and `*` stays within a component.
+        assert!(!exclusions.is_excluded(Path::new("./usr/share/mime/packages/foo.xml"), file));
+        assert!(!exclusions.is_excluded(Path::new("./opt/foo/__pycache__/bar.pyc"), file));
+        assert!(!exclusions.is_excluded(Path::new("./usr/lib/python3.8/foo.pyc"), file));
+        assert!(exclusions.is_excluded(
+            Path::new("./usr/lib/python3.8/site-packages/foo/bar/__pycache__"),
+            Some(FileType::Directory)
+        ));
+        let custom = ValidationExclusions::none().with_pattern("var/lib/foo/*", None);
+        assert!(custom.is_excluded(Path::new("./var/lib/foo/state"), file));
+        assert!(!custom.is_excluded(Path::new("./var/lib/foo/bar/state"), file));
+        assert!(!custom.is_excluded(Path::new("./etc/mtab"), file));
+        let deep = ValidationExclusions::none().with_pattern("var/lib/foo/**/*", None);
+        assert!(deep.is_excluded(Path::new("./var/lib/foo/bar/state"), file));
+        assert!(!ValidationExclusions::default().is_excluded(Path::new("./etc/mtab"), None));
+    }
+
+    #[test]
+    fn special_mode_bits() {
+        let raw = "#mtree\n./usr/bin/sudo type=file mode=4755\n./tmp type=dir mode=1777\n\
+                   ./usr/bin/ls type=file mode=755\n";
+        let modes: Vec<Option<u32>> = MTree::from_reader(raw.as_bytes())
+            .map(|entry| FileEntry {
+                entry: entry.unwrap(),
+            })
+            .map(|file| file.mode())
+            .collect();
+        assert_eq!(modes, [Some(0o4755), Some(0o1777), Some(0o755)]);
+    }
+
+    #[test]
+    fn backup_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("foo.conf"), "hello\n").unwrap();
+        let unchanged = BackupFile::parse("foo.conf\tb1946ac92492d2347c6235b4d2611184");
+        assert_eq!(unchanged.path, Path::new("foo.conf"));
+        assert!(!unchanged.is_modified(dir.path()).unwrap());
+        let changed = BackupFile::parse("foo.conf\t00000000000000000000000000000000");
+        assert!(changed.is_modified(dir.path()).unwrap());
+        let missing = BackupFile::parse("bar.conf\t00000000000000000000000000000000");
+        assert!(!missing.is_modified(dir.path()).unwrap());
+        assert_eq!(BackupFile::parse("baz.conf").md5sum, "");
+    }
+
+    #[test]
+    fn provenance_xdata() {
+        let provenance = Provenance {
+            repository: Some("core".into()),
+            mirror: Some(
+                "https://mirror.example/core/os/x86_64/bash-5.0-1-x86_64.pkg.tar.xz".into(),
+            ),
+            sha256: None,
+            transaction: Some("42".into()),
+        };
+        let mut xdata = vec!["pkgtype=pkg".to_owned()];
+        assert_eq!(Provenance::from_xdata(&xdata), None);
+        xdata.extend(vec![
+            "provenance.repository=core".to_owned(),
+            format!("provenance.mirror={}", provenance.mirror.as_ref().unwrap()),
+            "provenance.transaction=42".to_owned(),
+            "provenance.unknown=ignored".to_owned(),
+        ]);
+        assert_eq!(Provenance::from_xdata(&xdata), Some(provenance));
+    }
+
+    #[test]
+    fn replace_desc_section() {
+        let desc = "%NAME%\nbash\n\n%XDATA%\npkgtype=pkg\n\n%SIZE%\n10\n\n";
+        assert_eq!(section_values(desc, "XDATA"), vec!["pkgtype=pkg"]);
+        assert!(section_values(desc, "GROUPS").is_empty());
+        assert_eq!(
+            replace_section(desc, "XDATA", &["pkgtype=pkg".into(), "a=b".into()]),
+            "%NAME%\nbash\n\n%SIZE%\n10\n\n%XDATA%\npkgtype=pkg\na=b\n\n"
+        );
+        assert_eq!(
+            replace_section(desc, "XDATA", &[]),
+            "%NAME%\nbash\n\n%SIZE%\n10\n\n"
+        );
+        assert_eq!(
+            replace_section("%NAME%\nbash\n", "XDATA", &["a=b".into()]),
+            "%NAME%\nbash\n\n%XDATA%\na=b\n\n"
+        );
+    }
+
+    #[test]
+    fn compressed_file_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = "%FILES%\nusr/\nusr/bin/foo\n\n";
+        fs::write(dir.path().join(FILES_FILE), raw).unwrap();
+
+        assert!(convert_file_list(dir.path(), true).unwrap());
+        assert!(!dir.path().join(FILES_FILE).exists());
+        assert_eq!(read_file_list(dir.path()).unwrap(), raw);
+        assert!(!convert_file_list(dir.path(), true).unwrap());
+
+        assert!(convert_file_list(dir.path(), false).unwrap());
+        assert!(!dir.path().join(COMPRESSED_FILES_FILE).exists());
+        assert_eq!(
+            fs::read_to_string(dir.path().join(FILES_FILE)).unwrap(),
+            raw
+        );
+    }
+}
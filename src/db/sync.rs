@@ -1,34 +1,60 @@
 //! Remote databases (a.k.a. "sync databases")
 //!
 //! Sync databases are the same as the local database, except that they don't have the `file` and
-//! `mtree` files, and they are `tar`d and `gzipped` up.
+//! `mtree` files, and they are `tar`d and compressed (usually with gzip or zstd).
 
-use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::ops::Deref;
 use std::path::{self, Path, PathBuf};
 use std::rc::{Rc, Weak as WeakRc};
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::db::{
-    Database, DbStatus, DbUsage, SignatureLevel, DEFAULT_SYNC_DB_EXT, LOCAL_DB_NAME, SYNC_DB_DIR,
+    Database, DbStatus, DbUsage, InvalidReason, LocalDatabase, SignatureLevel, LOCAL_DB_NAME,
 };
+use crate::diagnostics::{self, Warning};
+use crate::download::{self, Conditions, Progress, Throttle};
+use crate::downloader::{DownloadRequest, Downloader};
 use crate::error::{Error, ErrorContext, ErrorKind};
-use crate::util::UrlOrStr;
-use crate::Handle;
+use crate::event::Event;
+use crate::hash;
+use crate::util::{self, UrlOrStr};
 
-use fs2::FileExt;
-use libflate::gzip;
+use self::cache::PackageCache;
+use self::files::{FileIndex, FILES_EXT, INDEX_EXT};
+use self::group::GroupCollector;
+use crate::{Alpm, Handle};
+
+use indexmap::IndexSet;
 use reqwest::Url;
 
+pub use self::delta::Delta;
+pub use self::files::FileMatch;
+pub(crate) use self::format::open_archive;
+pub use self::format::{Compression, DbFormat};
+pub use self::group::SyncGroup;
+pub use self::metadata::{SyncAttempt, SyncMetadata, SyncOutcome};
 pub use self::package::SyncPackage;
 
+mod cache;
+mod delta;
+mod differential;
+mod files;
+mod format;
+mod group;
+mod metadata;
 mod package;
 
-const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %T GMT";
+/// The extension of the file whose modification time records the last successful sync.
+const LAST_SYNC_EXT: &str = "lastsync";
+/// The extension of the file that stores `SyncMetadata`.
+const METADATA_EXT: &str = "syncinfo";
 
 /// A sync database of available packages.
 #[derive(Debug, Clone)]
@@ -44,7 +70,7 @@ impl SyncDatabase {
         SyncDatabase { inner, name }
     }
 
-    /// Get a copy of the registered servers for this database.
+    /// Get a copy of the registered servers for this database, in the order they were added.
     #[inline]
     pub fn servers<'a>(&'a self) -> Result<Vec<Url>, Error> {
         Ok(self
@@ -56,7 +82,7 @@ impl SyncDatabase {
             .collect())
     }
 
-    /// Add server
+    /// Add a server, which can be a directory mirror (see `SyncDatabaseOptions::with_server`).
     #[inline]
     pub fn add_server<U>(&mut self, url: U) -> Result<(), Error>
     where
@@ -80,7 +106,214 @@ impl SyncDatabase {
 
     /// Synchronize the database with any external sources.
     pub fn synchronize(&self, force: bool) -> Result<(), Error> {
-        self.inner.borrow_mut().synchronize(force)
+        let start = Instant::now();
+        let result = self.inner.borrow_mut().synchronize(force);
+        if let Ok(handle) = self.inner.borrow().get_handle() {
+            handle.borrow().measure(|metrics| {
+                metrics.database_synchronized(&self.name, start.elapsed(), result.is_ok())
+            });
+        }
+        result
+    }
+
+    /// The level of signature verification required for this database.
+    pub fn signature_level(&self) -> SignatureLevel {
+        self.inner.borrow().sig_level
+    }
+
+    /// Which operations this database is used for.
+    pub fn usage(&self) -> DbUsage {
+        self.inner.borrow().usage
+    }
+
+    /// The priority of this database (see `SyncDatabaseOptions::with_priority`).
+    pub fn priority(&self) -> i32 {
+        self.inner.borrow().priority
+    }
+
+    /// Download a package from this database's servers into `dest_dir`, returning the path of the
+    /// package archive.
+    ///
+    /// If `dest_dir` already has an archive of the package that passes
+    /// `SyncPackage::verify_archive`, it is used without downloading anything. An archive that
+    /// doesn't pass is replaced.
+    ///
+    /// If `installed` gives the version and archive of the currently installed package, and the
+    /// database lists deltas from it that are small enough (see `AlpmBuilder::with_delta_ratio`),
+    /// the deltas are downloaded and applied using `xdelta3` instead. If anything goes wrong with
+    /// the deltas, the whole package is downloaded.
+    pub fn download_package(
+        &self,
+        package: &SyncPackage,
+        dest_dir: &Path,
+        installed: Option<(&str, &Path)>,
+    ) -> Result<PathBuf, Error> {
+        self.inner
+            .borrow()
+            .download_package(package, dest_dir, installed)
+    }
+
+    /// Download a package into `dest_dir` for a later upgrade, at the rate set by
+    /// `AlpmBuilder::with_prefetch_rate_limit`.
+    ///
+    /// The archive is downloaded to `<filename>.part`, which is kept if the download fails, so
+    /// the next call resumes it rather than starting again.
+    pub(crate) fn prefetch_package(
+        &self,
+        package: &SyncPackage,
+        dest_dir: &Path,
+    ) -> Result<PathBuf, Error> {
+        self.inner.borrow().prefetch_package(package, dest_dir)
+    }
+
+    /// Read the database file, if it hasn't been read already.
+    ///
+    /// This happens automatically when packages are first requested, but calling it directly
+    /// means problems with the database file can be found up front.
+    pub fn load(&self) -> Result<(), Error> {
+        self.inner.borrow_mut().ensure_loaded()
+    }
+
+    /// Load the database if necessary, and borrow it.
+    fn loaded(&self) -> Result<Ref<SyncDatabaseInner>, Error> {
+        self.load()?;
+        Ok(self.inner.borrow())
+    }
+
+    /// The metadata recorded about synchronizing this database, or `None` if it isn't being
+    /// recorded (see `SyncDatabaseOptions::with_sync_metadata`).
+    pub fn sync_metadata(&self) -> Result<Option<SyncMetadata>, Error> {
+        let inner = self.inner.borrow();
+        if !inner.store_metadata {
+            return Ok(None);
+        }
+        SyncMetadata::load(&inner.metadata_path()).map(Some)
+    }
+
+    /// When the database was last successfully synchronized, even if it was already up to date.
+    ///
+    /// If it has never been synchronized by this library, the modification time of the database
+    /// file is used. This is `None` if there is no database file.
+    pub fn last_synced(&self) -> Option<SystemTime> {
+        self.inner.borrow().last_synced()
+    }
+
+    /// Whether the database was last synchronized more than `max_age` ago (or never).
+    ///
+    /// Frontends can use this to warn users before installing from old metadata.
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        match self.last_synced() {
+            // A time in the future means the clock has changed, so we can't tell.
+            Some(synced) => SystemTime::now()
+                .duration_since(synced)
+                .map_or(false, |age| age > max_age),
+            None => true,
+        }
+    }
+
+    /// The package groups in this database, sorted by name.
+    pub fn groups(&self) -> Result<Vec<Rc<SyncGroup>>, Error> {
+        Ok(self.loaded()?.group_cache.values().cloned().collect())
+    }
+
+    /// The group with the given name, or `None` if no package in this database is in it.
+    pub fn group(&self, name: &str) -> Result<Option<Rc<SyncGroup>>, Error> {
+        Ok(self.loaded()?.group_cache.get(name).cloned())
+    }
+
+    /// Whether this database has a package with the given name.
+    pub fn contains(&self, name: &str) -> Result<bool, Error> {
+        Ok(self.loaded()?.package_cache.contains(name))
+    }
+
+    /// The version of the package with the given name, or `None` if this database doesn't have
+    /// it.
+    pub fn version_of(&self, name: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .loaded()?
+            .package_cache
+            .version(name)
+            .map(str::to_owned))
+    }
+
+    /// The `desc` file of the package with the given name, exactly as it is in the database.
+    ///
+    /// This is for tools that need the bytes the repository published, for example to check a
+    /// signature over an entry or to cache it, without reading the database archive again. It is
+    /// `None` if this database doesn't have the package, and fails if the database can't be
+    /// loaded.
+    pub fn raw_entry(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self
+            .loaded()?
+            .package_cache
+            .desc(name)
+            .map(|desc| desc.as_bytes().to_vec()))
+    }
+
+    /// Find files in this database's packages (see `Alpm::search_files`), sorted by path.
+    ///
+    /// This needs the file list, which is downloaded when synchronizing if the database was
+    /// registered with `SyncDatabaseOptions::with_file_lists`.
+    pub fn search_files(&self, pattern: &str) -> Result<Vec<FileMatch>, Error> {
+        let index = self.inner.borrow_mut().file_index()?;
+        Ok(index
+            .search(pattern)
+            .into_iter()
+            .map(|(path, package)| FileMatch {
+                database: self.name.clone(),
+                package: package.to_owned(),
+                path: path.into(),
+            })
+            .collect())
+    }
+
+    /// The paths of the files in a package, from the file list (see `search_files`).
+    pub(crate) fn package_files(&self, package: &str) -> Result<Vec<String>, Error> {
+        let index = self.inner.borrow_mut().file_index()?;
+        Ok(index
+            .files_of(package)
+            .into_iter()
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// The extension and compression of the database file found on disk.
+    ///
+    /// This is `None` if the database has not been loaded from disk.
+    pub fn format(&self) -> Option<DbFormat> {
+        self.inner.borrow().format.clone()
+    }
+}
+
+/// A sync database borrowed from an `Alpm` instance (see `Alpm::sync_database_ref`).
+///
+/// This can be used wherever a `&SyncDatabase` can. `into_owned` gives up the borrow, along with
+/// the compile-time check that the instance is still alive.
+#[derive(Debug, Clone)]
+pub struct SyncDatabaseRef<'a> {
+    database: SyncDatabase,
+    alpm: PhantomData<&'a Alpm>,
+}
+
+impl<'a> SyncDatabaseRef<'a> {
+    pub(crate) fn new(database: SyncDatabase) -> Self {
+        SyncDatabaseRef {
+            database,
+            alpm: PhantomData,
+        }
+    }
+
+    /// Get an owned database, which is checked at runtime instead.
+    pub fn into_owned(self) -> SyncDatabase {
+        self.database
+    }
+}
+
+impl<'a> Deref for SyncDatabaseRef<'a> {
+    type Target = SyncDatabase;
+
+    fn deref(&self) -> &SyncDatabase {
+        &self.database
     }
 }
 
@@ -99,8 +332,9 @@ impl Database for SyncDatabase {
         self.inner.borrow().status()
     }
 
+    /// The number of packages in the database, or 0 if it could not be loaded.
     fn count(&self) -> usize {
-        unimplemented!()
+        self.loaded().map(|db| db.package_cache.len()).unwrap_or(0)
     }
 
     fn package(&self, name: impl AsRef<str>, version: impl AsRef<str>) -> Result<Self::Pkg, Error> {
@@ -108,15 +342,15 @@ impl Database for SyncDatabase {
 
         let name = name.as_ref();
         let version = version.as_ref();
-        let db = self.inner.borrow();
+        let db = self.loaded()?;
         let package = db
             .package_cache
-            .get(&Cow::Borrowed(name))
+            .get(name, &db.handle)?
             .ok_or(ErrorKind::InvalidLocalPackage(name.to_owned()))?;
         if version != package.version() {
             return Err(ErrorKind::InvalidLocalPackage(name.to_owned()))?;
         }
-        Ok(package.clone())
+        Ok(package)
     }
 
     fn package_latest<Str>(&self, name: Str) -> Result<Self::Pkg, Error>
@@ -124,13 +358,11 @@ impl Database for SyncDatabase {
         Str: AsRef<str>,
     {
         let name = name.as_ref();
-        let package = self
-            .inner
-            .borrow()
+        let db = self.loaded()?;
+        let package = db
             .package_cache
-            .get(&Cow::Borrowed(name))
-            .ok_or(ErrorKind::InvalidLocalPackage(name.to_owned()))?
-            .clone();
+            .get(name, &db.handle)?
+            .ok_or(ErrorKind::InvalidLocalPackage(name.to_owned()))?;
         Ok(package)
     }
 
@@ -139,14 +371,137 @@ impl Database for SyncDatabase {
         F: FnMut(Self::Pkg) -> Result<(), E>,
         E: From<Error>,
     {
-        let db = self.inner.borrow();
-        for package in db.package_cache.values() {
-            f(package.clone())?;
+        let db = self.loaded()?;
+        for package in db.package_cache.iter(&db.handle) {
+            f(package?)?;
         }
         Ok(())
     }
 }
 
+/// Options for registering a sync database, like a `[repo]` section in `pacman.conf`.
+///
+/// Anything not set is inherited from the alpm instance.
+#[derive(Debug, Clone, Default)]
+pub struct SyncDatabaseOptions {
+    /// The extension of the database file.
+    extension: Option<String>,
+    /// The level of signature verification required to accept packages.
+    sig_level: SignatureLevel,
+    /// Which operations this database will be used for.
+    usage: DbUsage,
+    /// Servers to add to the database.
+    servers: Vec<String>,
+    /// Databases with a higher priority are preferred.
+    priority: i32,
+    /// Whether to keep a `SyncMetadata` file.
+    sync_metadata: bool,
+    /// Whether to download and index the file list.
+    file_lists: bool,
+    /// Whether to try fetching only the changed entries when synchronizing.
+    differential: bool,
+    /// The most packages to keep parsed in memory, if limited.
+    parsed_package_limit: Option<usize>,
+}
+
+impl SyncDatabaseOptions {
+    /// Create options that inherit everything from the alpm instance.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Use a different extension for this database.
+    pub fn with_extension(mut self, extension: impl Into<String>) -> Self {
+        self.extension = Some(extension.into());
+        self
+    }
+
+    /// Use a different signature verification level for this database.
+    pub fn with_signature_level(mut self, sig_level: SignatureLevel) -> Self {
+        self.sig_level = sig_level;
+        self
+    }
+
+    /// Only use this database for the given operations.
+    pub fn with_usage(mut self, usage: DbUsage) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Add a server to the database.
+    ///
+    /// As well as HTTP(S) mirrors, this can be a directory mirror: a `file://` url or an absolute
+    /// path, like a mounted network share. Its files' modification times are used to tell if the
+    /// database has changed.
+    pub fn with_server(mut self, url: impl Into<String>) -> Self {
+        self.servers.push(url.into());
+        self
+    }
+
+    /// Set the priority of the database.
+    ///
+    /// When a package is in more than one database, it is taken from the one with the highest
+    /// priority, and then the one registered first. The default is 0.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Record which mirror was used, the HTTP validators and recent sync attempts in a file next
+    /// to the database (see `SyncDatabase::sync_metadata`).
+    ///
+    /// The stored `ETag` is also sent when synchronizing from the same mirror, so an unchanged
+    /// database isn't downloaded again.
+    pub fn with_sync_metadata(mut self, enabled: bool) -> Self {
+        self.sync_metadata = enabled;
+        self
+    }
+
+    /// Also download the list of files in each package (the `.files` database) when
+    /// synchronizing, and index it for `Alpm::search_files`.
+    ///
+    /// File lists are much bigger than the database, so this is off by default. If the file list
+    /// can't be synchronized, a `Warning::FileListSyncFailed` is given, and the sync of the
+    /// database still succeeds.
+    pub fn with_file_lists(mut self, enabled: bool) -> Self {
+        self.file_lists = enabled;
+        self
+    }
+
+    /// When synchronizing, download only the entries that have changed, from servers that publish
+    /// a manifest of the database.
+    ///
+    /// The manifest is `<database file>.manifest`, with a line for each entry giving its directory
+    /// name and the sha256 checksum of its `desc` file, and each `desc` file is published as
+    /// `<database file>.d/<directory name>/desc`. The whole database is downloaded from servers
+    /// without a manifest, or if anything goes wrong.
+    pub fn with_differential_sync(mut self, enabled: bool) -> Self {
+        self.differential = enabled;
+        self
+    }
+
+    /// Keep at most `limit` packages from this database parsed in memory.
+    ///
+    /// Only the text of each package's `desc` file is kept, along with the `limit` most recently
+    /// used packages. Others are parsed again each time they are needed, so this trades CPU for
+    /// memory, for very large databases in constrained environments. By default every package is
+    /// kept parsed.
+    pub fn with_parsed_package_limit(mut self, limit: usize) -> Self {
+        self.parsed_package_limit = Some(limit);
+        self
+    }
+
+    /// The extension to use, if set.
+    pub(crate) fn extension(&self) -> Option<&str> {
+        self.extension.as_ref().map(String::as_str)
+    }
+
+    /// The servers to add.
+    pub(crate) fn servers(&self) -> &[String] {
+        &self.servers
+    }
+}
+
 /// A package database.
 #[derive(Debug)]
 pub struct SyncDatabaseInner {
@@ -158,50 +513,84 @@ pub struct SyncDatabaseInner {
     sig_level: SignatureLevel,
     /// Which operations this database will be used for.
     usage: DbUsage,
-    /// A list of servers for this database
-    servers: HashSet<Url>,
+    /// A list of servers for this database, in the order they should be tried.
+    servers: IndexSet<Url>,
+    /// Databases with a higher priority are preferred.
+    priority: i32,
+    /// Whether to keep a `SyncMetadata` file.
+    store_metadata: bool,
+    /// Whether to download and index the file list when synchronizing.
+    file_lists: bool,
+    /// Whether to try fetching only the changed entries when synchronizing.
+    differential: bool,
+    /// The index of the file list, once it has been loaded.
+    file_index: Option<Rc<FileIndex>>,
     /// The database path.
     pub path: PathBuf,
-    /// The package cache (HashMap of package name to package)
+    /// The extension of the database file (the path and the file on the server).
+    extension: String,
+    /// The format of the database file, if it has been loaded.
+    format: Option<DbFormat>,
+    /// Whether the package cache has been read from the database file.
+    loaded: bool,
+    /// Why the database file could not be loaded, if it couldn't.
+    load_error: Option<String>,
+    /// The package cache (map of package name to package, ordered so iteration is deterministic)
     // Unlike in LocalDatabaseInner we don't have a version, since there is only one version of any
     // package in a sync repository.
-    package_cache: HashMap<Cow<'static, str>, Rc<SyncPackage>>,
-    /// Count of the number of packages (cached)
-    package_count: usize,
+    package_cache: PackageCache,
+    /// The package groups, by name, collected when the package cache is populated.
+    group_cache: BTreeMap<String, Rc<SyncGroup>>,
 }
 impl SyncDatabaseInner {
     /// Create a new sync db instance
     ///
-    /// The name of this database must not match LOCAL_DB_NAME
+    /// The name of this database must not match LOCAL_DB_NAME. Servers in the options are not
+    /// added, this is left to the caller so errors can be reported.
     ///
-    /// # Panics
-    ///
-    /// This function panics if a SyncDatabase already exists with the given name
+    /// The database file is not read until packages are first requested, so a missing or broken
+    /// file can be fixed (e.g. by synchronizing) after the database is registered.
     pub(crate) fn new(
         handle: Rc<RefCell<Handle>>,
         name: SyncDbName,
-        sig_level: SignatureLevel,
-    ) -> SyncDatabaseInner {
+        options: &SyncDatabaseOptions,
+    ) -> Result<SyncDatabaseInner, Error> {
         let handle_ref = handle.borrow();
-        // This is the caller's responsibility.
-        assert!(
-            !handle_ref.sync_database_registered(&name),
-            "internal error - database already exists"
-        );
-        let path = name.path(&handle_ref.database_path);
+        if handle_ref.sync_database_registered(&name) {
+            return Err(ErrorKind::DatabaseAlreadyExists(name.into()).into());
+        }
+        // Use whichever database file is present, falling back to the configured extension.
+        let preferred_ext = options
+            .extension
+            .as_ref()
+            .unwrap_or(&handle_ref.database_extension);
+        let sync_path = &handle_ref.sync_database_path;
+        let (path, extension) =
+            format::locate(&name, sync_path, preferred_ext).unwrap_or_else(|| {
+                let ext = preferred_ext.clone();
+                (name.path(sync_path, &ext), ext)
+            });
         drop(handle_ref);
-        let mut db = SyncDatabaseInner {
+        let db = SyncDatabaseInner {
             handle: Rc::downgrade(&handle),
             name,
-            sig_level,
-            usage: DbUsage::ALL,
-            servers: HashSet::new(),
+            sig_level: options.sig_level,
+            usage: options.usage,
+            servers: IndexSet::new(),
+            priority: options.priority,
+            store_metadata: options.sync_metadata,
+            file_lists: options.file_lists,
+            differential: options.differential,
+            file_index: None,
             path,
-            package_cache: HashMap::new(),
-            package_count: 0,
+            extension,
+            format: None,
+            loaded: false,
+            load_error: None,
+            package_cache: PackageCache::new(options.parsed_package_limit),
+            group_cache: BTreeMap::new(),
         };
-        db.populate_package_cache().unwrap();
-        db
+        Ok(db)
     }
 
     /// Add server
@@ -232,10 +621,12 @@ impl SyncDatabaseInner {
             self.name
         );
         if !self.servers.insert(url.clone()) {
-            log::warn!(
-                r#"server with url "{}" was already present in database "{}"."#,
-                url,
-                self.name
+            diagnostics::warn(
+                &self.handle,
+                Warning::DuplicateServer {
+                    url: url.to_string(),
+                    database: self.name.to_string(),
+                },
             );
         }
         Ok(())
@@ -259,11 +650,13 @@ impl SyncDatabaseInner {
             self.name
         );
 
-        if !self.servers.remove(&url) {
-            log::warn!(
-                r#"server with url "{}" was not present in database "{}"."#,
-                url,
-                self.name
+        if !self.servers.shift_remove(&url) {
+            diagnostics::warn(
+                &self.handle,
+                Warning::ServerNotFound {
+                    url: url.to_string(),
+                    database: self.name.to_string(),
+                },
             );
         }
         Ok(())
@@ -279,15 +672,20 @@ impl SyncDatabaseInner {
     ///
     /// # Params
     ///  - `md` metadata for the database root
-    ///  - `path` the path of the database root
     ///
-    /// Returns true if the database is valid, false otherwise
-    fn is_valid(&self, md: fs::Metadata) -> bool {
+    /// Returns a list of problems with the database, which is empty if it is valid.
+    fn invalid_reasons(&self, md: fs::Metadata) -> Vec<InvalidReason> {
         if !md.is_file() {
-            return false;
+            return vec![InvalidReason::NotAFile];
         }
-        // todo check signature
-        true
+        let mut reasons = Vec::new();
+        if let Err(e) = format::check_archive(&self.path) {
+            reasons.push(InvalidReason::BadArchive(e.to_string()));
+        } else if let Some(e) = &self.load_error {
+            reasons.push(InvalidReason::BadArchive(e.clone()));
+        }
+        // todo check signature (`InvalidReason::BadSignature`)
+        reasons
     }
 
     /// Get the status of this database.
@@ -301,88 +699,833 @@ impl SyncDatabaseInner {
             Ok(md) => md,
         };
 
-        Ok(if self.is_valid(metadata) {
-            DbStatus::Valid
-        } else {
-            DbStatus::Invalid
-        })
+        Ok(DbStatus::from_reasons(self.invalid_reasons(metadata)))
     }
 
     /// Synchronize the database with any external sources.
     fn synchronize(&mut self, mut force: bool) -> Result<(), Error> {
-        use chrono::{DateTime, Utc};
-        use reqwest::header::IF_MODIFIED_SINCE;
-        use reqwest::StatusCode;
-        use std::time::SystemTime;
-
         log::debug!(r#"Updating sync database "{}"."#, self.name);
 
         let handle = self.get_handle()?;
         let handle_ref = handle.borrow();
+        handle_ref.check_writable()?;
+        // Held until the end of the sync, including the file list.
+        let _lock = self.lock_for_update(&handle_ref)?;
 
-        // Force a reload when the db is invalid.
-        match self.status()? {
-            DbStatus::Valid => (),
-            DbStatus::Invalid | DbStatus::Missing => {
+        // Force a reload when the db is invalid. Otherwise remember what it contains, so we can
+        // tell if installed packages disappear from it.
+        let previous = match self.status()? {
+            DbStatus::Valid => self.package_names(),
+            DbStatus::Invalid { .. } | DbStatus::Missing => {
                 force = true;
+                BTreeSet::new()
             }
         };
 
-        // todo this possibly isn't how arch works - it may get the last update time from inside
-        // the db somehow
-        let modified = fs::metadata(&self.path).and_then(|md| md.modified()).ok();
+        let mut metadata = if self.store_metadata {
+            match SyncMetadata::load(&self.metadata_path()) {
+                Ok(metadata) => Some(metadata),
+                Err(e) => {
+                    log::warn!("starting new sync metadata for {}: {}", self.name, e);
+                    Some(SyncMetadata::default())
+                }
+            }
+        } else {
+            None
+        };
+
+        let servers = self.servers.clone();
+        let result = self.synchronize_from(&handle_ref, &servers, force, &mut metadata);
+        if let Some(metadata) = &metadata {
+            if let Err(e) = metadata.save(&self.metadata_path()) {
+                log::warn!("could not save sync metadata for {}: {}", self.name, e);
+            }
+        }
+        result?;
+        self.record_dropped(&handle_ref, previous)?;
+        // The database itself has been replaced by now, so failing here would report a sync
+        // that happened as failed.
+        if self.file_lists {
+            if let Err(e) = self.synchronize_file_list(&handle_ref, force) {
+                handle_ref.warn(Warning::FileListSyncFailed {
+                    database: self.name.to_string(),
+                    reason: e.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Lock the database against other instances updating it, until the returned file is
+    /// dropped.
+    ///
+    /// The lock is on `<name>.<extension>.lck` next to the database, not the database itself:
+    /// updates replace the database by renaming over it, so two instances that locked it could
+    /// each hold a lock on a different file.
+    fn lock_for_update(&self, handle_ref: &Handle) -> Result<fs::File, Error> {
+        let lock_path = self
+            .path
+            .with_file_name(format!("{}.lck", self.name.filename(&self.extension)));
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&lock_path)
+            .map_err(|e| Error::cannot_acquire_lock(lock_path.clone(), e))?;
+        util::lock_exclusive(&lock, &lock_path, handle_ref.lock_mode)?;
+        Ok(lock)
+    }
+
+    /// Download the file list if it has changed, and rebuild its index.
+    fn synchronize_file_list(&mut self, handle_ref: &Handle, force: bool) -> Result<(), Error> {
+        use reqwest::StatusCode;
+
+        let filename = self.name.filename(FILES_EXT);
+        let files_path = self.path.with_file_name(&filename);
+        let index_path = self.path.with_file_name(self.name.filename(INDEX_EXT));
+        let modified = download::if_modified_since(&files_path);
+        let sync_dir = self
+            .path
+            .parent()
+            .ok_or_else(|| ErrorKind::BadSyncDatabasePath(self.path.clone()))?
+            .to_owned();
 
         for server in self.servers.iter() {
-            let filename = self.name.filename(&handle_ref.database_extension);
-            let url = server.join(&filename).unwrap();
+            let url = self.join_url(server, &filename)?;
+            log::debug!("Requesting file list from {}", url);
+            let if_modified_since = modified.as_ref().filter(|_| !force);
+            let mut download = tempfile::NamedTempFile::new_in(&sync_dir)?;
+            let conditions = Conditions {
+                if_modified_since: if_modified_since.map(String::as_str),
+                ..Conditions::default()
+            };
+            let response = download::get(
+                handle_ref,
+                &url,
+                conditions,
+                download.as_file_mut(),
+                &mut |_| (),
+            )?;
+            match StatusCode::from_u16(response.status) {
+                Ok(StatusCode::NOT_MODIFIED) => {
+                    // The index may not have been built yet, or may have been removed.
+                    if !index_path.is_file() {
+                        FileIndex::build(self.name.as_ref(), &files_path, &index_path)?;
+                        self.file_index = None;
+                    }
+                    return Ok(());
+                }
+                Ok(StatusCode::OK) => (),
+                _ => {
+                    handle_ref.warn(Warning::UnexpectedStatus {
+                        url: url.to_string(),
+                        database: self.name.to_string(),
+                        status: response.status,
+                    });
+                    continue;
+                }
+            }
+            if let Err(e) = format::verify_archive(download.path()) {
+                if self.corrupt_download(handle_ref, &url, &filename, download, &sync_dir, &e)? {
+                    continue;
+                }
+                return Err(e);
+            }
+            download.persist(&files_path).map_err(|e| e.error)?;
+            set_modified(
+                &files_path,
+                response.last_modified.as_ref().map(String::as_str),
+            );
+            FileIndex::build(self.name.as_ref(), &files_path, &index_path)?;
+            self.file_index = None;
+            return Ok(());
+        }
+        if self.servers.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorKind::DownloadFailed(filename).into())
+        }
+    }
+
+    /// The index of the file list, loading it (and building it if necessary) the first time.
+    fn file_index(&mut self) -> Result<Rc<FileIndex>, Error> {
+        if let Some(index) = &self.file_index {
+            return Ok(index.clone());
+        }
+        let files_path = self.path.with_file_name(self.name.filename(FILES_EXT));
+        let index_path = self.path.with_file_name(self.name.filename(INDEX_EXT));
+        if !index_path.is_file() {
+            if !files_path.is_file() {
+                return Err(ErrorKind::FileListMissing(self.name.to_string()).into());
+            }
+            FileIndex::build(self.name.as_ref(), &files_path, &index_path)?;
+        }
+        let index = Rc::new(FileIndex::load(&index_path)?);
+        self.file_index = Some(index.clone());
+        Ok(index)
+    }
+
+    /// The names of the packages in the database, or none if it can't be loaded.
+    fn package_names(&mut self) -> BTreeSet<String> {
+        match self.ensure_loaded() {
+            Ok(()) => self.package_cache.names().map(str::to_owned).collect(),
+            Err(_) => BTreeSet::new(),
+        }
+    }
+
+    /// Record installed packages that were in the database before it was synchronized, but
+    /// aren't now, and tell the user about them.
+    fn record_dropped(&self, handle_ref: &Handle, previous: BTreeSet<String>) -> Result<(), Error> {
+        let local = match &handle_ref.local_database {
+            Some(db) => LocalDatabase::new(db.clone()),
+            None => return Ok(()),
+        };
+        let mut dropped = Vec::new();
+        for name in previous {
+            // Only the names are needed, so entries that can't be loaded are still counted.
+            if !self.package_cache.contains(&name) && local.contains(&name)? {
+                dropped.push(name);
+            }
+        }
+        if dropped.is_empty() {
+            return Ok(());
+        }
+        log::info!(
+            "installed packages dropped from {}: {}",
+            self.name,
+            dropped.join(", ")
+        );
+        handle_ref.dropped_packages.borrow_mut().extend(
+            dropped
+                .iter()
+                .map(|name| (name.clone(), self.name.to_string())),
+        );
+        handle_ref.emit(Event::PackagesDropped {
+            database: self.name.to_string(),
+            packages: dropped,
+        });
+        Ok(())
+    }
+
+    /// Try each server in turn until one gives us a valid database, recording attempts in the
+    /// metadata if there is any.
+    fn synchronize_from(
+        &mut self,
+        handle_ref: &Handle,
+        servers: &IndexSet<Url>,
+        force: bool,
+        metadata: &mut Option<SyncMetadata>,
+    ) -> Result<(), Error> {
+        use reqwest::StatusCode;
+
+        // The modification time is the server's `Last-Modified` date (see `set_modified`).
+        let modified = download::if_modified_since(&self.path);
+        // Download to a temporary file next to the database, so it can be checked before it
+        // replaces the current one.
+        let sync_dir = self
+            .path
+            .parent()
+            .ok_or_else(|| ErrorKind::BadSyncDatabasePath(self.path.clone()))?
+            .to_owned();
+
+        for server in servers.iter() {
+            let filename = self.name.filename(&self.extension);
+            let url = self.join_url(server, &filename)?;
             log::debug!("Requesting update from {}", url);
-            let mut request = handle_ref.http_client.get(url);
-            if let Some(modified) = modified {
-                log::debug!("Database last updated at {:?}", modified);
-                if !force {
-                    // Set If-Modified-Since header to avoid unnecessary download.
-                    let modified = <DateTime<Utc> as From<SystemTime>>::from(modified);
-                    let modified = format!("{}", modified.format(HTTP_DATE_FORMAT));
-                    request = request.header(IF_MODIFIED_SINCE, modified);
+            // An ETag only means something to the server that sent it, and only while we still
+            // have the database it was sent with; otherwise `304 Not Modified` would leave us
+            // without one.
+            let etag = match metadata.as_ref() {
+                Some(metadata)
+                    if !force
+                        && modified.is_some()
+                        && metadata.mirror() == Some(server.as_str()) =>
+                {
+                    metadata.etag().map(str::to_owned)
+                }
+                _ => None,
+            };
+            // Prefer the date this server sent, as it sent it.
+            let if_modified_since = match (metadata.as_ref(), &modified) {
+                (_, None) => None,
+                _ if force => None,
+                (Some(metadata), Some(modified)) if metadata.mirror() == Some(server.as_str()) => {
+                    Some(metadata.last_modified().unwrap_or(modified).to_owned())
+                }
+                (_, Some(modified)) => Some(modified.clone()),
+            };
+            log::debug!("Database last modified {:?}", if_modified_since);
+            // Without a database to reuse entries from there's nothing to gain.
+            if self.differential && !force && modified.is_some() {
+                let if_modified_since = if_modified_since.as_ref().map(String::as_str);
+                match self.synchronize_differential(
+                    handle_ref,
+                    server,
+                    if_modified_since,
+                    &sync_dir,
+                ) {
+                    Ok(Some(outcome)) => {
+                        let updated = outcome == SyncOutcome::Updated;
+                        record(metadata, server, outcome);
+                        if let (true, Some(metadata)) = (updated, metadata.as_mut()) {
+                            // The rebuilt database has no validators of its own.
+                            metadata.set_validators(None, None);
+                        }
+                        self.mark_synced();
+                        return if updated {
+                            self.reload_package_cache()
+                        } else {
+                            Ok(())
+                        };
+                    }
+                    Ok(None) => log::debug!("{} has no manifest for {}", server, self.name),
+                    Err(e) => log::debug!(
+                        "differential sync of {} failed, downloading it all: {}",
+                        self.name,
+                        e
+                    ),
                 }
             }
-            let mut response = request.send().context(ErrorKind::UnexpectedReqwest)?;
-            match response.status() {
-                StatusCode::NOT_MODIFIED => {
+            let mut download = tempfile::NamedTempFile::new_in(&sync_dir)?;
+            let database = self.name.to_string();
+            let conditions = Conditions {
+                if_modified_since: if_modified_since.as_ref().map(String::as_str),
+                if_none_match: etag.as_ref().map(String::as_str),
+                ..Conditions::default()
+            };
+            let response = download::get(
+                handle_ref,
+                &url,
+                conditions,
+                download.as_file_mut(),
+                &mut |progress| match progress {
+                    Progress::Started { total } => {
+                        handle_ref.emit(Event::DatabaseDownloadStarted {
+                            database: database.clone(),
+                            url: url.to_string(),
+                            total,
+                        })
+                    }
+                    Progress::Downloaded { downloaded, total } => {
+                        handle_ref.emit(Event::DatabaseDownloadProgress {
+                            database: database.clone(),
+                            downloaded,
+                            total,
+                        })
+                    }
+                },
+            );
+            let response = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    record(metadata, server, SyncOutcome::Failed(e.to_string()));
+                    return Err(e);
+                }
+            };
+            match StatusCode::from_u16(response.status) {
+                Ok(StatusCode::NOT_MODIFIED) => {
                     // We're done
                     log::debug!("Server reports db not modified - finishing update.");
+                    record(metadata, server, SyncOutcome::NotModified);
+                    self.mark_synced();
                     return Ok(());
                 }
-                StatusCode::OK => (),
-                code => {
-                    log::warn!(
-                        "Unexpected code {} while updating database {} - bailing",
-                        code,
-                        self.name
+                Ok(StatusCode::OK) => (),
+                _ => {
+                    record(
+                        metadata,
+                        server,
+                        SyncOutcome::Failed(format!("HTTP status {}", response.status)),
                     );
-                    return Ok(());
+                    handle_ref.warn(Warning::UnexpectedStatus {
+                        url: url.to_string(),
+                        database,
+                        status: response.status,
+                    });
+                    continue;
                 }
             }
-            let mut db_file_opts = fs::OpenOptions::new();
-            db_file_opts.create(true).write(true).truncate(true);
-            let mut db_file = db_file_opts.open(&*self.path)?;
-            match db_file.try_lock_exclusive() {
-                Ok(_) => Ok(()),
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    log::warn!(
-                        "database {} is in use, blocking on request for exclusive access",
-                        self.name
-                    );
-                    db_file.lock_exclusive()
+            let len = response.downloaded;
+            handle_ref.emit(Event::DatabaseDownloadFinished {
+                database,
+                downloaded: len,
+            });
+            log::debug!("Downloaded {} bytes for db {}", len, self.name);
+            if let Err(e) = format::verify_archive(download.path()) {
+                record(
+                    metadata,
+                    server,
+                    SyncOutcome::Failed(format!("corrupt download: {}", e)),
+                );
+                if self.corrupt_download(handle_ref, &url, &filename, download, &sync_dir, &e)? {
+                    continue;
                 }
-                Err(e) => Err(e),
-            }?;
-            let len = response
-                .copy_to(&mut db_file)
-                .context(ErrorKind::UnexpectedReqwest)?;
+                return Err(e);
+            }
+
+            download.persist(&self.path).map_err(|e| e.error)?;
             log::debug!("Wrote {} bytes to db file {}", len, self.path.display());
+            set_modified(
+                &self.path,
+                response.last_modified.as_ref().map(String::as_str),
+            );
+            record(metadata, server, SyncOutcome::Updated);
+            if let Some(metadata) = metadata.as_mut() {
+                metadata.set_validators(response.etag, response.last_modified);
+            }
+            self.mark_synced();
+            return self.reload_package_cache();
+        }
+        if servers.is_empty() {
+            log::warn!("database {} has no servers to update from", self.name);
+            Ok(())
+        } else {
+            Err(ErrorKind::DatabaseSyncFailed(self.name.to_string()).into())
+        }
+    }
+
+    /// Update the database from a server's manifest, downloading only the entries that changed
+    /// (see the `differential` module).
+    ///
+    /// Returns `None` if the server has no manifest.
+    fn synchronize_differential(
+        &self,
+        handle_ref: &Handle,
+        server: &Url,
+        if_modified_since: Option<&str>,
+        sync_dir: &Path,
+    ) -> Result<Option<SyncOutcome>, Error> {
+        use reqwest::StatusCode;
+        use std::io::Read;
+
+        let db_filename = self.name.filename(&self.extension);
+        let url = self.join_url(
+            server,
+            &format!("{}.{}", db_filename, differential::MANIFEST_EXT),
+        )?;
+        let mut manifest = tempfile::tempfile_in(sync_dir)?;
+        let conditions = Conditions {
+            if_modified_since,
+            ..Conditions::default()
+        };
+        let response = download::get(handle_ref, &url, conditions, &mut manifest, &mut |_| ())?;
+        match StatusCode::from_u16(response.status) {
+            Ok(StatusCode::NOT_MODIFIED) => return Ok(Some(SyncOutcome::NotModified)),
+            Ok(StatusCode::OK) => (),
+            _ => return Ok(None),
+        }
+        let mut raw = String::new();
+        manifest.seek(SeekFrom::Start(0))?;
+        manifest.read_to_string(&mut raw)?;
+        let manifest = differential::parse_manifest(&raw)?;
+
+        let mut current = differential::read_entries(&self.path)?;
+        let mut entries = Vec::with_capacity(manifest.len());
+        let mut fetched = 0;
+        for entry in manifest {
+            let desc = match current.remove(&entry.dirname) {
+                Some(desc) if hash::sha256(&desc[..])?.eq_ignore_ascii_case(&entry.sha256) => desc,
+                _ => {
+                    fetched += 1;
+                    let path = format!(
+                        "{}.{}/{}/desc",
+                        db_filename,
+                        differential::ENTRIES_EXT,
+                        entry.dirname
+                    );
+                    let url = self.join_url(server, &path)?;
+                    let mut download = tempfile::tempfile_in(sync_dir)?;
+                    let response = download::get(
+                        handle_ref,
+                        &url,
+                        Conditions::default(),
+                        &mut download,
+                        &mut |_| (),
+                    )?;
+                    if response.status != StatusCode::OK.as_u16() {
+                        return Err(ErrorKind::DownloadFailed(url.to_string()).into());
+                    }
+                    let mut desc = Vec::new();
+                    download.seek(SeekFrom::Start(0))?;
+                    download.read_to_end(&mut desc)?;
+                    if !hash::sha256(&desc[..])?.eq_ignore_ascii_case(&entry.sha256) {
+                        return Err(Error::from(ErrorKind::DownloadFailed(url.to_string()))
+                            .with_source(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "the sha256 checksum is different",
+                            )));
+                    }
+                    desc
+                }
+            };
+            entries.push((entry.dirname, desc));
+        }
+        log::debug!(
+            "downloaded {} of {} entries of {}",
+            fetched,
+            entries.len(),
+            self.name
+        );
+
+        let mut rebuilt = tempfile::NamedTempFile::new_in(sync_dir)?;
+        differential::write_database(rebuilt.as_file_mut(), &entries)?;
+        format::verify_archive(rebuilt.path())?;
+        rebuilt.persist(&self.path).map_err(|e| e.error)?;
+        set_modified(
+            &self.path,
+            response.last_modified.as_ref().map(String::as_str),
+        );
+        Ok(Some(SyncOutcome::Updated))
+    }
+
+    /// Report a download that failed verification, and keep it if the `IntegrityPolicy` says
+    /// to.
+    ///
+    /// Returns whether to try the next server.
+    fn corrupt_download(
+        &self,
+        handle: &Handle,
+        url: &Url,
+        filename: &str,
+        download: tempfile::NamedTempFile,
+        dir: &Path,
+        reason: &Error,
+    ) -> Result<bool, Error> {
+        self.report_corrupt(handle, url, filename, reason);
+        if handle.integrity_policy.keep_corrupt() {
+            let kept = dir.join(format!("{}.corrupt", filename));
+            download.persist(&kept).map_err(|e| e.error)?;
+        }
+        Ok(handle.integrity_policy.try_next_server())
+    }
+
+    /// Log and emit an event for a download that failed verification.
+    fn report_corrupt(&self, handle: &Handle, url: &Url, filename: &str, reason: &Error) {
+        log::warn!("download from {} is corrupt: {}", url, reason);
+        handle.emit(Event::DownloadCorrupt {
+            database: self.name.to_string(),
+            url: url.to_string(),
+            filename: filename.to_owned(),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Join a path to a server's url.
+    fn join_url(&self, server: &Url, path: &str) -> Result<Url, Error> {
+        util::join_url(server, path)
+            .ok_or_else(|| ErrorKind::DatabaseSyncFailed(self.name.to_string()).into())
+    }
+
+    /// The file whose modification time is the time of the last successful sync.
+    ///
+    /// The database file's own modification time can't be used, since it is sent as
+    /// `If-Modified-Since`, and it doesn't change when the server says nothing has changed.
+    fn last_sync_path(&self) -> PathBuf {
+        self.path.with_file_name(self.name.filename(LAST_SYNC_EXT))
+    }
+
+    /// Record that the database has just been synchronized.
+    fn mark_synced(&self) {
+        // This is only informational, so failing to write it shouldn't fail the sync.
+        if let Err(e) = fs::write(self.last_sync_path(), b"") {
+            log::warn!(
+                r#"could not record sync time for database "{}": {}"#,
+                self.name,
+                e
+            );
+        }
+    }
+
+    /// The file `SyncMetadata` is stored in.
+    fn metadata_path(&self) -> PathBuf {
+        self.path.with_file_name(self.name.filename(METADATA_EXT))
+    }
+
+    /// When the database was last synchronized (see `SyncDatabase::last_synced`).
+    fn last_synced(&self) -> Option<SystemTime> {
+        if self.store_metadata {
+            let recorded = SyncMetadata::load(&self.metadata_path())
+                .ok()
+                .and_then(|metadata| metadata.last_sync());
+            if recorded.is_some() {
+                return recorded;
+            }
+        }
+        [self.last_sync_path(), self.path.clone()]
+            .iter()
+            .filter_map(|path| fs::metadata(path).and_then(|md| md.modified()).ok())
+            .next()
+    }
+
+    /// Download a package, using deltas if possible.
+    fn download_package(
+        &self,
+        package: &SyncPackage,
+        dest_dir: &Path,
+        installed: Option<(&str, &Path)>,
+    ) -> Result<PathBuf, Error> {
+        use crate::package::Package;
+
+        let handle = self.get_handle()?;
+        let handle_ref = handle.borrow();
+        if let Some(path) = package.find_archive(Some(dest_dir), &handle_ref) {
+            log::debug!("using cached package \"{}\"", path.display());
+            return Ok(path);
+        }
+        if let Some((version, old)) = installed {
+            match self.download_deltas(&handle_ref, package, version, old, dest_dir) {
+                Ok(Some(path)) => return Ok(path),
+                Ok(None) => (),
+                Err(e) => handle_ref.warn(Warning::DeltaFailed {
+                    package: package.name().to_owned(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        if let Some(downloader) = &handle_ref.downloader {
+            match self.download_with(&handle_ref, &**downloader, package, dest_dir) {
+                Ok(Some(path)) => return Ok(path),
+                Ok(None) => (),
+                Err(e) => handle_ref.warn(Warning::DownloaderFailed {
+                    package: package.name().to_owned(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        self.fetch_file(&handle_ref, package.filename(), dest_dir, &|path| {
+            package.verify_archive(path)
+        })
+    }
+
+    /// Download a package with a custom downloader, checking the archive it writes.
+    ///
+    /// Returns `None` if the downloader leaves the package to the built-in client.
+    fn download_with(
+        &self,
+        handle: &Handle,
+        downloader: &dyn Downloader,
+        package: &SyncPackage,
+        dest_dir: &Path,
+    ) -> Result<Option<PathBuf>, Error> {
+        let urls = self
+            .servers
+            .iter()
+            .filter_map(|server| util::join_url(server, package.filename()))
+            .collect();
+        let request = DownloadRequest::new(self.name.as_ref(), package, urls, handle);
+        let download = tempfile::NamedTempFile::new_in(dest_dir)?;
+        if !downloader
+            .download(&request, download.path())
+            .context(ErrorKind::DownloadFailed(package.filename().to_owned()))?
+        {
+            return Ok(None);
+        }
+        package.verify_archive(download.path())?;
+        let dest = dest_dir.join(package.filename());
+        download.persist(&dest).map_err(|e| e.error)?;
+        Ok(Some(dest))
+    }
+
+    /// Make a package from the installed version using deltas.
+    ///
+    /// Returns `None` if there are no deltas from the installed version, or they are too big to
+    /// be worth using.
+    fn download_deltas(
+        &self,
+        handle: &Handle,
+        package: &SyncPackage,
+        from_version: &str,
+        old: &Path,
+        dest_dir: &Path,
+    ) -> Result<Option<PathBuf>, Error> {
+        use crate::package::Package;
+
+        let deltas = package.deltas()?;
+        let path = match delta::find_path(&deltas, from_version, package.version()) {
+            Some(ref path) if path.is_empty() => return Ok(None),
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let size: u64 = path.iter().map(|d| d.size).sum();
+        if !delta::worthwhile(size, package.compressed_size(), handle.delta_ratio) {
+            log::debug!(
+                "deltas for {} are {} bytes, not worth it for a {} byte package",
+                package.name(),
+                size,
+                package.compressed_size()
+            );
+            return Ok(None);
+        }
+
+        // Work in a directory next to the destination, so we can move the result into place.
+        let work_dir = tempfile::tempdir_in(dest_dir)?;
+        let mut current = old.to_owned();
+        for (step, delta) in path.into_iter().enumerate() {
+            let delta_file =
+                self.fetch_file(handle, &delta.filename, work_dir.path(), &|path| {
+                    delta.verify(path)
+                })?;
+            let next = work_dir.path().join(format!("step-{}", step));
+            delta::apply(&current, &delta_file, &next)?;
+            current = next;
+        }
+        // xdelta3 rebuilds the archive from the old one, which may not be what the server built.
+        package.verify_archive(&current)?;
+        let dest = dest_dir.join(package.filename());
+        fs::rename(&current, &dest)?;
+        Ok(Some(dest))
+    }
+
+    /// Download a file from the first server that has it, into the given directory.
+    ///
+    /// Downloads that `verify` rejects are handled according to the `IntegrityPolicy`.
+    fn fetch_file(
+        &self,
+        handle: &Handle,
+        filename: &str,
+        dir: &Path,
+        verify: &dyn Fn(&Path) -> Result<(), Error>,
+    ) -> Result<PathBuf, Error> {
+        use reqwest::StatusCode;
+
+        for server in self.servers.iter() {
+            let url =
+                util::join_url(server, filename).ok_or_else(|| ErrorKind::InvalidPackageUrl {
+                    server: server.to_string(),
+                    filename: filename.to_owned(),
+                })?;
+            let mut download = tempfile::NamedTempFile::new_in(dir)?;
+            let response = download::get(
+                handle,
+                &url,
+                Conditions::default(),
+                download.as_file_mut(),
+                &mut |_| (),
+            )?;
+            if response.status != StatusCode::OK.as_u16() {
+                handle.warn(Warning::UnexpectedStatus {
+                    url: url.to_string(),
+                    database: self.name.to_string(),
+                    status: response.status,
+                });
+                continue;
+            }
+            if let Err(e) = verify(download.path()) {
+                if self.corrupt_download(handle, &url, filename, download, dir, &e)? {
+                    continue;
+                }
+                return Err(e);
+            }
+            let dest = dir.join(filename);
+            download.persist(&dest).map_err(|e| e.error)?;
+            return Ok(dest);
+        }
+        Err(ErrorKind::DownloadFailed(filename.to_owned()).into())
+    }
+
+    /// Download a package to `<filename>.part` in `dest_dir`, resuming what is already there,
+    /// and move it into place once it is verified.
+    ///
+    /// A partial file is kept when the download fails, but not when it is corrupt, which is
+    /// handled according to the `IntegrityPolicy`.
+    fn prefetch_package(&self, package: &SyncPackage, dest_dir: &Path) -> Result<PathBuf, Error> {
+        use reqwest::StatusCode;
+
+        let handle = self.get_handle()?;
+        let handle_ref = handle.borrow();
+        let filename = package.filename();
+        let partial = dest_dir.join(format!("{}.part", filename));
+        let mut throttle = handle_ref.prefetch_rate_limit.map(Throttle::new);
+        for server in self.servers.iter() {
+            let url =
+                util::join_url(server, filename).ok_or_else(|| ErrorKind::InvalidPackageUrl {
+                    server: server.to_string(),
+                    filename: filename.to_owned(),
+                })?;
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .open(&partial)?;
+            let conditions = Conditions {
+                range_from: Some(file.seek(SeekFrom::End(0))?),
+                ..Conditions::default()
+            };
+            let response =
+                download::get(&handle_ref, &url, conditions, &mut file, &mut |progress| {
+                    if let Some(throttle) = &mut throttle {
+                        throttle.progress(progress);
+                    }
+                })?;
+            drop(file);
+            match StatusCode::from_u16(response.status) {
+                Ok(StatusCode::OK) | Ok(StatusCode::PARTIAL_CONTENT) => (),
+                // The partial file is already as long as the archive, so check it as it is.
+                Ok(StatusCode::RANGE_NOT_SATISFIABLE) => (),
+                _ => {
+                    handle_ref.warn(Warning::UnexpectedStatus {
+                        url: url.to_string(),
+                        database: self.name.to_string(),
+                        status: response.status,
+                    });
+                    continue;
+                }
+            }
+            if let Err(e) = package.verify_archive(&partial) {
+                self.report_corrupt(&handle_ref, &url, filename, &e);
+                if handle_ref.integrity_policy.keep_corrupt() {
+                    fs::rename(&partial, dest_dir.join(format!("{}.corrupt", filename)))?;
+                } else {
+                    fs::remove_file(&partial)?;
+                }
+                if handle_ref.integrity_policy.try_next_server() {
+                    continue;
+                }
+                return Err(e);
+            }
+            let dest = dest_dir.join(filename);
+            fs::rename(&partial, &dest)?;
+            return Ok(dest);
+        }
+        Err(ErrorKind::DownloadFailed(filename.to_owned()).into())
+    }
+
+    /// Throw away the package cache and load it again from disk.
+    fn reload_package_cache(&mut self) -> Result<(), Error> {
+        self.package_cache.clear();
+        self.group_cache.clear();
+        self.loaded = false;
+        self.load_error = None;
+        self.ensure_loaded()
+    }
+
+    /// Load the package cache from disk, if it hasn't been already.
+    ///
+    /// A database that fails to load is left empty, and reports itself as invalid, so that one bad
+    /// database doesn't stop the others from being used. Loading is tried again on the next call,
+    /// so the error can be fixed by synchronizing the database.
+    fn ensure_loaded(&mut self) -> Result<(), Error> {
+        if self.loaded {
+            return Ok(());
+        }
+        match self.populate_package_cache() {
+            Ok(()) => {
+                self.loaded = true;
+                self.load_error = None;
+                Ok(())
+            }
+            Err(e) => {
+                self.package_cache.clear();
+                self.group_cache.clear();
+                diagnostics::warn(
+                    &self.handle,
+                    Warning::DatabaseLoadFailed {
+                        database: self.name.to_string(),
+                        reason: e.to_string(),
+                    },
+                );
+                self.load_error = Some(e.to_string());
+                Err(e)
+            }
         }
-        Ok(())
     }
 
     /// Fetches an alpm handle and maps failure to an error
@@ -395,15 +1538,18 @@ impl SyncDatabaseInner {
         use std::io::Read;
 
         log::info!("Getting cache from {}", self.path.display());
-        // Times like this you wish you were in haskell
-        let mut reader = tar::Archive::new(gzip::Decoder::new(io::BufReader::new(
-            fs::File::open(&self.path)?,
-        ))?);
+        let (mut reader, compression) = format::open_archive(&self.path)?;
+        log::debug!("database compression: {}", compression);
+        self.format = Some(DbFormat {
+            extension: self.extension.clone(),
+            compression,
+        });
 
-        if !self.package_cache.is_empty() || self.package_count != 0 {
-            panic!("populate_package_cache should only be called once on database cration");
-        }
+        // Start from scratch, in case an earlier load got part of the way.
+        self.package_cache.clear();
+        self.group_cache.clear();
 
+        let mut groups = GroupCollector::default();
         for entry in reader.entries()? {
             let mut entry = entry?;
 
@@ -412,9 +1558,13 @@ impl SyncDatabaseInner {
                 Some(p) if p == "desc" => path
                     .parent()
                     .and_then(|parent| parent.file_name())
-                    .expect("TODO handle malformed db archive")
+                    .ok_or_else(|| ErrorKind::MalformedSyncDatabase {
+                        database: self.name.to_string(),
+                        entry: path.to_path_buf(),
+                    })?
+                    // Non-utf8 is hard until https://github.com/rust-lang/rfcs/pull/2295 lands
                     .to_str()
-                    .expect("TODO handle non-utf8 package name")
+                    .ok_or_else(|| ErrorKind::NonUtf8PackageName(path.to_path_buf()))?
                     .to_owned(),
                 _ => continue,
             };
@@ -427,20 +1577,24 @@ impl SyncDatabaseInner {
             entry.read_to_end(&mut contents)?;
             let contents = String::from_utf8(contents)
                 .context(ErrorKind::InvalidSyncPackage(name.to_owned()))?;
-            let package = SyncPackage::from_parts(&contents, &name, &version)?;
-
-            if self
-                .package_cache
-                .insert(Cow::Owned(name.to_owned()), Rc::new(package))
-                .is_some()
-            {
-                panic!(
-                    "internal error - there should only ever be 1 package with a given name \
-                     in a sync database"
+            let package = SyncPackage::from_parts(&contents, &name, &version, &self.handle)?;
+
+            // Like pacman, keep the first entry for a name; a valid database never has two.
+            if let Some(existing) = self.package_cache.version(name) {
+                diagnostics::warn(
+                    &self.handle,
+                    Warning::DuplicateSyncPackage {
+                        database: self.name.to_string(),
+                        name: name.to_owned(),
+                        versions: vec![existing.to_owned(), version.to_owned()],
+                    },
                 );
+                continue;
             }
-            self.package_count += 1;
+            groups.add(&package);
+            self.package_cache.insert(package);
         }
+        self.group_cache = groups.finish();
         Ok(())
     }
 }
@@ -472,8 +1626,8 @@ impl SyncDbName {
 
     /// The filename of the database on disk
     ///
-    /// This appends .db for sync databases. It is a String because it is used in Urls as well as
-    /// on the fs.
+    /// This appends the extension (e.g. `.db`) for sync databases. It is a String because it is
+    /// used in Urls as well as on the fs.
     fn filename(&self, ext: impl AsRef<str>) -> String {
         let ext = ext.as_ref();
         let mut buf = String::with_capacity(self.0.len() + ext.len() + 1);
@@ -485,13 +1639,12 @@ impl SyncDbName {
 
     /// Get the path for this database name
     ///
-    /// Must supply the root database path from the alpm instance.
-    pub(crate) fn path(&self, database_path: impl AsRef<Path>) -> PathBuf {
-        let database_path = database_path.as_ref();
-        //  database path `$db_path SEP "sync" SEP $name "." $ext`
-        let mut path = database_path.join(SYNC_DB_DIR);
-        path.push(&self.0);
-        path.set_extension(DEFAULT_SYNC_DB_EXT);
+    /// Must supply the sync database directory and the database extension from the alpm instance.
+    pub(crate) fn path(&self, sync_path: impl AsRef<Path>, ext: impl AsRef<str>) -> PathBuf {
+        //  database path `$sync_path SEP $name "." $ext`
+        let mut path = sync_path.as_ref().to_owned();
+        // We can't use `set_extension`, as the extension may contain dots (e.g. `db.tar.zst`).
+        path.push(self.filename(ext));
         path
     }
 
@@ -508,6 +1661,34 @@ impl SyncDbName {
     }
 }
 
+/// Record an attempt to sync from a server, if metadata is being kept.
+fn record(metadata: &mut Option<SyncMetadata>, server: &Url, outcome: SyncOutcome) {
+    if let Some(metadata) = metadata {
+        metadata.record(server.as_str(), outcome);
+    }
+}
+
+/// Set the modification time of a downloaded file to the server's date (see
+/// `download::set_modified`), logging any failure since the file itself is fine.
+fn set_modified(path: &Path, last_modified: Option<&str>) {
+    if let Err(e) = download::set_modified(path, last_modified) {
+        log::warn!(
+            "could not set the modification time of \"{}\": {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Check a string is a valid db extension.
+///
+/// The extension is one or more parts separated by dots (e.g. `db` or `db.tar.zst`), where each
+/// part is non-empty and ascii alphanumeric.
+pub(crate) fn is_valid_db_extension(ext: &str) -> bool {
+    ext.split('.')
+        .all(|part| !part.is_empty() && part.chars().all(|ch| ch.is_ascii_alphanumeric()))
+}
+
 impl fmt::Display for SyncDbName {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -562,4 +1743,269 @@ mod tests {
         assert!(SyncDbName::new("bad\\name").is_err());
         assert!(SyncDbName::new("bad.name").is_err());
     }
+
+    #[test]
+    fn db_extension() {
+        assert!(is_valid_db_extension("db"));
+        assert!(is_valid_db_extension("db.tar.zst"));
+        assert!(!is_valid_db_extension(""));
+        assert!(!is_valid_db_extension(".db"));
+        assert!(!is_valid_db_extension("db."));
+        assert!(!is_valid_db_extension("db..tar"));
+        assert!(!is_valid_db_extension("db/tar"));
+    }
+
+    #[test]
+    fn db_path_compound_extension() {
+        let name = SyncDbName::new("core").unwrap();
+        assert_eq!(
+            name.path("/var/lib/pacman/sync", "db.tar.zst"),
+            Path::new("/var/lib/pacman/sync/core.db.tar.zst")
+        );
+        assert_eq!(name.filename("db.tar.zst"), "core.db.tar.zst");
+    }
+
+    /// A valid desc file for `foo-1-1`.
+    const DESC: &str = "%FILENAME%\nfoo-1-1-any.pkg.tar.zst\n\n%NAME%\nfoo\n\n%VERSION%\n1-1\n\n\
+                        %DESC%\nfoo\n\n%CSIZE%\n1\n\n%ISIZE%\n1\n\n%MD5SUM%\n\n%SHA256SUM%\n\n\
+                        %PGPSIG%\n\n%ARCH%\nany\n\n%BUILDDATE%\n0\n\n%PACKAGER%\nme\n\n\
+                        %GROUPS%\nbar\n\n";
+
+    /// Broken versions of `DESC`.
+    fn corrupt_descs() -> Vec<Vec<u8>> {
+        let mut descs: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"\n\n\n".to_vec(),
+            b"%%\n\n".to_vec(),
+            b"%NAME%".to_vec(),
+            b"NAME\nfoo\n".to_vec(),
+            b"%NAME%\n\xff\xfe\n\n".to_vec(),
+            DESC.replace("%VERSION%\n1-1", "%VERSION%\n2-1")
+                .into_bytes(),
+            DESC.replace("%ISIZE%\n1", "%ISIZE%\n-1").into_bytes(),
+            DESC.replace("%ISIZE%\n1", "%ISIZE%\n99999999999999999999999")
+                .into_bytes(),
+            DESC.replace("\n\n", "\n").into_bytes(),
+            DESC.replace("%NAME%\nfoo", "%NAME%\nfoo\nbar").into_bytes(),
+            format!("{}{}", DESC, DESC).into_bytes(),
+        ];
+        for len in (0..DESC.len()).step_by(7) {
+            descs.push(DESC.as_bytes()[..len].to_vec());
+        }
+        descs
+    }
+
+    /// An alpm instance over a database directory in `root`, which is made if necessary.
+    fn alpm_in(root: &Path) -> Result<Alpm, Error> {
+        let db_path = root.join("db");
+        fs::create_dir_all(db_path.join(crate::db::SYNC_DB_DIR))?;
+        Alpm::new()
+            .with_root_path(root)
+            .with_database_path(&db_path)
+            .with_architecture("x86_64")
+            .with_read_only(true)
+            .build()
+    }
+
+    /// Use every part of a sync database that reads the database file.
+    fn read_sync(alpm: &Alpm) -> Result<(), Error> {
+        use crate::package::Package;
+
+        let db = alpm.register_sync_database("core", SyncDatabaseOptions::new())?;
+        db.load()?;
+        db.packages(|package| {
+            let _ = package.raw_field("NAME");
+            Ok::<(), Error>(())
+        })?;
+        let _ = db.package_latest("foo")?;
+        let _ = (db.groups()?, db.raw_entry("foo")?, db.version_of("foo")?);
+        Ok(())
+    }
+
+    /// Broken databases are errors, not panics.
+    #[test]
+    fn corrupt_databases() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut archives = Vec::new();
+        for desc in corrupt_descs() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("core.db");
+            let entries = vec![("foo-1-1".to_owned(), desc)];
+            differential::write_database(&mut fs::File::create(&path).unwrap(), &entries).unwrap();
+            archives.push(fs::read(&path).unwrap());
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core.db");
+        let entries = vec![("foo-1-1".to_owned(), DESC.as_bytes().to_vec())];
+        differential::write_database(&mut fs::File::create(&path).unwrap(), &entries).unwrap();
+        let valid = fs::read(&path).unwrap();
+        for len in (0..valid.len()).step_by(11) {
+            archives.push(valid[..len].to_vec());
+        }
+        for idx in (0..valid.len()).step_by(13) {
+            let mut flipped = valid.clone();
+            flipped[idx] ^= 0x55;
+            archives.push(flipped);
+        }
+        archives.push(b"not a database".to_vec());
+
+        for (case, archive) in archives.iter().enumerate() {
+            let root = tempfile::tempdir().unwrap();
+            let alpm = alpm_in(root.path()).unwrap();
+            fs::write(root.path().join("db/sync/core.db"), archive).unwrap();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| read_sync(&alpm)));
+            assert!(result.is_ok(), "sync database case {} panicked", case);
+        }
+
+        for (case, desc) in corrupt_descs().iter().enumerate() {
+            let root = tempfile::tempdir().unwrap();
+            let entry = root.path().join("db/local/foo-1-1");
+            fs::create_dir_all(&entry).unwrap();
+            fs::write(entry.join("desc"), desc).unwrap();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let alpm = alpm_in(root.path())?;
+                let package = alpm.local_database().package_latest("foo")?;
+                let _ = (package.size_on_disk(), package.validate());
+                Ok::<(), Error>(())
+            }));
+            assert!(result.is_ok(), "local database case {} panicked", case);
+        }
+    }
+
+    /// Packages for other architectures are passed over for compatible ones.
+    #[test]
+    fn foreign_architectures() {
+        use crate::package::Package;
+
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_in(root.path()).unwrap();
+        let foreign = DESC
+            .replace("1-1", "2-1")
+            .replace("%ARCH%\nany", "%ARCH%\naarch64");
+        for (name, entry, desc) in &[("core", "foo-2-1", &*foreign), ("extra", "foo-1-1", DESC)] {
+            let path = root.path().join("db/sync").join(format!("{}.db", name));
+            let entries = vec![(entry.to_string(), desc.as_bytes().to_vec())];
+            differential::write_database(&mut fs::File::create(&path).unwrap(), &entries).unwrap();
+            alpm.register_sync_database(*name, SyncDatabaseOptions::new())
+                .unwrap();
+        }
+        let found = alpm.find_package("foo").unwrap();
+        assert_eq!((found.db_name.as_str(), found.version()), ("extra", "1-1"));
+        let results = alpm.search("foo");
+        assert_eq!(results.get("core").map(Vec::len), Some(0));
+        assert_eq!(results.get("extra").map(Vec::len), Some(1));
+    }
+
+    /// A prefetch resumes from the end of a partial download, and moves it into place once the
+    /// archive is complete.
+    #[test]
+    fn prefetch_resumes() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_in(root.path()).unwrap();
+        let mirror = root.path().join("mirror");
+        fs::create_dir_all(&mirror).unwrap();
+        // Only the end of the mirror's copy matches, so a full download would fail to verify.
+        fs::write(mirror.join("foo-1-1-any.pkg.tar.zst"), b"XYZdef").unwrap();
+        let sha256 = hash::sha256(&b"abcdef"[..]).unwrap();
+        let desc = DESC
+            .replace("%CSIZE%\n1", "%CSIZE%\n6")
+            .replace("%SHA256SUM%\n", &format!("%SHA256SUM%\n{}", sha256));
+        let entries = vec![("foo-1-1".to_owned(), desc.into_bytes())];
+        let path = root.path().join("db/sync/core.db");
+        differential::write_database(&mut fs::File::create(&path).unwrap(), &entries).unwrap();
+        let server = Url::from_directory_path(&mirror).unwrap();
+        let db = alpm
+            .register_sync_database(
+                "core",
+                SyncDatabaseOptions::new().with_server(server.as_str()),
+            )
+            .unwrap();
+        let package = db.package_latest("foo").unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        let partial = dest.path().join("foo-1-1-any.pkg.tar.zst.part");
+        fs::write(&partial, b"abc").unwrap();
+        let archive = db.prefetch_package(&package, dest.path()).unwrap();
+        assert_eq!(fs::read(archive).unwrap(), b"abcdef");
+        assert!(!partial.exists());
+    }
+
+    /// Syncs lock a file next to the database, which stays put when the database is replaced.
+    #[test]
+    fn sync_locks_stable_path() {
+        use crate::util::LockMode;
+        use fs2::FileExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let db_path = root.path().join("db");
+        fs::create_dir_all(db_path.join(crate::db::SYNC_DB_DIR)).unwrap();
+        let alpm = Alpm::new()
+            .with_root_path(root.path())
+            .with_database_path(&db_path)
+            .with_lock_mode(LockMode::NoWait)
+            .build()
+            .unwrap();
+        let db = alpm
+            .register_sync_database("core", SyncDatabaseOptions::new())
+            .unwrap();
+        let lock = fs::File::create(db_path.join("sync/core.db.lck")).unwrap();
+        lock.lock_exclusive().unwrap();
+        match db.synchronize(false) {
+            Err(Error {
+                kind: ErrorKind::DatabaseBusy(path),
+                ..
+            }) => assert_eq!(path, db_path.join("sync/core.db.lck")),
+            other => panic!("expected the sync to be busy, got {:?}", other),
+        }
+        lock.unlock().unwrap();
+        db.synchronize(false).unwrap();
+    }
+
+    /// `HttpDownloader` falls back through its mirrors using the built-in client.
+    #[cfg(feature = "http-downloader")]
+    #[test]
+    fn http_downloader_mirrors() {
+        use crate::downloader::HttpDownloader;
+
+        let root = tempfile::tempdir().unwrap();
+        let (empty, mirror) = (root.path().join("empty"), root.path().join("mirror"));
+        fs::create_dir_all(&empty).unwrap();
+        fs::create_dir_all(&mirror).unwrap();
+        // An epoch in the filename, to check it's joined to the mirror as a path.
+        let desc = DESC.replace("foo-1-1-any", "foo-1:1-1-any");
+        fs::write(mirror.join("foo-1:1-1-any.pkg.tar.zst"), b"x").unwrap();
+
+        let fallbacks = Rc::new(RefCell::new(Vec::new()));
+        let on_fallback = fallbacks.clone();
+        let downloader = HttpDownloader::new()
+            .with_mirror(Url::from_directory_path(&empty).unwrap())
+            .with_mirror(Url::from_directory_path(&mirror).unwrap())
+            .with_on_fallback(move |url, _| on_fallback.borrow_mut().push(url.clone()));
+        let db_path = root.path().join("db");
+        fs::create_dir_all(db_path.join(crate::db::SYNC_DB_DIR)).unwrap();
+        let alpm = Alpm::new()
+            .with_root_path(root.path())
+            .with_database_path(&db_path)
+            .with_architecture("x86_64")
+            .with_read_only(true)
+            .with_downloader(downloader)
+            .build()
+            .unwrap();
+        let entries = vec![("foo-1-1".to_owned(), desc.into_bytes())];
+        let path = db_path.join("sync/core.db");
+        differential::write_database(&mut fs::File::create(&path).unwrap(), &entries).unwrap();
+        let db = alpm
+            .register_sync_database("core", SyncDatabaseOptions::new())
+            .unwrap();
+        let package = db.package_latest("foo").unwrap();
+
+        // The database has no servers, so only the downloader can have fetched it.
+        let dest = tempfile::tempdir().unwrap();
+        let archive = db.download_package(&package, dest.path(), None).unwrap();
+        assert_eq!(fs::read(archive).unwrap(), b"x");
+        let fallbacks = fallbacks.borrow();
+        assert_eq!(fallbacks.len(), 1);
+        assert!(fallbacks[0].path().starts_with(empty.to_str().unwrap()));
+    }
 }
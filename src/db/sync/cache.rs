@@ -0,0 +1,223 @@
+//! The packages of a loaded sync database.
+//!
+//! Normally every package is parsed when the database is loaded, and kept. For databases
+//! registered with `SyncDatabaseOptions::with_parsed_package_limit`, the text of each `desc` file
+//! is kept instead, along with only the most recently used packages in parsed form. Other
+//! packages are parsed again when they are next needed. The text takes much less memory than the
+//! parsed package, so this trades CPU for memory on very large databases.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::{Rc, Weak},
+};
+
+use crate::{db::SyncPackage, error::Error, package::Package, Handle};
+
+/// How a package is kept.
+#[derive(Debug)]
+enum Slot {
+    /// Parsed, for as long as the database is loaded.
+    Parsed(Rc<SyncPackage>),
+    /// As the text of its `desc` file, parsed when it is used.
+    Text { version: String, desc: String },
+}
+
+/// The packages of a sync database, by name.
+#[derive(Debug)]
+pub(crate) struct PackageCache {
+    /// Every package, ordered by name so iteration is deterministic.
+    slots: BTreeMap<String, Slot>,
+    /// The most packages to keep parsed, if limited.
+    limit: Option<usize>,
+    /// The recently used packages that are kept as text.
+    recent: RefCell<Lru<Rc<SyncPackage>>>,
+}
+
+impl PackageCache {
+    /// An empty cache, keeping at most `limit` packages parsed, or all of them.
+    pub(crate) fn new(limit: Option<usize>) -> PackageCache {
+        PackageCache {
+            slots: BTreeMap::new(),
+            limit,
+            recent: RefCell::new(Lru::new(limit.unwrap_or(0))),
+        }
+    }
+
+    /// Remove every package.
+    pub(crate) fn clear(&mut self) {
+        *self = PackageCache::new(self.limit);
+    }
+
+    /// The number of packages.
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Whether there is a package with the given name.
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.slots.contains_key(name)
+    }
+
+    /// The names of the packages, sorted.
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.slots.keys().map(String::as_str)
+    }
+
+    /// The version of the package with the given name, without parsing it.
+    pub(crate) fn version(&self, name: &str) -> Option<&str> {
+        self.slots.get(name).map(|slot| match slot {
+            Slot::Parsed(package) => package.version(),
+            Slot::Text { version, .. } => version.as_str(),
+        })
+    }
+
+    /// The `desc` file of the package with the given name, as it was in the database.
+    pub(crate) fn desc(&self, name: &str) -> Option<&str> {
+        self.slots.get(name).map(|slot| match slot {
+            Slot::Parsed(package) => package.desc_raw(),
+            Slot::Text { desc, .. } => desc.as_str(),
+        })
+    }
+
+    /// Add a package, replacing any with the same name.
+    ///
+    /// If the number of parsed packages is limited, the package is dropped and only its `desc`
+    /// file kept.
+    pub(crate) fn insert(&mut self, package: SyncPackage) {
+        let name = package.name().to_owned();
+        let slot = match self.limit {
+            Some(_) => Slot::Text {
+                version: package.version().to_owned(),
+                desc: package.desc_raw().to_owned(),
+            },
+            None => Slot::Parsed(Rc::new(package)),
+        };
+        self.slots.insert(name, slot);
+    }
+
+    /// The package with the given name, parsing it if necessary.
+    pub(crate) fn get(
+        &self,
+        name: &str,
+        handle: &Weak<RefCell<Handle>>,
+    ) -> Result<Option<Rc<SyncPackage>>, Error> {
+        match self.slots.get(name) {
+            Some(slot) => self.load(name, slot, handle).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Every package, in name order, parsing them as necessary.
+    pub(crate) fn iter<'a>(
+        &'a self,
+        handle: &'a Weak<RefCell<Handle>>,
+    ) -> impl Iterator<Item = Result<Rc<SyncPackage>, Error>> + 'a {
+        self.slots
+            .iter()
+            .map(move |(name, slot)| self.load(name, slot, handle))
+    }
+
+    /// Get a package out of its slot.
+    fn load(
+        &self,
+        name: &str,
+        slot: &Slot,
+        handle: &Weak<RefCell<Handle>>,
+    ) -> Result<Rc<SyncPackage>, Error> {
+        let (version, desc) = match slot {
+            Slot::Parsed(package) => return Ok(package.clone()),
+            Slot::Text { version, desc } => (version, desc),
+        };
+        let mut recent = self.recent.borrow_mut();
+        if let Some(package) = recent.get(name) {
+            return Ok(package);
+        }
+        log::trace!("parsing evicted package {}", name);
+        let package = Rc::new(SyncPackage::reparse(desc, name, version, handle)?);
+        recent.insert(name.to_owned(), package.clone());
+        Ok(package)
+    }
+}
+
+/// The most recently used values, up to a limit.
+#[derive(Debug)]
+struct Lru<T> {
+    /// The most values to keep.
+    limit: usize,
+    /// Counts uses, to order them.
+    clock: u64,
+    /// The values, with when they were last used.
+    values: HashMap<String, (T, u64)>,
+    /// The keys of the values, by when they were last used.
+    order: BTreeMap<u64, String>,
+}
+
+impl<T: Clone> Lru<T> {
+    fn new(limit: usize) -> Lru<T> {
+        Lru {
+            limit,
+            clock: 0,
+            values: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// The value for `key`, marking it as used.
+    fn get(&mut self, key: &str) -> Option<T> {
+        self.clock += 1;
+        let clock = self.clock;
+        let (value, used) = self.values.get_mut(key)?;
+        if let Some(key) = self.order.remove(&*used) {
+            self.order.insert(clock, key);
+        }
+        *used = clock;
+        Some(value.clone())
+    }
+
+    /// Add a value, dropping the least recently used if there are too many.
+    fn insert(&mut self, key: String, value: T) {
+        if let Some((_, used)) = self.values.remove(&key) {
+            self.order.remove(&used);
+        }
+        if self.limit == 0 {
+            return;
+        }
+        while self.values.len() >= self.limit {
+            let oldest = match self.order.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                self.values.remove(&key);
+            }
+        }
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.values.insert(key, (value, self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru() {
+        let mut lru = Lru::new(2);
+        lru.insert("a".to_owned(), 1);
+        lru.insert("b".to_owned(), 2);
+        assert_eq!(lru.get("a"), Some(1));
+        lru.insert("c".to_owned(), 3);
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a"), Some(1));
+        assert_eq!(lru.get("c"), Some(3));
+        lru.insert("c".to_owned(), 4);
+        assert_eq!(lru.values.len(), 2);
+        assert_eq!(lru.get("c"), Some(4));
+
+        let mut none = Lru::new(0);
+        none.insert("a".to_owned(), 1);
+        assert_eq!(none.get("a"), None);
+    }
+}
@@ -0,0 +1,218 @@
+//! Delta packages, which turn an old package archive into a new one using xdelta3.
+//!
+//! Sync databases can list deltas for a package, each of which takes one version of the package
+//! archive to another. When a chain of them leads from the installed version to the new version,
+//! and is small enough compared to the full package (controlled by the `delta_ratio`), we can
+//! download the chain instead of the whole package.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+    process::Command,
+};
+
+use crate::{
+    error::{Error, ErrorContext, ErrorKind},
+    hash,
+};
+
+/// The program used to apply deltas.
+const XDELTA3: &str = "xdelta3";
+
+/// A single delta, as listed in the `%DELTAS%` section of a package's `desc` file.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Delta {
+    /// The filename of the delta on the server.
+    pub filename: String,
+    /// The md5 checksum of the delta file.
+    pub md5sum: String,
+    /// The size of the delta file in bytes.
+    pub size: u64,
+    /// The version of the package this delta applies to.
+    pub from_version: String,
+    /// The version of the package this delta produces.
+    pub to_version: String,
+}
+
+impl Delta {
+    /// Parse a delta line of the form `filename md5sum size from_version to_version`.
+    pub(crate) fn parse(line: &str) -> Result<Delta, Error> {
+        let invalid = || ErrorKind::InvalidDelta(line.to_owned());
+        let mut parts = line.split_whitespace();
+        let mut next = || parts.next().ok_or_else(invalid);
+        let filename = next()?.to_owned();
+        let md5sum = next()?.to_owned();
+        let size = next()?.parse().map_err(|_| invalid())?;
+        let from_version = next()?.to_owned();
+        let to_version = next()?.to_owned();
+        if parts.next().is_some() {
+            return Err(invalid().into());
+        }
+        Ok(Delta {
+            filename,
+            md5sum,
+            size,
+            from_version,
+            to_version,
+        })
+    }
+}
+
+impl Delta {
+    /// Check a downloaded delta against the size and md5 checksum in the database.
+    pub(crate) fn verify(&self, path: &Path) -> Result<(), Error> {
+        let mismatch = |reason: String| ErrorKind::DeltaMismatch {
+            path: path.to_owned(),
+            reason,
+        };
+        let size = fs::metadata(path)?.len();
+        if size != self.size {
+            return Err(mismatch(format!(
+                "the size is {} bytes, expected {}",
+                size, self.size
+            ))
+            .into());
+        }
+        let md5sum = hash::md5_file(path)?;
+        if !md5sum.eq_ignore_ascii_case(&self.md5sum) {
+            return Err(mismatch("the md5 checksum is different".to_owned()).into());
+        }
+        Ok(())
+    }
+}
+
+/// Find the smallest chain of deltas that takes `from` to `to`.
+///
+/// Returns `None` if there is no such chain.
+pub(crate) fn find_path<'a>(deltas: &'a [Delta], from: &str, to: &str) -> Option<Vec<&'a Delta>> {
+    // Dijkstra's algorithm, with the size of the download as the cost. There are only ever a
+    // handful of deltas, so we don't bother with a priority queue.
+    let mut best: BTreeMap<&str, (u64, Option<&Delta>)> = BTreeMap::new();
+    let mut done: BTreeSet<&str> = BTreeSet::new();
+    best.insert(from, (0, None));
+    loop {
+        let (version, cost) = best
+            .iter()
+            .filter(|(version, _)| !done.contains(*version))
+            .min_by_key(|(_, (cost, _))| *cost)
+            .map(|(version, (cost, _))| (*version, *cost))?;
+        if version == to {
+            break;
+        }
+        done.insert(version);
+        for delta in deltas.iter().filter(|d| d.from_version == version) {
+            let cost = cost + delta.size;
+            let target = delta.to_version.as_str();
+            let better = best
+                .get(target)
+                .map_or(true, |(existing, _)| cost < *existing);
+            if better {
+                best.insert(target, (cost, Some(delta)));
+            }
+        }
+    }
+
+    let mut path = Vec::new();
+    let mut version = to;
+    while let Some((_, Some(delta))) = best.get(version) {
+        path.push(*delta);
+        version = &delta.from_version;
+    }
+    path.reverse();
+    Some(path)
+}
+
+/// Whether downloading `delta_size` bytes of deltas is worthwhile instead of the whole package.
+///
+/// A ratio of 0 disables deltas.
+pub(crate) fn worthwhile(delta_size: u64, package_size: u64, delta_ratio: f64) -> bool {
+    delta_ratio > 0.0 && (delta_size as f64) < (package_size as f64) * delta_ratio
+}
+
+/// Use xdelta3 to apply `delta` to `old`, writing the result to `new`.
+pub(crate) fn apply(old: &Path, delta: &Path, new: &Path) -> Result<(), Error> {
+    log::debug!(
+        r#"applying delta "{}" to "{}""#,
+        delta.display(),
+        old.display()
+    );
+    let status = Command::new(XDELTA3)
+        .arg("-d")
+        .arg("-q")
+        .arg("-f")
+        .arg("-s")
+        .arg(old)
+        .arg(delta)
+        .arg(new)
+        .status()
+        .context(ErrorKind::CannotApplyDelta(delta.to_owned()))?;
+    if !status.success() {
+        return Err(ErrorKind::CannotApplyDelta(delta.to_owned()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delta(from: &str, to: &str, size: u64) -> Delta {
+        Delta {
+            filename: format!("pkg-{}_to_{}.delta", from, to),
+            md5sum: String::new(),
+            size,
+            from_version: from.to_owned(),
+            to_version: to.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parse_delta() {
+        let d = Delta::parse("foo-1_to_2.delta 0123abcd 1024 1-1 2-1").unwrap();
+        assert_eq!(d.filename, "foo-1_to_2.delta");
+        assert_eq!(d.size, 1024);
+        assert_eq!(d.from_version, "1-1");
+        assert_eq!(d.to_version, "2-1");
+        assert!(Delta::parse("foo-1_to_2.delta 0123abcd big 1-1 2-1").is_err());
+        assert!(Delta::parse("foo-1_to_2.delta 0123abcd 1024 1-1").is_err());
+    }
+
+    #[test]
+    fn delta_path() {
+        let deltas = vec![
+            delta("1", "2", 10),
+            delta("2", "3", 10),
+            delta("1", "3", 50),
+            delta("3", "4", 10),
+        ];
+        let path = find_path(&deltas, "1", "3").unwrap();
+        assert_eq!(path, vec![&deltas[0], &deltas[1]]);
+        let path = find_path(&deltas, "2", "4").unwrap();
+        assert_eq!(path, vec![&deltas[1], &deltas[3]]);
+        assert!(find_path(&deltas, "4", "1").is_none());
+        assert!(find_path(&deltas, "1", "1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn verify_delta() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pkg-1_to_2.delta");
+        fs::write(&path, b"delta").unwrap();
+        let mut d = delta("1", "2", 5);
+        d.md5sum = "63BCABF86A9A991864777C631C5B7617".to_owned();
+        d.verify(&path).unwrap();
+        d.size = 6;
+        assert!(d.verify(&path).is_err());
+        d.size = 5;
+        d.md5sum = "0".repeat(32);
+        assert!(d.verify(&path).is_err());
+    }
+
+    #[test]
+    fn delta_ratio() {
+        assert!(worthwhile(10, 100, 0.7));
+        assert!(!worthwhile(80, 100, 0.7));
+        assert!(!worthwhile(10, 100, 0.0));
+    }
+}
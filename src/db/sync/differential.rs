@@ -0,0 +1,147 @@
+//! Fetching only the entries of a sync database that have changed.
+//!
+//! Most of a database is the same from one sync to the next, but the whole archive is downloaded
+//! each time. Mirrors that support differential syncs publish a manifest next to the database,
+//! `<database file>.manifest`, with a line for each entry: its directory name and the sha256
+//! checksum of its `desc` file, separated by a space. Each `desc` file is published as
+//! `<database file>.d/<directory name>/desc`.
+//!
+//! Entries whose checksum matches the current database are copied from it, and only the rest are
+//! downloaded. The database is then rebuilt locally as a gzip compressed tar. If anything goes
+//! wrong, the whole database is downloaded as usual.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use libflate::gzip::Encoder;
+
+use crate::{db::sync::format, error::Error};
+
+/// Added to the database filename to get the manifest's.
+pub(crate) const MANIFEST_EXT: &str = "manifest";
+/// Added to the database filename to get the directory holding the entries.
+pub(crate) const ENTRIES_EXT: &str = "d";
+
+/// A line of a manifest.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct ManifestEntry {
+    /// The entry's directory name, `<name>-<version>`.
+    pub dirname: String,
+    /// The sha256 checksum of the entry's `desc` file, in hex.
+    pub sha256: String,
+}
+
+/// Parse a manifest, rejecting directory names that aren't a single path component.
+pub(crate) fn parse_manifest(raw: &str) -> io::Result<Vec<ManifestEntry>> {
+    let invalid = |line: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid manifest line \"{}\"", line),
+        )
+    };
+    let mut entries = Vec::new();
+    for line in raw.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let mut fields = line.split_whitespace();
+        let (dirname, sha256) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(dirname), Some(sha256), None) => (dirname, sha256),
+            _ => return Err(invalid(line)),
+        };
+        if dirname.starts_with('.')
+            || dirname.contains('/')
+            || sha256.len() != 64
+            || !sha256.chars().all(|ch| ch.is_ascii_hexdigit())
+        {
+            return Err(invalid(line));
+        }
+        entries.push(ManifestEntry {
+            dirname: dirname.to_owned(),
+            sha256: sha256.to_owned(),
+        });
+    }
+    Ok(entries)
+}
+
+/// The `desc` file of every entry in a database archive, by directory name.
+pub(crate) fn read_entries(path: &Path) -> Result<HashMap<String, Vec<u8>>, Error> {
+    let (mut archive, _) = format::open_archive(path)?;
+    let mut entries = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if path.file_name().map_or(true, |name| name != "desc") {
+            continue;
+        }
+        let dirname = match path
+            .parent()
+            .and_then(|parent| parent.to_str())
+            .filter(|parent| !parent.is_empty() && !parent.contains('/'))
+        {
+            Some(dirname) => dirname.to_owned(),
+            None => continue,
+        };
+        let mut desc = Vec::new();
+        entry.read_to_end(&mut desc)?;
+        entries.insert(dirname, desc);
+    }
+    Ok(entries)
+}
+
+/// Write a gzip compressed database holding the given `desc` files, in order.
+pub(crate) fn write_database(file: &mut fs::File, entries: &[(String, Vec<u8>)]) -> io::Result<()> {
+    let mut archive = tar::Builder::new(Encoder::new(file)?);
+    for (dirname, desc) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_cksum();
+        archive.append_data(&mut header, format!("{}/", dirname), io::empty())?;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(desc.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, format!("{}/desc", dirname), &desc[..])?;
+    }
+    archive.into_inner()?.finish().into_result()?.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest() {
+        let sha256 = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+        let raw = format!("foo-1.0-1 {}\n\nbar-2:1-1  {}\n", sha256, sha256);
+        let entries = parse_manifest(&raw).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].dirname, "bar-2:1-1");
+        assert_eq!(entries[1].sha256, sha256);
+
+        assert!(parse_manifest("foo-1.0-1").is_err());
+        assert!(parse_manifest("foo-1.0-1 abc").is_err());
+        assert!(parse_manifest(&format!("../foo-1.0-1 {}", sha256)).is_err());
+        assert!(parse_manifest(&format!("foo/bar-1-1 {}", sha256)).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("core.db");
+        let entries = vec![
+            ("foo-1.0-1".to_owned(), b"%NAME%\nfoo\n\n".to_vec()),
+            ("bar-2.0-1".to_owned(), b"%NAME%\nbar\n\n".to_vec()),
+        ];
+        write_database(&mut fs::File::create(&path).unwrap(), &entries).unwrap();
+        format::verify_archive(&path).unwrap();
+        let read = read_entries(&path).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read["foo-1.0-1"], entries[0].1);
+        assert_eq!(read["bar-2.0-1"], entries[1].1);
+    }
+}
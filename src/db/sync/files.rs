@@ -0,0 +1,287 @@
+//! File lists of the packages in a sync database, for `pacman -F` style searches.
+//!
+//! Servers publish a `.files` database next to each sync database, with a `files` entry listing
+//! the contents of each package. Reading it means decompressing the whole archive, so after each
+//! download it is turned into an index file next to it, with one `<path>\t<package>` line per
+//! file, sorted by path. Searches load the index once, and then look file names up in memory.
+//!
+//! The index starts with the entries (`<name>-<version>` directories) it was built from. When
+//! the file list is downloaded again, only the `files` of entries that changed are read; the
+//! files of the others are taken from the old index.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    db::{split_package_dirname, sync::format},
+    error::{Error, ErrorKind},
+};
+
+/// The extension of the file list database.
+pub(crate) const FILES_EXT: &str = "files";
+/// The extension of the index built from the file list database.
+pub(crate) const INDEX_EXT: &str = "files.idx";
+/// The line before the entries an index was built from, which end at an empty line.
+const ENTRIES_HEADER: &str = "%ENTRIES%";
+
+/// A file in a package of a sync database, from `Alpm::search_files`.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct FileMatch {
+    /// The name of the database the package is in.
+    pub database: String,
+    /// The name of the package that contains the file.
+    pub package: String,
+    /// The path of the file, relative to the root.
+    pub path: PathBuf,
+}
+
+/// The index of a database's file list, loaded into memory.
+#[derive(Debug, Default)]
+pub(crate) struct FileIndex {
+    /// The path and package of every file, by file name.
+    by_name: HashMap<String, Vec<(String, String)>>,
+}
+
+impl FileIndex {
+    /// Write the index file for a file list database, reusing the files of entries that are
+    /// the same as when the existing index was built.
+    pub(crate) fn build(database: &str, files_db: &Path, index: &Path) -> Result<(), Error> {
+        // A missing or unreadable index just means everything is read.
+        let previous = fs::read_to_string(index).unwrap_or_default();
+        let (known, previous_files) = parse_index(&previous);
+        let mut unchanged: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (path, package) in previous_files {
+            unchanged.entry(package).or_default().push(path);
+        }
+
+        let (mut archive, _) = format::open_archive(files_db)?;
+        let mut entries = Vec::new();
+        let mut lines = Vec::new();
+        let mut reused = 0;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            if path.file_name() != Some(OsStr::new("files")) {
+                continue;
+            }
+            let malformed = || ErrorKind::MalformedSyncDatabase {
+                database: database.to_owned(),
+                entry: path.clone(),
+            };
+            let dirname = path.parent().and_then(Path::to_str).ok_or_else(malformed)?;
+            let package = split_package_dirname(dirname)
+                .map(|(name, _)| name.to_owned())
+                .ok_or_else(malformed)?;
+            entries.push(dirname.to_owned());
+            if known.contains(dirname) {
+                // The contents are skipped over without being read.
+                reused += 1;
+                for file in unchanged.get(package.as_str()).into_iter().flatten() {
+                    lines.push(format!("{}\t{}", file, package));
+                }
+                continue;
+            }
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            // Skip the `%FILES%` header, and directories.
+            for file in contents
+                .lines()
+                .filter(|line| !line.is_empty() && !line.starts_with('%') && !line.ends_with('/'))
+            {
+                lines.push(format!("{}\t{}", file, package));
+            }
+        }
+        entries.sort();
+        lines.sort();
+
+        let dir = index
+            .parent()
+            .ok_or_else(|| ErrorKind::BadSyncDatabasePath(index.to_owned()))?;
+        let mut out = tempfile::NamedTempFile::new_in(dir)?;
+        {
+            let mut writer = BufWriter::new(out.as_file_mut());
+            writeln!(writer, "{}", ENTRIES_HEADER)?;
+            for entry in &entries {
+                writeln!(writer, "{}", entry)?;
+            }
+            writeln!(writer)?;
+            for line in &lines {
+                writeln!(writer, "{}", line)?;
+            }
+            writer.flush()?;
+        }
+        out.persist(index).map_err(|e| e.error)?;
+        log::debug!(
+            "indexed {} files from {} ({} of {} entries unchanged)",
+            lines.len(),
+            files_db.display(),
+            reused,
+            entries.len()
+        );
+        Ok(())
+    }
+
+    /// Read an index file.
+    pub(crate) fn load(index: &Path) -> Result<FileIndex, Error> {
+        let contents = fs::read_to_string(index)?;
+        let mut by_name: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        for (path, package) in parse_index(&contents).1 {
+            by_name
+                .entry(file_name(path).to_owned())
+                .or_default()
+                .push((path.to_owned(), package.to_owned()));
+        }
+        Ok(FileIndex { by_name })
+    }
+
+    /// The paths of all the files in a package, sorted.
+    pub(crate) fn files_of(&self, package: &str) -> Vec<&str> {
+        let mut files: Vec<&str> = self
+            .by_name
+            .values()
+            .flatten()
+            .filter(|(_, owner)| owner == package)
+            .map(|(path, _)| path.as_str())
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// Find files matching a pattern, returning their paths and packages, sorted by path.
+    ///
+    /// A pattern containing a `/` must match the whole path (a leading `/` is ignored), otherwise
+    /// it must match the file name.
+    pub(crate) fn search(&self, pattern: &str) -> Vec<(&str, &str)> {
+        let pattern = pattern.trim_start_matches('/');
+        let whole_path = pattern.contains('/');
+        self.by_name
+            .get(file_name(pattern))
+            .into_iter()
+            .flatten()
+            .filter(|(path, _)| !whole_path || path == pattern)
+            .map(|(path, package)| (path.as_str(), package.as_str()))
+            .collect()
+    }
+}
+
+/// Split an index into the entries it was built from, and the path and package of each file.
+///
+/// Indexes written before entries were recorded have none, so they are rebuilt in full.
+fn parse_index(contents: &str) -> (HashSet<&str>, Vec<(&str, &str)>) {
+    let mut lines = contents.lines().peekable();
+    let mut entries = HashSet::new();
+    if lines.peek() == Some(&ENTRIES_HEADER) {
+        lines.next();
+        for line in &mut lines {
+            if line.is_empty() {
+                break;
+            }
+            entries.insert(line);
+        }
+    }
+    let files = lines
+        .filter_map(|line| {
+            // Package names can't contain tabs, but paths could.
+            let mut parts = line.rsplitn(2, '\t');
+            match (parts.next(), parts.next()) {
+                (Some(package), Some(path)) => Some((path, package)),
+                _ => None,
+            }
+        })
+        .collect();
+    (entries, files)
+}
+
+/// The last component of a path.
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search() {
+        let dir = tempfile::tempdir().unwrap();
+        let index_path = dir.path().join("core.files.idx");
+        fs::write(
+            &index_path,
+            "usr/bin/ls\tcoreutils\nusr/lib/ls\tother\nusr/share/man/man1/ls.1.gz\tcoreutils\n",
+        )
+        .unwrap();
+        let index = FileIndex::load(&index_path).unwrap();
+        assert_eq!(
+            index.search("ls"),
+            vec![("usr/bin/ls", "coreutils"), ("usr/lib/ls", "other")]
+        );
+        assert_eq!(
+            index.search("/usr/bin/ls"),
+            vec![("usr/bin/ls", "coreutils")]
+        );
+        assert!(index.search("bin/ls.1").is_empty());
+        assert!(index.search("cat").is_empty());
+    }
+
+    /// Write a plain tar file list with the given `files` entries.
+    fn write_files_db(path: &Path, entries: &[(&str, &str)]) {
+        let mut archive = tar::Builder::new(fs::File::create(path).unwrap());
+        for (dirname, files) in entries {
+            let contents = format!("%FILES%\n{}", files);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(
+                    &mut header,
+                    format!("{}/files", dirname),
+                    contents.as_bytes(),
+                )
+                .unwrap();
+        }
+        archive.into_inner().unwrap();
+    }
+
+    /// Rebuilding an index only reads the entries that changed since it was built.
+    #[test]
+    fn build_incrementally() {
+        let dir = tempfile::tempdir().unwrap();
+        let (files_path, index_path) = (
+            dir.path().join("core.files"),
+            dir.path().join("core.files.idx"),
+        );
+        write_files_db(
+            &files_path,
+            &[
+                ("foo-1-1", "usr/\nusr/bin/foo\n"),
+                ("bar-1-1", "usr/bin/bar\n"),
+            ],
+        );
+        FileIndex::build("core", &files_path, &index_path).unwrap();
+        let index = FileIndex::load(&index_path).unwrap();
+        assert_eq!(index.search("foo"), vec![("usr/bin/foo", "foo")]);
+        assert_eq!(index.search("bar"), vec![("usr/bin/bar", "bar")]);
+
+        // foo's entry is unchanged, so its new contents aren't read.
+        write_files_db(
+            &files_path,
+            &[
+                ("foo-1-1", "usr/bin/other\n"),
+                ("bar-2-1", "usr/bin/bar2\n"),
+            ],
+        );
+        FileIndex::build("core", &files_path, &index_path).unwrap();
+        let index = FileIndex::load(&index_path).unwrap();
+        assert_eq!(index.search("foo"), vec![("usr/bin/foo", "foo")]);
+        assert!(index.search("other").is_empty());
+        assert!(index.search("bar").is_empty());
+        assert_eq!(index.search("bar2"), vec![("usr/bin/bar2", "bar")]);
+        assert_eq!(index.files_of("bar"), vec!["usr/bin/bar2"]);
+    }
+}
@@ -0,0 +1,187 @@
+//! Detecting the on-disk format of sync databases.
+//!
+//! Repositories don't all use the same extension or compression, and `foo.db` is often a symlink
+//! to something like `foo.db.tar.zst`, so we look at what is actually on disk rather than
+//! trusting the configured extension.
+
+use std::{
+    fmt, fs,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use libflate::gzip;
+
+use crate::{
+    db::SyncDbName,
+    error::{Error, ErrorKind},
+};
+
+/// Extensions that sync databases are commonly found with, in the order they are probed.
+pub(crate) const KNOWN_EXTENSIONS: &[&str] = &[
+    "db",
+    "db.tar.gz",
+    "db.tar.zst",
+    "db.tar.xz",
+    "db.tar.bz2",
+    "db.tar",
+];
+
+/// The compression used for a sync database archive.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Compression {
+    /// A plain tar archive.
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+impl Compression {
+    /// Detect the compression from the start of a file.
+    ///
+    /// `header` should be at least 262 bytes long if possible, so that uncompressed tar archives
+    /// can be recognised.
+    pub(crate) fn detect(header: &[u8]) -> Option<Compression> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if header.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if header.get(257..262) == Some(&b"ustar"[..]) || header.is_empty() {
+            Some(Compression::None)
+        } else {
+            None
+        }
+    }
+
+    /// Whether this library was compiled with support for reading this compression.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Compression::None | Compression::Gzip => true,
+            Compression::Zstd => cfg!(feature = "zstd"),
+            Compression::Xz => cfg!(feature = "xz2"),
+            Compression::Bzip2 => false,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+        })
+    }
+}
+
+/// The format of a sync database found on disk.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct DbFormat {
+    /// The extension of the database file (without the leading dot).
+    pub extension: String,
+    /// The compression of the database archive.
+    pub compression: Compression,
+}
+
+/// Find the database file on disk.
+///
+/// The preferred extension is tried first, followed by the known extensions. Returns the path and
+/// extension of the first that exists, or `None` if the database is not present.
+pub(crate) fn locate(
+    name: &SyncDbName,
+    sync_path: &Path,
+    preferred_ext: &str,
+) -> Option<(PathBuf, String)> {
+    Some(preferred_ext)
+        .into_iter()
+        .chain(KNOWN_EXTENSIONS.iter().cloned())
+        .map(|ext| (name.path(sync_path, ext), ext))
+        .find(|(path, _)| path.is_file())
+        .map(|(path, ext)| (path, ext.to_owned()))
+}
+
+/// Open a database archive, choosing the decoder from the contents of the file.
+pub(crate) fn open_archive(
+    path: &Path,
+) -> Result<(tar::Archive<Box<dyn Read>>, Compression), Error> {
+    let mut file = fs::File::open(path)?;
+    let mut header = Vec::with_capacity(262);
+    (&mut file).take(262).read_to_end(&mut header)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let compression = Compression::detect(&header)
+        .ok_or_else(|| ErrorKind::UnknownDatabaseFormat(path.to_owned()))?;
+    let file = BufReader::new(file);
+    let reader: Box<dyn Read> = match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(gzip::Decoder::new(file)?),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(file)?),
+        #[cfg(feature = "xz2")]
+        Compression::Xz => Box::new(xz2::read::XzDecoder::new(file)),
+        other => {
+            return Err(ErrorKind::UnsupportedCompression {
+                path: path.to_owned(),
+                compression: other,
+            }
+            .into())
+        }
+    };
+    Ok((tar::Archive::new(reader), compression))
+}
+
+/// Check an archive can be opened and its first entry read.
+pub(crate) fn check_archive(path: &Path) -> Result<(), Error> {
+    let (mut archive, _) = open_archive(path)?;
+    match archive.entries()?.next() {
+        Some(entry) => entry.map(|_| ()).map_err(Error::from),
+        None => Ok(()),
+    }
+}
+
+/// Read the whole archive, so that truncated or corrupt files are detected.
+///
+/// Every entry is read to check the tar structure, and then the rest of the stream is read so the
+/// decoder checks the end of the compressed data (e.g. the gzip CRC).
+pub(crate) fn verify_archive(path: &Path) -> Result<(), Error> {
+    let (mut archive, _) = open_archive(path)?;
+    for entry in archive.entries()? {
+        io::copy(&mut entry?, &mut io::sink())?;
+    }
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_compression() {
+        assert_eq!(
+            Compression::detect(&[0x1f, 0x8b, 0x08]),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(
+            Compression::detect(b"\xfd7zXZ\x00\x00"),
+            Some(Compression::Xz)
+        );
+        assert_eq!(Compression::detect(b"BZh91AY"), Some(Compression::Bzip2));
+        let mut tar_header = vec![0; 512];
+        tar_header[257..262].copy_from_slice(b"ustar");
+        assert_eq!(Compression::detect(&tar_header), Some(Compression::None));
+        assert_eq!(Compression::detect(b"not an archive"), None);
+    }
+}
@@ -0,0 +1,81 @@
+//! Package groups (like `base-devel`) in a sync database.
+//!
+//! Groups aren't stored in the database themselves - each package lists the groups it is in - so
+//! they are collected when the database is loaded, to avoid scanning every package each time a
+//! group is looked up.
+
+use std::{collections::BTreeMap, rc::Rc};
+
+use crate::{db::SyncPackage, package::Package};
+
+/// A group of packages in a sync database.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct SyncGroup {
+    /// The name of the group.
+    name: String,
+    /// The names of the packages in the group, sorted.
+    members: Vec<String>,
+    /// The sum of the installed sizes of the members.
+    installed_size: u64,
+}
+
+/// Collects groups from packages as they are read, so the packages needn't all be kept.
+#[derive(Debug, Default)]
+pub(crate) struct GroupCollector {
+    groups: BTreeMap<String, SyncGroup>,
+}
+
+impl GroupCollector {
+    /// Add a package to the groups it is in.
+    pub(crate) fn add(&mut self, package: &SyncPackage) {
+        for name in package.groups() {
+            let group = self
+                .groups
+                .entry(name.clone())
+                .or_insert_with(|| SyncGroup {
+                    name: name.clone(),
+                    ..SyncGroup::default()
+                });
+            group.members.push(package.name().to_owned());
+            group.installed_size += package.size();
+        }
+    }
+
+    /// The groups, by group name.
+    pub(crate) fn finish(self) -> BTreeMap<String, Rc<SyncGroup>> {
+        self.groups
+            .into_iter()
+            .map(|(name, mut group)| {
+                group.members.sort();
+                (name, Rc::new(group))
+            })
+            .collect()
+    }
+}
+
+impl SyncGroup {
+    /// The name of the group.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The names of the packages in the group, sorted.
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    /// The number of packages in the group.
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Whether the group has no packages.
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// The disk space the whole group takes once installed, in bytes.
+    pub fn installed_size(&self) -> u64 {
+        self.installed_size
+    }
+}
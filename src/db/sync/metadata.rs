@@ -0,0 +1,237 @@
+//! State recorded about each sync of a database, stored next to the database file.
+//!
+//! This is only kept for databases registered with `SyncDatabaseOptions::with_sync_metadata`.
+
+use std::{
+    fs, io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    alpm_desc::{de, ser},
+    error::{Error, ErrorKind},
+};
+
+/// How many sync attempts to remember.
+const HISTORY_LEN: usize = 20;
+
+/// What happened the last times a database was synchronized.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SyncMetadata {
+    /// The server the database was last downloaded from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mirror: Option<String>,
+    /// The `ETag` the server sent with the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    /// The `Last-Modified` time the server sent with the database.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+    /// Seconds since the unix epoch of the last successful sync.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_sync: Option<u64>,
+    /// Recent attempts to download the database, oldest first, in the form of `SyncAttempt`'s
+    /// `Display` impl.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<String>,
+}
+
+impl SyncMetadata {
+    /// Read the metadata file, or return empty metadata if there isn't one.
+    pub(crate) fn load(path: &Path) -> Result<SyncMetadata, Error> {
+        match fs::read_to_string(path) {
+            Ok(raw) => de::from_str(&raw).map_err(|e| {
+                Error::from(ErrorKind::InvalidSyncMetadata(path.to_owned())).with_source(e)
+            }),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(SyncMetadata::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the metadata file.
+    pub(crate) fn save(&self, path: &Path) -> Result<(), Error> {
+        let raw = ser::to_string(self).map_err(|e| {
+            Error::from(ErrorKind::InvalidSyncMetadata(path.to_owned())).with_source(e)
+        })?;
+        fs::write(path, raw)?;
+        Ok(())
+    }
+
+    /// Record an attempt to download the database.
+    pub(crate) fn record(&mut self, server: &str, outcome: SyncOutcome) {
+        let attempt = SyncAttempt {
+            time: SystemTime::now(),
+            server: server.to_owned(),
+            outcome,
+        };
+        if attempt.outcome.is_success() {
+            self.mirror = Some(attempt.server.clone());
+            self.last_sync = Some(to_unix(attempt.time));
+        }
+        self.history.push(attempt.to_string());
+        if self.history.len() > HISTORY_LEN {
+            let extra = self.history.len() - HISTORY_LEN;
+            self.history.drain(..extra);
+        }
+    }
+
+    /// Remember the validators sent with a newly downloaded database.
+    pub(crate) fn set_validators(&mut self, etag: Option<String>, last_modified: Option<String>) {
+        self.etag = etag;
+        self.last_modified = last_modified;
+    }
+
+    /// The server the database was last successfully synchronized from.
+    pub fn mirror(&self) -> Option<&str> {
+        self.mirror.as_ref().map(String::as_str)
+    }
+
+    /// The `ETag` of the current database file, if the server sent one.
+    pub fn etag(&self) -> Option<&str> {
+        self.etag.as_ref().map(String::as_str)
+    }
+
+    /// The `Last-Modified` time of the current database file, if the server sent one.
+    pub fn last_modified(&self) -> Option<&str> {
+        self.last_modified.as_ref().map(String::as_str)
+    }
+
+    /// When the database was last successfully synchronized.
+    pub fn last_sync(&self) -> Option<SystemTime> {
+        self.last_sync
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Recent attempts to synchronize the database, oldest first.
+    ///
+    /// Entries that can't be understood are skipped.
+    pub fn history(&self) -> Vec<SyncAttempt> {
+        self.history
+            .iter()
+            .filter_map(|line| SyncAttempt::parse(line))
+            .collect()
+    }
+}
+
+/// A single attempt to download a database from a server.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SyncAttempt {
+    /// When the attempt was made.
+    pub time: SystemTime,
+    /// The server that was tried.
+    pub server: String,
+    /// What happened.
+    pub outcome: SyncOutcome,
+}
+
+impl SyncAttempt {
+    /// Parse a line of the form `<unix time> <server> <outcome>`.
+    fn parse(line: &str) -> Option<SyncAttempt> {
+        let mut parts = line.splitn(3, ' ');
+        let time = UNIX_EPOCH + Duration::from_secs(parts.next()?.parse().ok()?);
+        let server = parts.next()?.to_owned();
+        let outcome = SyncOutcome::parse(parts.next()?)?;
+        Some(SyncAttempt {
+            time,
+            server,
+            outcome,
+        })
+    }
+}
+
+impl std::fmt::Display for SyncAttempt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} {}", to_unix(self.time), self.server, self.outcome)
+    }
+}
+
+/// The outcome of a `SyncAttempt`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SyncOutcome {
+    /// A new database was downloaded.
+    Updated,
+    /// The server said the database hadn't changed.
+    NotModified,
+    /// The server couldn't provide the database, with the reason.
+    Failed(String),
+}
+
+impl SyncOutcome {
+    /// Whether the database is up to date after this outcome.
+    pub fn is_success(&self) -> bool {
+        match self {
+            SyncOutcome::Updated | SyncOutcome::NotModified => true,
+            SyncOutcome::Failed(_) => false,
+        }
+    }
+
+    fn parse(input: &str) -> Option<SyncOutcome> {
+        match input {
+            "updated" => Some(SyncOutcome::Updated),
+            "not-modified" => Some(SyncOutcome::NotModified),
+            _ if input.starts_with("failed: ") => {
+                Some(SyncOutcome::Failed(input["failed: ".len()..].to_owned()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for SyncOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SyncOutcome::Updated => f.write_str("updated"),
+            SyncOutcome::NotModified => f.write_str("not-modified"),
+            // Keep it on one line, since each attempt is a line.
+            SyncOutcome::Failed(reason) => write!(f, "failed: {}", reason.replace('\n', " ")),
+        }
+    }
+}
+
+/// Seconds since the unix epoch (0 for times before it).
+fn to_unix(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_roundtrip() {
+        let mut metadata = SyncMetadata::default();
+        metadata.record(
+            "https://a.example/core/",
+            SyncOutcome::Failed("HTTP status 404".into()),
+        );
+        metadata.record("https://b.example/core/", SyncOutcome::Updated);
+        metadata.set_validators(Some("\"abc\"".into()), None);
+        assert_eq!(metadata.mirror(), Some("https://b.example/core/"));
+        assert!(metadata.last_sync().is_some());
+
+        let raw = ser::to_string(&metadata).unwrap();
+        let parsed: SyncMetadata = de::from_str(&raw).unwrap();
+        assert_eq!(parsed, metadata);
+        let history = parsed.history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(
+            history[0].outcome,
+            SyncOutcome::Failed("HTTP status 404".into())
+        );
+        assert_eq!(history[1].server, "https://b.example/core/");
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut metadata = SyncMetadata::default();
+        for _ in 0..HISTORY_LEN + 5 {
+            metadata.record("https://a.example/", SyncOutcome::NotModified);
+        }
+        assert_eq!(metadata.history().len(), HISTORY_LEN);
+    }
+}
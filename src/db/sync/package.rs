@@ -1,22 +1,72 @@
+use std::{
+    cell::RefCell,
+    fs,
+    path::{Path, PathBuf},
+    rc::Weak,
+};
+
+use derivative::Derivative;
+use reqwest::Url;
 use serde_derive::{Deserialize, Serialize};
 
 use crate::{
-    alpm_desc::de,
+    db::{self, Delta, SyncDatabase},
     error::{Error, ErrorKind},
+    hash,
     package::Package,
+    util, Handle, Warning,
 };
 
+/// The suffix added to a file's name to get the name of its detached signature.
+const SIG_EXTENSION: &str = ".sig";
+
 /// A package from a sync database.
-#[derive(Debug, Clone, PartialEq, Hash)]
+#[derive(Debug, Clone, Derivative)]
+#[derivative(PartialEq, Hash)]
 pub struct SyncPackage {
     desc: SyncPackageDescription,
+    /// The desc file as it was in the database, for `SyncDatabase::raw_entry` and
+    /// `Package::raw_field`.
+    desc_raw: String,
+    #[derivative(PartialEq = "ignore", Hash = "ignore")]
+    handle: Weak<RefCell<Handle>>,
 }
 
 impl SyncPackage {
-    pub(crate) fn from_parts(desc_raw: &str, name: &str, version: &str) -> Result<Self, Error> {
+    pub(crate) fn from_parts(
+        desc_raw: &str,
+        name: &str,
+        version: &str,
+        handle: &Weak<RefCell<Handle>>,
+    ) -> Result<Self, Error> {
+        SyncPackage::parse(desc_raw, name, version, handle, true)
+    }
+
+    /// Parse a package again after it has been dropped from memory, without repeating warnings
+    /// about its desc file.
+    pub(crate) fn reparse(
+        desc_raw: &str,
+        name: &str,
+        version: &str,
+        handle: &Weak<RefCell<Handle>>,
+    ) -> Result<Self, Error> {
+        SyncPackage::parse(desc_raw, name, version, handle, false)
+    }
+
+    fn parse(
+        desc_raw: &str,
+        name: &str,
+        version: &str,
+        handle: &Weak<RefCell<Handle>>,
+        warn: bool,
+    ) -> Result<Self, Error> {
         // get package description
-        let desc: SyncPackageDescription =
-            de::from_str(&desc_raw).map_err(|err| Error::invalid_sync_package(name, err))?;
+        let desc: Result<SyncPackageDescription, _> = if warn {
+            db::parse_desc(desc_raw, name, handle)
+        } else {
+            db::parse_desc_quietly(desc_raw, handle).map(|(desc, _)| desc)
+        };
+        let desc = desc.map_err(|err| Error::invalid_sync_package(name, err))?;
 
         // check package name/version with path
         if desc.name != name {
@@ -38,10 +88,129 @@ impl SyncPackage {
             ));
         }
 
-        Ok(SyncPackage { desc })
+        Ok(SyncPackage {
+            desc,
+            desc_raw: desc_raw.to_owned(),
+            handle: handle.clone(),
+        })
+    }
+
+    /// The desc file as it was in the database.
+    pub(crate) fn desc_raw(&self) -> &str {
+        &self.desc_raw
+    }
+
+    /// The filename of the package archive on the server.
+    pub fn filename(&self) -> &str {
+        &self.desc.filename
+    }
+
+    /// The size of the package archive in bytes.
+    pub fn compressed_size(&self) -> u64 {
+        self.desc.compressed_size
+    }
+
+    /// The md5 checksum of the package archive in hex, if the database has one.
+    pub fn md5sum(&self) -> Option<&str> {
+        Some(self.desc.md5sum.as_str()).filter(|sum| !sum.is_empty())
+    }
+
+    /// The sha256 checksum of the package archive in hex, if the database has one.
+    pub fn sha256sum(&self) -> Option<&str> {
+        Some(self.desc.sha256sum.as_str()).filter(|sum| !sum.is_empty())
+    }
+
+    /// Check that a package archive is this package, using its size and sha256 checksum.
+    ///
+    /// This catches archives that were truncated, or are from another build with the same
+    /// filename. If the database gives no checksum, only the size is checked.
+    pub fn verify_archive(&self, path: &Path) -> Result<(), Error> {
+        let mismatch = |reason: String| ErrorKind::PackageArchiveMismatch {
+            path: path.to_owned(),
+            reason,
+        };
+        let size = fs::metadata(path)?.len();
+        if size != self.desc.compressed_size {
+            return Err(mismatch(format!(
+                "the size is {} bytes, expected {}",
+                size, self.desc.compressed_size
+            ))
+            .into());
+        }
+        if self.desc.sha256sum.is_empty() {
+            return Ok(());
+        }
+        let sha256sum = hash::sha256_file(path)?;
+        if !sha256sum.eq_ignore_ascii_case(&self.desc.sha256sum) {
+            return Err(mismatch("the sha256 checksum is different".to_owned()).into());
+        }
+        Ok(())
+    }
+
+    /// Find an archive of this package in one of the directories that passes `verify_archive`.
+    ///
+    /// Archives that don't pass are skipped, with a warning.
+    pub(crate) fn find_archive<'a>(
+        &self,
+        dirs: impl IntoIterator<Item = &'a Path>,
+        handle: &Handle,
+    ) -> Option<PathBuf> {
+        for dir in dirs {
+            let path = dir.join(self.filename());
+            if !path.is_file() {
+                continue;
+            }
+            match self.verify_archive(&path) {
+                Ok(()) => return Some(path),
+                Err(e) => handle.warn(Warning::CorruptCachedPackage {
+                    path,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        None
+    }
+
+    /// The deltas that can be used to make this package from older versions.
+    pub fn deltas(&self) -> Result<Vec<Delta>, Error> {
+        self.desc
+            .deltas
+            .iter()
+            .map(|line| Delta::parse(line))
+            .collect()
+    }
+
+    /// The urls that the package archive can be downloaded from, one for each server of the
+    /// database, in the order the servers should be tried.
+    pub fn urls(&self, database: &SyncDatabase) -> Result<Vec<Url>, Error> {
+        join_servers(database, &self.desc.filename)
+    }
+
+    /// The urls that the detached signature for the package can be downloaded from, in the same
+    /// order as `urls`.
+    pub fn signature_urls(&self, database: &SyncDatabase) -> Result<Vec<Url>, Error> {
+        let mut filename = self.desc.filename.clone();
+        filename.push_str(SIG_EXTENSION);
+        join_servers(database, &filename)
     }
 }
 
+/// Join each of the database's servers with the given filename.
+fn join_servers(database: &SyncDatabase, filename: &str) -> Result<Vec<Url>, Error> {
+    database
+        .servers()?
+        .into_iter()
+        .map(|server| {
+            util::join_url(&server, filename).ok_or_else(|| {
+                Error::from(ErrorKind::InvalidPackageUrl {
+                    server: server.to_string(),
+                    filename: filename.to_owned(),
+                })
+            })
+        })
+        .collect()
+}
+
 impl Package for SyncPackage {
     fn name(&self) -> &str {
         &self.desc.name
@@ -114,6 +283,10 @@ impl Package for SyncPackage {
     fn provides(&self) -> &[String] {
         &self.desc.provides
     }
+
+    fn raw_field(&self, key: &str) -> Option<Vec<String>> {
+        db::raw_desc_field(&self.desc_raw, key, &self.handle)
+    }
 }
 
 /// Struct to help deserializing `desc` file
@@ -160,4 +333,6 @@ pub(crate) struct SyncPackageDescription {
     pub(crate) conflicts: Vec<String>,
     #[serde(default)]
     pub(crate) provides: Vec<String>,
+    #[serde(default)]
+    pub(crate) deltas: Vec<String>,
 }
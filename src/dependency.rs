@@ -0,0 +1,263 @@
+//! Dependencies between packages, like `glibc>=2.31`.
+
+use std::{fmt, slice};
+
+use crate::{
+    error::{Error, ErrorKind},
+    package::Package,
+    target::{self, Comparison, VersionConstraint},
+};
+
+/// A dependency on a package (or something a package provides), with an optional version
+/// constraint.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Dependency {
+    /// The name of the package or provision depended on.
+    pub name: String,
+    /// The version that is required.
+    pub constraint: Option<VersionConstraint>,
+}
+
+impl Dependency {
+    /// Parse a dependency like `glibc` or `glibc>=2.31`, as found in `depends` and `provides`.
+    pub fn parse(input: &str) -> Result<Dependency, Error> {
+        Depend::parse(input)
+            .map(|depend| depend.to_dependency())
+            .ok_or_else(|| ErrorKind::InvalidDependency(input.to_owned()).into())
+    }
+
+    /// Whether the package satisfies this dependency, either directly or through something it
+    /// provides.
+    pub fn satisfied_by(&self, package: &impl Package) -> bool {
+        self.as_depend().satisfied_by(package)
+    }
+
+    /// Borrow this dependency.
+    pub fn as_depend(&self) -> Depend<'_> {
+        Depend {
+            name: &self.name,
+            constraint: self
+                .constraint
+                .as_ref()
+                .map(|c| (c.comparison, c.version.as_str())),
+        }
+    }
+}
+
+/// A dependency borrowed from a package's metadata, so it can be parsed without allocating.
+///
+/// These come from iterators like `Package::depends_iter`, which is cheaper than parsing every
+/// entry into a `Dependency` when resolving many packages.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Depend<'a> {
+    /// The name of the package or provision depended on.
+    pub name: &'a str,
+    /// How the version is compared, and the version compared against, if there is a constraint.
+    pub constraint: Option<(Comparison, &'a str)>,
+}
+
+impl<'a> Depend<'a> {
+    /// Parse a dependency like `glibc>=2.31`, returning `None` if it is not valid.
+    pub fn parse(input: &'a str) -> Option<Depend<'a>> {
+        let (name, constraint) = match input.find(|c| c == '<' || c == '>' || c == '=') {
+            Some(idx) => (
+                &input[..idx],
+                Some(target::parse_constraint(&input[idx..])?),
+            ),
+            None => (input, None),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        Some(Depend { name, constraint })
+    }
+
+    /// Parse an `optdepends` entry like `python>=3.7: for the python bindings` into the
+    /// dependency and the reason for it, returning `None` if the dependency is not valid.
+    pub fn parse_optional(input: &'a str) -> Option<(Depend<'a>, Option<String>)> {
+        let (dependency, reason) = split_optional(input);
+        Some((Depend::parse(dependency)?, reason.map(str::to_owned)))
+    }
+
+    /// Copy this into an owned `Dependency`.
+    pub fn to_dependency(&self) -> Dependency {
+        Dependency {
+            name: self.name.to_owned(),
+            constraint: self
+                .constraint
+                .map(|(comparison, version)| VersionConstraint {
+                    comparison,
+                    version: version.to_owned(),
+                }),
+        }
+    }
+
+    /// Whether the package satisfies this dependency, either directly or through something it
+    /// provides.
+    pub fn satisfied_by(&self, package: &impl Package) -> bool {
+        if package.name() == self.name
+            && self.constraint.map_or(true, |(comparison, version)| {
+                target::satisfies(package.version(), comparison, version)
+            })
+        {
+            return true;
+        }
+        package
+            .provides_iter()
+            .any(|provision| self.satisfied_by_provision(&provision))
+    }
+
+    /// Whether a `provides` entry satisfies this dependency.
+    ///
+    /// As in alpm, a versioned dependency is only satisfied by a provision with an exact version.
+    fn satisfied_by_provision(&self, provision: &Depend) -> bool {
+        if provision.name != self.name {
+            return false;
+        }
+        match (self.constraint, provision.constraint) {
+            (None, _) => true,
+            (Some((comparison, wanted)), Some((Comparison::Equal, provided))) => {
+                target::satisfies(provided, comparison, wanted)
+            }
+            (Some(_), _) => false,
+        }
+    }
+}
+
+impl fmt::Display for Depend<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)?;
+        if let Some((comparison, version)) = self.constraint {
+            write!(f, "{}{}", comparison.as_str(), version)?;
+        }
+        Ok(())
+    }
+}
+
+/// Split an `optdepends` entry into the dependency and the reason, if there is one.
+///
+/// As in alpm the reason follows `": "`, so a version with an epoch (`foo>=1:2.0`) is not split.
+pub(crate) fn split_optional(input: &str) -> (&str, Option<&str>) {
+    let (dependency, reason) = match input.find(": ") {
+        Some(idx) => (&input[..idx], Some(input[idx + 2..].trim())),
+        None => (input.trim_end_matches(':'), None),
+    };
+    (
+        dependency.trim(),
+        reason.filter(|reason| !reason.is_empty()),
+    )
+}
+
+/// An iterator parsing dependencies from a list as it goes, skipping any that are not valid.
+///
+/// See `Package::depends_iter`.
+#[derive(Debug, Clone)]
+pub struct DependIter<'a> {
+    entries: slice::Iter<'a, String>,
+}
+
+impl<'a> DependIter<'a> {
+    /// Iterate over the dependencies in a list of entries.
+    pub(crate) fn new(entries: &'a [String]) -> Self {
+        DependIter {
+            entries: entries.iter(),
+        }
+    }
+}
+
+impl<'a> Iterator for DependIter<'a> {
+    type Item = Depend<'a>;
+
+    fn next(&mut self) -> Option<Depend<'a>> {
+        self.entries.find_map(|entry| Depend::parse(entry))
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(constraint) = &self.constraint {
+            write!(f, "{}", constraint)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dep(input: &str) -> Dependency {
+        Dependency::parse(input).unwrap()
+    }
+
+    fn borrowed(input: &str) -> Depend {
+        Depend::parse(input).unwrap()
+    }
+
+    #[test]
+    fn parse_dependency() {
+        let d = dep("glibc>=2.31");
+        assert_eq!(d.name, "glibc");
+        assert_eq!(d.constraint.as_ref().unwrap().version, "2.31");
+        assert_eq!(d.to_string(), "glibc>=2.31");
+        assert_eq!(dep("sh").constraint, None);
+        assert!(Dependency::parse(">=1").is_err());
+        assert!(Dependency::parse("foo>=").is_err());
+    }
+
+    #[test]
+    fn provisions() {
+        assert!(borrowed("sh").satisfied_by_provision(&borrowed("sh")));
+        assert!(borrowed("sh").satisfied_by_provision(&borrowed("sh=5.0")));
+        assert!(borrowed("libfoo.so>=2").satisfied_by_provision(&borrowed("libfoo.so=3-64")));
+        assert!(!borrowed("libfoo.so>=2").satisfied_by_provision(&borrowed("libfoo.so=1-64")));
+        assert!(!borrowed("libfoo.so>=2").satisfied_by_provision(&borrowed("libfoo.so")));
+        assert!(!borrowed("bash").satisfied_by_provision(&borrowed("sh")));
+    }
+
+    #[test]
+    fn optional_with_reason() {
+        let (depend, reason) =
+            Depend::parse_optional("python>=3.7: for the python bindings").unwrap();
+        assert_eq!(depend, borrowed("python>=3.7"));
+        assert_eq!(reason.unwrap(), "for the python bindings");
+        let (depend, reason) = Depend::parse_optional("foo>=1:2.0: reason").unwrap();
+        assert_eq!(
+            depend.constraint,
+            Some((Comparison::GreaterOrEqual, "1:2.0"))
+        );
+        assert_eq!(reason.unwrap(), "reason");
+        assert_eq!(
+            Depend::parse_optional("bar").unwrap(),
+            (borrowed("bar"), None)
+        );
+        assert_eq!(
+            Depend::parse_optional("bar: ").unwrap(),
+            (borrowed("bar"), None)
+        );
+        assert_eq!(Depend::parse_optional(": no name"), None);
+    }
+
+    #[test]
+    fn iterate_without_copying() {
+        let entries = vec!["glibc>=2.31".to_owned(), ">=1".to_owned(), "sh".to_owned()];
+        let parsed: Vec<Depend> = DependIter::new(&entries).collect();
+        assert_eq!(
+            parsed,
+            vec![
+                Depend {
+                    name: "glibc",
+                    constraint: Some((Comparison::GreaterOrEqual, "2.31")),
+                },
+                Depend {
+                    name: "sh",
+                    constraint: None,
+                },
+            ]
+        );
+        assert_eq!(parsed[0].to_string(), "glibc>=2.31");
+        assert_eq!(parsed[0].to_dependency(), dep("glibc>=2.31"));
+        assert_eq!(dep("glibc>=2.31").as_depend(), parsed[0]);
+    }
+}
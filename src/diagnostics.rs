@@ -0,0 +1,422 @@
+//! Non-fatal problems found while using the library.
+//!
+//! Many problems (for example stray files in the database directory) don't stop the library from
+//! working, but a user may still want to know about them. As well as being logged, these are
+//! collected as `Warning`s, which can be retrieved using `Alpm::take_warnings`, or observed as
+//! they happen by registering a callback.
+//!
+//! A badly broken system can produce thousands of warnings of the same kind, so a warning that is
+//! the same as one already reported in the current window is dropped, and there can be a limit on
+//! how many of each kind are reported in a window (see `AlpmBuilder::with_warning_limit`). Those
+//! over the limit are counted, and reported as a single `Warning::Suppressed` for each kind when
+//! the window ends. A window ends when warnings are taken, when it has lasted long enough (see
+//! `AlpmBuilder::with_warning_window`), or when it has seen too many different warnings to
+//! remember.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, mem,
+    path::PathBuf,
+    rc::Weak,
+    time::{Duration, Instant},
+};
+
+use derivative::Derivative;
+
+use crate::Handle;
+
+/// A recoverable problem that was encountered.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Warning {
+    /// A file was found in the local database directory that does not belong there.
+    UnexpectedFile(PathBuf),
+    /// A server was added to a database that already contained it.
+    DuplicateServer { url: String, database: String },
+    /// A server was removed from a database that did not contain it.
+    ServerNotFound { url: String, database: String },
+    /// The version file of the local database could not be understood.
+    InvalidVersionFile { path: PathBuf, contents: String },
+    /// The local database is not the version this library expects.
+    OutdatedDatabaseVersion { found: u64, expected: u64 },
+    /// A database could not be unregistered because it was not registered.
+    DatabaseNotRegistered(String),
+    /// A server responded with an unexpected status code while synchronizing a database.
+    UnexpectedStatus {
+        url: String,
+        database: String,
+        status: u16,
+    },
+    /// Deltas could not be used to update a package, so the whole package was downloaded.
+    DeltaFailed { package: String, reason: String },
+    /// The configured `Downloader` could not download a package, so the built-in client was used.
+    DownloaderFailed { package: String, reason: String },
+    /// A chosen optional dependency was not in any sync database.
+    OptionalDependencyNotFound { package: String, dependency: String },
+    /// A sync database was synchronized, but its file list (see
+    /// `SyncDatabaseOptions::with_file_lists`) could not be, so file searches use the old one.
+    FileListSyncFailed { database: String, reason: String },
+    /// A sync database could not be loaded, so it will be treated as empty and invalid.
+    DatabaseLoadFailed { database: String, reason: String },
+    /// A package archive in a cache directory was truncated or outdated, so it won't be used.
+    CorruptCachedPackage { path: PathBuf, reason: String },
+    /// An installed package is built for an architecture that isn't configured.
+    ForeignArchitecture { package: String, arch: String },
+    /// The local database has more than one version of a package installed.
+    DuplicateLocalPackage { name: String, versions: Vec<String> },
+    /// A sync database has more than one entry for a package, so only the first was used.
+    DuplicateSyncPackage {
+        database: String,
+        name: String,
+        versions: Vec<String>,
+    },
+    /// A package's desc or files file has a key more than once (see `DuplicateKeyPolicy`).
+    DuplicateDescKey { package: String, key: String },
+    /// Packages being installed depend on each other in a cycle, so some are installed before
+    /// their dependencies.
+    DependencyCycle { packages: Vec<String> },
+    /// More warnings of a kind (see `Warning::kind`) occurred than the limit, and were not
+    /// reported.
+    Suppressed { kind: String, count: usize },
+}
+
+impl Warning {
+    /// The name of the kind of warning, for grouping similar warnings.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Warning::UnexpectedFile(_) => "UnexpectedFile",
+            Warning::DuplicateServer { .. } => "DuplicateServer",
+            Warning::ServerNotFound { .. } => "ServerNotFound",
+            Warning::InvalidVersionFile { .. } => "InvalidVersionFile",
+            Warning::OutdatedDatabaseVersion { .. } => "OutdatedDatabaseVersion",
+            Warning::DatabaseNotRegistered(_) => "DatabaseNotRegistered",
+            Warning::UnexpectedStatus { .. } => "UnexpectedStatus",
+            Warning::DeltaFailed { .. } => "DeltaFailed",
+            Warning::DownloaderFailed { .. } => "DownloaderFailed",
+            Warning::OptionalDependencyNotFound { .. } => "OptionalDependencyNotFound",
+            Warning::FileListSyncFailed { .. } => "FileListSyncFailed",
+            Warning::DatabaseLoadFailed { .. } => "DatabaseLoadFailed",
+            Warning::CorruptCachedPackage { .. } => "CorruptCachedPackage",
+            Warning::ForeignArchitecture { .. } => "ForeignArchitecture",
+            Warning::DuplicateLocalPackage { .. } => "DuplicateLocalPackage",
+            Warning::DuplicateSyncPackage { .. } => "DuplicateSyncPackage",
+            Warning::DuplicateDescKey { .. } => "DuplicateDescKey",
+            Warning::DependencyCycle { .. } => "DependencyCycle",
+            Warning::Suppressed { .. } => "Suppressed",
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::UnexpectedFile(path) => write!(
+                f,
+                "unexpected file \"{}\" found in local db directory",
+                path.display()
+            ),
+            Warning::DuplicateServer { url, database } => write!(
+                f,
+                "server with url \"{}\" was already present in database \"{}\"",
+                url, database
+            ),
+            Warning::ServerNotFound { url, database } => write!(
+                f,
+                "server with url \"{}\" was not present in database \"{}\"",
+                url, database
+            ),
+            Warning::InvalidVersionFile { path, contents } => write!(
+                f,
+                "\"{}\" (in \"{}\") is not a valid version",
+                contents,
+                path.display()
+            ),
+            Warning::OutdatedDatabaseVersion { found, expected } => write!(
+                f,
+                "local database version is \"{}\" which is not the latest (\"{}\")",
+                found, expected
+            ),
+            Warning::DatabaseNotRegistered(name) => write!(
+                f,
+                "could not unregister a database with name \"{}\" (not found)",
+                name
+            ),
+            Warning::UnexpectedStatus {
+                url,
+                database,
+                status,
+            } => write!(
+                f,
+                "unexpected code {} from \"{}\" while updating database \"{}\"",
+                status, url, database
+            ),
+            Warning::DeltaFailed { package, reason } => write!(
+                f,
+                "could not use deltas for package \"{}\", downloading the whole package: {}",
+                package, reason
+            ),
+            Warning::DownloaderFailed { package, reason } => write!(
+                f,
+                "could not download package \"{}\" with the configured downloader, using HTTP: {}",
+                package, reason
+            ),
+            Warning::OptionalDependencyNotFound {
+                package,
+                dependency,
+            } => write!(
+                f,
+                "optional dependency \"{}\" of \"{}\" was not found in any database",
+                dependency, package
+            ),
+            Warning::FileListSyncFailed { database, reason } => write!(
+                f,
+                "could not synchronize the file list of database \"{}\": {}",
+                database, reason
+            ),
+            Warning::DatabaseLoadFailed { database, reason } => {
+                write!(f, "could not load database \"{}\": {}", database, reason)
+            }
+            Warning::CorruptCachedPackage { path, reason } => write!(
+                f,
+                "cached package \"{}\" can't be used, it will be downloaded again: {}",
+                path.display(),
+                reason
+            ),
+            Warning::ForeignArchitecture { package, arch } => write!(
+                f,
+                "installed package \"{}\" is built for \"{}\", which is not a configured architecture",
+                package, arch
+            ),
+            Warning::DuplicateLocalPackage { name, versions } => write!(
+                f,
+                "local database has more than one version of \"{}\" installed ({})",
+                name,
+                versions.join(", ")
+            ),
+            Warning::DuplicateSyncPackage {
+                database,
+                name,
+                versions,
+            } => write!(
+                f,
+                "sync database \"{}\" has more than one entry for \"{}\" ({}), using the first",
+                database,
+                name,
+                versions.join(", ")
+            ),
+            Warning::DuplicateDescKey { package, key } => write!(
+                f,
+                "the description of \"{}\" has more than one \"%{}%\" entry",
+                package, key
+            ),
+            Warning::DependencyCycle { packages } => write!(
+                f,
+                "dependency cycle between {}, so they may be installed in the wrong order",
+                packages.join(", ")
+            ),
+            Warning::Suppressed { kind, count } => {
+                write!(f, "and {} more {} warnings", count, kind)
+            }
+        }
+    }
+}
+
+/// How long a window lasts by default.
+pub(crate) const DEFAULT_WARNING_WINDOW: Duration = Duration::from_secs(60);
+
+/// The most different warnings remembered in a window, so memory use stays bounded when nobody
+/// takes the warnings.
+const MAX_SEEN: usize = 4096;
+
+/// Collects warnings until the user asks for them.
+#[derive(Derivative, Default)]
+#[derivative(Debug)]
+pub(crate) struct Diagnostics {
+    /// Warnings that have not yet been taken by the user.
+    warnings: Vec<Warning>,
+    /// The warnings reported in this window, so repeats can be dropped.
+    seen: HashSet<Warning>,
+    /// How many warnings of each kind have been reported in this window.
+    counts: HashMap<&'static str, usize>,
+    /// How many warnings of each kind were over the limit in this window.
+    suppressed: BTreeMap<&'static str, usize>,
+    /// The most warnings of each kind to report, if limited.
+    limit: Option<usize>,
+    /// How long a window lasts, if not `DEFAULT_WARNING_WINDOW`.
+    window: Option<Duration>,
+    /// When the first warning of this window was reported.
+    window_start: Option<Instant>,
+    /// Called with each warning as it happens.
+    #[derivative(Debug = "ignore")]
+    callback: Option<Box<dyn FnMut(&Warning)>>,
+}
+
+impl Diagnostics {
+    /// Set the function to call whenever a warning occurs.
+    pub(crate) fn set_callback(&mut self, callback: Option<Box<dyn FnMut(&Warning)>>) {
+        self.callback = callback;
+    }
+
+    /// Set the most warnings of each kind to report in a window.
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Set how long a window lasts.
+    pub(crate) fn set_window(&mut self, window: Duration) {
+        self.window = Some(window);
+    }
+
+    /// Log a warning, pass it to the callback, and store it, unless it is a repeat or over the
+    /// limit.
+    pub(crate) fn warn(&mut self, warning: Warning) {
+        let window = self.window.unwrap_or(DEFAULT_WARNING_WINDOW);
+        let expired = self
+            .window_start
+            .map_or(false, |start| start.elapsed() >= window);
+        if expired || self.seen.len() >= MAX_SEEN {
+            self.end_window();
+        }
+        if self.window_start.is_none() {
+            self.window_start = Some(Instant::now());
+        }
+        if self.seen.contains(&warning) {
+            log::trace!("repeated warning: {}", warning);
+            return;
+        }
+        let kind = warning.kind();
+        let count = self.counts.entry(kind).or_insert(0);
+        if self.limit.map_or(false, |limit| *count >= limit) {
+            let suppressed = self.suppressed.entry(kind).or_insert(0);
+            if *suppressed == 0 {
+                log::warn!("too many {} warnings, only counting the rest", kind);
+            }
+            *suppressed += 1;
+            return;
+        }
+        *count += 1;
+        log::warn!("{}", warning);
+        if let Some(callback) = self.callback.as_mut() {
+            callback(&warning);
+        }
+        self.seen.insert(warning.clone());
+        self.warnings.push(warning);
+    }
+
+    /// End the window, and remove and return all stored warnings.
+    pub(crate) fn take(&mut self) -> Vec<Warning> {
+        self.end_window();
+        mem::replace(&mut self.warnings, Vec::new())
+    }
+
+    /// Report the number of warnings of each kind that were over the limit like any other
+    /// warning, and forget the warnings reported, so they will be reported again.
+    fn end_window(&mut self) {
+        let suppressed = mem::replace(&mut self.suppressed, BTreeMap::new());
+        for (kind, count) in suppressed {
+            let warning = Warning::Suppressed {
+                kind: kind.to_owned(),
+                count,
+            };
+            log::warn!("{}", warning);
+            if let Some(callback) = self.callback.as_mut() {
+                callback(&warning);
+            }
+            self.warnings.push(warning);
+        }
+        self.seen.clear();
+        self.counts.clear();
+        self.window_start = None;
+    }
+}
+
+impl Drop for Diagnostics {
+    fn drop(&mut self) {
+        // Make sure the logs say how many warnings were left out.
+        self.end_window();
+    }
+}
+
+/// Record a warning against the handle, or just log it if the handle has been dropped.
+pub(crate) fn warn(handle: &Weak<RefCell<Handle>>, warning: Warning) {
+    match handle.upgrade() {
+        Some(handle) => handle.borrow().warn(warning),
+        None => log::warn!("{}", warning),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_and_repeats() {
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.set_limit(Some(2));
+        for idx in 0..5 {
+            diagnostics.warn(Warning::UnexpectedFile(format!("file{}", idx).into()));
+        }
+        diagnostics.warn(Warning::DatabaseNotRegistered("core".into()));
+        diagnostics.warn(Warning::DatabaseNotRegistered("core".into()));
+        assert_eq!(
+            diagnostics.take(),
+            vec![
+                Warning::UnexpectedFile("file0".into()),
+                Warning::UnexpectedFile("file1".into()),
+                Warning::DatabaseNotRegistered("core".into()),
+                Warning::Suppressed {
+                    kind: "UnexpectedFile".into(),
+                    count: 3,
+                },
+            ]
+        );
+
+        // Taking warnings starts counting again.
+        diagnostics.warn(Warning::DatabaseNotRegistered("core".into()));
+        assert_eq!(diagnostics.take().len(), 1);
+    }
+
+    #[test]
+    fn windows_without_taking() {
+        use std::rc::Rc;
+
+        let delivered = Rc::new(RefCell::new(Vec::new()));
+        let mut diagnostics = Diagnostics::default();
+        let to_callback = delivered.clone();
+        diagnostics.set_callback(Some(Box::new(move |warning: &Warning| {
+            to_callback.borrow_mut().push(warning.clone())
+        })));
+        diagnostics.set_limit(Some(1));
+        diagnostics.set_window(Duration::from_secs(0));
+        // Each warning is in a window of its own, so nothing is a repeat or over the limit.
+        for _ in 0..2 {
+            diagnostics.warn(Warning::DatabaseNotRegistered("core".into()));
+        }
+        assert_eq!(delivered.borrow().len(), 2);
+
+        delivered.borrow_mut().clear();
+        diagnostics.set_window(Duration::from_secs(3600));
+        for idx in 0..3 {
+            diagnostics.warn(Warning::UnexpectedFile(format!("file{}", idx).into()));
+        }
+        // The count is delivered when the window ends, here because it has seen too much.
+        diagnostics.set_limit(None);
+        for idx in 0..MAX_SEEN {
+            diagnostics.warn(Warning::DatabaseNotRegistered(format!("db{}", idx)));
+            assert!(diagnostics.seen.len() <= MAX_SEEN);
+        }
+        let suppressed = Warning::Suppressed {
+            kind: "UnexpectedFile".into(),
+            count: 2,
+        };
+        assert!(delivered.borrow().contains(&suppressed));
+        assert_eq!(
+            diagnostics
+                .take()
+                .iter()
+                .filter(|w| **w == suppressed)
+                .count(),
+            1
+        );
+    }
+}
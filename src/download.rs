@@ -0,0 +1,787 @@
+//! Making HTTP requests, optionally as an unprivileged download user.
+//!
+//! When running as root, the HTTP/TLS stack is a large attack surface to expose with full
+//! privileges. If a download user is configured (like `DownloadUser` in `pacman.conf`), each
+//! request is made by `curl` running as that user. The body comes back through a pipe and is
+//! written to the file by this process, so the download user doesn't need access to the
+//! directory the file lives in.
+//!
+//! Requests that fail in a way that may not happen again (a timeout, or a server error) are
+//! retried according to the `RetryPolicy`, waiting longer after each attempt.
+//!
+//! Servers can also be directory mirrors: `file://` urls of a directory laid out like a mirror,
+//! for example an NFS or SMB share on a network without internet access. These are copied
+//! directly, without a download user, and answer like an HTTP server would.
+//!
+//! A download can resume a partial file with a `Range` request, and can be slowed down with a
+//! `Throttle` so it doesn't take all of a slow connection.
+
+use std::{
+    cmp,
+    error::Error as StdError,
+    fs,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use nix::{
+    sys::{
+        stat::utimes,
+        time::{TimeVal, TimeValLike},
+    },
+    unistd::{geteuid, User},
+};
+use reqwest::{
+    header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RANGE},
+    Url,
+};
+
+use crate::{
+    error::{Error, ErrorContext, ErrorKind},
+    Handle,
+};
+
+/// How much to download between progress reports.
+const CHUNK_SIZE: usize = 16 * 1024;
+/// The program that makes requests as the download user.
+const DOWNLOAD_HELPER: &str = "curl";
+/// Exit codes of the download helper for failures that may not happen again: the host couldn't
+/// be resolved or connected to, the request timed out, or the connection failed part way.
+const TRANSIENT_HELPER_EXITS: &[i32] = &[6, 7, 28, 35, 52, 55, 56];
+
+/// How network requests are retried when they time out or the server fails (a 5xx status).
+///
+/// Other failures, like a missing file, are not retried: the next server is tried instead. The
+/// default is 3 attempts, waiting 1 second after the first and doubling each time up to 30
+/// seconds.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// The default policy.
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// A policy that never retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::default().with_max_attempts(1)
+    }
+
+    /// Make at most this many attempts in total (at least 1).
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = cmp::max(max_attempts, 1);
+        self
+    }
+
+    /// Wait `initial` after the first failed attempt, doubling after each one up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// The number of attempts made at most.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// How long to wait after the given (1-based) failed attempt.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        2u32.checked_pow(attempt.saturating_sub(1))
+            .and_then(|factor| self.initial_backoff.checked_mul(factor))
+            .map_or(self.max_backoff, |backoff| {
+                cmp::min(backoff, self.max_backoff)
+            })
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What to do when a download doesn't match what the sync database says it should be, like a
+/// package archive with the wrong size or sha256 checksum, or a truncated database.
+///
+/// Each failure is reported as an `Event::DownloadCorrupt`. By default the corrupt download is
+/// deleted and the next server is tried.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IntegrityPolicy {
+    try_next_server: bool,
+    keep_corrupt: bool,
+}
+
+impl IntegrityPolicy {
+    /// The default policy.
+    pub fn new() -> IntegrityPolicy {
+        IntegrityPolicy::default()
+    }
+
+    /// Whether to try the next server after a corrupt download, or fail straight away.
+    pub fn with_try_next_server(mut self, try_next_server: bool) -> Self {
+        self.try_next_server = try_next_server;
+        self
+    }
+
+    /// Whether to keep corrupt downloads, as `<filename>.corrupt` next to where they would have
+    /// gone, so they can be inspected. Each one replaces the last.
+    pub fn with_keep_corrupt(mut self, keep_corrupt: bool) -> Self {
+        self.keep_corrupt = keep_corrupt;
+        self
+    }
+
+    /// Whether the next server is tried after a corrupt download.
+    pub fn try_next_server(&self) -> bool {
+        self.try_next_server
+    }
+
+    /// Whether corrupt downloads are kept.
+    pub fn keep_corrupt(&self) -> bool {
+        self.keep_corrupt
+    }
+}
+
+impl Default for IntegrityPolicy {
+    fn default() -> Self {
+        IntegrityPolicy {
+            try_next_server: true,
+            keep_corrupt: false,
+        }
+    }
+}
+
+/// Progress of a download.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Progress {
+    /// The server responded successfully, and the body is about to be downloaded.
+    Started { total: Option<u64> },
+    /// Some of the body has been downloaded.
+    Downloaded { downloaded: u64, total: Option<u64> },
+}
+
+/// Limits the rate of a download, by waiting after each chunk until the download is no further
+/// ahead than the rate allows. Use `progress` as (or from) the progress callback.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Throttle {
+    bytes_per_second: u64,
+    started: Instant,
+}
+
+impl Throttle {
+    /// A throttle allowing `bytes_per_second` (at least 1).
+    pub(crate) fn new(bytes_per_second: u64) -> Throttle {
+        Throttle {
+            bytes_per_second: cmp::max(bytes_per_second, 1),
+            started: Instant::now(),
+        }
+    }
+
+    /// Wait if the download is ahead of the rate.
+    pub(crate) fn progress(&mut self, progress: Progress) {
+        match progress {
+            Progress::Started { .. } => self.started = Instant::now(),
+            Progress::Downloaded { downloaded, .. } => {
+                let delay = self.delay(downloaded, self.started.elapsed());
+                if delay > Duration::from_secs(0) {
+                    thread::sleep(delay);
+                }
+            }
+        }
+    }
+
+    /// How long to wait once `downloaded` bytes of the body have taken `elapsed`.
+    fn delay(&self, downloaded: u64, elapsed: Duration) -> Duration {
+        let due = Duration::from_millis(downloaded.saturating_mul(1000) / self.bytes_per_second);
+        due.checked_sub(elapsed).unwrap_or_default()
+    }
+}
+
+/// Validators that make a request conditional, so nothing is downloaded if the file hasn't
+/// changed.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Conditions<'a> {
+    /// Sent as `If-Modified-Since`.
+    pub if_modified_since: Option<&'a str>,
+    /// Sent as `If-None-Match`.
+    pub if_none_match: Option<&'a str>,
+    /// Sent as `Range: bytes=<n>-`, to get the rest of a partial file of this length. The body
+    /// of a `206 Partial Content` response is appended to the file. If the server sends the
+    /// whole file instead, the file is emptied first.
+    pub range_from: Option<u64>,
+}
+
+/// The outcome of a request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Response {
+    /// The HTTP status code. The body is only written for `200 OK` and `206 Partial Content`.
+    pub status: u16,
+    /// The number of bytes written to the file.
+    pub downloaded: u64,
+    /// The `ETag` header, if the server sent one.
+    pub etag: Option<String>,
+    /// The `Last-Modified` header, if the server sent one.
+    pub last_modified: Option<String>,
+}
+
+/// Make a GET request, writing the body to `file` if the response is `200 OK` (or `206 Partial
+/// Content`, for a range request).
+///
+/// The request is made as the download user if there is one and we are root, and is retried
+/// according to the handle's `RetryPolicy`. `file` is emptied before each retry, unless this is
+/// a range request, when the retry asks for the rest of what has been written so far.
+/// `file` should be positioned at its end for a range request.
+pub(crate) fn get(
+    handle: &Handle,
+    url: &Url,
+    conditions: Conditions,
+    file: &mut fs::File,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Response, Error> {
+    let policy = handle.retry_policy;
+    let mut conditions = conditions;
+    let mut attempt = 1;
+    loop {
+        let result = get_once(handle, url, conditions, file, progress);
+        if let Ok(response) = &result {
+            if response.downloaded > 0 {
+                handle.measure(|metrics| metrics.bytes_downloaded(response.downloaded));
+            }
+        }
+        let retry = match &result {
+            Ok(response) => is_server_error(response.status),
+            Err(e) => is_transient(e),
+        };
+        if !retry || attempt >= policy.max_attempts() {
+            return result;
+        }
+        let backoff = policy.backoff(attempt);
+        log::debug!(
+            "attempt {} to get {} failed, retrying in {:?}",
+            attempt,
+            url,
+            backoff
+        );
+        thread::sleep(backoff);
+        if conditions.range_from.is_some() {
+            conditions.range_from = Some(file.seek(SeekFrom::End(0))?);
+        } else {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+        }
+        attempt += 1;
+    }
+}
+
+/// Make a single attempt at a request.
+fn get_once(
+    handle: &Handle,
+    url: &Url,
+    conditions: Conditions,
+    file: &mut fs::File,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Response, Error> {
+    if url.scheme() == "file" {
+        return copy_local(url, conditions, file, progress);
+    }
+    match &handle.download_user {
+        Some(user) if geteuid().is_root() => get_as_user(user, url, conditions, file, progress),
+        _ => fetch(&handle.http_client, url, conditions, file, progress),
+    }
+}
+
+/// Copy a file from a directory mirror.
+///
+/// A missing file is `404 Not Found`, and the file's modification time is its `Last-Modified`
+/// date, so `If-Modified-Since` avoids copying a database that hasn't changed.
+fn copy_local(
+    url: &Url,
+    conditions: Conditions,
+    file: &mut fs::File,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Response, Error> {
+    let path = url.to_file_path().map_err(|()| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("\"{}\" is not a local path", url),
+        )
+    })?;
+    log::debug!("copying {}", path.display());
+    let mut source = match fs::File::open(&path) {
+        Ok(source) => source,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Response {
+                status: 404,
+                downloaded: 0,
+                etag: None,
+                last_modified: None,
+            })
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let md = source.metadata()?;
+    let modified = md.modified()?;
+    let last_modified = Some(httpdate::fmt_http_date(modified));
+    // HTTP dates are only precise to the second.
+    let secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    };
+    let not_modified = conditions
+        .if_modified_since
+        .and_then(|since| httpdate::parse_http_date(since).ok())
+        .map_or(false, |since| secs(modified) <= secs(since));
+    if not_modified {
+        return Ok(Response {
+            status: 304,
+            downloaded: 0,
+            etag: None,
+            last_modified,
+        });
+    }
+
+    let (status, total) = match conditions.range_from {
+        Some(start) if start > 0 && start < md.len() => {
+            source.seek(SeekFrom::Start(start))?;
+            (206, Some(md.len() - start))
+        }
+        Some(start) if start > 0 => {
+            return Ok(Response {
+                status: 416,
+                downloaded: 0,
+                etag: None,
+                last_modified,
+            })
+        }
+        _ => (200, Some(md.len())),
+    };
+    start_body(status, conditions, file)?;
+    progress(Progress::Started { total });
+    let mut buf = [0; CHUNK_SIZE];
+    let mut downloaded = 0;
+    loop {
+        let len = source.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        file.write_all(&buf[..len])?;
+        downloaded += len as u64;
+        progress(Progress::Downloaded { downloaded, total });
+    }
+    Ok(Response {
+        status,
+        downloaded,
+        etag: None,
+        last_modified,
+    })
+}
+
+/// Make the request in this process.
+fn fetch(
+    client: &reqwest::Client,
+    url: &Url,
+    conditions: Conditions,
+    file: &mut fs::File,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Response, Error> {
+    log::debug!("requesting {}", url);
+    let mut request = client.get(url.clone());
+    if let Some(modified) = conditions.if_modified_since {
+        request = request.header(IF_MODIFIED_SINCE, modified);
+    }
+    if let Some(etag) = conditions.if_none_match {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(start) = conditions.range_from {
+        request = request.header(RANGE, format!("bytes={}-", start));
+    }
+    let mut response = request.send().context(ErrorKind::UnexpectedReqwest)?;
+    let status = response.status().as_u16();
+    let etag = header(response.headers(), ETAG);
+    let last_modified = header(response.headers(), LAST_MODIFIED).and_then(valid_http_date);
+    if !has_body(status) {
+        return Ok(Response {
+            status,
+            downloaded: 0,
+            etag,
+            last_modified,
+        });
+    }
+
+    start_body(status, conditions, file)?;
+    let total = response.content_length();
+    progress(Progress::Started { total });
+    let mut buf = [0; CHUNK_SIZE];
+    let mut downloaded = 0;
+    loop {
+        let len = response
+            .read(&mut buf)
+            .context(ErrorKind::UnexpectedReqwest)?;
+        if len == 0 {
+            break;
+        }
+        file.write_all(&buf[..len])?;
+        downloaded += len as u64;
+        progress(Progress::Downloaded { downloaded, total });
+    }
+    Ok(Response {
+        status,
+        downloaded,
+        etag,
+        last_modified,
+    })
+}
+
+/// Whether the body of a response with this status is written to the file.
+fn has_body(status: u16) -> bool {
+    status == 200 || status == 206
+}
+
+/// Get `file` ready for the body of a response: if a range was asked for but the server sent
+/// the whole file, what was there before is thrown away.
+fn start_body(status: u16, conditions: Conditions, file: &mut fs::File) -> io::Result<()> {
+    if status == 200 && conditions.range_from.is_some() {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+    }
+    Ok(())
+}
+
+/// Check a date from a server, and write it in the preferred format.
+///
+/// Servers may use any of the three formats HTTP allows, and some send invalid dates. Sending one
+/// of those back in `If-Modified-Since` could make the server send the whole file every time, so
+/// invalid dates are dropped.
+fn valid_http_date(date: String) -> Option<String> {
+    match httpdate::parse_http_date(&date) {
+        Ok(time) => Some(httpdate::fmt_http_date(time)),
+        Err(_) => {
+            log::debug!("ignoring invalid HTTP date \"{}\"", date);
+            None
+        }
+    }
+}
+
+/// Set the modification time of a downloaded file to the `Last-Modified` date the server sent,
+/// if there was one.
+///
+/// The modification time is sent back as `If-Modified-Since`, so it has to be the server's time,
+/// not the time of the download by the local clock.
+pub(crate) fn set_modified(path: &Path, last_modified: Option<&str>) -> io::Result<()> {
+    let modified = match last_modified.and_then(|date| httpdate::parse_http_date(date).ok()) {
+        Some(modified) => modified,
+        None => return Ok(()),
+    };
+    let secs = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let time = TimeVal::seconds(secs as i64);
+    utimes(path, &time, &time).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+/// The `If-Modified-Since` date for a file downloaded earlier (see `set_modified`).
+pub(crate) fn if_modified_since(path: &Path) -> Option<String> {
+    let modified = fs::metadata(path).and_then(|md| md.modified()).ok()?;
+    Some(httpdate::fmt_http_date(modified))
+}
+
+/// Whether an HTTP status is a server error, which may not happen again.
+fn is_server_error(status: u16) -> bool {
+    (500..600).contains(&status)
+}
+
+/// Whether a failed request timed out, so may succeed if it is tried again.
+fn is_transient(e: &Error) -> bool {
+    let mut source = e.source();
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+            if e.is_timeout() {
+                return true;
+            }
+        } else if let Some(e) = e.downcast_ref::<io::Error>() {
+            if e.kind() == io::ErrorKind::TimedOut {
+                return true;
+            }
+        } else if let Some(e) = e.downcast_ref::<HelperError>() {
+            return e.transient;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Get a header as a string, if it is present and valid.
+fn header(headers: &HeaderMap, name: reqwest::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Make the request with `DOWNLOAD_HELPER`, running as `user`.
+///
+/// The helper is a new process rather than a fork of this one: we may already have threads (the
+/// HTTP client starts one), and a forked child of a threaded process can't safely allocate or
+/// use TLS. The helper writes the response headers to its standard error, and the body to its
+/// standard output, which we copy into `file`.
+fn get_as_user(
+    user: &User,
+    url: &Url,
+    conditions: Conditions,
+    file: &mut fs::File,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Response, Error> {
+    let failed = || ErrorKind::DownloadUserFailed(user.name.clone());
+    log::debug!("requesting {} as user {}", url, user.name);
+    let mut command = Command::new(DOWNLOAD_HELPER);
+    // `--disable` must come first; it stops curl reading a config file.
+    command.args(&[
+        "--disable",
+        "--silent",
+        "--show-error",
+        "--location",
+        "--proto",
+        "=http,https",
+        "--dump-header",
+        "/dev/stderr",
+    ]);
+    if let Some(modified) = conditions.if_modified_since {
+        command
+            .arg("--header")
+            .arg(format!("If-Modified-Since: {}", modified));
+    }
+    if let Some(etag) = conditions.if_none_match {
+        command
+            .arg("--header")
+            .arg(format!("If-None-Match: {}", etag));
+    }
+    if let Some(start) = conditions.range_from {
+        command
+            .arg("--header")
+            .arg(format!("Range: bytes={}-", start));
+    }
+    // Setting the user also drops the supplementary groups.
+    let mut child = command
+        .arg(url.as_str())
+        .uid(user.uid.as_raw())
+        .gid(user.gid.as_raw())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(failed())?;
+    let (mut body, mut stderr) = match (child.stdout.take(), child.stderr.take()) {
+        (Some(body), Some(stderr)) => (body, BufReader::new(stderr)),
+        _ => return Err(failed().into()),
+    };
+    let mut messages = String::new();
+    let headers = read_headers(&mut stderr, &mut messages).context(failed())?;
+    let mut downloaded = 0;
+    match &headers {
+        Some(headers) if has_body(headers.status) => {
+            start_body(headers.status, conditions, file)?;
+            let total = headers.content_length;
+            progress(Progress::Started { total });
+            let mut buf = [0; CHUNK_SIZE];
+            loop {
+                let len = body.read(&mut buf).context(failed())?;
+                if len == 0 {
+                    break;
+                }
+                file.write_all(&buf[..len])?;
+                downloaded += len as u64;
+                progress(Progress::Downloaded { downloaded, total });
+            }
+        }
+        _ => {
+            io::copy(&mut body, &mut io::sink()).context(failed())?;
+        }
+    }
+    let mut rest = Vec::new();
+    stderr.read_to_end(&mut rest).context(failed())?;
+    messages.push_str(&String::from_utf8_lossy(&rest));
+    let status = child.wait().context(failed())?;
+    if !status.success() {
+        let transient = status
+            .code()
+            .map_or(false, |code| TRANSIENT_HELPER_EXITS.contains(&code));
+        let message = messages.trim().to_owned();
+        return Err(Error::from(failed()).with_source(HelperError { message, transient }));
+    }
+    match headers {
+        Some(headers) => Ok(Response {
+            status: headers.status,
+            downloaded,
+            etag: headers.etag,
+            last_modified: headers.last_modified.and_then(valid_http_date),
+        }),
+        None => Err(Error::from(failed()).with_source(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the download helper gave no response",
+        ))),
+    }
+}
+
+/// The headers of the final response, after following redirects.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+struct Headers {
+    status: u16,
+    content_length: Option<u64>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    location: bool,
+}
+
+/// Read response headers as written by `curl --dump-header`, until the headers of a response
+/// that isn't followed by another (a redirect or an informational response).
+///
+/// Lines that aren't part of a response, like error messages, are added to `messages`. Returns
+/// `None` if the output ends before a final response.
+fn read_headers(reader: &mut impl BufRead, messages: &mut String) -> io::Result<Option<Headers>> {
+    let mut current: Option<Headers> = None;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end();
+        if text.starts_with("HTTP/") {
+            let status = text.split_whitespace().nth(1).and_then(|s| s.parse().ok());
+            current = Some(Headers {
+                status: status.unwrap_or(0),
+                ..Headers::default()
+            });
+            continue;
+        }
+        let headers = match &mut current {
+            Some(headers) => headers,
+            None => {
+                messages.push_str(text);
+                messages.push('\n');
+                continue;
+            }
+        };
+        if text.is_empty() {
+            let followed = (100..200).contains(&headers.status)
+                || ((300..400).contains(&headers.status) && headers.location);
+            if followed {
+                current = None;
+                continue;
+            }
+            return Ok(current);
+        }
+        let (name, value) = match text.find(':') {
+            Some(idx) => (&text[..idx], text[idx + 1..].trim()),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            headers.content_length = value.parse().ok();
+        } else if name.eq_ignore_ascii_case("etag") {
+            headers.etag = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("last-modified") {
+            headers.last_modified = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("location") {
+            headers.location = true;
+        }
+    }
+}
+
+/// An error reported by the download helper, which we only have the message of.
+#[derive(Debug)]
+struct HelperError {
+    message: String,
+    /// Whether the request may succeed if it is tried again (see `is_transient`).
+    transient: bool,
+}
+
+impl std::fmt::Display for HelperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdError for HelperError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_delay() {
+        let throttle = Throttle::new(1000);
+        assert_eq!(
+            throttle.delay(500, Duration::from_millis(100)),
+            Duration::from_millis(400)
+        );
+        assert_eq!(
+            throttle.delay(500, Duration::from_secs(1)),
+            Duration::from_secs(0)
+        );
+        assert_eq!(
+            Throttle::new(0).delay(1, Duration::from_secs(0)),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn http_dates() {
+        let preferred = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(valid_http_date(preferred.into()).unwrap(), preferred);
+        assert_eq!(
+            valid_http_date("Sunday, 06-Nov-94 08:49:37 GMT".into()).unwrap(),
+            preferred
+        );
+        assert_eq!(
+            valid_http_date("Sun Nov  6 08:49:37 1994".into()).unwrap(),
+            preferred
+        );
+        assert_eq!(valid_http_date("yesterday".into()), None);
+    }
+
+    #[test]
+    fn helper_headers() {
+        let output = "HTTP/1.1 301 Moved Permanently\r\nLocation: https://b/core.db\r\n\r\n\
+                      HTTP/2 200\r\ncontent-length: 12\r\nETag: \"abc\"\r\n\
+                      Last-Modified: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n";
+        let mut messages = String::new();
+        let headers = read_headers(&mut output.as_bytes(), &mut messages).unwrap();
+        assert_eq!(
+            headers,
+            Some(Headers {
+                status: 200,
+                content_length: Some(12),
+                etag: Some("\"abc\"".into()),
+                last_modified: Some("Sun, 06 Nov 1994 08:49:37 GMT".into()),
+                location: false,
+            })
+        );
+
+        let output = "HTTP/1.1 304 Not Modified\r\n\r\n";
+        let headers = read_headers(&mut output.as_bytes(), &mut messages).unwrap();
+        assert_eq!(headers.map(|h| h.status), Some(304));
+
+        let output = "curl: (6) Could not resolve host: a\n";
+        assert_eq!(
+            read_headers(&mut output.as_bytes(), &mut messages).unwrap(),
+            None
+        );
+        assert_eq!(messages, "curl: (6) Could not resolve host: a\n");
+    }
+}
@@ -0,0 +1,210 @@
+//! Downloading package archives with other transports, like BitTorrent or IPFS.
+//!
+//! A `Downloader` given to `AlpmBuilder::with_downloader` is asked for each package archive
+//! before the built-in HTTP client. It gets what the sync database says about the archive (its
+//! size and checksums) as well as the mirror urls, and whatever it writes is checked with
+//! `SyncPackage::verify_archive` before it is used. If it fails, or the archive doesn't match, a
+//! warning is recorded and the built-in client downloads the package instead.
+//!
+//! `HttpDownloader`, behind the `http-downloader` feature, is a reference implementation.
+
+use std::{fmt, io, path::Path};
+
+use derivative::Derivative;
+use reqwest::Url;
+
+use crate::{db::SyncPackage, Handle};
+
+/// A package archive to download.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct DownloadRequest<'a> {
+    database: &'a str,
+    package: &'a SyncPackage,
+    urls: Vec<Url>,
+    /// The instance downloading the package, for the built-in client's settings.
+    #[derivative(Debug = "ignore")]
+    handle: &'a Handle,
+}
+
+impl<'a> DownloadRequest<'a> {
+    pub(crate) fn new(
+        database: &'a str,
+        package: &'a SyncPackage,
+        urls: Vec<Url>,
+        handle: &'a Handle,
+    ) -> DownloadRequest<'a> {
+        DownloadRequest {
+            database,
+            package,
+            urls,
+            handle,
+        }
+    }
+
+    /// The name of the sync database the package is from.
+    pub fn database(&self) -> &str {
+        self.database
+    }
+
+    /// The package, as described by the sync database.
+    pub fn package(&self) -> &SyncPackage {
+        self.package
+    }
+
+    /// The urls of the archive on the database's servers, in the order the built-in client would
+    /// try them.
+    pub fn urls(&self) -> &[Url] {
+        &self.urls
+    }
+
+    /// The filename of the archive.
+    pub fn filename(&self) -> &str {
+        self.package.filename()
+    }
+
+    /// The size of the archive in bytes.
+    pub fn size(&self) -> u64 {
+        self.package.compressed_size()
+    }
+
+    /// The sha256 checksum of the archive in hex, if the database has one.
+    pub fn sha256sum(&self) -> Option<&str> {
+        self.package.sha256sum()
+    }
+
+    /// The md5 checksum of the archive in hex, if the database has one.
+    pub fn md5sum(&self) -> Option<&str> {
+        self.package.md5sum()
+    }
+}
+
+/// Something that can download package archives.
+pub trait Downloader: fmt::Debug {
+    /// Download the archive to `dest`, which may already exist and should be overwritten.
+    ///
+    /// Return `Ok(false)` to leave the package to the built-in HTTP client, for example if this
+    /// transport doesn't have it.
+    fn download(&self, request: &DownloadRequest, dest: &Path) -> io::Result<bool>;
+}
+
+#[cfg(feature = "http-downloader")]
+pub use self::http::HttpDownloader;
+
+#[cfg(feature = "http-downloader")]
+mod http {
+    use std::{fs, io, path::Path};
+
+    use derivative::Derivative;
+    use reqwest::Url;
+
+    use super::{DownloadRequest, Downloader};
+    use crate::{
+        download::{self, Conditions},
+        hash, util,
+    };
+
+    /// Downloads over HTTP, trying extra mirrors before the database's servers.
+    ///
+    /// Requests go through the built-in client, so they are retried according to the
+    /// `RetryPolicy` and made as the download user, like any other download. Each download is
+    /// checked against the size and sha256 checksum from the database, and the next url is tried
+    /// if it doesn't match.
+    #[derive(Derivative)]
+    #[derivative(Debug)]
+    pub struct HttpDownloader {
+        mirrors: Vec<Url>,
+        #[derivative(Debug = "ignore")]
+        on_fallback: Option<Box<dyn Fn(&Url, &io::Error)>>,
+    }
+
+    impl HttpDownloader {
+        /// A downloader using only the database's servers.
+        pub fn new() -> HttpDownloader {
+            HttpDownloader {
+                mirrors: Vec::new(),
+                on_fallback: None,
+            }
+        }
+
+        /// Try this mirror, like a caching proxy on the local network, before the database's
+        /// servers.
+        ///
+        /// The archive's filename is added to the end of the url's path.
+        pub fn with_mirror(mut self, mirror: Url) -> Self {
+            self.mirrors.push(mirror);
+            self
+        }
+
+        /// Call `on_fallback` with the url and the error whenever a url fails.
+        pub fn with_on_fallback(
+            mut self,
+            on_fallback: impl Fn(&Url, &io::Error) + 'static,
+        ) -> Self {
+            self.on_fallback = Some(Box::new(on_fallback));
+            self
+        }
+
+        /// Download from one url, checking the archive matches the request.
+        fn fetch(&self, url: &Url, request: &DownloadRequest, dest: &Path) -> io::Result<()> {
+            let failed = |message: String| io::Error::new(io::ErrorKind::Other, message);
+            log::debug!("downloading {}", url);
+            let response = download::get(
+                request.handle,
+                url,
+                Conditions::default(),
+                &mut fs::File::create(dest)?,
+                &mut |_| (),
+            )
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            if response.status != 200 {
+                return Err(failed(format!("HTTP status {}", response.status)));
+            }
+            let size = response.downloaded;
+            if size != request.size() {
+                return Err(failed(format!(
+                    "downloaded {} bytes, expected {}",
+                    size,
+                    request.size()
+                )));
+            }
+            if let Some(expected) = request.sha256sum() {
+                if !hash::sha256_file(dest)?.eq_ignore_ascii_case(expected) {
+                    return Err(failed("the sha256 checksum is different".to_owned()));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Default for HttpDownloader {
+        fn default() -> Self {
+            HttpDownloader::new()
+        }
+    }
+
+    impl Downloader for HttpDownloader {
+        fn download(&self, request: &DownloadRequest, dest: &Path) -> io::Result<bool> {
+            let mirrors = self
+                .mirrors
+                .iter()
+                .filter_map(|mirror| util::join_url(mirror, request.filename()));
+            let mut last_error = None;
+            for url in mirrors.chain(request.urls().iter().cloned()) {
+                match self.fetch(&url, request, dest) {
+                    Ok(()) => return Ok(true),
+                    Err(e) => {
+                        if let Some(on_fallback) = &self.on_fallback {
+                            on_fallback(&url, &e);
+                        }
+                        last_error = Some(e);
+                    }
+                }
+            }
+            match last_error {
+                Some(e) => Err(e),
+                None => Ok(false),
+            }
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use mtree;
 use std::{error::Error as StdError, fmt, io, path::PathBuf};
 
+use crate::db::Compression;
+
 /// The different kinds of error that can occur in this library.
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum ErrorKind {
@@ -15,6 +17,12 @@ pub enum ErrorKind {
     /// Indicates that the specified sync database directory is not valid, either because it is
     /// inaccessible, or because it is not a directory.
     BadSyncDatabasePath(PathBuf),
+    /// A cache directory is not a directory we can create files in.
+    BadCacheDirectory(PathBuf),
+    /// Several of the options given to `AlpmBuilder` were invalid.
+    ///
+    /// The source of the error is an `Errors` with the full error for each problem.
+    InvalidConfiguration(Vec<ErrorKind>),
     /// Indicates a general error creating lockfile, for example due to permissions.
     CannotAcquireLock(PathBuf),
     /// Indicates there was a lockfile already present.
@@ -29,17 +37,125 @@ pub enum ErrorKind {
     DatabaseAlreadyExists(String),
     /// Cannot find a database with the given name.
     DatabaseNotFound(String),
+    /// A database file is locked by another process, and we were not willing to wait for it.
+    DatabaseBusy(PathBuf),
+    /// The instance was opened read-only, so it can't make changes.
+    ReadOnly,
     /// There was an unexpected error when creating a database.
     CannotCreateDatabase(String),
     /// Could not query database on the filesystem.
     CannotQueryDatabase(String),
+    /// None of the servers for a database gave a valid copy of it.
+    DatabaseSyncFailed(String),
+    /// The format of the database file at the given path was not recognised.
+    UnknownDatabaseFormat(PathBuf),
+    /// The database file at the given path uses a compression this library can't read.
+    ///
+    /// Support for some compressions is enabled with crate features.
+    UnsupportedCompression {
+        path: PathBuf,
+        compression: Compression,
+    },
     /// Failed to add server with given url to database.
     CannotAddServerToDatabase {
         url: String,
         database: String,
     },
+    /// A url for a package could not be made from the server url and the package filename.
+    InvalidPackageUrl {
+        server: String,
+        filename: String,
+    },
     InvalidLocalPackage(String),
     InvalidSyncPackage(String),
+    /// A delta listed for a package could not be understood.
+    InvalidDelta(String),
+    /// xdelta3 could not apply a delta.
+    CannotApplyDelta(PathBuf),
+    /// A downloaded delta is not the one the sync database describes.
+    DeltaMismatch {
+        path: PathBuf,
+        reason: String,
+    },
+    /// None of the servers for a database could provide a file.
+    DownloadFailed(String),
+    /// The download user does not exist.
+    UnknownDownloadUser(String),
+    /// Something went wrong downloading as the download user.
+    DownloadUserFailed(String),
+    /// The extraction sandbox could not be set up (or the `sandbox` feature is disabled).
+    SandboxUnavailable,
+    /// An entry in a package archive would be extracted outside of the root.
+    UnsafeArchivePath {
+        archive: PathBuf,
+        entry: PathBuf,
+    },
+    /// A package archive could not be extracted.
+    ExtractionFailed(PathBuf),
+    /// The file list of a sync database has not been downloaded.
+    FileListMissing(String),
+    /// The file of sync metadata for a database could not be read or written.
+    InvalidSyncMetadata(PathBuf),
+    /// A dependency or provision of a package could not be parsed.
+    InvalidDependency(String),
+    /// A target given by the user could not be parsed.
+    InvalidTarget(String),
+    /// No package or group matches a target.
+    TargetNotFound(String),
+    /// Plans can't yet be made from this kind of target.
+    UnsupportedTarget(String),
+    /// Removing a package would remove or break a held package.
+    PackageHeld {
+        package: String,
+        held: String,
+    },
+    /// No package satisfies a dependency of a package being installed.
+    UnsatisfiedDependency {
+        package: String,
+        dependency: String,
+    },
+    /// Two packages that conflict would both be installed.
+    PackageConflict {
+        package: String,
+        conflict: String,
+    },
+    /// A scriptlet or hook command could not be run, or failed.
+    ScriptFailed(String),
+    /// No cache directory has a valid archive of the package.
+    PackageNotCached(String),
+    /// A package archive is not the package the sync database describes.
+    PackageArchiveMismatch {
+        path: PathBuf,
+        reason: String,
+    },
+    /// No keyring with this name is installed.
+    KeyringNotFound(String),
+    /// Running gpg to manage the keyring failed.
+    GpgFailed(String),
+    /// A key fingerprint was not a string of hex digits.
+    InvalidFingerprint(String),
+    /// The local database has more than one version of a package installed.
+    DuplicateLocalPackage {
+        name: String,
+        versions: Vec<String>,
+    },
+    /// A package is built for an architecture that isn't configured.
+    ForeignArchitecture {
+        package: String,
+        arch: String,
+    },
+    /// A hook file could not be read or parsed.
+    InvalidHook {
+        path: PathBuf,
+        reason: String,
+    },
+    /// An entry in a sync database archive did not have the expected layout.
+    MalformedSyncDatabase {
+        database: String,
+        entry: PathBuf,
+    },
+    /// A package name was not valid utf8.
+    NonUtf8PackageName(PathBuf),
     /// There was an error when getting/updating the database version.
     DatabaseVersion(String),
     /// Error configuring gpg.
@@ -67,17 +183,54 @@ impl fmt::Display for ErrorKind {
             ErrorKind::BadDatabasePath(path) => write!(f, "The database path \"{}\" does not point to a valid directory on the system.", path.display()),
             ErrorKind::BadSyncDatabaseExt(ext) => write!(f, "\"{}\" is not a valid database extension.", ext),
             ErrorKind::BadSyncDatabasePath(path) => write!(f, "The sync database path \"{}\" does not point to a valid directory on the system.", path.display()),
+            ErrorKind::BadCacheDirectory(path) => write!(f, "The cache directory \"{}\" is not a writable directory.", path.display()),
+            ErrorKind::InvalidConfiguration(problems) => { write!(f, "The configuration has {} problems:", problems.len())?; for problem in problems { write!(f, " {}", problem)?; } Ok(()) },
             ErrorKind::CannotAcquireLock(path) => write!(f, "Cannot create the lockfile at \"{}\"", path.display()),
             ErrorKind::LockAlreadyExists(path) => write!(f, "Lockfile at \"{}\" already exists - you may delete it if you are certain no other instance is running", path.display()),
             ErrorKind::CannotReleaseLock(path) => write!(f, "Cannot release (remove) the lockfile at \"{}\"", path.display()),
             ErrorKind::InvalidDatabaseName(name) => write!(f, "Cannot use \"{}\" as a database name - it is not a valid directory name", name),
             ErrorKind::DatabaseAlreadyExists(name) => write!(f, "Database with name \"{}\" already exists", name),
+            ErrorKind::DatabaseBusy(path) => write!(f, "The database at \"{}\" is locked by another process", path.display()),
+            ErrorKind::ReadOnly => write!(f, "this alpm instance was opened read-only, so it can't make changes"),
             ErrorKind::DatabaseNotFound(name) => write!(f, "Cannot find database with name \"{}\"", name),
             ErrorKind::CannotCreateDatabase(name) => write!(f, "Could not create database \"{}\" on the filesystem.", name),
             ErrorKind::CannotQueryDatabase(name) => write!(f, "Could not query database \"{}\" on the filesystem.", name),
+            ErrorKind::DatabaseSyncFailed(name) => write!(f, "Could not get a valid copy of database \"{}\" from any server.", name),
+            ErrorKind::UnknownDatabaseFormat(path) => write!(f, "The format of the database at \"{}\" was not recognised.", path.display()),
+            ErrorKind::UnsupportedCompression { path, compression } => write!(f, "The database at \"{}\" uses {} compression, which is not supported.", path.display(), compression),
             ErrorKind::CannotAddServerToDatabase { url, database } => write!(f, "Cannot add server with url \"{}\" to database \"{}\".", url, database),
+            ErrorKind::InvalidPackageUrl { server, filename } => write!(f, "Cannot make a url for package file \"{}\" on server \"{}\".", filename, server),
             ErrorKind::InvalidLocalPackage(name) => write!(f, "A package (\"{}\") in the local database was invalid", name),
             ErrorKind::InvalidSyncPackage(name) => write!(f, "A package (\"{}\") in a sync database was invalid", name),
+            ErrorKind::InvalidDelta(line) => write!(f, "The delta \"{}\" is not of the form \"filename md5sum size from_version to_version\"", line),
+            ErrorKind::CannotApplyDelta(path) => write!(f, "Could not apply the delta at \"{}\" using xdelta3", path.display()),
+            ErrorKind::DeltaMismatch { path, reason } => write!(f, "The delta \"{}\" does not match its sync database: {}", path.display(), reason),
+            ErrorKind::DownloadFailed(filename) => write!(f, "Could not download \"{}\" from any server", filename),
+            ErrorKind::UnknownDownloadUser(name) => write!(f, "The download user \"{}\" does not exist", name),
+            ErrorKind::DownloadUserFailed(name) => write!(f, "Could not download as user \"{}\"", name),
+            ErrorKind::SandboxUnavailable => write!(f, "The extraction sandbox is not available on this system"),
+            ErrorKind::UnsafeArchivePath { archive, entry } => write!(f, "The entry \"{}\" in \"{}\" would be extracted outside of the root", entry.display(), archive.display()),
+            ErrorKind::FileListMissing(name) => write!(f, "The file list for database \"{}\" has not been downloaded", name),
+            ErrorKind::InvalidSyncMetadata(path) => write!(f, "The sync metadata in \"{}\" is not valid", path.display()),
+            ErrorKind::InvalidDependency(dependency) => write!(f, "\"{}\" is not a valid dependency", dependency),
+            ErrorKind::InvalidTarget(target) => write!(f, "\"{}\" is not a valid target", target),
+            ErrorKind::TargetNotFound(target) => write!(f, "No package or group matches the target \"{}\"", target),
+            ErrorKind::UnsupportedTarget(target) => write!(f, "Package archives (\"{}\") cannot be planned yet", target),
+            ErrorKind::PackageHeld { package, held } => if package == held { write!(f, "The package \"{}\" is held and cannot be removed", package) } else { write!(f, "The package \"{}\" cannot be removed because the held package \"{}\" depends on it", package, held) },
+            ErrorKind::UnsatisfiedDependency { package, dependency } => write!(f, "Nothing satisfies the dependency \"{}\" of \"{}\"", dependency, package),
+            ErrorKind::PackageConflict { package, conflict } => write!(f, "The package \"{}\" conflicts with \"{}\"", package, conflict),
+            ErrorKind::ExtractionFailed(path) => write!(f, "Could not extract the package \"{}\"", path.display()),
+            ErrorKind::ScriptFailed(command) => write!(f, "The command \"{}\" failed", command),
+            ErrorKind::PackageNotCached(package) => write!(f, "No cache directory has a valid archive of the package \"{}\"", package),
+            ErrorKind::PackageArchiveMismatch { path, reason } => write!(f, "The package archive \"{}\" does not match its sync database: {}", path.display(), reason),
+            ErrorKind::KeyringNotFound(name) => write!(f, "The keyring \"{}\" is not installed", name),
+            ErrorKind::GpgFailed(command) => write!(f, "The gpg command \"{}\" failed", command),
+            ErrorKind::InvalidFingerprint(fingerprint) => write!(f, "\"{}\" is not a valid key fingerprint", fingerprint),
+            ErrorKind::DuplicateLocalPackage { name, versions } => write!(f, "The local database has more than one version of \"{}\" installed ({})", name, versions.join(", ")),
+            ErrorKind::ForeignArchitecture { package, arch } => write!(f, "The package \"{}\" is built for \"{}\", which is not a configured architecture", package, arch),
+            ErrorKind::InvalidHook { path, reason } => write!(f, "The hook \"{}\" is not valid: {}", path.display(), reason),
+            ErrorKind::MalformedSyncDatabase { database, entry } => write!(f, "The entry \"{}\" in sync database \"{}\" was not in the expected place.", entry.display(), database),
+            ErrorKind::NonUtf8PackageName(path) => write!(f, "The package name in \"{}\" is not valid utf8, which is not yet supported.", path.display()),
             ErrorKind::DatabaseVersion(name) => write!(f, "there was an unexpected error getting/updating the version for database \"{}\"", name),
             ErrorKind::Gpgme => write!(f, "there was an error configuring gpgme"),
             ErrorKind::SignatureMissing => write!(f, "a signature was missing"),
@@ -174,6 +327,27 @@ impl From<mtree::Error> for Error {
     }
 }
 
+/// Several errors that happened together, like all the problems with a configuration.
+#[derive(Debug)]
+pub struct Errors(pub Vec<Error>);
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, error) in self.0.iter().enumerate() {
+            if idx > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{}", error)?;
+            if let Some(source) = error.source() {
+                write!(f, " ({})", source)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StdError for Errors {}
+
 /// Helper trait to help working with `Result<T, Error>` where `Error` is our error.
 pub trait ErrorContext<T, E>
 where
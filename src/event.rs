@@ -0,0 +1,75 @@
+//! Progress of long-running operations.
+//!
+//! Operations like synchronizing databases can take a while, so they report what they are doing
+//! as `Event`s. Register a callback with `AlpmBuilder::with_event_callback` or
+//! `Alpm::set_event_callback` to receive them, for example to show a progress bar.
+
+use derivative::Derivative;
+
+/// Something that happened during an operation.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Event {
+    /// Started downloading a sync database.
+    DatabaseDownloadStarted {
+        database: String,
+        url: String,
+        /// The size of the download in bytes, if the server told us.
+        total: Option<u64>,
+    },
+    /// Some more of a sync database has been downloaded.
+    DatabaseDownloadProgress {
+        database: String,
+        /// The number of bytes downloaded so far.
+        downloaded: u64,
+        /// The size of the download in bytes, if the server told us.
+        total: Option<u64>,
+    },
+    /// Finished downloading a sync database.
+    DatabaseDownloadFinished {
+        database: String,
+        /// The number of bytes downloaded.
+        downloaded: u64,
+    },
+    /// A download didn't match what the sync database says it should be, so it wasn't used. What
+    /// happens next depends on the `IntegrityPolicy`.
+    DownloadCorrupt {
+        database: String,
+        /// Where the download came from, which identifies the mirror.
+        url: String,
+        /// The name of the downloaded file.
+        filename: String,
+        /// Why the download was rejected.
+        reason: String,
+    },
+    /// Installed packages that were in a sync database are no longer there after synchronizing
+    /// it, for example because they were dropped to the AUR. See `Alpm::dropped_packages`.
+    PackagesDropped {
+        database: String,
+        /// The names of the installed packages, sorted.
+        packages: Vec<String>,
+    },
+}
+
+/// Passes events on to the user's callback, if there is one.
+#[derive(Derivative, Default)]
+#[derivative(Debug)]
+pub(crate) struct Events {
+    /// Called with each event as it happens.
+    #[derivative(Debug = "ignore")]
+    callback: Option<Box<dyn FnMut(&Event)>>,
+}
+
+impl Events {
+    /// Set the function to call whenever an event occurs.
+    pub(crate) fn set_callback(&mut self, callback: Option<Box<dyn FnMut(&Event)>>) {
+        self.callback = callback;
+    }
+
+    /// Pass an event to the callback.
+    pub(crate) fn emit(&mut self, event: Event) {
+        log::trace!("{:?}", event);
+        if let Some(callback) = self.callback.as_mut() {
+            callback(&event);
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! Unpacking package archives onto the system.
+
+use std::{
+    collections::BTreeSet,
+    env,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use tar::EntryType;
+
+use crate::{
+    db::open_archive,
+    error::{Error, ErrorContext, ErrorKind},
+    sandbox,
+};
+
+/// The program that extracts a package in a sandbox (see `helper_main`).
+pub(crate) const EXTRACTION_HELPER: &str = "alpm-extract";
+
+/// Files in a package archive that describe the package, rather than being installed.
+const METADATA_FILES: &[&str] = &[".PKGINFO", ".MTREE", ".BUILDINFO", ".INSTALL", ".CHANGELOG"];
+
+/// Unpack the files of a package archive under `root`.
+///
+/// If a `helper` is given, this happens in that program, which can only write beneath `root`.
+pub(crate) fn extract_package(
+    archive: &Path,
+    root: &Path,
+    helper: Option<&Path>,
+) -> Result<(), Error> {
+    log::debug!(
+        "extracting \"{}\" to \"{}\"{}",
+        archive.display(),
+        root.display(),
+        if helper.is_some() { " (sandboxed)" } else { "" }
+    );
+    match helper {
+        Some(helper) => in_sandbox(archive, root, helper),
+        None => unpack(archive, root),
+    }
+}
+
+/// Unpack the archive in this process.
+///
+/// The whole archive is checked before anything is written, so a bad archive doesn't leave a
+/// partly installed package behind.
+fn unpack(archive_path: &Path, root: &Path) -> Result<(), Error> {
+    validate(archive_path)?;
+    let (mut archive, _) = open_archive(archive_path)?;
+    archive.set_preserve_permissions(true);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if METADATA_FILES.iter().any(|file| path == Path::new(file)) {
+            continue;
+        }
+        // `unpack_in` also refuses paths containing `..`, and tells us by returning false.
+        if !entry.unpack_in(root)? {
+            return Err(ErrorKind::UnsafeArchivePath {
+                archive: archive_path.to_owned(),
+                entry: path,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// The paths of the files, symlinks and hard links an archive would write, relative to the root.
+///
+/// Directories are left out, since packages share them.
+pub(crate) fn written_paths(archive_path: &Path) -> Result<BTreeSet<PathBuf>, Error> {
+    let (mut archive, _) = open_archive(archive_path)?;
+    let mut paths = BTreeSet::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = normalize(&entry.path()?);
+        if entry.header().entry_type() == EntryType::Directory
+            || METADATA_FILES.iter().any(|file| path == Path::new(file))
+        {
+            continue;
+        }
+        paths.insert(path);
+    }
+    Ok(paths)
+}
+
+/// Split packages into groups to extract one after the other, so no two packages in a group write
+/// the same path, given the paths each writes (see `written_paths`).
+///
+/// A package that writes a path an earlier package also writes is in a later group, so the one
+/// later in the plan is extracted last, as it would be if they were extracted in order.
+pub(crate) fn without_overlaps(paths: &[BTreeSet<PathBuf>]) -> Vec<Vec<usize>> {
+    let mut group_of: Vec<usize> = Vec::with_capacity(paths.len());
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (idx, written) in paths.iter().enumerate() {
+        let group = paths[..idx]
+            .iter()
+            .zip(&group_of)
+            .filter(|(earlier, _)| !earlier.is_disjoint(written))
+            .map(|(_, &group)| group + 1)
+            .max()
+            .unwrap_or(0);
+        group_of.push(group);
+        if group == groups.len() {
+            groups.push(Vec::new());
+        }
+        groups[group].push(idx);
+    }
+    groups
+}
+
+/// Check that every entry of the archive stays beneath the root.
+///
+/// Rejects absolute paths, `..` components, hard links to anything outside the root, and
+/// entries that would be written, or hard links that would be resolved, through a symlink from
+/// earlier in the archive.
+fn validate(archive_path: &Path) -> Result<(), Error> {
+    let (mut archive, _) = open_archive(archive_path)?;
+    let mut symlinks = BTreeSet::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let raw_path = entry.path()?;
+        let unsafe_path = || {
+            Error::from(ErrorKind::UnsafeArchivePath {
+                archive: archive_path.to_owned(),
+                entry: raw_path.to_path_buf(),
+            })
+        };
+        if !is_contained(&raw_path) {
+            return Err(unsafe_path());
+        }
+        let through_symlink =
+            |path: &Path| path.ancestors().skip(1).any(|dir| symlinks.contains(dir));
+        let path = normalize(&raw_path);
+        if through_symlink(&path) {
+            return Err(unsafe_path());
+        }
+        match entry.header().entry_type() {
+            EntryType::Link => match entry.link_name()? {
+                Some(ref target)
+                    if is_contained(target) && !through_symlink(&normalize(target)) => {}
+                _ => return Err(unsafe_path()),
+            },
+            EntryType::Symlink => {
+                symlinks.insert(path);
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Whether a relative path stays beneath the directory it is relative to.
+fn is_contained(path: &Path) -> bool {
+    path.components().all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        Component::ParentDir | Component::RootDir | Component::Prefix(_) => false,
+    })
+}
+
+/// Remove any `.` components, so paths can be compared.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+/// Unpack the archive by running the sandboxed extraction helper.
+///
+/// The helper is a new process rather than a fork of this one, since a forked child of a process
+/// with threads can't safely allocate, which unpacking needs to do.
+fn in_sandbox(archive: &Path, root: &Path, helper: &Path) -> Result<(), Error> {
+    let failed = || ErrorKind::ExtractionFailed(archive.to_owned());
+    let output = Command::new(helper)
+        .arg(root)
+        .arg(archive)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context(failed())?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let mut message = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    if message.is_empty() {
+        message = format!("extraction helper ended unexpectedly: {}", output.status);
+    }
+    Err(Error::from(failed()).with_source(message))
+}
+
+/// The body of the extraction helper: unpack the archive given as the second argument beneath the
+/// root given as the first, after restricting this process to writing beneath the root.
+///
+/// Errors are written to standard error. Returns the exit code.
+pub(crate) fn helper_main() -> i32 {
+    let args: Vec<PathBuf> = env::args_os().skip(1).map(PathBuf::from).collect();
+    let result = match args.as_slice() {
+        [root, archive] => sandbox::restrict(root).and_then(|()| unpack(archive, root)),
+        _ => {
+            eprintln!("usage: {} ROOT ARCHIVE", EXTRACTION_HELPER);
+            return 2;
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io};
+
+    /// An archive entry: its path, its type, and what it links to.
+    type Entry<'a> = (&'a str, EntryType, Option<&'a str>);
+
+    /// An empty entry's header, with the path and link target written directly into it so that
+    /// unsafe paths can be used.
+    fn raw_header((path, kind, link): &Entry) -> tar::Header {
+        fn set_raw(field: &mut [u8; 100], value: &str) {
+            field[..value.len()].copy_from_slice(value.as_bytes());
+        }
+
+        let mut header = tar::Header::new_gnu();
+        set_raw(&mut header.as_old_mut().name, path);
+        if let Some(link) = link {
+            set_raw(&mut header.as_old_mut().linkname, link);
+        }
+        header.set_entry_type(*kind);
+        header.set_mode(0o644);
+        header.set_size(0);
+        header.set_cksum();
+        header
+    }
+
+    /// Build an uncompressed archive of empty entries.
+    fn archive(entries: &[Entry]) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut builder = tar::Builder::new(file.reopen().unwrap());
+        for entry in entries {
+            builder.append(&raw_header(entry), io::empty()).unwrap();
+        }
+        builder.finish().unwrap();
+        file
+    }
+
+    fn extract(entries: &[Entry]) -> (tempfile::TempDir, bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        let archive = archive(entries);
+        let ok = unpack(archive.path(), &root).is_ok();
+        (dir, ok)
+    }
+
+    #[test]
+    fn extract_normal_files() {
+        let (dir, ok) = extract(&[
+            (".PKGINFO", EntryType::Regular, None),
+            ("usr/bin/foo", EntryType::Regular, None),
+            ("usr/bin/bar", EntryType::Link, Some("usr/bin/foo")),
+            ("usr/lib/baz", EntryType::Symlink, Some("/usr/bin/foo")),
+        ]);
+        assert!(ok);
+        let root = dir.path().join("root");
+        assert!(root.join("usr/bin/foo").is_file());
+        assert!(root.join("usr/bin/bar").is_file());
+        assert!(!root.join(".PKGINFO").exists());
+    }
+
+    #[test]
+    fn reject_unsafe_entries() {
+        let unsafe_archives: &[&[Entry]] = &[
+            &[("../evil", EntryType::Regular, None)],
+            &[("/tmp/evil", EntryType::Regular, None)],
+            &[
+                ("etc", EntryType::Symlink, Some("/etc")),
+                ("etc/passwd", EntryType::Regular, None),
+            ],
+            // A hard link is resolved through the symlink when it is made.
+            &[
+                ("etc", EntryType::Symlink, Some("/etc")),
+                ("x", EntryType::Link, Some("etc/shadow")),
+            ],
+            &[
+                ("lib", EntryType::Symlink, Some("/")),
+                ("x", EntryType::Link, Some("./lib/./etc/shadow")),
+            ],
+        ];
+        for entries in unsafe_archives {
+            let (dir, ok) = extract(entries);
+            assert!(!ok, "{:?} was extracted", entries);
+            assert!(!dir.path().join("evil").exists());
+        }
+        // Hard links to symlinks themselves are fine.
+        let archive = archive(&[
+            ("usr/lib/foo", EntryType::Symlink, Some("bar")),
+            ("usr/lib/baz", EntryType::Link, Some("usr/lib/foo")),
+        ]);
+        validate(archive.path()).unwrap();
+    }
+
+    #[test]
+    fn reject_hard_link_outside_root() {
+        let (dir, ok) = extract(&[
+            ("usr/bin/foo", EntryType::Regular, None),
+            ("usr/bin/bar", EntryType::Link, Some("../outside")),
+        ]);
+        assert!(!ok);
+        // Nothing is written if any entry is bad.
+        assert!(!dir.path().join("root/usr/bin/foo").exists());
+    }
+
+    #[test]
+    fn overlapping_packages() {
+        let paths = |list: &[&str]| list.iter().map(PathBuf::from).collect::<BTreeSet<_>>();
+        let written = vec![
+            paths(&["usr/bin/a"]),
+            paths(&["usr/bin/b", "usr/share/doc"]),
+            paths(&["usr/share/doc", "usr/bin/c"]),
+            paths(&["usr/bin/a"]),
+            paths(&["usr/bin/d"]),
+        ];
+        assert_eq!(without_overlaps(&written), [vec![0, 1, 4], vec![2, 3]]);
+        assert!(without_overlaps(&[]).is_empty());
+
+        let archive = archive(&[
+            (".PKGINFO", EntryType::Regular, None),
+            ("usr/", EntryType::Directory, None),
+            ("./usr/bin/foo", EntryType::Regular, None),
+            ("usr/bin/bar", EntryType::Symlink, Some("foo")),
+        ]);
+        assert_eq!(
+            written_paths(archive.path()).unwrap(),
+            paths(&["usr/bin/bar", "usr/bin/foo"])
+        );
+    }
+}
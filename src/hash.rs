@@ -0,0 +1,160 @@
+//! Checksums of files, and spreading work over threads.
+//!
+//! Checksums are streamed, so large archives aren't read into memory, and returned as lowercase
+//! hex, as they appear in databases and mtrees.
+
+use std::{
+    fs,
+    io::{self, Read},
+    panic,
+    path::Path,
+    sync::Arc,
+    thread,
+};
+
+use md5::{Digest, Md5};
+use sha2::Sha256;
+
+/// How much of a file to read at a time, when computing several checksums at once.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// The md5 and sha256 checksums of some data.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub(crate) struct Digests {
+    pub md5: String,
+    pub sha256: String,
+}
+
+/// The md5 checksum of everything read from `reader`.
+pub(crate) fn md5(mut reader: impl Read) -> io::Result<String> {
+    let mut hasher = Md5::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// The sha256 checksum of everything read from `reader`.
+pub(crate) fn sha256(mut reader: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Both checksums of everything read from `reader`, reading it once.
+pub(crate) fn digests(mut reader: impl Read) -> io::Result<Digests> {
+    let mut md5 = Md5::new();
+    let mut sha256 = Sha256::new();
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                md5.input(&buf[..len]);
+                sha256.input(&buf[..len]);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Digests {
+        md5: format!("{:x}", md5.result()),
+        sha256: format!("{:x}", sha256.result()),
+    })
+}
+
+/// The md5 checksum of a file.
+pub(crate) fn md5_file(path: &Path) -> io::Result<String> {
+    md5(fs::File::open(path)?)
+}
+
+/// The sha256 checksum of a file.
+pub(crate) fn sha256_file(path: &Path) -> io::Result<String> {
+    sha256(fs::File::open(path)?)
+}
+
+/// Both checksums of a file.
+pub(crate) fn digests_file(path: &Path) -> io::Result<Digests> {
+    digests(fs::File::open(path)?)
+}
+
+/// Apply `f` to every item, spreading the work over `parallelism` threads.
+///
+/// The results are in the same order as the items. If `f` panics, so does this: the first
+/// panicking worker's payload is passed to `panic::resume_unwind` once the earlier workers have
+/// been joined, so callers see the original panic rather than a generic join failure.
+pub(crate) fn par_map<T, R, F>(items: Vec<T>, parallelism: usize, f: F) -> Vec<R>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> R + Send + Sync + 'static,
+{
+    let parallelism = parallelism.max(1);
+    let len = items.len();
+    let mut jobs = (0..parallelism).map(|_| Vec::new()).collect::<Vec<_>>();
+    for (idx, item) in items.into_iter().enumerate() {
+        jobs[idx % parallelism].push((idx, item));
+    }
+    let f = Arc::new(f);
+    let workers = jobs
+        .into_iter()
+        .filter(|job| !job.is_empty())
+        .map(|job| {
+            let f = f.clone();
+            thread::spawn(move || {
+                job.into_iter()
+                    .map(|(idx, item)| (idx, f(item)))
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut results = (0..len).map(|_| None).collect::<Vec<_>>();
+    for worker in workers {
+        let done = worker.join().unwrap_or_else(|e| panic::resume_unwind(e));
+        for (idx, result) in done {
+            results[idx] = Some(result);
+        }
+    }
+    results
+        .into_iter()
+        .map(|result| result.expect("every item is mapped"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        let md5sum = "b1946ac92492d2347c6235b4d2611184";
+        let sha256sum = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+        assert_eq!(md5(&b"hello\n"[..]).unwrap(), md5sum);
+        assert_eq!(sha256(&b"hello\n"[..]).unwrap(), sha256sum);
+        assert_eq!(
+            digests(&b"hello\n"[..]).unwrap(),
+            Digests {
+                md5: md5sum.to_owned(),
+                sha256: sha256sum.to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn par_map_keeps_order() {
+        let items = (0..100).collect::<Vec<u32>>();
+        let expected = items.iter().map(|n| n * 2).collect::<Vec<_>>();
+        assert_eq!(par_map(items.clone(), 7, |n| n * 2), expected);
+        assert_eq!(par_map(items, 0, |n| n * 2), expected);
+        assert!(par_map(Vec::<u32>::new(), 4, |n| n).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "item 3 is bad")]
+    fn par_map_resumes_panics() {
+        par_map((0..8).collect(), 4, |n: u32| {
+            if n == 3 {
+                panic!("item {} is bad", n);
+            }
+            n
+        });
+    }
+}
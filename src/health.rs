@@ -0,0 +1,281 @@
+//! Checks on the overall state of an alpm installation, like `pacman -Dk`.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    db::{Database, DbStatus},
+    error::Error,
+    package::Package,
+    Alpm, LOCKFILE,
+};
+
+/// The result of running `Alpm::health_check`.
+#[derive(Debug)]
+pub struct HealthReport {
+    /// The status of the local database.
+    pub local_database: DatabaseHealth,
+    /// The status of each registered sync database, in registration order.
+    pub sync_databases: Vec<DatabaseHealth>,
+    /// Whether the lockfile is still in place (always ok for read-only instances, which don't hold
+    /// it).
+    pub lockfile: Check,
+    /// Whether each cache directory is present and writable.
+    pub cache_directories: Vec<(PathBuf, Check)>,
+    /// Whether the gpg directory is present.
+    pub gpg_directory: Check,
+}
+
+impl HealthReport {
+    /// Did all the checks pass.
+    pub fn is_healthy(&self) -> bool {
+        self.local_database.is_healthy()
+            && self.sync_databases.iter().all(DatabaseHealth::is_healthy)
+            && self.lockfile.is_ok()
+            && self
+                .cache_directories
+                .iter()
+                .all(|(_, check)| check.is_ok())
+            && self.gpg_directory.is_ok()
+    }
+}
+
+/// The status of a single database.
+#[derive(Debug)]
+pub struct DatabaseHealth {
+    /// The name of the database.
+    pub name: String,
+    /// The status of the database, or the error encountered while finding it.
+    pub status: Result<DbStatus, Error>,
+}
+
+impl DatabaseHealth {
+    fn new(db: &impl Database) -> Self {
+        DatabaseHealth {
+            name: db.name().to_owned(),
+            status: db.status(),
+        }
+    }
+
+    /// Is the database present and valid.
+    pub fn is_healthy(&self) -> bool {
+        match &self.status {
+            Ok(status) => status.is_valid(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// The outcome of a single check.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Check {
+    /// Nothing is wrong.
+    Ok,
+    /// Something is wrong, with a description of the problem.
+    Problem(String),
+}
+
+impl Check {
+    /// Did the check pass.
+    pub fn is_ok(&self) -> bool {
+        *self == Check::Ok
+    }
+}
+
+impl From<io::Result<()>> for Check {
+    fn from(result: io::Result<()>) -> Check {
+        match result {
+            Ok(()) => Check::Ok,
+            Err(e) => Check::Problem(e.to_string()),
+        }
+    }
+}
+
+/// Run all checks on an alpm instance.
+pub(crate) fn check(alpm: &Alpm) -> HealthReport {
+    let local_database = DatabaseHealth::new(&alpm.local_database());
+    let mut sync_databases = Vec::new();
+    alpm.sync_databases(|db| sync_databases.push(DatabaseHealth::new(&db)));
+
+    let handle = alpm.handle.borrow();
+    let lockfile = if handle.read_only {
+        Check::Ok
+    } else {
+        check_lockfile(handle.database_path.join(LOCKFILE))
+    };
+    let cache_directories = handle
+        .cache_directories
+        .iter()
+        .map(|dir| (dir.clone(), check_writable_directory(dir)))
+        .collect();
+    let gpg_directory = check_directory(&handle.gpg_path);
+
+    HealthReport {
+        local_database,
+        sync_databases,
+        lockfile,
+        cache_directories,
+        gpg_directory,
+    }
+}
+
+/// We hold the lock, so the lockfile should exist and be a file.
+fn check_lockfile(path: PathBuf) -> Check {
+    match fs::metadata(&path) {
+        Ok(ref md) if md.is_file() => Check::Ok,
+        Ok(_) => Check::Problem(format!("\"{}\" is not a file", path.display())),
+        Err(e) => Check::Problem(format!("lockfile \"{}\": {}", path.display(), e)),
+    }
+}
+
+/// Check the path is a directory.
+fn check_directory(path: &Path) -> Check {
+    match fs::metadata(path) {
+        Ok(ref md) if md.is_dir() => Check::Ok,
+        Ok(_) => Check::Problem(format!("\"{}\" is not a directory", path.display())),
+        Err(e) => Check::Problem(format!("\"{}\": {}", path.display(), e)),
+    }
+}
+
+/// Check the path is a directory we can create files in.
+fn check_writable_directory(path: &Path) -> Check {
+    match check_directory(path) {
+        Check::Ok => tempfile::tempfile_in(path).map(|_| ()).into(),
+        problem => problem,
+    }
+}
+
+/// A package that is in more than one sync database, with different versions.
+///
+/// Only the package from the preferred database (by pin, then priority, then registration
+/// order) will ever be used, unless the database is named explicitly (e.g. `extra/foo`).
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ShadowedPackage {
+    /// The package name.
+    pub name: String,
+    /// Each database that has the package, and the version it has, in order of preference.
+    pub databases: Vec<(String, String)>,
+}
+
+impl ShadowedPackage {
+    /// The database whose package is used.
+    pub fn winner(&self) -> &str {
+        &self.databases[0].0
+    }
+
+    /// The version that is used.
+    pub fn winning_version(&self) -> &str {
+        &self.databases[0].1
+    }
+}
+
+/// Find packages that are in several sync databases with different versions.
+///
+/// Databases that can't be loaded are skipped (a warning is raised when loading them).
+pub(crate) fn shadowed_packages(alpm: &Alpm) -> Vec<ShadowedPackage> {
+    let mut found: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    alpm.sync_databases(|db| {
+        let result = db.packages(|package| -> Result<(), Error> {
+            found
+                .entry(package.name().to_owned())
+                .or_default()
+                .push((db.name().to_owned(), package.version().to_owned()));
+            Ok(())
+        });
+        if let Err(e) = result {
+            log::debug!("skipping database {}: {}", db.name(), e);
+        }
+    });
+    let handle = alpm.handle.borrow();
+    found
+        .into_iter()
+        .filter(|(_, databases)| {
+            databases
+                .iter()
+                .any(|(_, version)| *version != databases[0].1)
+        })
+        .map(|(name, mut databases)| {
+            let preferred = handle.sync_databases_for(&name);
+            databases.sort_by_key(|(database, _)| {
+                preferred
+                    .iter()
+                    .position(|db| db.name() == database)
+                    .unwrap_or(preferred.len())
+            });
+            ShadowedPackage { name, databases }
+        })
+        .collect()
+}
+
+/// An installed package that is no longer in the sync database it was in.
+///
+/// This usually means the package was dropped from the repository (e.g. moved to the AUR), so it
+/// won't get any more updates.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct DroppedPackage {
+    /// The package name.
+    pub name: String,
+    /// The installed version.
+    pub version: String,
+    /// The database the package disappeared from.
+    pub database: String,
+}
+
+/// Find packages that were dropped from a sync database, and are still installed and unavailable.
+pub(crate) fn dropped_packages(alpm: &Alpm) -> Result<Vec<DroppedPackage>, Error> {
+    let local = alpm.local_database();
+    let recorded = alpm.handle.borrow().dropped_packages.borrow().clone();
+    let mut dropped = Vec::new();
+    for (name, database) in recorded {
+        if alpm.find_package(&name).is_some() {
+            continue;
+        }
+        // Installed packages are reported even if their entries can't be loaded.
+        if let Some(version) = local.version_of(&name)? {
+            dropped.push(DroppedPackage {
+                name,
+                version,
+                database,
+            });
+        }
+    }
+    Ok(dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::mutation::tests::alpm_with;
+
+    #[test]
+    fn dropped_packages_that_cannot_be_loaded() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| builder,
+            &[("foo", "1-1", &[]), ("bar", "1-1", &[])],
+            &[],
+        );
+        fs::write(root.path().join("db/local/foo-1-1/desc"), "%NAME%\n").unwrap();
+        {
+            let handle = alpm.handle.borrow();
+            let mut recorded = handle.dropped_packages.borrow_mut();
+            recorded.insert("foo".into(), "core".into());
+            recorded.insert("qux".into(), "core".into());
+        }
+        let dropped = alpm.dropped_packages().unwrap();
+        assert_eq!(
+            dropped,
+            vec![DroppedPackage {
+                name: "foo".into(),
+                version: "1-1".into(),
+                database: "core".into(),
+            }]
+        );
+    }
+}
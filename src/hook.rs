@@ -0,0 +1,588 @@
+//! Hooks, which run commands before or after a transaction when it touches certain packages or
+//! files.
+//!
+//! Hooks are `.hook` files in the hook directories, in the same format as pacman's (see
+//! `alpm-hooks(5)`). A file in a later directory replaces one with the same name in an earlier
+//! directory, and a hook that is a symlink to `/dev/null` is disabled.
+//!
+//! Hooks are not run yet. This module lets them be listed, and lets you see which would be run
+//! by a plan, which helps when writing them.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    db::LocalPackage,
+    error::{Error, ErrorContext, ErrorKind},
+};
+
+/// The extension of hook files.
+const HOOK_EXT: &str = ".hook";
+
+/// A kind of change to a package or file, that a trigger can match.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Operation {
+    /// A package or file is being installed.
+    Install,
+    /// A package or file is being upgraded (replaced by another version).
+    Upgrade,
+    /// A package or file is being removed.
+    Remove,
+}
+
+impl FromStr for Operation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Operation, ()> {
+        match s {
+            "Install" => Ok(Operation::Install),
+            "Upgrade" => Ok(Operation::Upgrade),
+            "Remove" => Ok(Operation::Remove),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the targets of a trigger are matched against.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum TriggerKind {
+    /// File paths, relative to the root (e.g. `usr/lib/modules/*`).
+    Path,
+    /// Package names.
+    Package,
+}
+
+/// When a hook runs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum When {
+    /// Before any package is changed.
+    PreTransaction,
+    /// After all packages have been changed.
+    PostTransaction,
+}
+
+/// A `[Trigger]` section of a hook.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Trigger {
+    /// The operations that can fire the trigger.
+    pub operations: Vec<Operation>,
+    /// What the targets are matched against.
+    pub kind: TriggerKind,
+    /// Glob patterns of package names or paths. Patterns starting with `!` exclude what they
+    /// match, and the last pattern that matches decides.
+    pub targets: Vec<String>,
+}
+
+impl Trigger {
+    /// Whether an operation on a package name or path fires this trigger.
+    pub fn matches(&self, operation: Operation, target: &str) -> bool {
+        if !self.operations.contains(&operation) {
+            return false;
+        }
+        for pattern in self.targets.iter().rev() {
+            let (negated, pattern) = if pattern.starts_with('!') {
+                (true, &pattern[1..])
+            } else {
+                (false, &pattern[..])
+            };
+            if fnmatch(pattern.as_bytes(), target.as_bytes()) {
+                return !negated;
+            }
+        }
+        false
+    }
+}
+
+/// A hook, parsed from a hook file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Hook {
+    /// The name of the hook file, which decides the order hooks run in.
+    pub name: String,
+    /// Where the hook was read from.
+    pub path: PathBuf,
+    /// The hook runs if any of these fire.
+    pub triggers: Vec<Trigger>,
+    /// A description to show while the hook runs.
+    pub description: Option<String>,
+    /// When the hook runs.
+    pub when: When,
+    /// The command to run.
+    pub exec: String,
+    /// Packages that must be installed for the hook to run.
+    pub depends: Vec<String>,
+    /// Whether the transaction is cancelled if the hook fails (only for pre-transaction hooks).
+    pub abort_on_fail: bool,
+    /// Whether the matched targets are passed to the command on standard input.
+    pub needs_targets: bool,
+}
+
+impl Hook {
+    /// Parse the contents of a hook file.
+    fn parse(name: String, path: PathBuf, contents: &str) -> Result<Hook, Error> {
+        let invalid = |line: usize, reason: &str| {
+            Error::from(ErrorKind::InvalidHook {
+                path: path.clone(),
+                reason: format!("line {}: {}", line, reason),
+            })
+        };
+
+        let mut section = None;
+        let mut triggers: Vec<PartialTrigger> = Vec::new();
+        let mut description = None;
+        let mut when = None;
+        let mut exec = None;
+        let mut depends = Vec::new();
+        let mut abort_on_fail = false;
+        let mut needs_targets = false;
+
+        for (idx, line) in contents.lines().enumerate() {
+            let line_no = idx + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                match &line[1..line.len() - 1] {
+                    "Trigger" => {
+                        triggers.push(PartialTrigger::default());
+                        section = Some(Section::Trigger);
+                    }
+                    "Action" => section = Some(Section::Action),
+                    _ => return Err(invalid(line_no, "unknown section")),
+                }
+                continue;
+            }
+            let (key, raw_value) = match line.find('=') {
+                Some(pos) => (line[..pos].trim(), Some(line[pos + 1..].trim())),
+                None => (line, None),
+            };
+            let value = || raw_value.ok_or_else(|| invalid(line_no, "missing value"));
+            match (section, key) {
+                (None, _) => return Err(invalid(line_no, "option outside of a section")),
+                (Some(Section::Trigger), "Operation") => {
+                    let operation = value()?
+                        .parse()
+                        .map_err(|()| invalid(line_no, "unknown operation"))?;
+                    current(&mut triggers).operations.push(operation);
+                }
+                (Some(Section::Trigger), "Type") => {
+                    current(&mut triggers).kind = Some(match value()? {
+                        // `File` is the old name of `Path`.
+                        "Path" | "File" => TriggerKind::Path,
+                        "Package" => TriggerKind::Package,
+                        _ => return Err(invalid(line_no, "unknown trigger type")),
+                    });
+                }
+                (Some(Section::Trigger), "Target") => {
+                    current(&mut triggers).targets.push(value()?.to_owned());
+                }
+                (Some(Section::Action), "Description") => description = Some(value()?.to_owned()),
+                (Some(Section::Action), "When") => {
+                    when = Some(match value()? {
+                        "PreTransaction" => When::PreTransaction,
+                        "PostTransaction" => When::PostTransaction,
+                        _ => return Err(invalid(line_no, "unknown value for When")),
+                    });
+                }
+                (Some(Section::Action), "Exec") => exec = Some(value()?.to_owned()),
+                (Some(Section::Action), "Depends") => depends.push(value()?.to_owned()),
+                (Some(Section::Action), "AbortOnFail") => abort_on_fail = true,
+                (Some(Section::Action), "NeedsTargets") => needs_targets = true,
+                _ => return Err(invalid(line_no, "unknown option")),
+            }
+        }
+
+        let missing = |reason: &str| {
+            Error::from(ErrorKind::InvalidHook {
+                path: path.clone(),
+                reason: reason.to_owned(),
+            })
+        };
+        if triggers.is_empty() {
+            return Err(missing("no triggers"));
+        }
+        let triggers = triggers
+            .into_iter()
+            .map(|trigger| match trigger.kind {
+                Some(kind) if !trigger.operations.is_empty() && !trigger.targets.is_empty() => {
+                    Ok(Trigger {
+                        operations: trigger.operations,
+                        kind,
+                        targets: trigger.targets,
+                    })
+                }
+                _ => Err(missing("a trigger needs an operation, a type and a target")),
+            })
+            .collect::<Result<_, _>>()?;
+        let when = when.ok_or_else(|| missing("missing When"))?;
+        if abort_on_fail && when != When::PreTransaction {
+            log::warn!(
+                "AbortOnFail in \"{}\" has no effect after the transaction",
+                path.display()
+            );
+        }
+        Ok(Hook {
+            name,
+            triggers,
+            description,
+            when,
+            exec: exec.ok_or_else(|| missing("missing Exec"))?,
+            depends,
+            abort_on_fail,
+            needs_targets,
+            path,
+        })
+    }
+
+    /// The package names or paths that fire this hook, out of the changes given.
+    fn matched_targets(&self, changes: &Changes) -> BTreeSet<String> {
+        let mut matched = BTreeSet::new();
+        for trigger in &self.triggers {
+            let changes = match trigger.kind {
+                TriggerKind::Package => &changes.packages,
+                TriggerKind::Path => &changes.paths,
+            };
+            matched.extend(
+                changes
+                    .iter()
+                    .filter(|(operation, target)| trigger.matches(*operation, target))
+                    .map(|(_, target)| target.clone()),
+            );
+        }
+        matched
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.description {
+            Some(description) => write!(f, "{} ({})", self.name, description),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// A hook that a plan would run, from `Alpm::hooks_for_plan`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HookMatch {
+    /// The hook.
+    pub hook: Hook,
+    /// The package names or paths that fire the hook, sorted.
+    ///
+    /// These are what the command would be given if the hook `needs_targets`.
+    pub targets: Vec<String>,
+}
+
+/// The package names and paths a plan changes, and how.
+#[derive(Debug, Default)]
+pub(crate) struct Changes {
+    pub packages: Vec<(Operation, String)>,
+    pub paths: Vec<(Operation, String)>,
+}
+
+impl Changes {
+    /// Record a package being installed (replacing `old_files` if it is an upgrade).
+    ///
+    /// `new_files` is `None` if the package's files aren't known, in which case path triggers
+    /// can't match it.
+    pub fn install(
+        &mut self,
+        name: &str,
+        new_files: Option<Vec<String>>,
+        old_files: Option<Vec<String>>,
+    ) {
+        let operation = match old_files {
+            Some(_) => Operation::Upgrade,
+            None => Operation::Install,
+        };
+        self.packages.push((operation, name.to_owned()));
+        let new_files: BTreeSet<String> = match new_files {
+            Some(files) => files.into_iter().collect(),
+            None => return,
+        };
+        let old_files: BTreeSet<String> = old_files.into_iter().flatten().collect();
+        for file in new_files.iter() {
+            let operation = if old_files.contains(file) {
+                Operation::Upgrade
+            } else {
+                Operation::Install
+            };
+            self.paths.push((operation, file.clone()));
+        }
+        for file in old_files.difference(&new_files) {
+            self.paths.push((Operation::Remove, file.clone()));
+        }
+    }
+
+    /// Record a package being removed.
+    pub fn remove(&mut self, name: &str, files: Vec<String>) {
+        self.packages.push((Operation::Remove, name.to_owned()));
+        self.paths
+            .extend(files.into_iter().map(|file| (Operation::Remove, file)));
+    }
+}
+
+/// Read the hooks in the given directories, sorted by name.
+///
+/// Directories that don't exist are skipped.
+pub(crate) fn load_hooks(directories: &[PathBuf]) -> Result<Vec<Hook>, Error> {
+    let mut files = BTreeMap::new();
+    for dir in directories {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) if name.ends_with(HOOK_EXT) => name.to_owned(),
+                _ => continue,
+            };
+            files.insert(name, path);
+        }
+    }
+
+    let mut hooks = Vec::new();
+    for (name, path) in files {
+        if is_disabled(&path) {
+            log::debug!("hook \"{}\" is disabled", path.display());
+            continue;
+        }
+        let contents = fs::read_to_string(&path).context(ErrorKind::InvalidHook {
+            path: path.clone(),
+            reason: "could not read the file".to_owned(),
+        })?;
+        hooks.push(Hook::parse(name, path, &contents)?);
+    }
+    Ok(hooks)
+}
+
+/// The hooks that would run for some changes, with the targets that fire them.
+pub(crate) fn triggered(hooks: Vec<Hook>, changes: &Changes) -> Vec<HookMatch> {
+    hooks
+        .into_iter()
+        .filter_map(|hook| {
+            let targets = hook.matched_targets(changes);
+            if targets.is_empty() {
+                None
+            } else {
+                Some(HookMatch {
+                    hook,
+                    targets: targets.into_iter().collect(),
+                })
+            }
+        })
+        .collect()
+}
+
+/// The paths of the files of an installed package, relative to the root.
+pub(crate) fn local_files(package: &LocalPackage) -> Vec<String> {
+    package
+        .file_names()
+        .filter_map(|path| path.to_str())
+        .map(|path| path.trim_start_matches("./"))
+        .filter(|path| !path.is_empty() && *path != ".")
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Whether a hook file has been disabled by linking it to `/dev/null`.
+fn is_disabled(path: &Path) -> bool {
+    fs::read_link(path)
+        .map(|target| target == Path::new("/dev/null"))
+        .unwrap_or(false)
+}
+
+/// The section of a hook file being parsed.
+#[derive(Debug, Copy, Clone)]
+enum Section {
+    Trigger,
+    Action,
+}
+
+/// A trigger that hasn't been checked yet.
+#[derive(Debug, Default)]
+struct PartialTrigger {
+    operations: Vec<Operation>,
+    kind: Option<TriggerKind>,
+    targets: Vec<String>,
+}
+
+/// The trigger being parsed (there always is one in a `[Trigger]` section).
+fn current(triggers: &mut Vec<PartialTrigger>) -> &mut PartialTrigger {
+    triggers.last_mut().expect("in a trigger section")
+}
+
+/// Match a glob pattern like `fnmatch(3)` with no flags (`*` also matches `/`).
+pub(crate) fn fnmatch(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| fnmatch(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && fnmatch(rest, &text[1..]),
+        Some((b'[', rest)) => match (text.split_first(), match_class(rest)) {
+            (Some((&c, text)), Some((class, rest))) => class(c) && fnmatch(rest, text),
+            // An unclosed `[` is matched literally.
+            (Some((b'[', text)), None) => fnmatch(rest, text),
+            _ => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && fnmatch(&rest[1..], &text[1..])
+        }
+        Some((&p, rest)) => text.first() == Some(&p) && fnmatch(rest, &text[1..]),
+    }
+}
+
+/// Parse a bracket expression (after the `[`), returning a test for it and the rest of the
+/// pattern.
+fn match_class(pattern: &[u8]) -> Option<(impl Fn(u8) -> bool, &[u8])> {
+    let (negated, body) = match pattern.first() {
+        Some(b'!') | Some(b'^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+    // A `]` straight after the `[` is part of the class.
+    let end = body.iter().skip(1).position(|&c| c == b']')? + 1;
+    let class = body[..end].to_vec();
+    let test = move |c: u8| {
+        let mut found = false;
+        let mut i = 0;
+        while i < class.len() {
+            if i + 2 < class.len() && class[i + 1] == b'-' {
+                found |= class[i] <= c && c <= class[i + 2];
+                i += 3;
+            } else {
+                found |= class[i] == c;
+                i += 1;
+            }
+        }
+        found != negated
+    };
+    Some((test, &body[end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNEL_HOOK: &str = "
+# Regenerate the initramfs
+[Trigger]
+Operation = Install
+Operation = Upgrade
+Type = Path
+Target = usr/lib/modules/*/vmlinuz
+Target = !usr/lib/modules/*-lts/vmlinuz
+
+[Trigger]
+Operation = Remove
+Type = Package
+Target = mkinitcpio
+
+[Action]
+Description = Updating linux initcpios...
+When = PostTransaction
+Exec = /usr/share/libalpm/scripts/mkinitcpio-install
+NeedsTargets
+";
+
+    fn kernel_hook() -> Hook {
+        Hook::parse(
+            "90-mkinitcpio-install.hook".into(),
+            "/usr/share/libalpm/hooks/90-mkinitcpio-install.hook".into(),
+            KERNEL_HOOK,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn read_only_runs_nothing() {
+        use crate::{mutation::tests::alpm_with, script::ScriptCommand};
+
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(root.path(), |builder| builder, &[], &[]);
+        let hook = HookMatch {
+            hook: kernel_hook(),
+            targets: vec!["mkinitcpio".into()],
+        };
+        let read_only = |result: Result<(), Error>| match result {
+            Err(Error {
+                kind: ErrorKind::ReadOnly,
+                ..
+            }) => (),
+            other => panic!("expected a read-only error, got {:?}", other),
+        };
+        read_only(alpm.run_hook(&hook));
+        let command = ScriptCommand::new(root.path(), vec!["/bin/true".into()]);
+        read_only(alpm.run_script(&command));
+    }
+
+    #[test]
+    fn parse() {
+        let hook = kernel_hook();
+        assert_eq!(hook.triggers.len(), 2);
+        assert_eq!(
+            hook.triggers[0].operations,
+            vec![Operation::Install, Operation::Upgrade]
+        );
+        assert_eq!(hook.triggers[1].kind, TriggerKind::Package);
+        assert_eq!(hook.when, When::PostTransaction);
+        assert!(hook.needs_targets);
+        assert!(!hook.abort_on_fail);
+
+        let bad = "[Trigger]\nOperation = Install\nType = Path\n[Action]\nWhen = PostTransaction\nExec = /bin/true\n";
+        assert!(Hook::parse("bad.hook".into(), "bad.hook".into(), bad).is_err());
+        let unknown = "[Trigger]\nOperation = Frobnicate\n";
+        assert!(Hook::parse("bad.hook".into(), "bad.hook".into(), unknown).is_err());
+    }
+
+    #[test]
+    fn trigger_matches() {
+        let hook = kernel_hook();
+        let trigger = &hook.triggers[0];
+        assert!(trigger.matches(Operation::Install, "usr/lib/modules/5.4.1-arch1/vmlinuz"));
+        assert!(!trigger.matches(Operation::Install, "usr/lib/modules/5.4.1-lts/vmlinuz"));
+        assert!(!trigger.matches(Operation::Remove, "usr/lib/modules/5.4.1-arch1/vmlinuz"));
+        assert!(!trigger.matches(Operation::Install, "usr/lib/modules/5.4.1-arch1/extra"));
+    }
+
+    #[test]
+    fn triggered_by_changes() {
+        let mut changes = Changes::default();
+        changes.install(
+            "linux",
+            Some(vec!["usr/lib/modules/5.4.2-arch1/vmlinuz".into()]),
+            Some(vec!["usr/lib/modules/5.4.1-arch1/vmlinuz".into()]),
+        );
+        changes.remove("mkinitcpio", vec![]);
+        let matches = triggered(vec![kernel_hook()], &changes);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(
+            matches[0].targets,
+            vec![
+                "mkinitcpio".to_owned(),
+                "usr/lib/modules/5.4.2-arch1/vmlinuz".to_owned()
+            ]
+        );
+
+        let mut changes = Changes::default();
+        changes.install("vim", None, None);
+        assert!(triggered(vec![kernel_hook()], &changes).is_empty());
+    }
+
+    #[test]
+    fn globs() {
+        assert!(fnmatch(b"usr/*/ls", b"usr/bin/ls"));
+        assert!(fnmatch(b"*", b"usr/bin/ls"));
+        assert!(fnmatch(b"lib?", b"lib3"));
+        assert!(fnmatch(b"python[23]", b"python3"));
+        assert!(!fnmatch(b"python[!23]", b"python3"));
+        assert!(fnmatch(b"x[a-c]", b"xb"));
+        assert!(!fnmatch(b"x[a-c]", b"xd"));
+        assert!(fnmatch(b"a\\*", b"a*"));
+        assert!(!fnmatch(b"a\\*", b"ab"));
+    }
+}
@@ -0,0 +1,532 @@
+//! Managing the keyring that package and database signatures are checked against, like
+//! `pacman-key`.
+//!
+//! Distributions ship their keys in a keyring package (e.g. `archlinux-keyring`), which installs
+//! three files per keyring under `usr/share/pacman/keyrings/`:
+//!
+//!  - `<name>.gpg` - the keys,
+//!  - `<name>-trusted` - the keys to trust, as `<fingerprint>:<trust level>:` lines,
+//!  - `<name>-revoked` - the fingerprints of keys that must no longer be used.
+//!
+//! Like `pacman-key`, this runs `gpg` on the keyring in the gpg directory (see
+//! `AlpmBuilder::with_gpg_path`). Trusted keys are signed with the keyring's own key, so the
+//! keyring must have been initialized (`pacman-key --init`) first.
+//!
+//! `audit_signatures` checks the signatures already on disk against the keyring, so expired keys
+//! and signatures can be found before an install fails because of them. `sign_file` makes
+//! signatures, for people running their own repositories.
+
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    db::Database,
+    error::{Error, ErrorContext, ErrorKind},
+    Alpm,
+};
+
+/// Where keyring packages install their keyrings, relative to the root.
+pub const KEYRING_DIR: &str = "usr/share/pacman/keyrings";
+/// The program used to manage the keyring.
+const GPG: &str = "gpg";
+/// The extension of detached signatures.
+const SIG_EXT: &str = "sig";
+
+/// What `populate` did.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Populated {
+    /// The name of the keyring.
+    pub keyring: String,
+    /// The number of keys imported (including keys that were already present).
+    pub imported: usize,
+    /// The fingerprints of the keys that are now trusted.
+    pub trusted: Vec<String>,
+    /// The fingerprints of the keys that are now disabled.
+    pub revoked: Vec<String>,
+}
+
+/// How a signature checked out against the keyring.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SignatureStatus {
+    /// The signature is good.
+    Valid,
+    /// The signature is good, but has expired.
+    ExpiredSignature,
+    /// The signature is good, but the key that made it has expired.
+    ExpiredKey,
+    /// The signature is good, but the key that made it has been revoked.
+    RevokedKey,
+    /// The key that made the signature is not in the keyring.
+    UnknownKey,
+    /// The signature does not match the file, or could not be checked.
+    Bad,
+}
+
+/// A signature checked by `audit_signatures`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SignatureCheck {
+    /// The file that is signed.
+    pub path: PathBuf,
+    /// How the signature checked out.
+    pub status: SignatureStatus,
+    /// The fingerprint (or, if the key is unknown, the id) of the key that made the signature.
+    pub key: Option<String>,
+}
+
+/// A key in the keyring that has expired or will soon.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct KeyExpiry {
+    /// The fingerprint of the key.
+    pub fingerprint: String,
+    /// The key's primary user id.
+    pub uid: Option<String>,
+    /// When the key expires.
+    pub expires: SystemTime,
+}
+
+/// The signatures on disk and the keys in the keyring, from `Alpm::audit_signatures`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SignatureAudit {
+    /// Every signature of a sync database or cached package that was found, sorted by path.
+    pub signatures: Vec<SignatureCheck>,
+    /// Keys that have expired.
+    pub expired_keys: Vec<KeyExpiry>,
+    /// Keys that will expire within the period asked about.
+    pub expiring_keys: Vec<KeyExpiry>,
+}
+
+impl SignatureAudit {
+    /// The signatures that are not valid.
+    pub fn invalid_signatures(&self) -> impl Iterator<Item = &SignatureCheck> {
+        self.signatures
+            .iter()
+            .filter(|check| check.status != SignatureStatus::Valid)
+    }
+
+    /// Whether there is nothing to worry about.
+    pub fn is_ok(&self) -> bool {
+        self.invalid_signatures().next().is_none()
+            && self.expired_keys.is_empty()
+            && self.expiring_keys.is_empty()
+    }
+}
+
+/// Check the signatures of the sync databases and cached packages against the keyring, and find
+/// keys that have expired or will expire within `within`.
+pub(crate) fn audit_signatures(alpm: &Alpm, within: Duration) -> Result<SignatureAudit, Error> {
+    let handle = alpm.handle.borrow();
+    let mut signed = Vec::new();
+    for db in handle.ordered_sync_databases() {
+        signed.push(db.path());
+    }
+    for dir in &handle.cache_directories {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::debug!("skipping cache directory \"{}\": {}", dir.display(), e);
+                continue;
+            }
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == SIG_EXT) {
+                signed.push(path.with_extension(""));
+            }
+        }
+    }
+    signed.sort();
+    signed.dedup();
+
+    let mut signatures = Vec::new();
+    for path in signed {
+        let signature = signature_path(&path);
+        if !signature.is_file() || !path.is_file() {
+            continue;
+        }
+        let output = run_gpg(
+            &handle.gpg_path,
+            &[
+                OsStr::new("--verify"),
+                signature.as_os_str(),
+                path.as_os_str(),
+            ],
+            Stdio::null(),
+            b"",
+        )?;
+        let (status, key) = parse_verify(&String::from_utf8_lossy(&output.stdout));
+        signatures.push(SignatureCheck { path, status, key });
+    }
+
+    let output = gpg(
+        &handle.gpg_path,
+        &["--with-colons", "--fixed-list-mode", "--list-keys"],
+        Stdio::null(),
+        b"",
+    )?;
+    let now = SystemTime::now();
+    let mut expired_keys = Vec::new();
+    let mut expiring_keys = Vec::new();
+    for (key, expired) in parse_keys(&output) {
+        if expired || key.expires <= now {
+            expired_keys.push(key);
+        } else if now
+            .checked_add(within)
+            .map_or(true, |limit| key.expires <= limit)
+        {
+            expiring_keys.push(key);
+        }
+    }
+
+    Ok(SignatureAudit {
+        signatures,
+        expired_keys,
+        expiring_keys,
+    })
+}
+
+/// Make a detached signature of a file, like `repo-add --sign`, returning the path of the
+/// signature (the file's path with `.sig` added).
+///
+/// The key is chosen by its fingerprint (at least the 16 hex digit long key id). Its secret key
+/// must be in the user's own keyring (`GNUPGHOME`, or `~/.gnupg`), not the keyring packages are
+/// checked against.
+pub fn sign_file(path: impl AsRef<Path>, fingerprint: &str) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    if fingerprint.len() < 16 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ErrorKind::InvalidFingerprint(fingerprint.to_owned()).into());
+    }
+    let home = env::var_os("GNUPGHOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".gnupg")))
+        .ok_or_else(|| ErrorKind::GpgFailed(format!("{} --detach-sign", GPG)))?;
+    let signature = signature_path(path);
+    let args = [
+        OsStr::new("--local-user"),
+        OsStr::new(fingerprint),
+        OsStr::new("--detach-sign"),
+        OsStr::new("--no-armor"),
+        OsStr::new("--output"),
+        signature.as_os_str(),
+        path.as_os_str(),
+    ];
+    let output = run_gpg(&home, &args, Stdio::null(), b"")?;
+    let created = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.starts_with("[GNUPG:] SIG_CREATED"));
+    if !output.status.success() || !created {
+        log::debug!("{}", String::from_utf8_lossy(&output.stderr));
+        return Err(ErrorKind::GpgFailed(describe(&args)).into());
+    }
+    Ok(signature)
+}
+
+/// The detached signature of a file.
+fn signature_path(path: &Path) -> PathBuf {
+    let mut signature = path.as_os_str().to_owned();
+    signature.push(".");
+    signature.push(SIG_EXT);
+    signature.into()
+}
+
+/// Read the status output of `gpg --verify`.
+fn parse_verify(output: &str) -> (SignatureStatus, Option<String>) {
+    let mut status = None;
+    let mut key = None;
+    let mut fingerprint = None;
+    for line in output.lines() {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("[GNUPG:]") {
+            continue;
+        }
+        let keyword = fields.next().unwrap_or("");
+        let arg = fields.next().map(str::to_owned);
+        let this = match keyword {
+            "GOODSIG" => SignatureStatus::Valid,
+            "EXPSIG" => SignatureStatus::ExpiredSignature,
+            "EXPKEYSIG" => SignatureStatus::ExpiredKey,
+            "REVKEYSIG" => SignatureStatus::RevokedKey,
+            "BADSIG" => SignatureStatus::Bad,
+            // The return code in `ERRSIG` is 9 when the key is missing.
+            "ERRSIG" if line.split_whitespace().nth(7) == Some("9") => SignatureStatus::UnknownKey,
+            "ERRSIG" => SignatureStatus::Bad,
+            "VALIDSIG" => {
+                fingerprint = fingerprint.or(arg);
+                continue;
+            }
+            _ => continue,
+        };
+        if status.is_none() {
+            status = Some(this);
+            key = arg;
+        }
+    }
+    (status.unwrap_or(SignatureStatus::Bad), fingerprint.or(key))
+}
+
+/// Read the keys from `gpg --with-colons --list-keys`, with whether gpg considers them expired.
+///
+/// Keys that are revoked, disabled, or never expire are left out.
+fn parse_keys(output: &str) -> Vec<(KeyExpiry, bool)> {
+    let mut keys = Vec::new();
+    // The key being read, which is only kept if it expires.
+    let mut current: Option<(KeyExpiry, bool)> = None;
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        let field = |idx: usize| fields.get(idx).cloned().unwrap_or("");
+        match field(0) {
+            "pub" => {
+                keys.extend(current.take());
+                let validity = field(1);
+                let disabled = field(11).contains('D');
+                let expires = field(6).parse::<u64>().ok().filter(|&secs| secs > 0);
+                if let (Some(expires), false, false) = (expires, validity == "r", disabled) {
+                    current = Some((
+                        KeyExpiry {
+                            fingerprint: String::new(),
+                            uid: None,
+                            expires: UNIX_EPOCH + Duration::from_secs(expires),
+                        },
+                        validity == "e",
+                    ));
+                }
+            }
+            "fpr" => {
+                if let Some((key, _)) = &mut current {
+                    if key.fingerprint.is_empty() {
+                        key.fingerprint = field(9).to_owned();
+                    }
+                }
+            }
+            "uid" => {
+                if let Some((key, _)) = &mut current {
+                    if key.uid.is_none() {
+                        key.uid = Some(field(9).to_owned());
+                    }
+                }
+            }
+            // Subkeys have their own fingerprints, which must not replace the primary key's.
+            "sub" => keys.extend(current.take()),
+            _ => (),
+        }
+    }
+    keys.extend(current.take());
+    keys
+}
+
+/// The names of the keyrings installed under the root, sorted.
+pub fn keyrings(alpm: &Alpm) -> Result<Vec<String>, Error> {
+    let entries = match fs::read_dir(alpm.root_path().join(KEYRING_DIR)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "gpg") {
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                names.push(name.to_owned());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Add the keys of an installed keyring to the keyring, like `pacman-key --populate <keyring>`.
+///
+/// The keys are imported, the keys listed as trusted are given their trust level and signed, and
+/// the keys listed as revoked are disabled. Running it again after the keyring package is
+/// upgraded brings the keyring up to date.
+pub fn populate(alpm: &Alpm, keyring: &str) -> Result<Populated, Error> {
+    let dir = alpm.root_path().join(KEYRING_DIR);
+    let keys = dir.join(format!("{}.gpg", keyring));
+    if !keys.is_file() {
+        return Err(ErrorKind::KeyringNotFound(keyring.to_owned()).into());
+    }
+    let gpg_path = alpm.handle.borrow().gpg_path.clone();
+
+    let output = gpg(&gpg_path, &["--import"], fs::File::open(&keys)?.into(), b"")?;
+    let imported = output
+        .lines()
+        .filter(|line| line.starts_with("[GNUPG:] IMPORT_OK"))
+        .count();
+    log::debug!("imported {} keys from \"{}\"", imported, keys.display());
+
+    let trust = read_list(&dir.join(format!("{}-trusted", keyring)))?;
+    let trusted: Vec<String> = trust
+        .iter()
+        .filter_map(|line| line.split(':').next())
+        .map(str::to_owned)
+        .collect();
+    if !trust.is_empty() {
+        let mut ownertrust = trust.join("\n");
+        ownertrust.push('\n');
+        gpg(
+            &gpg_path,
+            &["--import-ownertrust"],
+            Stdio::piped(),
+            ownertrust.as_bytes(),
+        )?;
+        for fingerprint in &trusted {
+            gpg(
+                &gpg_path,
+                &["--quick-lsign-key", fingerprint.as_str()],
+                Stdio::null(),
+                b"",
+            )?;
+        }
+    }
+
+    let revoked = read_list(&dir.join(format!("{}-revoked", keyring)))?;
+    for fingerprint in &revoked {
+        gpg(
+            &gpg_path,
+            &["--command-fd", "0", "--edit-key", fingerprint.as_str()],
+            Stdio::piped(),
+            b"disable\nquit\n",
+        )?;
+    }
+
+    Ok(Populated {
+        keyring: keyring.to_owned(),
+        imported,
+        trusted,
+        revoked,
+    })
+}
+
+/// Read a list of keys, skipping blank lines and comments. A missing file is an empty list.
+fn read_list(path: &Path) -> Result<Vec<String>, Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Run `gpg` on the keyring in `home`, returning its status output.
+///
+/// If `stdin` is piped, `input` is written to it.
+fn gpg(home: &Path, args: &[&str], stdin: Stdio, input: &[u8]) -> Result<String, Error> {
+    let args: Vec<&OsStr> = args.iter().map(OsStr::new).collect();
+    let output = run_gpg(home, &args, stdin, input)?;
+    if !output.status.success() {
+        log::debug!("{}", String::from_utf8_lossy(&output.stderr));
+        return Err(ErrorKind::GpgFailed(describe(&args)).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Describe a gpg command for messages.
+fn describe(args: &[&OsStr]) -> String {
+    let args: Vec<_> = args.iter().map(|arg| arg.to_string_lossy()).collect();
+    format!("{} {}", GPG, args.join(" "))
+}
+
+/// Run `gpg` on the keyring in `home`, whether or not it succeeds.
+fn run_gpg(home: &Path, args: &[&OsStr], stdin: Stdio, input: &[u8]) -> Result<Output, Error> {
+    let description = describe(args);
+    let failed = || ErrorKind::GpgFailed(description.clone());
+    log::debug!("running {}", description);
+    let mut child = Command::new(GPG)
+        .arg("--homedir")
+        .arg(home)
+        .args(&[
+            "--batch",
+            "--yes",
+            "--no-permission-warning",
+            "--status-fd",
+            "1",
+        ])
+        .args(args)
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(failed())?;
+    if let Some(mut pipe) = child.stdin.take() {
+        pipe.write_all(input).context(failed())?;
+    }
+    child.wait_with_output().context(failed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_status() {
+        let good = "[GNUPG:] NEWSIG\n\
+                    [GNUPG:] GOODSIG 786C63F330D7CB92 Someone <someone@archlinux.org>\n\
+                    [GNUPG:] VALIDSIG 0123456789ABCDEF0123456789ABCDEF01234567 2020-01-01 1577836800 0 4 0 1 10 00 0123456789ABCDEF0123456789ABCDEF01234567\n";
+        assert_eq!(
+            parse_verify(good),
+            (
+                SignatureStatus::Valid,
+                Some("0123456789ABCDEF0123456789ABCDEF01234567".to_owned())
+            )
+        );
+        let expired = "[GNUPG:] EXPKEYSIG 786C63F330D7CB92 Someone <someone@archlinux.org>\n";
+        assert_eq!(
+            parse_verify(expired),
+            (
+                SignatureStatus::ExpiredKey,
+                Some("786C63F330D7CB92".to_owned())
+            )
+        );
+        let missing = "[GNUPG:] ERRSIG 786C63F330D7CB92 1 10 00 1577836800 9 -\n\
+                       [GNUPG:] NO_PUBKEY 786C63F330D7CB92\n";
+        assert_eq!(parse_verify(missing).0, SignatureStatus::UnknownKey);
+        assert_eq!(parse_verify("").0, SignatureStatus::Bad);
+    }
+
+    #[test]
+    fn key_expiry() {
+        let output = "tru::1:1577836800:0:3:1:5\n\
+                      pub:f:4096:1:786C63F330D7CB92:1262304000:1893456000::-:::scSC::::::23::0:\n\
+                      fpr:::::::::0123456789ABCDEF0123456789ABCDEF01234567:\n\
+                      uid:f::::1262304000::HASH::Someone <someone@archlinux.org>::::::::::0:\n\
+                      sub:f:4096:1:1111111111111111:1262304000:1893456000:::::e::::::23:\n\
+                      fpr:::::::::1111111111111111111111111111111111111111:\n\
+                      pub:e:4096:1:2222222222222222:1262304000:1262390400::-:::sc::::::23::0:\n\
+                      fpr:::::::::2222222222222222222222222222222222222222:\n\
+                      pub:f:4096:1:3333333333333333:1262304000:::-:::scSC::::::23::0:\n\
+                      fpr:::::::::3333333333333333333333333333333333333333:\n\
+                      pub:f:4096:1:4444444444444444:1262304000:1893456000::-:::scSCD::::::23::0:\n\
+                      fpr:::::::::4444444444444444444444444444444444444444:\n";
+        let keys = parse_keys(output);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            keys[0].0.fingerprint,
+            "0123456789ABCDEF0123456789ABCDEF01234567"
+        );
+        assert_eq!(
+            keys[0].0.uid.as_ref().map(String::as_str),
+            Some("Someone <someone@archlinux.org>")
+        );
+        assert_eq!(
+            keys[0].0.expires,
+            UNIX_EPOCH + Duration::from_secs(1_893_456_000)
+        );
+        assert!(!keys[0].1);
+        assert_eq!(
+            keys[1].0.fingerprint,
+            "2222222222222222222222222222222222222222"
+        );
+        assert!(keys[1].1);
+    }
+}
@@ -0,0 +1,100 @@
+//! Where things live under the root.
+
+use std::path::{Path, PathBuf};
+
+use crate::db::{LOCAL_DB_NAME, SYNC_DB_DIR};
+
+/// Where the databases, log file and hooks live, for distributions and images that don't use
+/// pacman's layout.
+///
+/// Relative paths are relative to the root path; absolute paths are used as they are. The
+/// default is pacman's layout: databases in `var/lib/pacman` (sync databases in `sync`, the local
+/// database in `local`), the log in `var/log/pacman.log`, and hooks from installed packages in
+/// `usr/share/libalpm/hooks`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Layout {
+    database_directory: PathBuf,
+    sync_directory: String,
+    local_directory: String,
+    log_file: PathBuf,
+    hook_directories: Vec<PathBuf>,
+}
+
+impl Layout {
+    /// Pacman's layout.
+    pub fn new() -> Layout {
+        Layout::default()
+    }
+
+    /// Keep the databases in this directory.
+    ///
+    /// `AlpmBuilder::with_database_path` takes precedence over this.
+    pub fn with_database_directory(mut self, database_directory: impl Into<PathBuf>) -> Self {
+        self.database_directory = database_directory.into();
+        self
+    }
+
+    /// Keep the sync databases in this directory of the database directory.
+    pub fn with_sync_directory(mut self, sync_directory: impl Into<String>) -> Self {
+        self.sync_directory = sync_directory.into();
+        self
+    }
+
+    /// Keep the local database in this directory of the database directory.
+    pub fn with_local_directory(mut self, local_directory: impl Into<String>) -> Self {
+        self.local_directory = local_directory.into();
+        self
+    }
+
+    /// Use this log file.
+    pub fn with_log_file(mut self, log_file: impl Into<PathBuf>) -> Self {
+        self.log_file = log_file.into();
+        self
+    }
+
+    /// Look for hooks from installed packages in these directories, instead of
+    /// `usr/share/libalpm/hooks`.
+    ///
+    /// Later directories take precedence, as with `AlpmBuilder::with_hook_directory`.
+    pub fn with_hook_directories(mut self, hook_directories: Vec<PathBuf>) -> Self {
+        self.hook_directories = hook_directories;
+        self
+    }
+
+    /// The directory holding the databases.
+    pub fn database_directory(&self) -> &Path {
+        &self.database_directory
+    }
+
+    /// The name of the directory holding the sync databases.
+    pub fn sync_directory(&self) -> &str {
+        &self.sync_directory
+    }
+
+    /// The name of the directory holding the local database.
+    pub fn local_directory(&self) -> &str {
+        &self.local_directory
+    }
+
+    /// The log file.
+    pub fn log_file(&self) -> &Path {
+        &self.log_file
+    }
+
+    /// The directories holding hooks from installed packages.
+    pub fn hook_directories(&self) -> &[PathBuf] {
+        &self.hook_directories
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout {
+            database_directory: "var/lib/pacman".into(),
+            sync_directory: SYNC_DB_DIR.into(),
+            local_directory: LOCAL_DB_NAME.into(),
+            log_file: "var/log/pacman.log".into(),
+            hook_directories: vec!["usr/share/libalpm/hooks".into()],
+        }
+    }
+}
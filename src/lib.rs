@@ -5,41 +5,108 @@
 #[cfg(not(unix))]
 compile_error!("Only works on unix for now");
 
+mod capabilities;
+mod dependency;
+mod diagnostics;
+mod download;
 mod error;
+mod event;
+mod extract;
+mod hash;
+mod layout;
+mod lock;
 //mod signing;
 mod util;
-mod version;
 
 pub mod alpm_desc;
 pub mod db;
+pub mod downloader;
+pub mod health;
+pub mod hook;
+pub mod keys;
+pub mod license;
+pub mod listing;
+pub mod metrics;
+pub mod mtree_writer;
 pub mod mutation;
 mod package;
+mod per_db;
+pub mod question;
+mod sandbox;
+pub mod script;
+mod search_index;
+pub mod statistics;
+mod target;
+pub mod version;
 
+use crate::alpm_desc::de::DuplicateKeyPolicy;
 use crate::db::{
-    LocalDatabase, LocalDatabaseInner, SignatureLevel, SyncDatabase, SyncDatabaseInner, SyncDbName,
-    DEFAULT_SYNC_DB_EXT, SYNC_DB_DIR,
+    is_valid_db_extension, Database, DuplicatePolicy, DynDatabase, FileMatch, LocalDatabase,
+    LocalDatabaseInner, Located, SignatureLevel, SyncDatabase, SyncDatabaseInner,
+    SyncDatabaseOptions, SyncDatabaseRef, SyncDbName, SyncPackage, ValidationExclusions,
+    ValidationSummary, DEFAULT_SYNC_DB_EXT,
 };
+use crate::diagnostics::{Diagnostics, DEFAULT_WARNING_WINDOW};
+use crate::downloader::Downloader;
+use crate::event::Events;
+use crate::lock::DatabaseLock;
+use crate::metrics::Metrics;
+use crate::question::{NonInteractive, Questions};
+use crate::script::{ChrootRunner, ScriptCommand, ScriptRunner};
+use crate::search_index::SearchIndex;
 
-use lockfile::Lockfile;
+use indexmap::IndexMap;
 use uname::uname;
 
 use std::{
     cell::RefCell,
-    collections::{HashMap, HashSet},
-    io,
-    path::PathBuf,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    path::{Path, PathBuf},
     rc::Rc,
+    time::{Duration, Instant},
 };
 
 pub use crate::{
-    error::{Error, ErrorContext, ErrorKind},
+    capabilities::{capabilities, Capabilities},
+    dependency::{Depend, DependIter, Dependency},
+    diagnostics::Warning,
+    download::{IntegrityPolicy, RetryPolicy},
+    error::{Error, ErrorContext, ErrorKind, Errors},
+    event::Event,
+    layout::Layout,
     package::{Package, PackageKey},
+    per_db::PerDb,
+    target::{Comparison, Target, VersionConstraint},
+    util::LockMode,
 };
 
 /// The name of the lockfile (hard-coded).
 const LOCKFILE: &str = "db.lck";
 
+/// Environment variables read by `AlpmBuilder::with_env_overrides`.
+const ENV_ROOT: &str = "ALPM_ROOT";
+const ENV_DB_PATH: &str = "ALPM_DB_PATH";
+const ENV_CACHE_DIRS: &str = "ALPM_CACHE_DIRS";
+
+/// The body of the `alpm-extract` helper program, which extracts a package in a sandbox (see
+/// `AlpmBuilder::with_extraction_sandbox`). Returns the exit code.
+#[doc(hidden)]
+pub fn extraction_helper_main() -> i32 {
+    extract::helper_main()
+}
+
 /// The main alpm object that owns the system handle.
+///
+/// Cloning is cheap, and gives another handle to the same instance: the same databases, options,
+/// callbacks and locks. Changes made through one clone, like registering a sync database, are seen
+/// by all of them. The instance lives until the last clone is dropped or released.
+///
+/// The instance isn't thread safe, and uses interior mutability, so clones share it within a
+/// thread. Methods only borrow it while they run, but calling back into the instance from a
+/// callback (a warning or event callback, `Questions`, and so on) may panic.
+#[derive(Clone)]
 pub struct Alpm {
     handle: Rc<RefCell<Handle>>,
 }
@@ -75,8 +142,208 @@ impl Alpm {
         })
     }
 
+    /// Validate every installed package against its metadata, spreading the work over
+    /// `parallelism` threads.
+    ///
+    /// Files that are expected to change are skipped; see
+    /// `AlpmBuilder::with_validation_exclusions`. I/O problems are returned as errors; if a
+    /// validation thread panics, the panic carries on in the calling thread, as if the packages
+    /// had been validated there.
+    pub fn validate_all(&self, parallelism: usize) -> Result<ValidationSummary, Error> {
+        let mut packages = Vec::new();
+        self.local_database()
+            .packages(|package| -> Result<(), Error> {
+                packages.push(package);
+                Ok(())
+            })?;
+        let handle = self.handle.borrow();
+        Ok(db::validate_all(
+            &packages,
+            &handle.root_path,
+            &handle.validation_exclusions,
+            parallelism,
+        )?)
+    }
+
+    /// The licenses of every installed package, normalized to SPDX identifiers where possible.
+    pub fn license_report(&self) -> Result<license::LicenseReport, Error> {
+        let mut packages = Vec::new();
+        self.local_database()
+            .packages(|package| -> Result<(), Error> {
+                packages.push(package);
+                Ok(())
+            })?;
+        Ok(license::LicenseReport::new(&packages))
+    }
+
+    /// Find a package in the sync databases, taking priorities and pins into account.
+    ///
+    /// Packages built for other architectures are skipped, unless
+    /// `AlpmBuilder::with_foreign_architectures` is set. Returns `None` if no database has the
+    /// package.
+    pub fn find_package(&self, name: &str) -> Option<Located<Rc<SyncPackage>>> {
+        let handle = self.handle.borrow();
+        handle.sync_databases_for(name).into_iter().find_map(|db| {
+            db.package_latest(name)
+                .ok()
+                .filter(|package| !handle.skip_architecture(&**package))
+                .map(|package| Located::new(db, package))
+        })
+    }
+
+    /// Search the sync databases for packages whose name, description or provisions contain
+    /// `query`, ignoring case.
+    ///
+    /// Results are grouped by database, in order of preference, and sorted by name within each
+    /// database. Databases that can't be loaded are listed in the failures, and the others are
+    /// still searched. `PerDb::into_flat` gives all the results in one list.
+    ///
+    /// If the search index is enabled (see `AlpmBuilder::with_search_index`) it is used instead
+    /// of looking at every package. Packages built for other architectures are left out, unless
+    /// `AlpmBuilder::with_foreign_architectures` is set.
+    pub fn search(&self, query: &str) -> PerDb<Vec<Located<Rc<SyncPackage>>>> {
+        let handle = self.handle.borrow();
+        let databases = handle.ordered_sync_databases();
+        let mut results = PerDb::new();
+        if handle.use_search_index {
+            let index = handle.search_index(&databases);
+            let mut found: Vec<Vec<_>> = databases.iter().map(|_| Vec::new()).collect();
+            for (idx, name) in index.search(query) {
+                let db = &databases[idx];
+                if let Ok(package) = db.package_latest(name) {
+                    if !handle.skip_architecture(&*package) {
+                        found[idx].push(Located::new(db.clone(), package));
+                    }
+                }
+            }
+            for (db, found) in databases.iter().zip(found) {
+                results.record(db.name(), db.load().map(|()| found));
+            }
+            return results;
+        }
+        let query = query.to_lowercase();
+        for db in databases {
+            let mut found = Vec::new();
+            let result = db.packages(|package| -> Result<(), Error> {
+                if handle.skip_architecture(&*package) {
+                    return Ok(());
+                }
+                if package.name().to_lowercase().contains(&query)
+                    || package.description().to_lowercase().contains(&query)
+                    || package
+                        .provides()
+                        .iter()
+                        .any(|provision| provision.to_lowercase().contains(&query))
+                {
+                    found.push(package);
+                }
+                Ok(())
+            });
+            let found = found
+                .into_iter()
+                .map(|package| Located::new(db.clone(), package))
+                .collect();
+            results.record(db.name(), result.map(|()| found));
+        }
+        results
+    }
+
+    /// Find the packages in the sync databases that contain a file, like `pacman -F`.
+    ///
+    /// A pattern containing a `/` must match the whole path of a file (a leading `/` is
+    /// ignored), otherwise it must match the file name. Results are grouped by database, in order
+    /// of preference, and sorted by path within each database.
+    ///
+    /// Only databases registered with `SyncDatabaseOptions::with_file_lists` and synchronized
+    /// since are searched; others are skipped.
+    pub fn search_files(&self, pattern: &str) -> Vec<FileMatch> {
+        let mut results = Vec::new();
+        for db in self.handle.borrow().ordered_sync_databases() {
+            match db.search_files(pattern) {
+                Ok(found) => results.extend(found),
+                Err(e) => log::debug!("skipping database {}: {}", db.name(), e),
+            }
+        }
+        results
+    }
+
+    /// The installed packages that have newer versions in the sync databases, grouped by the
+    /// database each new version comes from and sorted by name.
+    ///
+    /// Database priorities and package pins decide where each new version comes from. Sync
+    /// databases that can't be loaded are listed in the failures, and upgrades are looked for in
+    /// the others, except for packages a failed database comes before; only a problem with the
+    /// local database is an error.
+    pub fn upgradable(&self) -> Result<PerDb<Vec<mutation::Upgrade>>, Error> {
+        mutation::upgradable(&self.handle, &self.local_database())
+    }
+
+    /// Synchronize every sync database, in order of preference.
+    ///
+    /// A database that fails to synchronize is listed in the failures, and the others are still
+    /// synchronized.
+    pub fn synchronize_all(&self, force: bool) -> PerDb<()> {
+        let databases = self.handle.borrow().ordered_sync_databases();
+        mutation::synchronize_all(&databases, force)
+    }
+
+    /// How much each upgradable package would grow or shrink on disk, and how much needs
+    /// downloading, largest growth first.
+    ///
+    /// This answers "what is using up my disk after upgrading" without planning an upgrade. Fails
+    /// if any sync database can't be loaded, as `MutationPlan::sysupgrade` does.
+    pub fn upgrade_impact(&self) -> Result<Vec<mutation::UpgradeImpact>, Error> {
+        mutation::upgrade_impact(&self.handle, &self.local_database())
+    }
+
+    /// Download upgradable packages into the first cache directory ahead of time, so a later
+    /// upgrade doesn't have to wait for the network.
+    ///
+    /// At most `limit_bytes` are downloaded, at the rate set by
+    /// `AlpmBuilder::with_prefetch_rate_limit`. Archives already in a cache are kept, and a
+    /// download that is cut off leaves a `.part` file that the next call resumes, so calling this
+    /// again (for example from a timer while the machine is idle) carries on with the packages
+    /// that were deferred or failed. Fails if any sync database can't be loaded.
+    pub fn prefetch_upgrades(&self, limit_bytes: u64) -> Result<mutation::PrefetchReport, Error> {
+        self.handle.borrow().check_writable()?;
+        mutation::prefetch_upgrades(&self.handle, &self.local_database(), limit_bytes)
+    }
+
+    /// Whether the package is ignored during upgrades, and why, so frontends can mark it as
+    /// pacman does (see `AlpmBuilder::with_ignore_package` and `AlpmBuilder::with_ignore_group`).
+    pub fn is_ignored(&self, package: &impl Package) -> Option<mutation::IgnoreReason> {
+        self.handle.borrow().ignore_reason(package)
+    }
+
+    /// Start planning a change to the system.
+    pub fn plan(&self) -> mutation::MutationPlan {
+        mutation::MutationPlan::new(Rc::downgrade(&self.handle), self.local_database())
+    }
+
+    /// Start planning to install packages along with everything they depend on.
+    pub fn resolver(&self) -> mutation::Resolver {
+        mutation::Resolver::new(Rc::downgrade(&self.handle), self.local_database())
+    }
+
+    /// The local database followed by every sync database, in order of preference.
+    ///
+    /// This is for code that treats all databases the same way; see `DynDatabase`.
+    pub fn databases(&self) -> Vec<DynDatabase> {
+        let mut databases = vec![DynDatabase::from(self.local_database())];
+        databases.extend(
+            self.handle
+                .borrow()
+                .ordered_sync_databases()
+                .into_iter()
+                .map(DynDatabase::from),
+        );
+        databases
+    }
+
     /// Get a sync database with the given name for this alpm instance.
     ///
+    /// If the database is not registered, it is registered with the default options.
+    ///
     /// The database is only valid while the `Alpm` instance is in scope. Once it is dropped, all
     /// calls to the database will error.
     pub fn sync_database(&self, name: impl AsRef<str>) -> Result<SyncDatabase, Error> {
@@ -91,13 +358,40 @@ impl Alpm {
         // Second stage to release borrow
         let db = match db {
             Some(db) => db,
-            None => self.register_sync_database(&db_name),
+            None => self.insert_sync_database(&db_name, &SyncDatabaseOptions::default())?,
         };
 
         let name = db_name.into();
         Ok(SyncDatabase::new(db, name))
     }
 
+    /// Like `sync_database`, but the database borrows this alpm instance.
+    ///
+    /// The borrow means the compiler checks the database isn't used after the instance is dropped
+    /// or released, or after the database is unregistered, rather than failing with
+    /// `ErrorKind::UseAfterDrop` at runtime.
+    pub fn sync_database_ref(&self, name: impl AsRef<str>) -> Result<SyncDatabaseRef<'_>, Error> {
+        self.sync_database(name).map(SyncDatabaseRef::new)
+    }
+
+    /// Register a new sync database with the given options.
+    ///
+    /// This is like a `[repo]` section in `pacman.conf`: anything not set in the options is
+    /// inherited from this alpm instance. It is an error to register a database twice.
+    pub fn register_sync_database(
+        &self,
+        name: impl AsRef<str>,
+        options: SyncDatabaseOptions,
+    ) -> Result<SyncDatabase, Error> {
+        let db_name = SyncDbName::new(name.as_ref())?;
+        if self.handle.borrow().sync_database_registered(&db_name) {
+            return Err(ErrorKind::DatabaseAlreadyExists(db_name.into()).into());
+        }
+        let db = self.insert_sync_database(&db_name, &options)?;
+        Ok(SyncDatabase::new(db, db_name.into()))
+    }
+
+    /// Run a callback on each registered sync database, in the order they were registered.
     pub fn sync_databases<F>(&self, mut f: F)
     where
         F: FnMut(SyncDatabase),
@@ -107,23 +401,31 @@ impl Alpm {
         }
     }
 
-    /// Register a new sync database
+    /// Create a new sync database and add it to the handle.
     ///
-    /// The name must not match `LOCAL_DB_NAME`.
-    fn register_sync_database(&self, name: &SyncDbName) -> Rc<RefCell<SyncDatabaseInner>> {
+    /// The name must not match `LOCAL_DB_NAME`, and must not already be registered.
+    fn insert_sync_database(
+        &self,
+        name: &SyncDbName,
+        options: &SyncDatabaseOptions,
+    ) -> Result<Rc<RefCell<SyncDatabaseInner>>, Error> {
+        if let Some(ext) = options.extension() {
+            if !is_valid_db_extension(ext) {
+                return Err(ErrorKind::BadSyncDatabaseExt(ext.to_owned()).into());
+            }
+        }
         let handle = self.handle.clone();
-        let new_db = SyncDatabaseInner::new(handle, name.clone(), SignatureLevel::default());
+        let mut new_db = SyncDatabaseInner::new(handle, name.clone(), options)?;
+        for server in options.servers() {
+            new_db.add_server(server.as_str())?;
+        }
         let new_db = Rc::new(RefCell::new(new_db));
-        if self
-            .handle
-            .borrow_mut()
-            .sync_databases
-            .insert(name.clone(), new_db.clone())
-            .is_some()
-        {
-            panic!(r#"internal error: database "{}" already registered"#, name);
-        };
-        new_db
+        let mut handle = self.handle.borrow_mut();
+        if handle.sync_database_registered(name) {
+            return Err(ErrorKind::DatabaseAlreadyExists(name.clone().into()).into());
+        }
+        handle.sync_databases.insert(name.clone(), new_db.clone());
+        Ok(new_db)
     }
 
     /// Are there any databases already registered with the given name
@@ -149,17 +451,11 @@ impl Alpm {
                 return;
             }
         };
-        if !self
-            .handle
-            .borrow_mut()
-            .sync_databases
-            .remove(&name)
-            .is_none()
-        {
-            log::warn!(
-                "could not unregister a database with name \"{}\" (not found)",
-                name
-            );
+        let removed = self.handle.borrow_mut().sync_databases.shift_remove(&name);
+        if removed.is_none() {
+            self.handle
+                .borrow()
+                .warn(Warning::DatabaseNotRegistered(name.into()));
         }
     }
 
@@ -179,7 +475,22 @@ impl Alpm {
         self.handle.borrow().database_path.clone()
     }
 
-    /// Get the parent database path
+    /// Get the architectures packages may be built for, besides "any".
+    pub fn architectures(&self) -> Vec<String> {
+        self.handle.borrow().architectures.clone()
+    }
+
+    /// Get the files that validation skips.
+    pub fn validation_exclusions(&self) -> ValidationExclusions {
+        self.handle.borrow().validation_exclusions.clone()
+    }
+
+    /// Get the path of the log file.
+    pub fn log_path(&self) -> PathBuf {
+        self.handle.borrow().log_path.clone()
+    }
+
+    /// Get the extension used for sync databases
     pub fn database_extension(&self) -> String {
         self.handle.borrow().database_extension.clone()
     }
@@ -188,6 +499,302 @@ impl Alpm {
     pub fn root_path(&self) -> PathBuf {
         self.handle.borrow().root_path.clone()
     }
+
+    /// Unpack the files of a package archive into the root path.
+    ///
+    /// This only writes the files: it doesn't check dependencies, run scripts or record the
+    /// package in the local database.
+    pub fn extract_package(&self, archive: impl AsRef<Path>) -> Result<(), Error> {
+        let handle = self.handle.borrow();
+        handle.check_writable()?;
+        let start = Instant::now();
+        let result = extract::extract_package(
+            archive.as_ref(),
+            &handle.root_path,
+            handle.extraction_helper.as_ref().map(PathBuf::as_path),
+        );
+        handle.measure(|metrics| metrics.package_extracted(start.elapsed(), result.is_ok()));
+        result
+    }
+
+    /// Unpack the archives of the packages a plan installs into the root path, taking them from
+    /// the cache directories.
+    ///
+    /// Each batch of `MutationPlan::extraction_batches` is extracted on up to `parallelism`
+    /// threads. Packages in a batch that would write the same path are extracted one after the
+    /// other, in plan order. Once a batch is done, `extracted` is called for each of its packages
+    /// in turn on this thread, so scriptlets and hooks still run one at a time, after the packages
+    /// they need. If a package fails, the packages being extracted alongside it are finished, and
+    /// the first error is returned without extracting any more. Sandboxed extraction (see
+    /// `AlpmBuilder::with_extraction_sandbox`) runs one package at a time on this thread.
+    ///
+    /// Like `extract_package`, this only writes the files. If `extracted` records a package in the
+    /// local database, its provenance (see `LocalPackage::provenance`) is added to the entry.
+    pub fn extract_plan(
+        &self,
+        plan: &mutation::MutationPlan,
+        parallelism: usize,
+        mut extracted: impl FnMut(&mutation::PlannedInstall) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let (root, helper, cache_directories) = {
+            let handle = self.handle.borrow();
+            handle.check_writable()?;
+            (
+                handle.root_path.clone(),
+                handle.extraction_helper.clone(),
+                handle.cache_directories.clone(),
+            )
+        };
+        for batch in plan.extraction_batches() {
+            let mut archives = Vec::new();
+            for planned in &batch {
+                let archive = planned
+                    .package
+                    .find_archive(
+                        cache_directories.iter().map(PathBuf::as_path),
+                        &self.handle.borrow(),
+                    )
+                    .ok_or_else(|| {
+                        ErrorKind::PackageNotCached(planned.package.name().to_owned())
+                    })?;
+                archives.push(archive);
+            }
+            let paths = archives
+                .iter()
+                .map(|archive| extract::written_paths(archive))
+                .collect::<Result<Vec<_>, Error>>()?;
+            for group in extract::without_overlaps(&paths) {
+                let timed = |archive: &Path, root: &Path, helper: Option<&Path>| {
+                    let start = Instant::now();
+                    let result = extract::extract_package(archive, root, helper);
+                    (start.elapsed(), result)
+                };
+                let results = match &helper {
+                    Some(helper) => group
+                        .iter()
+                        .map(|&idx| timed(&archives[idx], &root, Some(helper)))
+                        .collect(),
+                    None => {
+                        let jobs = group
+                            .iter()
+                            .map(|&idx| (archives[idx].clone(), root.clone()))
+                            .collect();
+                        hash::par_map(jobs, parallelism, move |(archive, root)| {
+                            timed(&archive, &root, None)
+                        })
+                    }
+                };
+                let mut first_error = None;
+                for (elapsed, result) in results {
+                    self.handle
+                        .borrow()
+                        .measure(|metrics| metrics.package_extracted(elapsed, result.is_ok()));
+                    if let Err(e) = result {
+                        first_error.get_or_insert(e);
+                    }
+                }
+                if let Some(e) = first_error {
+                    return Err(e);
+                }
+            }
+            for planned in batch {
+                extracted(planned)?;
+                let provenance = db::Provenance {
+                    repository: Some(planned.database.clone()),
+                    sha256: planned.package.sha256sum().map(str::to_owned),
+                    ..db::Provenance::default()
+                };
+                self.local_database().record_provenance(
+                    planned.package.name(),
+                    planned.package.version(),
+                    &provenance,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The hooks in the hook directories, sorted by name (the order they would run in).
+    ///
+    /// Hooks replaced by a hook with the same name in a later directory, and disabled hooks, are
+    /// left out. This fails if any hook is invalid.
+    pub fn hooks(&self) -> Result<Vec<hook::Hook>, Error> {
+        hook::load_hooks(&self.handle.borrow().hook_directories)
+    }
+
+    /// The hooks that would run if a plan were carried out, with the targets that would fire
+    /// them, without running anything.
+    ///
+    /// Path triggers can only match the files of installed packages, and of new packages from
+    /// sync databases whose file list has been downloaded (see
+    /// `SyncDatabaseOptions::with_file_lists`).
+    pub fn hooks_for_plan(
+        &self,
+        plan: &mutation::MutationPlan,
+    ) -> Result<Vec<hook::HookMatch>, Error> {
+        let hooks = self.hooks()?;
+        let mut changes = hook::Changes::default();
+        for install in plan.installs() {
+            let name = install.package.name();
+            let new_files = self
+                .sync_database(&install.database)
+                .and_then(|db| db.package_files(name))
+                .map_err(|e| log::debug!("files of {} are not known: {}", name, e))
+                .ok();
+            let old_files = install.upgrades.as_ref().map(|old| hook::local_files(old));
+            changes.install(name, new_files, old_files);
+        }
+        for removal in plan.removals() {
+            changes.remove(removal.name(), hook::local_files(removal));
+        }
+        Ok(hook::triggered(hooks, &changes))
+    }
+
+    /// Run a hook's command in the root, using the script runner (see
+    /// `AlpmBuilder::with_script_runner`).
+    ///
+    /// If the hook `needs_targets`, the matched targets are given on standard input, one per
+    /// line. The command is split on whitespace; quoting is not supported. The hook's `depends`
+    /// are not checked. Like `run_script`, this fails with `ErrorKind::ReadOnly` on a read-only
+    /// instance.
+    pub fn run_hook(&self, hook: &hook::HookMatch) -> Result<(), Error> {
+        let args = hook
+            .hook
+            .exec
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect();
+        let mut command = ScriptCommand::new(self.root_path(), args);
+        if hook.hook.needs_targets {
+            let mut targets = hook.targets.join("\n");
+            targets.push('\n');
+            command = command.with_stdin(targets.into_bytes());
+        }
+        self.run_script(&command)
+    }
+
+    /// Run a command in the root, using the script runner (see
+    /// `AlpmBuilder::with_script_runner`).
+    ///
+    /// This fails if the command can't be run or exits unsuccessfully. Scripts change the root,
+    /// so a read-only instance, which may be running alongside a writer, fails with
+    /// `ErrorKind::ReadOnly` instead.
+    pub fn run_script(&self, command: &ScriptCommand) -> Result<(), Error> {
+        // The runner may take a while, so the handle isn't kept borrowed.
+        let runner = {
+            let handle = self.handle.borrow();
+            handle.check_writable()?;
+            handle.script_runner.clone()
+        };
+        let status = runner
+            .run(command)
+            .context(ErrorKind::ScriptFailed(command.to_string()))?;
+        if !status.success() {
+            return Err(ErrorKind::ScriptFailed(command.to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// Check the signatures of the sync databases and cached packages against the keyring, and
+    /// find keys that have expired or will expire within `within`.
+    ///
+    /// This finds problems that would otherwise only show up when installing. Files without a
+    /// signature are skipped.
+    pub fn audit_signatures(&self, within: Duration) -> Result<keys::SignatureAudit, Error> {
+        keys::audit_signatures(self, within)
+    }
+
+    /// Counts and sizes describing this installation, gathered in one pass.
+    ///
+    /// This fails if a sync database can't be loaded.
+    pub fn statistics(&self) -> Result<statistics::Statistics, Error> {
+        statistics::statistics(self)
+    }
+
+    /// Check the local database, every registered sync database, the lockfile, the cache
+    /// directories and the gpg directory, and report any problems.
+    pub fn health_check(&self) -> health::HealthReport {
+        health::check(self)
+    }
+
+    /// Packages that are in more than one sync database with different versions, sorted by name.
+    ///
+    /// Which database wins can be surprising, so this helps explain why a particular version is
+    /// chosen.
+    pub fn shadowed_packages(&self) -> Vec<health::ShadowedPackage> {
+        health::shadowed_packages(self)
+    }
+
+    /// Installed packages that disappeared from their sync database when it was synchronized by
+    /// this instance, and aren't available from any other, sorted by name.
+    ///
+    /// An `Event::PackagesDropped` is also emitted when this is noticed. Packages that come back,
+    /// or that are removed, are no longer reported.
+    pub fn dropped_packages(&self) -> Result<Vec<health::DroppedPackage>, Error> {
+        health::dropped_packages(self)
+    }
+
+    /// Take all the warnings that have occurred since the last call to this method.
+    ///
+    /// Warnings are problems that did not stop an operation from completing, but that the user
+    /// may want to know about. A warning that repeats one already reported in the same window
+    /// (see `AlpmBuilder::with_warning_window`) is left out, as are warnings over the limit set
+    /// with `AlpmBuilder::with_warning_limit`.
+    pub fn take_warnings(&self) -> Vec<Warning> {
+        self.handle.borrow().diagnostics.borrow_mut().take()
+    }
+
+    /// Set a function to be called whenever a warning occurs.
+    ///
+    /// Warnings are still stored for `take_warnings`. The callback must not call back into this
+    /// alpm instance.
+    pub fn set_warning_callback(&self, callback: impl FnMut(&Warning) + 'static) {
+        self.handle
+            .borrow()
+            .diagnostics
+            .borrow_mut()
+            .set_callback(Some(Box::new(callback)));
+    }
+
+    /// Set a function to be called with progress events during long-running operations.
+    ///
+    /// The callback must not call back into this alpm instance.
+    pub fn set_event_callback(&self, callback: impl FnMut(&Event) + 'static) {
+        self.handle
+            .borrow()
+            .events
+            .borrow_mut()
+            .set_callback(Some(Box::new(callback)));
+    }
+
+    /// Close this alpm instance, releasing its locks and removing the lockfile if it has one.
+    ///
+    /// Dropping the instance does the same, but can't report errors. Database writes happen as
+    /// each operation completes, so the locks are all that is left to clean up. Databases and
+    /// packages from this instance can't be used afterwards.
+    ///
+    /// If there are other clones of this instance, only this clone is dropped, and the instance
+    /// is closed when the last one is released or dropped.
+    pub fn release(self) -> Result<(), Error> {
+        if Rc::strong_count(&self.handle) > 1 {
+            return Ok(());
+        }
+        let mut handle = self.handle.borrow_mut();
+        match handle.lock.take() {
+            Some(lock) => lock.release(&handle.database_path),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `self` and `other` are clones of the same instance.
+    pub fn same_instance(&self, other: &Alpm) -> bool {
+        Rc::ptr_eq(&self.handle, &other.handle)
+    }
+
+    /// Whether this instance was opened read-only (see `AlpmBuilder::with_read_only`).
+    pub fn is_read_only(&self) -> bool {
+        self.handle.borrow().read_only
+    }
 }
 
 /// Handle to an alpm instance. Uses a lockfile to prevent concurrent processes accessing the
@@ -196,30 +803,42 @@ impl Alpm {
 struct Handle {
     /// The local package database
     local_database: Option<Rc<RefCell<LocalDatabaseInner>>>,
-    /// A list of all sync databases
+    /// A list of all sync databases, in the order they were registered.
     ///
     /// We can access these concurrently, as they manage their own mutability.
-    sync_databases: HashMap<SyncDbName, Rc<RefCell<SyncDatabaseInner>>>,
+    sync_databases: IndexMap<SyncDbName, Rc<RefCell<SyncDatabaseInner>>>,
     /// Managed filesystem root (normally this will be "/")
     root_path: PathBuf,
     /// The path of the alpm package database
     database_path: PathBuf,
+    /// The directory holding the sync databases.
+    sync_database_path: PathBuf,
+    /// The directory holding the local database.
+    local_database_path: PathBuf,
+    /// The log file.
+    log_path: PathBuf,
     /// The extension to use for sync databases
     database_extension: String,
-    /// The lockfile, preventing multiple processes
-    /// interacting with the database concurrently.
-    #[allow(unused)]
-    lockfile: Lockfile,
+    /// The locks, preventing other processes writing to the database while we use it.
+    ///
+    /// This is only `None` while the instance is being released.
+    lock: Option<DatabaseLock>,
+    /// Whether we only read, so don't hold `db.lck`.
+    read_only: bool,
     /// Path to the directory where gpg files are stored
     gpg_path: PathBuf,
     /// List of paths to the cache directories
     cache_directories: Vec<PathBuf>,
-    /// List of paths to the hook directories
-    hook_dirs_paths: HashSet<PathBuf>,
+    /// The hook directories, in increasing order of precedence.
+    hook_directories: Vec<PathBuf>,
     /// List of paths that may be overwritten
     overwrite_file_paths: HashSet<PathBuf>,
     /// List of packages not to upgrade.
     packages_no_upgrade: HashSet<String>,
+    /// List of packages that must not be removed.
+    packages_hold: HashSet<String>,
+    /// Packages that must come from a particular sync database, by package name.
+    package_pins: HashMap<String, String>,
     /// List of packages not to extract.
     packages_no_extract: HashSet<String>,
     /// List of packages to ignore.
@@ -228,12 +847,26 @@ struct Handle {
     groups_ignore: HashSet<String>,
     /// List of virtual packages used to satisfy dependencies.
     packages_assume_installed: HashSet<String>,
-    /// The architecture of the packages to be installed.
-    arch: String,
+    /// The architectures of the packages that may be installed (besides "any").
+    architectures: Vec<String>,
+    /// Whether searches and target resolution include packages for other architectures.
+    foreign_architectures: bool,
     /// Download deltas if possible; a ratio value.
     delta_ratio: f64,
+    /// The most bytes per second `prefetch_upgrades` downloads, if it is limited.
+    prefetch_rate_limit: Option<u64>,
     /// Whether to check free disk space before installing.
     check_space: bool,
+    /// What to do when a database file we want to write is locked.
+    lock_mode: LockMode,
+    /// What to do when more than one version of a package is installed.
+    duplicate_policy: DuplicatePolicy,
+    /// What to do when a key is repeated in a package's desc or files file.
+    duplicate_key_policy: DuplicateKeyPolicy,
+    /// Keys in desc files to read as other keys.
+    desc_aliases: Vec<(String, &'static str)>,
+    /// Files that validation skips.
+    validation_exclusions: ValidationExclusions,
     // database_extension: String,
     ///// The signature veritification level to use when databases or packages inherit.
     // signature_level: SignatureLevel,
@@ -241,6 +874,33 @@ struct Handle {
     // remote_files_signature_level: SignatureLevel,
     /// Cached reqwest client, for speed
     http_client: reqwest::Client,
+    /// How failed network requests are retried.
+    retry_policy: RetryPolicy,
+    /// What to do with downloads that fail verification.
+    integrity_policy: IntegrityPolicy,
+    /// The user to make network requests as, when running as root.
+    download_user: Option<nix::unistd::User>,
+    /// The helper program that extracts packages in a sandbox, if they are sandboxed.
+    extraction_helper: Option<PathBuf>,
+    /// Runs scriptlets and hooks in the root.
+    script_runner: Rc<dyn ScriptRunner>,
+    /// Where to send measurements, if anywhere.
+    metrics: Option<Rc<dyn Metrics>>,
+    /// Downloads package archives before the built-in client, if set.
+    downloader: Option<Rc<dyn Downloader>>,
+    /// Answers questions, like which provider to install.
+    questions: Rc<dyn Questions>,
+    /// Warnings that have not yet been taken by the user.
+    diagnostics: RefCell<Diagnostics>,
+    /// Installed packages that disappeared from a sync database when it was synchronized, with
+    /// the name of the database.
+    dropped_packages: RefCell<BTreeMap<String, String>>,
+    /// Whether `Alpm::search` uses the search index.
+    use_search_index: bool,
+    /// The search index, once it has been loaded or built.
+    search_index: RefCell<Option<Rc<SearchIndex>>>,
+    /// Where to send progress events.
+    events: RefCell<Events>,
 }
 
 impl Handle {
@@ -248,6 +908,129 @@ impl Handle {
     fn sync_database_registered(&self, name: &SyncDbName) -> bool {
         self.sync_databases.contains_key(&name)
     }
+
+    /// Fail with `ErrorKind::ReadOnly` if this instance may not write.
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            Err(ErrorKind::ReadOnly.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Record a warning.
+    fn warn(&self, warning: Warning) {
+        self.diagnostics.borrow_mut().warn(warning)
+    }
+
+    /// Report progress.
+    fn emit(&self, event: Event) {
+        self.events.borrow_mut().emit(event)
+    }
+
+    /// Report a measurement, if anyone is listening.
+    fn measure(&self, f: impl FnOnce(&dyn Metrics)) {
+        if let Some(metrics) = &self.metrics {
+            f(&**metrics)
+        }
+    }
+
+    /// The search index for the given sync databases.
+    ///
+    /// The index is loaded from the database path, and rebuilt when any of the databases has
+    /// changed since it was built. A rebuilt index is saved if the instance isn't read-only.
+    fn search_index(&self, databases: &[SyncDatabase]) -> Rc<SearchIndex> {
+        let fingerprint = SearchIndex::fingerprint_of(databases);
+        if let Some(index) = &*self.search_index.borrow() {
+            if index.fingerprint() == fingerprint {
+                return index.clone();
+            }
+        }
+        let path = self.database_path.join(search_index::INDEX_FILE);
+        let index = match SearchIndex::load(&path) {
+            Ok(index) if index.fingerprint() == fingerprint => index,
+            _ => {
+                log::debug!("building the search index");
+                let index = SearchIndex::build(databases, fingerprint);
+                // Read-only instances don't hold `db.lck`, so they leave the file alone.
+                if self.check_writable().is_ok() {
+                    if let Err(e) = index.save(&path) {
+                        log::debug!("could not save \"{}\": {}", path.display(), e);
+                    }
+                }
+                index
+            }
+        };
+        let index = Rc::new(index);
+        *self.search_index.borrow_mut() = Some(index.clone());
+        index
+    }
+
+    /// All sync databases, by priority and then registration order.
+    fn ordered_sync_databases(&self) -> Vec<SyncDatabase> {
+        let mut databases = self
+            .sync_databases
+            .iter()
+            .map(|(name, db)| SyncDatabase::new(db.clone(), name.to_string()))
+            .collect::<Vec<_>>();
+        // The sort is stable, so registration order is kept for equal priorities.
+        databases.sort_by_key(|db| Reverse(db.priority()));
+        databases
+    }
+
+    /// The sync databases to look for a package in, in order of preference.
+    ///
+    /// If the package is pinned, this is just the database it is pinned to.
+    fn sync_databases_for(&self, package: &str) -> Vec<SyncDatabase> {
+        self.ordered_sync_databases()
+            .into_iter()
+            .filter(|db| self.may_use(package, db.name()))
+            .collect()
+    }
+
+    /// Whether the package is built for an architecture that may be installed.
+    fn architecture_allowed(&self, package: &impl Package) -> bool {
+        package.is_arch_compatible(&self.architectures)
+    }
+
+    /// Whether searches and target resolution should skip the package because of its
+    /// architecture.
+    fn skip_architecture(&self, package: &impl Package) -> bool {
+        !self.foreign_architectures && !self.architecture_allowed(package)
+    }
+
+    /// Why the package is ignored, if it is.
+    ///
+    /// Ignored packages and groups may be globs, as in pacman.
+    fn ignore_reason(&self, package: &impl Package) -> Option<mutation::IgnoreReason> {
+        let name = package.name();
+        if self.packages_ignore.contains(name) {
+            return Some(mutation::IgnoreReason::Package);
+        }
+        if let Some(pattern) = self
+            .packages_ignore
+            .iter()
+            .find(|pattern| hook::fnmatch(pattern.as_bytes(), name.as_bytes()))
+        {
+            return Some(mutation::IgnoreReason::Pattern(pattern.clone()));
+        }
+        package
+            .groups()
+            .iter()
+            .find(|group| {
+                self.groups_ignore
+                    .iter()
+                    .any(|pattern| hook::fnmatch(pattern.as_bytes(), group.as_bytes()))
+            })
+            .map(|group| mutation::IgnoreReason::Group(group.clone()))
+    }
+
+    /// Whether the package may be taken from the named database, given any pins.
+    fn may_use(&self, package: &str, database: &str) -> bool {
+        self.package_pins
+            .get(package)
+            .map_or(true, |pinned| pinned == database)
+    }
 }
 
 /// Builder-pattern constructor for the Alpm struct.
@@ -268,8 +1051,68 @@ pub struct AlpmBuilder {
     cache_directories: Vec<PathBuf>,
     /// A set of packages to skip during upgrade.
     packages_no_upgrade: HashSet<String>,
-    /// The architecture to use when installing packages.
-    arch: Option<String>,
+    /// A set of packages that must not be removed.
+    packages_hold: HashSet<String>,
+    /// A set of packages to leave out of upgrades.
+    packages_ignore: HashSet<String>,
+    /// A set of groups whose packages are left out of upgrades.
+    groups_ignore: HashSet<String>,
+    /// The sync database each pinned package must come from.
+    package_pins: HashMap<String, String>,
+    /// The architectures to use when installing packages.
+    architectures: Vec<String>,
+    /// Whether searches and target resolution include packages for other architectures.
+    foreign_architectures: bool,
+    /// A function to call whenever a warning occurs.
+    warning_callback: Option<Box<dyn FnMut(&Warning)>>,
+    /// The most warnings of each kind to report, if limited.
+    warning_limit: Option<usize>,
+    /// How long repeated warnings are dropped for.
+    warning_window: Duration,
+    /// A function to call with progress events.
+    event_callback: Option<Box<dyn FnMut(&Event)>>,
+    /// Use deltas when they are smaller than this fraction of the package.
+    delta_ratio: f64,
+    /// The most bytes per second to prefetch upgrades at.
+    prefetch_rate_limit: Option<u64>,
+    /// The name of the user to make network requests as, when running as root.
+    download_user: Option<String>,
+    /// How failed network requests are retried.
+    retry_policy: RetryPolicy,
+    /// What to do with downloads that fail verification.
+    integrity_policy: IntegrityPolicy,
+    /// Whether to extract packages in a sandbox.
+    sandbox_extraction: bool,
+    /// The helper program that extracts packages in a sandbox, if not the default.
+    extraction_helper: Option<PathBuf>,
+    /// Whether to open the databases for reading only.
+    read_only: bool,
+    /// What to do when a database file we want to write is locked.
+    lock_mode: LockMode,
+    /// What to do when more than one version of a package is installed.
+    duplicate_policy: DuplicatePolicy,
+    /// What to do when a key is repeated in a package's desc or files file.
+    duplicate_key_policy: DuplicateKeyPolicy,
+    /// Keys in desc files to read as other keys.
+    desc_aliases: Vec<(String, &'static str)>,
+    /// Files that validation skips.
+    validation_exclusions: ValidationExclusions,
+    /// Where the databases, log and hooks live.
+    layout: Layout,
+    /// Extra hook directories.
+    hook_directories: Vec<PathBuf>,
+    /// Runs scriptlets and hooks in the root.
+    script_runner: Rc<dyn ScriptRunner>,
+    /// Where to send measurements.
+    metrics: Option<Rc<dyn Metrics>>,
+    /// Downloads package archives.
+    downloader: Option<Rc<dyn Downloader>>,
+    /// Answers questions.
+    questions: Rc<dyn Questions>,
+    /// Whether paths can be overridden by environment variables.
+    env_overrides: bool,
+    /// Whether searches use the search index.
+    search_index: bool,
 }
 
 impl Default for AlpmBuilder {
@@ -281,7 +1124,37 @@ impl Default for AlpmBuilder {
             gpg_path: None,
             cache_directories: Vec::new(),
             packages_no_upgrade: HashSet::new(),
-            arch: None,
+            packages_hold: HashSet::new(),
+            packages_ignore: HashSet::new(),
+            groups_ignore: HashSet::new(),
+            package_pins: HashMap::new(),
+            architectures: Vec::new(),
+            foreign_architectures: false,
+            warning_callback: None,
+            warning_limit: None,
+            warning_window: DEFAULT_WARNING_WINDOW,
+            event_callback: None,
+            delta_ratio: 0.0,
+            prefetch_rate_limit: None,
+            download_user: None,
+            retry_policy: RetryPolicy::default(),
+            integrity_policy: IntegrityPolicy::default(),
+            sandbox_extraction: false,
+            extraction_helper: None,
+            read_only: false,
+            lock_mode: LockMode::default(),
+            duplicate_policy: DuplicatePolicy::default(),
+            duplicate_key_policy: DuplicateKeyPolicy::MergeLists,
+            desc_aliases: Vec::new(),
+            validation_exclusions: ValidationExclusions::default(),
+            layout: Layout::default(),
+            hook_directories: Vec::new(),
+            script_runner: Rc::new(ChrootRunner),
+            metrics: None,
+            downloader: None,
+            questions: Rc::new(NonInteractive::default()),
+            env_overrides: false,
+            search_index: false,
         }
     }
 }
@@ -293,18 +1166,30 @@ impl AlpmBuilder {
         self
     }
 
+    /// Use a non-standard layout of the databases, log and hooks (see `Layout`).
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
     /// Use custom database path
+    ///
+    /// This takes precedence over the database directory of the layout.
     pub fn with_database_path(mut self, database_path: impl Into<PathBuf>) -> Self {
         self.database_path = Some(database_path.into());
         self
     }
 
-    /// Use custom database path
+    /// Use custom database extension
+    ///
+    /// This can contain dots, for example `db.tar.zst`.
     pub fn with_database_extension(mut self, database_extension: impl Into<String>) -> Self {
         self.database_extension = Some(database_extension.into());
         self
     }
     /// Use custom gpg location
+    ///
+    /// The default is `etc/pacman.d/gnupg` under the root.
     pub fn with_gpg_path(mut self, gpg_path: impl Into<PathBuf>) -> Self {
         self.gpg_path = Some(gpg_path.into());
         self
@@ -316,63 +1201,423 @@ impl AlpmBuilder {
         self
     }
 
+    /// Add a hook directory.
+    ///
+    /// The hook directories of the layout (`usr/share/libalpm/hooks` under the root by default)
+    /// are always used, and hooks in directories added here replace hooks with the same file name
+    /// in them, or in directories added before.
+    pub fn with_hook_directory(mut self, hook_directory: impl Into<PathBuf>) -> Self {
+        self.hook_directories.push(hook_directory.into());
+        self
+    }
+
+    /// Choose how scriptlets and hook commands are run in the root.
+    ///
+    /// By default they are run after changing root, like pacman (see `script::ChrootRunner`),
+    /// which needs root privileges unless the root is `/`.
+    pub fn with_script_runner(mut self, runner: impl ScriptRunner + 'static) -> Self {
+        self.script_runner = Rc::new(runner);
+        self
+    }
+
+    /// Send measurements of synchronizing, downloading, resolving and extracting to `metrics`.
+    pub fn with_metrics(mut self, metrics: impl Metrics + 'static) -> Self {
+        self.metrics = Some(Rc::new(metrics));
+        self
+    }
+
+    /// Download package archives with `downloader`, falling back to the built-in HTTP client.
+    ///
+    /// See the `downloader` module.
+    pub fn with_downloader(mut self, downloader: impl Downloader + 'static) -> Self {
+        self.downloader = Some(Rc::new(downloader));
+        self
+    }
+
+    /// Choose how questions, like which of several providers to install, are answered.
+    ///
+    /// By default they are answered by `question::NonInteractive::default()`.
+    pub fn with_questions(mut self, questions: impl Questions + 'static) -> Self {
+        self.questions = Rc::new(questions);
+        self
+    }
+
+    /// Allow packages built for an architecture to be installed (like `Architecture` in
+    /// `pacman.conf`). Packages built for "any" are always allowed.
+    ///
+    /// Call this more than once to allow several. If it isn't called, the architecture of the
+    /// running machine is used.
+    pub fn with_architecture(mut self, arch: impl Into<String>) -> Self {
+        self.architectures.push(arch.into());
+        self
+    }
+
+    /// Include packages built for other architectures when searching and resolving targets.
+    ///
+    /// By default they are skipped, so a compatible package from a lower priority database is
+    /// used instead. Installing a package for another architecture is refused either way.
+    pub fn with_foreign_architectures(mut self, include: bool) -> Self {
+        self.foreign_architectures = include;
+        self
+    }
+
     /// Mark a package as no-upgrade.
     pub fn mark_no_upgrade(mut self, no_upgrade: impl Into<String>) -> Self {
         self.packages_no_upgrade.insert(no_upgrade.into());
         self
     }
 
+    /// Hold a package, so that planning fails if it, or a package it depends on, would be removed
+    /// (like `HoldPkg` in `pacman.conf`).
+    pub fn with_hold_package(mut self, package: impl Into<String>) -> Self {
+        self.packages_hold.insert(package.into());
+        self
+    }
+
+    /// Leave a package out of system upgrades (like `IgnorePkg` in `pacman.conf`).
+    ///
+    /// This may be a glob, like `linux-*`.
+    pub fn with_ignore_package(mut self, package: impl Into<String>) -> Self {
+        self.packages_ignore.insert(package.into());
+        self
+    }
+
+    /// Leave packages in a group out of system upgrades (like `IgnoreGroup` in `pacman.conf`).
+    ///
+    /// This may be a glob. The groups of the new version of each package are checked.
+    pub fn with_ignore_group(mut self, group: impl Into<String>) -> Self {
+        self.groups_ignore.insert(group.into());
+        self
+    }
+
+    /// Always take a package from the named sync database, whatever the database priorities.
+    ///
+    /// For example, pinning `linux-lts` to `custom` means it will only ever be installed or
+    /// upgraded from the `custom` database.
+    pub fn with_package_pin(
+        mut self,
+        package: impl Into<String>,
+        database: impl Into<String>,
+    ) -> Self {
+        self.package_pins.insert(package.into(), database.into());
+        self
+    }
+
+    /// Call a function whenever a warning occurs.
+    ///
+    /// This is set before the local database is loaded, so it will see warnings produced while
+    /// building.
+    pub fn with_warning_callback(mut self, callback: impl FnMut(&Warning) + 'static) -> Self {
+        self.warning_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Report at most `limit` warnings of each kind (see `Warning::kind`) in each window (see
+    /// `with_warning_window`).
+    ///
+    /// Further warnings of a kind are counted instead of logged, stored or passed to the callback,
+    /// and the count is reported as a `Warning::Suppressed` when the window ends. This keeps
+    /// logs usable on badly broken systems. Warnings that repeat one already reported in the
+    /// window are always dropped. By default there is no limit.
+    pub fn with_warning_limit(mut self, limit: usize) -> Self {
+        self.warning_limit = Some(limit);
+        self
+    }
+
+    /// End each window of warnings after `window`, so repeated warnings are reported again and
+    /// the limit starts again, even if warnings are only observed through the callback.
+    ///
+    /// A window also ends when `Alpm::take_warnings` is called. The default is a minute.
+    pub fn with_warning_window(mut self, window: Duration) -> Self {
+        self.warning_window = window;
+        self
+    }
+
+    /// Call a function with progress events during long-running operations.
+    pub fn with_event_callback(mut self, callback: impl FnMut(&Event) + 'static) -> Self {
+        self.event_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Use deltas to update packages when they are smaller than this fraction of the package size
+    /// (like `UseDelta` in `pacman.conf`, where 0.7 is typical).
+    ///
+    /// The default of 0 means deltas are never used.
+    pub fn with_delta_ratio(mut self, delta_ratio: f64) -> Self {
+        self.delta_ratio = delta_ratio;
+        self
+    }
+
+    /// Limit `Alpm::prefetch_upgrades` to downloading this many bytes per second, so prefetching
+    /// in the background doesn't take all of a slow connection.
+    ///
+    /// By default the rate isn't limited. Other downloads are never limited.
+    pub fn with_prefetch_rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.prefetch_rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Make network requests as the given user when running as root (like `DownloadUser` in
+    /// `pacman.conf`).
+    ///
+    /// Requests are made by running `curl` as that user, so that a bug in the HTTP or TLS code
+    /// can't be used to take over the system. `curl` must be installed.
+    pub fn with_download_user(mut self, user: impl Into<String>) -> Self {
+        self.download_user = Some(user.into());
+        self
+    }
+
+    /// Choose how network requests that time out, or get a server error, are retried.
+    ///
+    /// See `RetryPolicy` for the default. Use `RetryPolicy::none()` to try each server once.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Choose what happens when a downloaded package or database fails verification.
+    ///
+    /// See `IntegrityPolicy` for the default.
+    pub fn with_integrity_policy(mut self, integrity_policy: IntegrityPolicy) -> Self {
+        self.integrity_policy = integrity_policy;
+        self
+    }
+
+    /// Extract packages in a sandbox that can only write beneath the root path.
+    ///
+    /// Each package is extracted by the `alpm-extract` helper program, which is built with the
+    /// `sandbox` feature and must be installed (see `with_extraction_helper`). This needs a kernel
+    /// supporting Landlock and seccomp. Building fails if the feature is disabled.
+    pub fn with_extraction_sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox_extraction = sandbox;
+        self
+    }
+
+    /// Run this program to extract packages in a sandbox, instead of looking for `alpm-extract`
+    /// on the `PATH`.
+    pub fn with_extraction_helper(mut self, helper: impl Into<PathBuf>) -> Self {
+        self.extraction_helper = Some(helper.into());
+        self
+    }
+
+    /// Open the databases for reading only, like `pacman -Q`.
+    ///
+    /// Read-only instances don't create the lockfile, so they can run alongside each other and
+    /// while pacman is changing the system. They do wait for writing instances of this library
+    /// (according to `with_lock_mode`), so they never see a database half written by one.
+    /// Anything that would write, like synchronizing a database, fails with `ErrorKind::ReadOnly`.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Choose what happens when a database file is locked by another process.
+    ///
+    /// By default we wait for as long as it takes.
+    pub fn with_lock_mode(mut self, lock_mode: LockMode) -> Self {
+        self.lock_mode = lock_mode;
+        self
+    }
+
+    /// Choose what happens when the local database has more than one version of a package
+    /// installed.
+    ///
+    /// By default a warning is raised, and the latest version is used.
+    pub fn with_duplicate_policy(mut self, duplicate_policy: DuplicatePolicy) -> Self {
+        self.duplicate_policy = duplicate_policy;
+        self
+    }
+
+    /// Choose what happens when a key is repeated in a package's desc or files file.
+    ///
+    /// By default the values of lists are joined and other values use the first, and a
+    /// `Warning::DuplicateDescKey` is raised for each repeat.
+    pub fn with_duplicate_key_policy(mut self, policy: DuplicateKeyPolicy) -> Self {
+        self.duplicate_key_policy = policy;
+        self
+    }
+
+    /// Read the key `key` in packages' desc and files files as `as_key`, for example if a new
+    /// version of pacman renames a key this crate reads.
+    ///
+    /// Keys are matched ignoring ASCII case, so `with_desc_alias("INSTALLEDSIZE", "ISIZE")` reads
+    /// `%INSTALLEDSIZE%` as the installed size.
+    pub fn with_desc_alias(mut self, key: impl Into<String>, as_key: &'static str) -> Self {
+        self.desc_aliases.push((key.into(), as_key));
+        self
+    }
+
+    /// Choose which files validation skips.
+    ///
+    /// By default every file is checked. `ValidationExclusions::volatile` skips a built-in set of
+    /// files that are expected to change after they are installed.
+    pub fn with_validation_exclusions(mut self, exclusions: ValidationExclusions) -> Self {
+        self.validation_exclusions = exclusions;
+        self
+    }
+
+    /// Let environment variables override paths when the instance is built.
+    ///
+    /// This is for test harnesses and chroot tools that can't pass options through to here. The
+    /// variables are:
+    ///
+    ///  - `ALPM_ROOT` - the root path,
+    ///  - `ALPM_DB_PATH` - the database path,
+    ///  - `ALPM_CACHE_DIRS` - the cache directories, separated like `PATH`, replacing any given
+    ///    here.
+    ///
+    /// Variables that are unset or empty are ignored.
+    pub fn with_env_overrides(mut self) -> Self {
+        self.env_overrides = true;
+        self
+    }
+
+    /// Keep an index of the sync packages' names, descriptions and provisions for `Alpm::search`.
+    ///
+    /// The index is saved in the database path and rebuilt whenever a sync database changes, so
+    /// searching no longer reads every package. This is worthwhile for frontends that search as
+    /// the user types.
+    pub fn with_search_index(mut self, enabled: bool) -> Self {
+        self.search_index = enabled;
+        self
+    }
+
+    /// Replace options with any set in the environment (see `with_env_overrides`).
+    fn apply_env_overrides(&mut self) {
+        let var = |name: &str| env::var_os(name).filter(|value| !value.is_empty());
+        if let Some(root_path) = var(ENV_ROOT) {
+            log::debug!("{} overrides the root path", ENV_ROOT);
+            self.root_path = Some(root_path.into());
+        }
+        if let Some(database_path) = var(ENV_DB_PATH) {
+            log::debug!("{} overrides the database path", ENV_DB_PATH);
+            self.database_path = Some(database_path.into());
+        }
+        if let Some(cache_directories) = var(ENV_CACHE_DIRS) {
+            log::debug!("{} overrides the cache directories", ENV_CACHE_DIRS);
+            self.cache_directories = env::split_paths(&cache_directories).collect();
+        }
+    }
+
     /// Build the alpm instance.
+    ///
+    /// All the configuration is checked before anything is set up, so if there are several
+    /// problems they are reported together in an `ErrorKind::InvalidConfiguration`. A single
+    /// problem is returned as it is.
     pub fn build(mut self) -> Result<Alpm, Error> {
+        if self.env_overrides {
+            self.apply_env_overrides();
+        }
+        let mut problems = Vec::new();
+
         // todo check that root path is not relative.
         #[cfg(windows)]
         let root_path = self.root_path.unwrap_or("C:\\".into());
         #[cfg(not(windows))]
         let root_path = self.root_path.unwrap_or("/".into());
         log::debug!("root path: {}", root_path.display());
-        util::check_valid_directory(&root_path)
-            .context(ErrorKind::BadRootPath(root_path.clone()))?;
+        if let Err(e) = util::check_valid_directory(&root_path)
+            .context(ErrorKind::BadRootPath(root_path.clone()))
+        {
+            problems.push(e);
+        }
 
         // todo sensible default database path on windows
         let database_path = match self.database_path {
             Some(path) => path,
-            None => {
-                let mut db_path = root_path.clone();
-                db_path.push("var/lib/pacman");
-                db_path
-            }
+            None => root_path.join(self.layout.database_directory()),
         };
 
         log::debug!("database path: {}", database_path.display());
-        // todo should I be checking database_path is valid here?
+        let sync_db_path = database_path.join(self.layout.sync_directory());
+        log::debug!("sync database path: {}", sync_db_path.display());
+        // The sync database directory is only worth checking if its parent is fine.
+        match util::check_valid_directory(&database_path) {
+            Ok(()) => {
+                if let Err(e) = util::check_valid_directory(&sync_db_path)
+                    .context(ErrorKind::BadSyncDatabasePath(sync_db_path.clone()))
+                {
+                    problems.push(e);
+                }
+            }
+            Err(e) => problems.push(
+                Error::from(ErrorKind::BadDatabasePath(database_path.clone())).with_source(e),
+            ),
+        }
 
         let database_extension = self
             .database_extension
             .unwrap_or(DEFAULT_SYNC_DB_EXT.to_owned());
         if !is_valid_db_extension(&database_extension) {
-            return Err(ErrorKind::BadSyncDatabaseExt(database_extension).into());
+            problems.push(ErrorKind::BadSyncDatabaseExt(database_extension.clone()).into());
         }
         log::debug!("database extension: .{}", &database_extension);
 
-        let sync_db_path = database_path.join(SYNC_DB_DIR);
-        log::debug!("sync database path: {}", sync_db_path.display());
-        util::check_valid_directory(&sync_db_path)
-            .context(ErrorKind::BadSyncDatabasePath(sync_db_path.clone()))?;
+        // Only cache directories the user chose are checked, so the default doesn't stop
+        // read-only use by ordinary users.
+        for dir in &self.cache_directories {
+            if let Err(e) = util::check_writable_directory(dir)
+                .context(ErrorKind::BadCacheDirectory(dir.clone()))
+            {
+                problems.push(e);
+            }
+        }
 
-        let lockfile_path = database_path.join(LOCKFILE);
-        log::debug!("lockfile path: {}", lockfile_path.display());
+        if self.sandbox_extraction && !cfg!(feature = "sandbox") {
+            problems.push(ErrorKind::SandboxUnavailable.into());
+        }
 
-        let lockfile = Lockfile::create(&lockfile_path).map_err(|e| {
-            let kind = e.kind();
-            if kind == io::ErrorKind::AlreadyExists {
-                Error::lock_already_exists(lockfile_path, e)
-            } else {
-                Error::cannot_acquire_lock(lockfile_path, e)
+        let download_user = match self.download_user {
+            Some(name) => match nix::unistd::User::from_name(&name) {
+                Ok(Some(user)) => {
+                    log::debug!("download user: {}", name);
+                    Some(user)
+                }
+                Ok(None) => {
+                    problems.push(ErrorKind::UnknownDownloadUser(name).into());
+                    None
+                }
+                Err(e) => {
+                    problems.push(Error::from(ErrorKind::UnknownDownloadUser(name)).with_source(e));
+                    None
+                }
+            },
+            None => None,
+        };
+
+        match problems.len() {
+            0 => (),
+            1 => return Err(problems.remove(0)),
+            _ => {
+                let kinds = problems.iter().map(|e| e.kind.clone()).collect();
+                return Err(Error::from(ErrorKind::InvalidConfiguration(kinds))
+                    .with_source(Errors(problems)));
             }
-        })?;
+        }
 
-        // todo
-        let gpg_path = root_path.clone();
+        let local_db_path = database_path.join(self.layout.local_directory());
+        log::debug!("local database path: {}", local_db_path.display());
+        let log_path = root_path.join(self.layout.log_file());
+        log::debug!("log path: {}", log_path.display());
+
+        let mut hook_directories: Vec<PathBuf> = self
+            .layout
+            .hook_directories()
+            .iter()
+            .map(|dir| root_path.join(dir))
+            .collect();
+        hook_directories.extend(self.hook_directories);
+        log::debug!("hook directories: {:?}", hook_directories);
+
+        let lock = if self.read_only {
+            DatabaseLock::shared(&database_path, self.lock_mode)?
+        } else {
+            DatabaseLock::exclusive(&database_path, self.lock_mode)?
+        };
+
+        let gpg_path = self
+            .gpg_path
+            .unwrap_or_else(|| root_path.join("etc/pacman.d/gnupg"));
         log::debug!("gpg path: {}", gpg_path.display());
 
         self.cache_directories.dedup();
@@ -381,48 +1626,88 @@ impl AlpmBuilder {
         }
 
         // Get architecture of computer
+        let mut architectures = self.architectures;
+        architectures.dedup();
         #[cfg(not(windows))]
-        let arch = match self.arch {
-            Some(arch) => arch,
-            None => {
+        {
+            if architectures.is_empty() {
                 let info = uname().context(ErrorKind::UnexpectedIo)?;
                 log::info!("detected arch: {}", &info.machine);
-                info.machine
+                architectures.push(info.machine);
             }
-        };
+        }
         #[cfg(windows)]
-        let arch = match self.arch {
-            Some(arch) => arch,
-            None => {
+        {
+            if architectures.is_empty() {
                 error!("You must specify an arch on windows. I will assume x86_64.");
-                "x86_64".into()
+                architectures.push("x86_64".into());
             }
-        };
-        log::debug!("arch: {}", &arch);
+        }
+        log::debug!("architectures: {:?}", &architectures);
 
         //signing::init(&gpg_path)?;
 
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.set_callback(self.warning_callback);
+        diagnostics.set_limit(self.warning_limit);
+        diagnostics.set_window(self.warning_window);
+        let mut events = Events::default();
+        events.set_callback(self.event_callback);
+
         // Chicken-and-egg problem for local_database
         let handle = Rc::new(RefCell::new(Handle {
             local_database: None,
-            sync_databases: HashMap::new(),
+            sync_databases: IndexMap::new(),
             root_path,
             database_path,
+            sync_database_path: sync_db_path,
+            local_database_path: local_db_path,
+            log_path,
             database_extension,
-            lockfile,
+            lock: Some(lock),
+            read_only: self.read_only,
             gpg_path,
             cache_directories: self.cache_directories,
-            hook_dirs_paths: HashSet::new(),
+            hook_directories,
             overwrite_file_paths: HashSet::new(),
             packages_no_upgrade: self.packages_no_upgrade,
+            packages_hold: self.packages_hold,
+            package_pins: self.package_pins,
             packages_no_extract: HashSet::new(),
-            packages_ignore: HashSet::new(),
-            groups_ignore: HashSet::new(),
+            packages_ignore: self.packages_ignore,
+            groups_ignore: self.groups_ignore,
             packages_assume_installed: HashSet::new(),
-            arch,
-            delta_ratio: 0.0,
+            architectures,
+            foreign_architectures: self.foreign_architectures,
+            delta_ratio: self.delta_ratio,
+            prefetch_rate_limit: self.prefetch_rate_limit,
             check_space: true,
+            lock_mode: self.lock_mode,
+            duplicate_policy: self.duplicate_policy,
+            duplicate_key_policy: self.duplicate_key_policy,
+            desc_aliases: self.desc_aliases,
+            validation_exclusions: self.validation_exclusions,
             http_client: reqwest::Client::new(),
+            retry_policy: self.retry_policy,
+            integrity_policy: self.integrity_policy,
+            download_user,
+            extraction_helper: if self.sandbox_extraction {
+                Some(
+                    self.extraction_helper
+                        .unwrap_or_else(|| PathBuf::from(extract::EXTRACTION_HELPER)),
+                )
+            } else {
+                None
+            },
+            script_runner: self.script_runner,
+            metrics: self.metrics,
+            downloader: self.downloader,
+            questions: self.questions,
+            diagnostics: RefCell::new(diagnostics),
+            dropped_packages: RefCell::new(BTreeMap::new()),
+            use_search_index: self.search_index,
+            search_index: RefCell::new(None),
+            events: RefCell::new(events),
         }));
         let mut local_database = LocalDatabaseInner::new(&handle, SignatureLevel::default());
         local_database.populate_package_cache()?;
@@ -430,10 +1715,3 @@ impl AlpmBuilder {
         Ok(Alpm { handle })
     }
 }
-
-/// Check a string is a valid db extension.
-///
-/// For now, just allow ascii alphanumeric. This could be relaxed later.
-fn is_valid_db_extension(ext: &str) -> bool {
-    ext.chars().all(|ch| ch.is_alphanumeric())
-}
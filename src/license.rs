@@ -0,0 +1,224 @@
+//! An inventory of the licenses of installed packages, for compliance checks.
+//!
+//! Arch packages use their own short names for common licenses (`GPL3`, `APACHE`, ...), and
+//! `custom` or `custom:<name>` for everything else. Where the meaning is clear these are
+//! normalized to SPDX identifiers, so reports can be compared with other tools.
+
+use std::{collections::BTreeMap, fmt, rc::Rc};
+
+use crate::{db::LocalPackage, package::Package};
+
+/// Arch license names, and the SPDX identifiers they mean.
+///
+/// Names that don't say which version they mean (like `GPL` or `BSD`) aren't listed, since they
+/// can't be normalized without guessing.
+const ARCH_LICENSES: &[(&str, &str)] = &[
+    ("AGPL3", "AGPL-3.0-only"),
+    ("APACHE", "Apache-2.0"),
+    ("Boost", "BSL-1.0"),
+    ("CDDL", "CDDL-1.0"),
+    ("EPL", "EPL-1.0"),
+    ("FDL1.2", "GFDL-1.2-only"),
+    ("FDL1.3", "GFDL-1.3-only"),
+    ("GPL2", "GPL-2.0-only"),
+    ("GPL3", "GPL-3.0-only"),
+    ("LGPL2.1", "LGPL-2.1-only"),
+    ("LGPL3", "LGPL-3.0-only"),
+    ("LPPL", "LPPL-1.3c"),
+    ("MPL", "MPL-1.1"),
+    ("MPL2", "MPL-2.0"),
+    ("PerlArtistic", "Artistic-1.0-Perl"),
+    ("PHP", "PHP-3.01"),
+    ("PSF", "Python-2.0"),
+    ("RUBY", "Ruby"),
+    ("ZLIB", "Zlib"),
+];
+
+/// SPDX identifiers that are used as they are, besides the ones in `ARCH_LICENSES`.
+const SPDX_LICENSES: &[&str] = &[
+    "0BSD",
+    "Apache-2.0",
+    "BSD-2-Clause",
+    "BSD-3-Clause",
+    "CC0-1.0",
+    "GPL-2.0-only",
+    "GPL-2.0-or-later",
+    "GPL-3.0-only",
+    "GPL-3.0-or-later",
+    "ISC",
+    "LGPL-2.0-only",
+    "LGPL-2.0-or-later",
+    "LGPL-2.1-or-later",
+    "LGPL-3.0-or-later",
+    "MIT",
+    "OFL-1.1",
+    "Unlicense",
+    "W3C",
+    "WTFPL",
+];
+
+/// A license of a package, normalized where possible.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum License {
+    /// A license with a known SPDX identifier.
+    Spdx(String),
+    /// A license specific to the package (`custom`, `custom:<name>` or `LicenseRef-<name>`), with
+    /// its name if it has one.
+    ///
+    /// These need to be read by a person.
+    Custom(Option<String>),
+    /// A license name that couldn't be normalized, as written by the packager.
+    Unknown(String),
+}
+
+impl License {
+    /// Normalize a license name from a package's metadata.
+    pub fn normalize(raw: &str) -> License {
+        let raw = raw.trim();
+        if raw == "custom" {
+            return License::Custom(None);
+        }
+        for prefix in &["custom:", "LicenseRef-"] {
+            if raw.starts_with(prefix) {
+                return License::Custom(Some(raw[prefix.len()..].to_owned()));
+            }
+        }
+        let arch = ARCH_LICENSES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(raw))
+            .map(|(_, spdx)| *spdx);
+        let spdx = SPDX_LICENSES
+            .iter()
+            .chain(ARCH_LICENSES.iter().map(|(_, spdx)| spdx))
+            .find(|spdx| spdx.eq_ignore_ascii_case(raw))
+            .cloned();
+        match arch.or(spdx) {
+            Some(spdx) => License::Spdx(spdx.to_owned()),
+            None => License::Unknown(raw.to_owned()),
+        }
+    }
+
+    /// Whether this license is specific to the package.
+    pub fn is_custom(&self) -> bool {
+        match self {
+            License::Custom(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for License {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            License::Spdx(id) => f.write_str(id),
+            License::Custom(None) => f.write_str("custom"),
+            License::Custom(Some(name)) => write!(f, "custom:{}", name),
+            License::Unknown(name) => f.write_str(name),
+        }
+    }
+}
+
+/// The licenses of every installed package, from `Alpm::license_report`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LicenseReport {
+    /// The normalized licenses of each package, by package name.
+    packages: BTreeMap<String, Vec<License>>,
+}
+
+impl LicenseReport {
+    /// Normalize the licenses of the given packages.
+    pub(crate) fn new(packages: &[Rc<LocalPackage>]) -> LicenseReport {
+        let packages = packages
+            .iter()
+            .map(|package| {
+                let licenses = package
+                    .license()
+                    .iter()
+                    .map(|raw| License::normalize(raw))
+                    .collect();
+                (package.name().to_owned(), licenses)
+            })
+            .collect();
+        LicenseReport { packages }
+    }
+
+    /// The licenses of each package, sorted by package name.
+    pub fn packages(&self) -> impl Iterator<Item = (&str, &[License])> {
+        self.packages
+            .iter()
+            .map(|(name, licenses)| (name.as_str(), licenses.as_slice()))
+    }
+
+    /// The licenses of the named package, or `None` if it isn't installed.
+    pub fn package(&self, name: &str) -> Option<&[License]> {
+        self.packages.get(name).map(Vec::as_slice)
+    }
+
+    /// Each license, with the names of the packages that use it.
+    pub fn by_license(&self) -> BTreeMap<&License, Vec<&str>> {
+        let mut by_license: BTreeMap<&License, Vec<&str>> = BTreeMap::new();
+        for (name, licenses) in &self.packages {
+            for license in licenses {
+                by_license.entry(license).or_default().push(name);
+            }
+        }
+        by_license
+    }
+
+    /// Packages with at least one custom license, which need to be checked by hand.
+    pub fn custom(&self) -> impl Iterator<Item = &str> {
+        self.packages
+            .iter()
+            .filter(|(_, licenses)| licenses.iter().any(License::is_custom))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Packages with licenses that couldn't be normalized.
+    pub fn unknown(&self) -> impl Iterator<Item = &str> {
+        self.packages
+            .iter()
+            .filter(|(_, licenses)| {
+                licenses.iter().any(|license| match license {
+                    License::Unknown(_) => true,
+                    _ => false,
+                })
+            })
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Packages that don't declare any license.
+    pub fn unlicensed(&self) -> impl Iterator<Item = &str> {
+        self.packages
+            .iter()
+            .filter(|(_, licenses)| licenses.is_empty())
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        let spdx = |id: &str| License::Spdx(id.to_owned());
+        assert_eq!(License::normalize("GPL3"), spdx("GPL-3.0-only"));
+        assert_eq!(License::normalize("apache"), spdx("Apache-2.0"));
+        assert_eq!(License::normalize("MIT"), spdx("MIT"));
+        assert_eq!(
+            License::normalize("gpl-2.0-or-later"),
+            spdx("GPL-2.0-or-later")
+        );
+        assert_eq!(License::normalize("custom"), License::Custom(None));
+        assert_eq!(
+            License::normalize("custom:OFL"),
+            License::Custom(Some("OFL".into()))
+        );
+        assert_eq!(
+            License::normalize("LicenseRef-foo"),
+            License::Custom(Some("foo".into()))
+        );
+        assert_eq!(License::normalize("GPL"), License::Unknown("GPL".into()));
+        assert_eq!(License::normalize("custom:OFL").to_string(), "custom:OFL");
+    }
+}
@@ -0,0 +1,213 @@
+//! Ordering and paging lists of packages, for user interfaces.
+//!
+//! The orders are total (ties are broken by name, then version, then repository), so a list
+//! sorted twice comes out the same, and pages of it don't overlap or skip packages.
+
+use std::{cmp::Ordering, rc::Rc, time::SystemTime};
+
+use crate::{
+    db::{LocalPackage, Located, SyncPackage},
+    package::Package,
+    version::Version,
+};
+
+/// Something shown as a package in a list: a package, or a package with where it was found.
+pub trait Listed {
+    /// The package type.
+    type Package: Package;
+
+    /// The package.
+    fn package(&self) -> &Self::Package;
+
+    /// The name of the database the package is from, if known.
+    fn repository(&self) -> Option<&str> {
+        None
+    }
+
+    /// When the package was installed, for installed packages.
+    fn install_date(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+impl Listed for LocalPackage {
+    type Package = LocalPackage;
+
+    fn package(&self) -> &LocalPackage {
+        self
+    }
+
+    fn install_date(&self) -> Option<SystemTime> {
+        LocalPackage::install_date(self)
+    }
+}
+
+impl Listed for Rc<LocalPackage> {
+    type Package = LocalPackage;
+
+    fn package(&self) -> &LocalPackage {
+        self
+    }
+
+    fn install_date(&self) -> Option<SystemTime> {
+        LocalPackage::install_date(self)
+    }
+}
+
+impl Listed for SyncPackage {
+    type Package = SyncPackage;
+
+    fn package(&self) -> &SyncPackage {
+        self
+    }
+}
+
+impl Listed for Rc<SyncPackage> {
+    type Package = SyncPackage;
+
+    fn package(&self) -> &SyncPackage {
+        self
+    }
+}
+
+impl Listed for Located<Rc<SyncPackage>> {
+    type Package = SyncPackage;
+
+    fn package(&self) -> &SyncPackage {
+        &self.package
+    }
+
+    fn repository(&self) -> Option<&str> {
+        Some(&self.db_name)
+    }
+}
+
+/// How to order a list of packages.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SortOrder {
+    /// By name, then version (oldest first).
+    Name,
+    /// Most recently installed first, then packages without an install date.
+    InstallDate,
+    /// Largest installed size first.
+    Size,
+    /// By repository name, then packages without a repository.
+    Repository,
+}
+
+/// Sort `items` in the given order.
+pub fn sort<T: Listed>(items: &mut [T], order: SortOrder) {
+    items.sort_by(|a, b| {
+        let first = match order {
+            SortOrder::Name => Ordering::Equal,
+            SortOrder::InstallDate => last_if_none(b.install_date(), a.install_date()),
+            SortOrder::Size => b.package().size().cmp(&a.package().size()),
+            SortOrder::Repository => last_if_none(a.repository(), b.repository()),
+        };
+        first.then_with(|| by_name(a, b))
+    })
+}
+
+/// Compare by name, then version, then repository.
+fn by_name<T: Listed>(a: &T, b: &T) -> Ordering {
+    let (pa, pb) = (a.package(), b.package());
+    pa.name()
+        .cmp(pb.name())
+        .then_with(|| Version::parse(pa.version()).cmp(&Version::parse(pb.version())))
+        .then_with(|| last_if_none(a.repository(), b.repository()))
+}
+
+/// Compare two options, putting `None` after everything else.
+fn last_if_none<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Part of a list, with enough information to show where it is in the whole.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Page<T> {
+    /// The items on this page.
+    pub items: Vec<T>,
+    /// The position of the first item in the whole list.
+    pub offset: usize,
+    /// The length of the whole list.
+    pub total: usize,
+}
+
+impl<T> Page<T> {
+    /// Take up to `limit` items from `items`, starting at `offset`.
+    ///
+    /// An offset past the end gives an empty page.
+    pub fn new(items: Vec<T>, offset: usize, limit: usize) -> Page<T> {
+        let total = items.len();
+        let items = items.into_iter().skip(offset).take(limit).collect();
+        Page {
+            items,
+            offset,
+            total,
+        }
+    }
+
+    /// Whether there are items after this page.
+    pub fn has_more(&self) -> bool {
+        self.offset.saturating_add(self.items.len()) < self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Weak};
+
+    use super::*;
+    use crate::Handle;
+
+    fn package(name: &str, version: &str, size: u64) -> SyncPackage {
+        let desc = format!(
+            "%FILENAME%\n{name}-{version}-x86_64.pkg.tar.xz\n\n%NAME%\n{name}\n\n\
+             %VERSION%\n{version}\n\n%DESC%\ntest\n\n%CSIZE%\n1\n\n%ISIZE%\n{size}\n\n\
+             %MD5SUM%\n\n%SHA256SUM%\n\n%PGPSIG%\n\n%ARCH%\nx86_64\n\n%BUILDDATE%\n0\n\n\
+             %PACKAGER%\nme\n\n",
+            name = name,
+            version = version,
+            size = size
+        );
+        SyncPackage::from_parts(&desc, name, version, &Weak::<RefCell<Handle>>::new()).unwrap()
+    }
+
+    fn names(items: &[SyncPackage]) -> Vec<String> {
+        items
+            .iter()
+            .map(|p| format!("{}-{}", p.name(), p.version()))
+            .collect()
+    }
+
+    #[test]
+    fn orders() {
+        let mut items = vec![
+            package("b", "1-1", 10),
+            package("a", "10-1", 5),
+            package("a", "9-1", 5),
+            package("c", "1-1", 10),
+        ];
+        sort(&mut items, SortOrder::Name);
+        assert_eq!(names(&items), ["a-9-1", "a-10-1", "b-1-1", "c-1-1"]);
+        sort(&mut items, SortOrder::Size);
+        assert_eq!(names(&items), ["b-1-1", "c-1-1", "a-9-1", "a-10-1"]);
+    }
+
+    #[test]
+    fn pages() {
+        let page = Page::new((0..10).collect(), 8, 5);
+        assert_eq!(page.items, [8, 9]);
+        assert_eq!(page.total, 10);
+        assert!(!page.has_more());
+        assert!(Page::new((0..10).collect::<Vec<_>>(), 0, 5).has_more());
+        assert!(Page::new((0..10).collect::<Vec<_>>(), 20, 5)
+            .items
+            .is_empty());
+    }
+}
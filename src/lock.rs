@@ -0,0 +1,118 @@
+//! Locking the database directory against other processes.
+//!
+//! Pacman creates `db.lck` before it changes anything, and refuses to start if the file is already
+//! there. It takes no lock to read, so `pacman -Q` works while another pacman is installing
+//! packages. Instances that write do the same as pacman, so they exclude pacman and pacman
+//! excludes them.
+//!
+//! Read-only instances (see `AlpmBuilder::with_read_only`) don't create `db.lck`, so any number of
+//! them can run alongside each other, and alongside pacman, like `pacman -Q`. So that they don't
+//! see databases half written by a writing instance of this library, every instance also `flock`s
+//! `db.lck.rw`: readers take a shared lock and writers an exclusive one, each held until the
+//! instance is released. Pacman doesn't know about this file, so it neither waits for readers nor
+//! is waited for. A writer waits for readers before it creates `db.lck`, so a long-lived reader
+//! never leaves pacman locked out by a writer that is only waiting.
+
+use std::{fs, io, path::Path};
+
+use lockfile::Lockfile;
+
+use crate::{
+    error::{Error, ErrorContext, ErrorKind},
+    util::{self, LockMode},
+    LOCKFILE,
+};
+
+/// The name of the file readers and writers `flock`.
+const RW_LOCKFILE: &str = "db.lck.rw";
+
+/// The locks held by an alpm instance.
+#[derive(Debug)]
+pub(crate) enum DatabaseLock {
+    /// A writer holds `db.lck`, and an exclusive lock on `db.lck.rw`.
+    Exclusive { lockfile: Lockfile, rw: fs::File },
+    /// A reader holds a shared lock on `db.lck.rw`, if it was able to open it.
+    Shared { rw: Option<fs::File> },
+}
+
+impl DatabaseLock {
+    /// Lock the database directory for writing.
+    ///
+    /// Waits for readers according to `mode`, then fails straight away if `db.lck` exists, as
+    /// pacman does.
+    ///
+    /// `db.lck` is only created once we hold `db.lck.rw`, so pacman is never kept out while we
+    /// wait.
+    pub(crate) fn exclusive(database_path: &Path, mode: LockMode) -> Result<DatabaseLock, Error> {
+        let rw_path = database_path.join(RW_LOCKFILE);
+        let rw = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&rw_path)
+            .map_err(|e| Error::cannot_acquire_lock(rw_path.clone(), e))?;
+        util::lock_exclusive(&rw, &rw_path, mode)?;
+
+        let lockfile_path = database_path.join(LOCKFILE);
+        log::debug!("lockfile path: {}", lockfile_path.display());
+        // If this fails, `rw` is closed on return, releasing the `flock`.
+        let lockfile = Lockfile::create(&lockfile_path).map_err(|e| {
+            if e.kind() == io::ErrorKind::AlreadyExists {
+                Error::lock_already_exists(lockfile_path, e)
+            } else {
+                Error::cannot_acquire_lock(lockfile_path, e)
+            }
+        })?;
+        Ok(DatabaseLock::Exclusive { lockfile, rw })
+    }
+
+    /// Lock the database directory for reading, waiting for writers according to `mode`.
+    ///
+    /// If `db.lck.rw` doesn't exist and can't be created (usually because only root can write to
+    /// the database directory and no writer has run yet), there can't be a writer using this
+    /// protocol, so we go ahead without the lock.
+    pub(crate) fn shared(database_path: &Path, mode: LockMode) -> Result<DatabaseLock, Error> {
+        if database_path.join(LOCKFILE).exists() {
+            log::info!("the database is locked for writing by another process, reading anyway");
+        }
+        let rw_path = database_path.join(RW_LOCKFILE);
+        match open_rw(&rw_path) {
+            Ok(rw) => {
+                util::lock_shared(&rw, &rw_path, mode)?;
+                Ok(DatabaseLock::Shared { rw: Some(rw) })
+            }
+            Err(e) => {
+                log::debug!("not locking \"{}\": {}", rw_path.display(), e);
+                Ok(DatabaseLock::Shared { rw: None })
+            }
+        }
+    }
+
+    /// Release the locks, removing `db.lck` if we created it.
+    pub(crate) fn release(self, database_path: &Path) -> Result<(), Error> {
+        // Closing `db.lck.rw` releases the `flock`.
+        match self {
+            DatabaseLock::Exclusive { lockfile, rw } => {
+                let released = lockfile
+                    .release()
+                    .context(ErrorKind::CannotReleaseLock(database_path.join(LOCKFILE)));
+                drop(rw);
+                released
+            }
+            DatabaseLock::Shared { rw } => {
+                drop(rw);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Open the `flock`ed file for reading, creating it if we can.
+fn open_rw(path: &Path) -> io::Result<fs::File> {
+    match fs::File::open(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::OpenOptions::new().write(true).create(true).open(path)
+        }
+        result => result,
+    }
+}
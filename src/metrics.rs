@@ -0,0 +1,28 @@
+//! Measurements of what the library is doing, for daemons to export to monitoring systems like
+//! Prometheus.
+//!
+//! Implement `Metrics` and pass it to `AlpmBuilder::with_metrics`. Every method does nothing by
+//! default, so only the measurements of interest need implementing, and more can be added
+//! without breaking implementations.
+
+use std::{fmt, time::Duration};
+
+/// Receives measurements from the major operations.
+///
+/// The methods take `&self`, so implementations will usually use atomics or cells for their
+/// counters.
+pub trait Metrics: fmt::Debug {
+    /// A sync database was synchronized, or failed to be.
+    fn database_synchronized(&self, _database: &str, _duration: Duration, _success: bool) {}
+
+    /// A response body was downloaded from a server.
+    ///
+    /// This is called for every attempt, so retried downloads count more than once.
+    fn bytes_downloaded(&self, _bytes: u64) {}
+
+    /// A target was resolved to packages for a plan, or could not be.
+    fn target_resolved(&self, _duration: Duration, _success: bool) {}
+
+    /// A package archive was extracted, or failed to be.
+    fn package_extracted(&self, _duration: Duration, _success: bool) {}
+}
@@ -0,0 +1,154 @@
+//! Writing mtree files, which record the metadata of every file in a package.
+//!
+//! Pacman keeps one, gzip compressed, as `mtree` in each local database entry, and checks
+//! installed files against it. This writes the format `bsdtar --format=mtree` produces with the
+//! options makepkg uses, for packages whose archives don't include an `.MTREE`.
+
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use libflate::gzip::Encoder;
+
+use crate::hash;
+
+/// The defaults given in the `/set` line; entries only list what differs.
+const SET_LINE: &str = "/set type=file uid=0 gid=0 mode=644";
+
+/// Generate an mtree for the files under `root` (like an extracted package), sorted by path.
+pub fn generate(root: impl AsRef<Path>) -> io::Result<String> {
+    let root = root.as_ref();
+    let mut out = String::from("#mtree\n");
+    out.push_str(SET_LINE);
+    out.push('\n');
+    walk(root, Path::new(""), &mut out)?;
+    Ok(out)
+}
+
+/// Generate an mtree for the files under `root`, and write it gzip compressed, as pacman stores
+/// it.
+pub fn write_gz(root: impl AsRef<Path>, out: impl Write) -> io::Result<()> {
+    let mtree = generate(root)?;
+    let mut encoder = Encoder::new(out)?;
+    encoder.write_all(mtree.as_bytes())?;
+    encoder.finish().into_result()?;
+    Ok(())
+}
+
+/// Add the entries for the contents of `root/relative`, recursively.
+fn walk(root: &Path, relative: &Path, out: &mut String) -> io::Result<()> {
+    let mut entries = fs::read_dir(root.join(relative))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let relative = relative.join(entry.file_name());
+        let md = entry.path().symlink_metadata()?;
+        out.push_str(&entry_line(root, &relative, &md)?);
+        out.push('\n');
+        if md.file_type().is_dir() {
+            walk(root, &relative, out)?;
+        }
+    }
+    Ok(())
+}
+
+/// The line for a single file.
+fn entry_line(root: &Path, relative: &Path, md: &fs::Metadata) -> io::Result<String> {
+    let mut line = String::from("./");
+    line.push_str(&escape(relative.as_os_str().as_bytes()));
+    let mtime = md
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    match mtime.subsec_nanos() {
+        0 => line.push_str(&format!(" time={}.0", mtime.as_secs())),
+        nanos => line.push_str(&format!(" time={}.{:09}", mtime.as_secs(), nanos)),
+    }
+    if md.uid() != 0 {
+        line.push_str(&format!(" uid={}", md.uid()));
+    }
+    if md.gid() != 0 {
+        line.push_str(&format!(" gid={}", md.gid()));
+    }
+    let mode = md.mode() & 0o7777;
+    let file_type = md.file_type();
+    if file_type.is_symlink() {
+        let target = fs::read_link(root.join(relative))?;
+        line.push_str(&format!(
+            " mode={:o} type=link link={}",
+            mode,
+            escape(target.as_os_str().as_bytes())
+        ));
+    } else if file_type.is_dir() {
+        line.push_str(&format!(" mode={:o} type=dir", mode));
+    } else if file_type.is_file() {
+        if mode != 0o644 {
+            line.push_str(&format!(" mode={:o}", mode));
+        }
+        let digests = hash::digests_file(&root.join(relative))?;
+        line.push_str(&format!(
+            " size={} md5digest={} sha256digest={}",
+            md.len(),
+            digests.md5,
+            digests.sha256
+        ));
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file, directory or link", relative.display()),
+        ));
+    }
+    Ok(line)
+}
+
+/// Escape a path as mtree does, writing bytes that aren't printable (and `\`) as octal.
+fn escape(path: &[u8]) -> String {
+    let mut out = String::with_capacity(path.len());
+    for &byte in path {
+        if byte.is_ascii_graphic() && byte != b'\\' {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("\\{:03o}", byte));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(escape(b"usr/bin/ls"), "usr/bin/ls");
+        assert_eq!(escape(b"my file\\"), "my\\040file\\134");
+    }
+
+    #[test]
+    fn generate_and_parse() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("usr")).unwrap();
+        fs::write(dir.path().join("usr/hello"), "hello\n").unwrap();
+        std::os::unix::fs::symlink("hello", dir.path().join("usr/link")).unwrap();
+
+        let mtree = generate(dir.path()).unwrap();
+        let lines: Vec<&str> = mtree.lines().collect();
+        assert_eq!(lines[0], "#mtree");
+        assert_eq!(lines[1], SET_LINE);
+        assert!(lines[2].starts_with("./usr "));
+        assert!(lines[2].ends_with(" type=dir"));
+        assert!(lines[3].starts_with("./usr/hello "));
+        assert!(lines[3].contains(" md5digest=b1946ac92492d2347c6235b4d2611184 "));
+        assert!(lines[4].ends_with(" type=link link=hello"));
+
+        let entries = mtree::MTree::from_reader(mtree.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].path(), Path::new("./usr/hello"));
+        assert_eq!(entries[1].size(), Some(6));
+    }
+}
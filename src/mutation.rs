@@ -5,17 +5,1645 @@
 //!
 //! These are Transactions in alpm.
 
-use bitflags::bitflags;
+use std::{cell::RefCell, collections::HashSet, path::PathBuf, rc::Rc, rc::Weak, time::Instant};
 
-use std::collections::BTreeSet as Set;
+mod resolver;
 
-use crate::package::PackageKey;
+pub use self::resolver::Resolver;
+
+use crate::{
+    db::{
+        Database, InstallReason, LocalDatabase, LocalPackage, Located, SyncDatabase, SyncPackage,
+    },
+    dependency::{self, Depend},
+    error::{Error, ErrorKind},
+    package::Package,
+    target::{self, Target},
+    version::Version,
+    Handle, PerDb, Warning,
+};
+
+/// A package that the plan will install, either new or as an upgrade.
+#[derive(Debug, Clone)]
+pub struct PlannedInstall {
+    /// The name of the database the package comes from.
+    pub database: String,
+    /// The package to install.
+    pub package: Rc<SyncPackage>,
+    /// Why the package is being installed.
+    pub reason: InstallReason,
+    /// The installed package this replaces, if this is an upgrade.
+    pub upgrades: Option<Rc<LocalPackage>>,
+}
 
 /// This struct holds a plan for a system mutation.
+#[derive(Debug)]
 pub struct MutationPlan {
-    packages_to_add: Set<PackageKey<'static>>,
-    packages_to_remove: Set<PackageKey<'static>>,
-    packages_to_upgrade: Set<PackageKey<'static>>,
+    handle: Weak<RefCell<Handle>>,
+    local: LocalDatabase,
+    /// Packages to install or upgrade, in the order they were added.
+    installs: Vec<PlannedInstall>,
+    /// Packages to remove, in the order they were added.
+    removals: Vec<Rc<LocalPackage>>,
+    /// Upgrades left out because the package or one of its groups is ignored.
+    skipped: Vec<SkippedUpgrade>,
+    /// Installed packages being replaced by packages that list them in `replaces`.
+    replacements: Vec<Replacement>,
+    /// Whether held packages may be removed.
+    override_hold: bool,
+}
+
+impl MutationPlan {
+    /// Create an empty plan.
+    pub(crate) fn new(handle: Weak<RefCell<Handle>>, local: LocalDatabase) -> Self {
+        MutationPlan {
+            handle,
+            local,
+            installs: Vec::new(),
+            removals: Vec::new(),
+            skipped: Vec::new(),
+            replacements: Vec::new(),
+            override_hold: false,
+        }
+    }
+
+    /// Plan to install a package from a sync database.
+    ///
+    /// If a version of the package is already installed, this is an upgrade (or downgrade). Adding
+    /// the same package twice replaces the earlier entry. Packages built for an architecture that
+    /// isn't configured (see `AlpmBuilder::with_architecture`) are refused.
+    pub fn install(
+        &mut self,
+        database: &SyncDatabase,
+        package: Rc<SyncPackage>,
+        reason: InstallReason,
+    ) -> Result<(), Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        if !handle.borrow().architecture_allowed(&*package) {
+            return Err(ErrorKind::ForeignArchitecture {
+                package: package.name().to_owned(),
+                arch: package.arch().to_owned(),
+            }
+            .into());
+        }
+        let upgrades = self.local.find(package.name())?;
+        self.installs
+            .retain(|planned| planned.package.name() != package.name());
+        self.installs.push(PlannedInstall {
+            database: database.name().to_owned(),
+            package,
+            reason,
+            upgrades,
+        });
+        Ok(())
+    }
+
+    /// Plan to upgrade every installed package that has a newer version (see
+    /// `Alpm::upgradable`), keeping why each was installed.
+    ///
+    /// As in pacman, a sync package that lists installed packages in `replaces` replaces them, if
+    /// `Questions::replace_package` agrees; see `replacements`.
+    ///
+    /// Packages that are ignored (see `AlpmBuilder::with_ignore_package`), or in an ignored group
+    /// (see `AlpmBuilder::with_ignore_group`), are left out and listed in `skipped`.
+    ///
+    /// Fails if any sync database can't be loaded, rather than making a partial upgrade from the
+    /// others.
+    pub fn sysupgrade(&mut self) -> Result<(), Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        self.plan_replacements(&handle)?;
+        for upgrade in upgrades_by_name(&handle, &self.local)? {
+            if self
+                .removals
+                .iter()
+                .any(|removed| removed.name() == upgrade.installed.name())
+            {
+                continue;
+            }
+            let available = &upgrade.available;
+            let reason = handle.borrow().ignore_reason(available.package.as_ref());
+            match reason {
+                Some(reason) => {
+                    log::info!("skipping upgrade of ignored package {}", available.name());
+                    self.skipped
+                        .retain(|skipped| skipped.name != available.name());
+                    self.skipped.push(SkippedUpgrade {
+                        name: available.name().to_owned(),
+                        old_version: upgrade.installed.version().to_owned(),
+                        new_version: available.version().to_owned(),
+                        reason,
+                    });
+                }
+                None => self.install(
+                    &available.database,
+                    available.package.clone(),
+                    upgrade
+                        .installed
+                        .reason()
+                        .unwrap_or(InstallReason::Explicit),
+                )?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Plan replacing installed packages with the sync packages that list them in `replaces`.
+    ///
+    /// Ignored installed packages are kept, and replacements built for an architecture that isn't
+    /// configured are passed over, so neither stops the rest of the upgrade.
+    fn plan_replacements(&mut self, handle: &Rc<RefCell<Handle>>) -> Result<(), Error> {
+        let databases = handle.borrow().ordered_sync_databases();
+        let mut candidates = Vec::new();
+        for db in databases {
+            db.packages(|package: Rc<SyncPackage>| -> Result<(), Error> {
+                if !package.replaces().is_empty() {
+                    candidates.push(Located::new(db.clone(), package));
+                }
+                Ok(())
+            })?;
+        }
+        let questions = handle.borrow().questions.clone();
+        for replacement in candidates {
+            // If the replacement is already installed this is just an upgrade.
+            if self.local.contains(replacement.name())?
+                || !handle
+                    .borrow()
+                    .may_use(replacement.name(), &replacement.db_name)
+                || handle
+                    .borrow()
+                    .ignore_reason(&*replacement.package)
+                    .is_some()
+            {
+                continue;
+            }
+            if !handle.borrow().architecture_allowed(&*replacement.package) {
+                log::warn!(
+                    "not using {} as a replacement, it is built for {}",
+                    replacement.name(),
+                    replacement.arch()
+                );
+                continue;
+            }
+            for depend in replacement
+                .replaces()
+                .iter()
+                .filter_map(|r| Depend::parse(r))
+            {
+                let installed = match self.local.find(depend.name)? {
+                    Some(installed) => installed,
+                    None => continue,
+                };
+                let matches = depend.constraint.map_or(true, |(comparison, version)| {
+                    target::satisfies(installed.version(), comparison, version)
+                });
+                if !matches
+                    || self
+                        .replacements
+                        .iter()
+                        .any(|planned| planned.replaced.name() == installed.name())
+                {
+                    continue;
+                }
+                if handle.borrow().ignore_reason(&*installed).is_some() {
+                    log::info!(
+                        "not replacing ignored package {} with {}",
+                        installed.name(),
+                        replacement.name()
+                    );
+                    continue;
+                }
+                if !questions.replace_package(&installed, &replacement) {
+                    log::info!(
+                        "not replacing {} with {}",
+                        installed.name(),
+                        replacement.name()
+                    );
+                    continue;
+                }
+                self.remove(installed.clone())?;
+                self.install(
+                    &replacement.database,
+                    replacement.package.clone(),
+                    installed.reason().unwrap_or(InstallReason::Explicit),
+                )?;
+                self.replacements.push(Replacement {
+                    replaced: installed,
+                    replacement: replacement.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Plan to remove an installed package.
+    ///
+    /// This fails if the package is held (see `AlpmBuilder::with_hold_package`), or a held package
+    /// depends on it directly or through other installed packages, unless `override_hold` has been
+    /// called.
+    pub fn remove(&mut self, package: Rc<LocalPackage>) -> Result<(), Error> {
+        if !self.override_hold {
+            self.check_hold(&package)?;
+        }
+        if !self
+            .removals
+            .iter()
+            .any(|planned| planned.name() == package.name())
+        {
+            self.removals.push(package);
+        }
+        Ok(())
+    }
+
+    /// Allow held packages, and packages they depend on, to be removed.
+    pub fn override_hold(&mut self, allow: bool) {
+        self.override_hold = allow;
+    }
+
+    /// Fail if removing the package would remove or break a held package.
+    fn check_hold(&self, package: &LocalPackage) -> Result<(), Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        let held_names = handle.borrow().packages_hold.clone();
+        if held_names.is_empty() {
+            return Ok(());
+        }
+        let providers = self.local.providers()?;
+        for held_name in held_names {
+            // Walk everything the held package needs, following dependencies through whichever
+            // installed packages satisfy them.
+            let mut needed: HashSet<String> = HashSet::new();
+            needed.insert(held_name.clone());
+            let mut queue = vec![held_name.clone()];
+            while let Some(name) = queue.pop() {
+                if name == package.name() {
+                    return Err(ErrorKind::PackageHeld {
+                        package: package.name().to_owned(),
+                        held: held_name,
+                    }
+                    .into());
+                }
+                let current = match self.local.find(&name)? {
+                    Some(current) => current,
+                    None => continue,
+                };
+                for dependency in current.depends() {
+                    let satisfiers = match providers.get(strip_version(dependency)) {
+                        Some(satisfiers) => satisfiers,
+                        None => continue,
+                    };
+                    for satisfier in satisfiers {
+                        if needed.insert(satisfier.name().to_owned()) {
+                            queue.push(satisfier.name().to_owned());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Plan to install a target, as typed by the user (see `Target`).
+    ///
+    /// A name is looked up as a package in each sync database in turn (or just the one given),
+    /// and if there is no such package, as a group, in which case every package in the group is
+    /// installed, and then as something packages provide. When several packages provide it, the
+    /// configured `question::Questions` chooses one. Package archives can't be planned yet.
+    ///
+    /// Databases are tried by priority, and pinned packages are only taken from the database
+    /// they are pinned to, unless a database is given explicitly. Packages built for other
+    /// architectures are passed over in favour of compatible ones in later databases (unless
+    /// `AlpmBuilder::with_foreign_architectures` is set); if only those match, the target is
+    /// refused with `ErrorKind::ForeignArchitecture`.
+    pub fn install_target(&mut self, target: &Target, reason: InstallReason) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = self.resolve_install_target(target, reason);
+        self.measure_resolve(start, result.is_ok());
+        result
+    }
+
+    fn resolve_install_target(
+        &mut self,
+        target: &Target,
+        reason: InstallReason,
+    ) -> Result<(), Error> {
+        let (database, name, constraint) = match target {
+            Target::Name {
+                database,
+                name,
+                constraint,
+            } => (database.as_ref(), name.as_str(), constraint.as_ref()),
+            _ => return Err(ErrorKind::UnsupportedTarget(target.to_string()).into()),
+        };
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        let databases = handle
+            .borrow()
+            .ordered_sync_databases()
+            .into_iter()
+            .filter(|db| database.map_or(true, |wanted| db.name() == wanted))
+            .collect::<Vec<_>>();
+        let may_use = |package: &str, db: &SyncDatabase| {
+            database.is_some() || handle.borrow().may_use(package, db.name())
+        };
+        let skip_architecture = |package: &SyncPackage| handle.borrow().skip_architecture(package);
+        let mut foreign = None;
+        for db in databases.iter().filter(|&db| may_use(name, db)) {
+            // Databases that can't be loaded are errors, not just databases without the package.
+            if !db.contains(name)? {
+                continue;
+            }
+            let package = db.package_latest(name)?;
+            if !constraint.map_or(true, |c| c.satisfied_by(package.version())) {
+                continue;
+            }
+            if !skip_architecture(&package) {
+                return self.install(db, package, reason);
+            }
+            if foreign.is_none() {
+                foreign = Some((db, package));
+            }
+        }
+        if let Some((db, package)) = foreign {
+            // Gives the error explaining why the package can't be used.
+            return self.install(db, package, reason);
+        }
+        // Groups don't have versions.
+        if constraint.is_none() {
+            let mut members: Vec<(&SyncDatabase, Rc<SyncPackage>)> = Vec::new();
+            for db in &databases {
+                let group = match db.group(name)? {
+                    Some(group) => group,
+                    None => continue,
+                };
+                for member in group.members() {
+                    // Like packages, earlier databases take precedence.
+                    let seen = members
+                        .iter()
+                        .any(|(_, package)| package.name() == member.as_str());
+                    if may_use(member.as_str(), db) && !seen {
+                        let package = db.package_latest(member)?;
+                        if skip_architecture(&package) {
+                            log::debug!("skipping {} for {}", member, package.arch());
+                            continue;
+                        }
+                        members.push((db, package));
+                    }
+                }
+            }
+            if !members.is_empty() {
+                members.sort_by(|(_, a), (_, b)| a.name().cmp(b.name()));
+                for (db, package) in members {
+                    self.install(db, package, reason)?;
+                }
+                return Ok(());
+            }
+            // Finally, packages providing the name, like `bash` providing `sh`.
+            let mut providers: Vec<Located<Rc<SyncPackage>>> = Vec::new();
+            for db in &databases {
+                db.packages(|package: Rc<SyncPackage>| -> Result<(), Error> {
+                    let provides = package
+                        .provides()
+                        .iter()
+                        .any(|provision| strip_version(provision) == name);
+                    let seen = providers
+                        .iter()
+                        .any(|provider| provider.name() == package.name());
+                    if provides
+                        && !seen
+                        && may_use(package.name(), db)
+                        && !skip_architecture(&package)
+                    {
+                        providers.push(Located::new(db.clone(), package));
+                    }
+                    Ok(())
+                })?;
+            }
+            let chosen = match providers.len() {
+                0 => None,
+                1 => Some(0),
+                _ => {
+                    let questions = handle.borrow().questions.clone();
+                    questions.choose_provider(name, &providers)
+                }
+            };
+            if let Some(provider) = chosen.and_then(|idx| providers.into_iter().nth(idx)) {
+                return self.install(&provider.database, provider.package, reason);
+            }
+        }
+        Err(ErrorKind::TargetNotFound(target.to_string()).into())
+    }
+
+    /// Plan to remove a target, as typed by the user (see `Target`).
+    ///
+    /// A name is looked up as an installed package, and then as a group of installed packages.
+    pub fn remove_target(&mut self, target: &Target) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = self.resolve_remove_target(target);
+        self.measure_resolve(start, result.is_ok());
+        result
+    }
+
+    fn resolve_remove_target(&mut self, target: &Target) -> Result<(), Error> {
+        let not_found = || Error::from(ErrorKind::TargetNotFound(target.to_string()));
+        let (name, constraint) = match target {
+            Target::Name {
+                database,
+                name,
+                constraint,
+            } => {
+                if database
+                    .as_ref()
+                    .map_or(false, |db| db != self.local.name())
+                {
+                    return Err(not_found());
+                }
+                (name, constraint.as_ref())
+            }
+            _ => return Err(ErrorKind::UnsupportedTarget(target.to_string()).into()),
+        };
+        if let Some(package) = self.local.find(name)? {
+            if constraint.map_or(true, |c| c.satisfied_by(package.version())) {
+                return self.remove(package);
+            }
+        }
+        if constraint.is_none() {
+            let mut members = Vec::new();
+            self.local
+                .packages(|package: Rc<LocalPackage>| -> Result<(), Error> {
+                    if package.groups().iter().any(|group| group == name) {
+                        members.push(package);
+                    }
+                    Ok(())
+                })?;
+            if !members.is_empty() {
+                members.sort_by(|a, b| a.name().cmp(b.name()));
+                for package in members {
+                    self.remove(package)?;
+                }
+                return Ok(());
+            }
+        }
+        Err(not_found())
+    }
+
+    /// Report how long resolving a target took.
+    fn measure_resolve(&self, start: Instant, success: bool) {
+        if let Some(handle) = self.handle.upgrade() {
+            handle
+                .borrow()
+                .measure(|metrics| metrics.target_resolved(start.elapsed(), success));
+        }
+    }
+
+    /// Plan to install a package because it is an optional dependency of something in the plan.
+    ///
+    /// The package is recorded as a dependency, unless it is already planned as explicit.
+    pub fn include_optional(
+        &mut self,
+        database: &SyncDatabase,
+        package: Rc<SyncPackage>,
+    ) -> Result<(), Error> {
+        let reason = match self.planned(package.name()) {
+            Some(planned) => planned.reason,
+            None => InstallReason::Depend,
+        };
+        self.install(database, package, reason)
+    }
+
+    /// The optional dependencies of packages being installed that are neither installed nor
+    /// already in the plan.
+    ///
+    /// Frontends can offer these to the user, and add the chosen ones with `include_optional`.
+    pub fn optional_dependencies(&self) -> Result<Vec<OptionalDependency>, Error> {
+        let mut optional = Vec::new();
+        for planned in &self.installs {
+            for raw in planned.package.optional_depends() {
+                let dependency = OptionalDependency::parse(planned.package.name(), raw);
+                let wanted = dependency.package_name();
+                let seen = optional
+                    .iter()
+                    .any(|d: &OptionalDependency| d.package_name() == wanted);
+                if !seen && self.planned(wanted).is_none() && self.local.find(wanted)?.is_none() {
+                    optional.push(dependency);
+                }
+            }
+        }
+        Ok(optional)
+    }
+
+    /// Add the optional dependencies chosen by the configured `question::Questions` to the plan.
+    ///
+    /// See `choose_optional_dependencies`.
+    pub fn answer_optional_dependencies(&mut self) -> Result<(), Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        let questions = handle.borrow().questions.clone();
+        self.choose_optional_dependencies(|dependency| {
+            questions.install_optional_dependency(dependency)
+        })
+    }
+
+    /// Ask which optional dependencies to install, and add the chosen ones to the plan.
+    ///
+    /// `choose` is called for each of `optional_dependencies`. Chosen packages are taken from the
+    /// preferred sync database that has them; ones that can't be found are skipped with a
+    /// warning. Databases that can't be loaded are errors, rather than passed over.
+    pub fn choose_optional_dependencies(
+        &mut self,
+        mut choose: impl FnMut(&OptionalDependency) -> bool,
+    ) -> Result<(), Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        for dependency in self.optional_dependencies()? {
+            if !choose(&dependency) {
+                continue;
+            }
+            let name = dependency.package_name();
+            let databases = handle.borrow().sync_databases_for(name);
+            let mut found = None;
+            for db in databases {
+                if !db.contains(name)? {
+                    continue;
+                }
+                let package = db.package_latest(name)?;
+                if !handle.borrow().skip_architecture(&*package) {
+                    found = Some((db, package));
+                    break;
+                }
+            }
+            match found {
+                Some((db, package)) => self.include_optional(&db, package)?,
+                None => handle.borrow().warn(Warning::OptionalDependencyNotFound {
+                    package: dependency.package,
+                    dependency: dependency.name,
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    /// The planned install of the package with the given name, if there is one.
+    fn planned(&self, name: &str) -> Option<&PlannedInstall> {
+        self.installs
+            .iter()
+            .find(|planned| planned.package.name() == name)
+    }
+
+    /// The packages that will be installed or upgraded.
+    pub fn installs(&self) -> &[PlannedInstall] {
+        &self.installs
+    }
+
+    /// The packages that will be installed or upgraded, in batches that can be extracted at the
+    /// same time (see `Alpm::extract_plan`).
+    ///
+    /// No package depends on another in the same batch, and each package is in a later batch
+    /// than the packages it depends on. Dependency cycles are broken as `Resolver` breaks them.
+    pub fn extraction_batches(&self) -> Vec<Vec<&PlannedInstall>> {
+        resolver::extraction_batches(&self.installs)
+            .into_iter()
+            .map(|batch| batch.into_iter().map(|idx| &self.installs[idx]).collect())
+            .collect()
+    }
+
+    /// The packages that will be removed.
+    pub fn removals(&self) -> &[Rc<LocalPackage>] {
+        &self.removals
+    }
+
+    /// The installed packages `sysupgrade` replaces with packages that list them in `replaces`.
+    ///
+    /// The replaced packages are also in `removals`, and their replacements in `installs`.
+    pub fn replacements(&self) -> &[Replacement] {
+        &self.replacements
+    }
+
+    /// The changed configuration files that upgrading will probably leave a `.pacnew` file next
+    /// to, sorted by path, so a merge tool can be offered once the transaction is done.
+    ///
+    /// Pacman only writes a `.pacnew` file if the new package's version of the file is different
+    /// too, which isn't known until the package is downloaded, so some of these may not appear.
+    /// Files that can't be read (like `/etc/sudoers` when not running as root) are listed with
+    /// `unknown` set, since whether they were changed can't be told.
+    pub fn pending_merges(&self) -> Result<Vec<PendingMerge>, Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        let root = handle.borrow().root_path.clone();
+        let mut merges = Vec::new();
+        for planned in &self.installs {
+            let installed = match &planned.upgrades {
+                Some(installed) => installed,
+                None => continue,
+            };
+            for backup in installed.backup_files() {
+                let unknown = match backup.is_modified(&root) {
+                    Ok(true) => false,
+                    Ok(false) => continue,
+                    Err(e) => {
+                        log::debug!("cannot check \"{}\": {}", backup.path.display(), e);
+                        true
+                    }
+                };
+                let path = root.join(&backup.path);
+                let mut pacnew = path.clone().into_os_string();
+                pacnew.push(".pacnew");
+                merges.push(PendingMerge {
+                    package: planned.package.name().to_owned(),
+                    path,
+                    pacnew: pacnew.into(),
+                    unknown,
+                });
+            }
+        }
+        merges.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(merges)
+    }
+
+    /// The upgrades left out by `sysupgrade` because they are ignored.
+    pub fn skipped(&self) -> &[SkippedUpgrade] {
+        &self.skipped
+    }
+
+    /// Everything needed to show the user what the plan will do before it is run, like the table
+    /// pacman prints before a transaction.
+    pub fn summary(&self) -> Result<PlanSummary, Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        let handle_ref = handle.borrow();
+        // Cached archives that are truncated or outdated will have to be downloaded again.
+        let is_cached = |package: &SyncPackage| {
+            package
+                .find_archive(
+                    handle_ref.cache_directories.iter().map(PathBuf::as_path),
+                    &handle_ref,
+                )
+                .is_some()
+        };
+
+        let mut summary = PlanSummary::default();
+        for planned in &self.installs {
+            let package = &planned.package;
+            let download_size = if is_cached(package) {
+                0
+            } else {
+                package.compressed_size()
+            };
+            let old_size = planned.upgrades.as_ref().map_or(0, |old| old.size());
+            match planned.upgrades {
+                Some(_) => summary.upgraded += 1,
+                None => summary.added += 1,
+            }
+            let target = SummaryTarget {
+                database: planned.database.clone(),
+                name: package.name().to_owned(),
+                old_version: planned
+                    .upgrades
+                    .as_ref()
+                    .map(|old| old.version().to_owned()),
+                new_version: Some(package.version().to_owned()),
+                download_size,
+                size_change: package.size() as i64 - old_size as i64,
+            };
+            summary.download_size += target.download_size;
+            summary.size_change += target.size_change;
+            summary.targets.push(target);
+        }
+        for package in &self.removals {
+            summary.removed += 1;
+            let target = SummaryTarget {
+                database: self.local.name().to_owned(),
+                name: package.name().to_owned(),
+                old_version: Some(package.version().to_owned()),
+                new_version: None,
+                download_size: 0,
+                size_change: -(package.size() as i64),
+            };
+            summary.size_change += target.size_change;
+            summary.targets.push(target);
+        }
+        summary
+            .targets
+            .sort_by(|a, b| (&a.database, &a.name).cmp(&(&b.database, &b.name)));
+        summary.pending_merges = self.pending_merges()?;
+        summary.skipped = self.skipped.clone();
+        summary.skipped.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(summary)
+    }
+
+    /// Synchronize only the sync databases that packages in the plan come from, instead of all of
+    /// them.
+    ///
+    /// A database that fails to synchronize doesn't stop the others. The planned packages are not
+    /// updated: plan again afterwards to pick up newer versions.
+    pub fn refresh_required_dbs(&self, force: bool) -> Result<PerDb<()>, Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        let databases: Vec<SyncDatabase> = handle
+            .borrow()
+            .ordered_sync_databases()
+            .into_iter()
+            .filter(|db| {
+                self.installs
+                    .iter()
+                    .any(|planned| planned.database == db.name())
+            })
+            .collect();
+        Ok(synchronize_all(&databases, force))
+    }
+
+    /// Synchronize only the sync databases that could provide the targets.
+    ///
+    /// For a target naming a database, that is the database. Otherwise it is the databases the
+    /// target may be taken from (see `AlpmBuilder::with_package_pin`) that have it as a package or
+    /// group, or all of those if none has it yet. Targets that aren't names need no databases. A
+    /// database that fails to synchronize doesn't stop the others.
+    pub fn refresh_dbs_for_targets(
+        &self,
+        targets: &[Target],
+        force: bool,
+    ) -> Result<PerDb<()>, Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        let handle = handle.borrow();
+        let mut wanted: Vec<String> = Vec::new();
+        for target in targets {
+            let names: Vec<String> = match target {
+                Target::Name {
+                    database: Some(database),
+                    ..
+                } => vec![database.clone()],
+                Target::Name { name, .. } => {
+                    let allowed = handle.sync_databases_for(name);
+                    // Databases that haven't been downloaded yet don't have anything.
+                    let having: Vec<String> = allowed
+                        .iter()
+                        .filter(|db| {
+                            db.package_latest(name).is_ok()
+                                || db.group(name).map_or(false, |group| group.is_some())
+                        })
+                        .map(|db| db.name().to_owned())
+                        .collect();
+                    if having.is_empty() {
+                        allowed.iter().map(|db| db.name().to_owned()).collect()
+                    } else {
+                        having
+                    }
+                }
+                Target::File(_) | Target::Url(_) => Vec::new(),
+            };
+            wanted.extend(names);
+        }
+        let databases: Vec<SyncDatabase> = handle
+            .ordered_sync_databases()
+            .into_iter()
+            .filter(|db| wanted.iter().any(|name| name == db.name()))
+            .collect();
+        drop(handle);
+        Ok(synchronize_all(&databases, force))
+    }
+
+    /// The plan as pacman shows it before a transaction, for a terminal `width` characters wide.
+    ///
+    /// This is the `Packages (N)` list of names and versions, wrapped to the width, followed by
+    /// the total download, installed, removed and net sizes that apply. When the plan both
+    /// installs and removes, removals are marked `[removal]`.
+    pub fn format_table(&self, width: usize) -> Result<String, Error> {
+        let summary = self.summary()?;
+        let mut targets: Vec<&SummaryTarget> = summary.targets.iter().collect();
+        targets.sort_by(|a, b| a.name.cmp(&b.name));
+        let mixed = !self.installs.is_empty() && !self.removals.is_empty();
+        let items: Vec<String> = targets
+            .iter()
+            .map(|target| match (&target.new_version, &target.old_version) {
+                (Some(version), _) => format!("{}-{}", target.name, version),
+                (None, Some(version)) if mixed => {
+                    format!("{}-{} [removal]", target.name, version)
+                }
+                (None, Some(version)) => format!("{}-{}", target.name, version),
+                (None, None) => target.name.clone(),
+            })
+            .collect();
+        let mut out = wrap_list(&format!("Packages ({})", items.len()), &items, width);
+        out.push_str("\n\n");
+
+        let mut sizes = Vec::new();
+        if !self.installs.is_empty() {
+            if summary.download_size > 0 {
+                sizes.push(("Total Download Size:", summary.download_size as i64));
+            }
+            let installed: u64 = self.installs.iter().map(|p| p.package.size()).sum();
+            sizes.push(("Total Installed Size:", installed as i64));
+            if summary.upgraded > 0 || mixed {
+                sizes.push(("Net Upgrade Size:", summary.size_change));
+            }
+        } else if !self.removals.is_empty() {
+            let removed: u64 = self.removals.iter().map(|p| p.size()).sum();
+            sizes.push(("Total Removed Size:", removed as i64));
+        }
+        let sizes: Vec<(&str, String)> = sizes
+            .into_iter()
+            .map(|(label, bytes)| (label, format_mib(bytes)))
+            .collect();
+        let label_width = sizes
+            .iter()
+            .map(|(label, _)| label.len())
+            .max()
+            .unwrap_or(0);
+        let size_width = sizes.iter().map(|(_, size)| size.len()).max().unwrap_or(0);
+        for (label, size) in sizes {
+            out.push_str(&format!(
+                "{:<lw$}  {:>sw$}\n",
+                label,
+                size,
+                lw = label_width,
+                sw = size_width
+            ));
+        }
+        Ok(out)
+    }
+}
+
+/// Synchronize the databases in turn, carrying on past failures.
+pub(crate) fn synchronize_all(databases: &[SyncDatabase], force: bool) -> PerDb<()> {
+    let mut results = PerDb::new();
+    for db in databases {
+        results.record(db.name(), db.synchronize(force));
+    }
+    results
+}
+
+/// A label followed by items separated by two spaces, wrapped to `width` with continuation lines
+/// indented to line up with the first item.
+///
+/// An item longer than a line gets a line to itself. A width of 0 means no wrapping.
+fn wrap_list(label: &str, items: &[String], width: usize) -> String {
+    let indent = label.chars().count() + 1;
+    let mut out = label.to_owned();
+    let mut column = indent - 1;
+    for (idx, item) in items.iter().enumerate() {
+        let len = item.chars().count();
+        let sep = if idx == 0 { 1 } else { 2 };
+        if width > 0 && idx > 0 && column + sep + len > width {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent));
+            column = indent;
+        } else {
+            out.push_str(&" ".repeat(sep));
+            column += sep;
+        }
+        out.push_str(item);
+        column += len;
+    }
+    out
+}
+
+/// A size in MiB with two decimal places, as pacman shows totals.
+fn format_mib(bytes: i64) -> String {
+    format!("{:.2} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// An optional dependency of a package, from its `optdepends`.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct OptionalDependency {
+    /// The package that optionally depends on this.
+    pub package: String,
+    /// The dependency, possibly with a version constraint.
+    pub name: String,
+    /// Why the package might want this dependency.
+    pub description: Option<String>,
+}
+
+impl OptionalDependency {
+    /// Parse an entry of the form `name: description`.
+    fn parse(package: &str, raw: &str) -> Self {
+        let (name, description) = dependency::split_optional(raw);
+        OptionalDependency {
+            package: package.to_owned(),
+            name: name.to_owned(),
+            description: description.map(str::to_owned),
+        }
+    }
+
+    /// The name of the dependency without any version constraint.
+    pub fn package_name(&self) -> &str {
+        strip_version(&self.name)
+    }
+}
+
+/// Remove any version constraint (e.g. `>=1.0`) from a dependency.
+fn strip_version(dependency: &str) -> &str {
+    match dependency.find(|c| c == '<' || c == '>' || c == '=') {
+        Some(idx) => &dependency[..idx],
+        None => dependency,
+    }
+}
+
+/// A summary of what a plan will do.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PlanSummary {
+    /// The number of bytes that need downloading (packages already in a cache are not counted).
+    pub download_size: u64,
+    /// How much the installed size will change by, in bytes.
+    pub size_change: i64,
+    /// The number of packages that will be newly installed.
+    pub added: usize,
+    /// The number of packages that will be removed.
+    pub removed: usize,
+    /// The number of installed packages that will be replaced by a different version.
+    pub upgraded: usize,
+    /// Each package in the plan, sorted by database and then name.
+    pub targets: Vec<SummaryTarget>,
+    /// Changed configuration files that will probably get a `.pacnew` file, sorted by path,
+    /// including any that couldn't be read (see `MutationPlan::pending_merges`).
+    pub pending_merges: Vec<PendingMerge>,
+    /// Upgrades left out because they are ignored, sorted by name.
+    pub skipped: Vec<SkippedUpgrade>,
+}
+
+/// A changed configuration file that upgrading will probably write a `.pacnew` file next to,
+/// from `MutationPlan::pending_merges`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PendingMerge {
+    /// The package being upgraded.
+    pub package: String,
+    /// The changed file.
+    pub path: PathBuf,
+    /// Where the new version of the file will be written.
+    pub pacnew: PathBuf,
+    /// Whether the file couldn't be read, so it may not have been changed.
+    pub unknown: bool,
+}
+
+/// A single package in a `PlanSummary`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SummaryTarget {
+    /// The database the package comes from (the local database for removals).
+    pub database: String,
+    /// The package name.
+    pub name: String,
+    /// The version currently installed, if any.
+    pub old_version: Option<String>,
+    /// The version that will be installed, or `None` if the package is being removed.
+    pub new_version: Option<String>,
+    /// The number of bytes that need downloading for this package.
+    pub download_size: u64,
+    /// How much the installed size will change by, in bytes.
+    pub size_change: i64,
+}
+
+/// An installed package that `MutationPlan::sysupgrade` replaces with a package that lists it in
+/// `replaces`.
+#[derive(Debug, Clone)]
+pub struct Replacement {
+    /// The installed package being removed.
+    pub replaced: Rc<LocalPackage>,
+    /// The package replacing it, and where it comes from.
+    pub replacement: Located<Rc<SyncPackage>>,
+}
+
+/// An upgrade that `MutationPlan::sysupgrade` left out.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SkippedUpgrade {
+    /// The package name.
+    pub name: String,
+    /// The version installed.
+    pub old_version: String,
+    /// The version that would have been installed.
+    pub new_version: String,
+    /// Why the upgrade was left out.
+    pub reason: IgnoreReason,
+}
+
+/// Why a package is ignored, from `Alpm::is_ignored`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum IgnoreReason {
+    /// The package is ignored by name.
+    Package,
+    /// The package name matches this ignored pattern, like `linux-*`.
+    Pattern(String),
+    /// The package is in this ignored group (the group name, not the pattern it matched).
+    Group(String),
+}
+
+/// An installed package that has a newer version in a sync database.
+#[derive(Debug, Clone)]
+pub struct Upgrade {
+    /// The installed package.
+    pub installed: Rc<LocalPackage>,
+    /// The new version of the package, and where it comes from.
+    pub available: Located<Rc<SyncPackage>>,
+}
+
+/// Find the installed packages that have newer versions available, grouped by the database they
+/// come from and sorted by name.
+///
+/// Each package is looked for in the sync databases by priority, respecting pins and skipping
+/// versions built for other architectures, and packages that shouldn't be upgraded are skipped.
+/// Databases that can't be loaded are recorded as failures, and a package isn't looked for past
+/// one, since an older version in a lower-priority database would look like the upgrade.
+pub(crate) fn upgradable(
+    handle: &Rc<RefCell<Handle>>,
+    local: &LocalDatabase,
+) -> Result<PerDb<Vec<Upgrade>>, Error> {
+    let mut upgrades = PerDb::new();
+    let databases = handle.borrow().ordered_sync_databases();
+    for db in databases {
+        upgrades.record(db.name(), db.load().map(|()| Vec::new()));
+    }
+    local.packages(|installed: Rc<LocalPackage>| -> Result<(), Error> {
+        let handle = handle.borrow();
+        if handle.packages_no_upgrade.contains(installed.name()) {
+            return Ok(());
+        }
+        let mut found = None;
+        for db in handle.sync_databases_for(installed.name()) {
+            if upgrades.get(db.name()).is_none() {
+                break;
+            }
+            let pkg = db
+                .package_latest(installed.name())
+                .ok()
+                .filter(|pkg| !handle.skip_architecture(&**pkg));
+            if let Some(pkg) = pkg {
+                found = Some((db, pkg));
+                break;
+            }
+        }
+        if let Some((db, available)) = found {
+            if Version::parse(available.version()) > Version::parse(installed.version()) {
+                if let Some(found) = upgrades.get_mut(db.name()) {
+                    found.push(Upgrade {
+                        installed,
+                        available: Located::new(db, available),
+                    });
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(upgrades)
+}
+
+/// The upgrades from every database, sorted by name.
+///
+/// Fails if any database couldn't be loaded, since acting on the rest would be a partial upgrade.
+fn upgrades_by_name(
+    handle: &Rc<RefCell<Handle>>,
+    local: &LocalDatabase,
+) -> Result<Vec<Upgrade>, Error> {
+    let mut upgrades: Vec<Upgrade> = upgradable(handle, local)?
+        .into_result()?
+        .into_iter()
+        .flat_map(|(_, upgrades)| upgrades)
+        .collect();
+    upgrades.sort_by(|a, b| a.installed.name().cmp(b.installed.name()));
+    Ok(upgrades)
+}
+
+/// How upgrading a package changes disk usage, from `Alpm::upgrade_impact`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UpgradeImpact {
+    /// The database the new version comes from.
+    pub database: String,
+    /// The package name.
+    pub name: String,
+    /// The version currently installed.
+    pub old_version: String,
+    /// The version that would be installed.
+    pub new_version: String,
+    /// How much the installed size would change by, in bytes.
+    pub size_change: i64,
+    /// The number of bytes that need downloading (0 if the package is already in a cache).
+    pub download_size: u64,
+}
+
+/// The installed-size change and download size of every upgradable package, largest first.
+///
+/// Packages are sorted by size change, then download size, both descending, and then by name.
+pub(crate) fn upgrade_impact(
+    handle: &Rc<RefCell<Handle>>,
+    local: &LocalDatabase,
+) -> Result<Vec<UpgradeImpact>, Error> {
+    let upgrades = upgrades_by_name(handle, local)?;
+    let handle = handle.borrow();
+    let mut impact: Vec<UpgradeImpact> = upgrades
+        .into_iter()
+        .map(|upgrade| {
+            let available = &upgrade.available;
+            let cached = available
+                .find_archive(
+                    handle.cache_directories.iter().map(PathBuf::as_path),
+                    &handle,
+                )
+                .is_some();
+            UpgradeImpact {
+                database: available.db_name.clone(),
+                name: upgrade.installed.name().to_owned(),
+                old_version: upgrade.installed.version().to_owned(),
+                new_version: available.version().to_owned(),
+                size_change: available.size() as i64 - upgrade.installed.size() as i64,
+                download_size: if cached {
+                    0
+                } else {
+                    available.compressed_size()
+                },
+            }
+        })
+        .collect();
+    impact.sort_by(|a, b| {
+        b.size_change
+            .cmp(&a.size_change)
+            .then(b.download_size.cmp(&a.download_size))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(impact)
+}
+
+/// What `Alpm::prefetch_upgrades` did.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PrefetchReport {
+    /// The packages that were downloaded.
+    pub downloaded: Vec<String>,
+    /// The number of bytes downloaded.
+    pub downloaded_bytes: u64,
+    /// The packages that were already in a cache.
+    pub cached: Vec<String>,
+    /// The packages left for a later call, because they didn't fit in the limit.
+    pub deferred: Vec<String>,
+    /// The packages that could not be downloaded, with why.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Download the archives of upgradable packages into the first cache directory, up to
+/// `limit_bytes`.
+///
+/// Ignored packages are left out, since they won't be upgraded. Packages are tried in name order,
+/// and one that doesn't fit in what is left of the limit is deferred, while smaller ones after it
+/// may still be downloaded. A failed download doesn't stop the others, and what it got is kept
+/// to be resumed next time.
+pub(crate) fn prefetch_upgrades(
+    handle: &Rc<RefCell<Handle>>,
+    local: &LocalDatabase,
+    limit_bytes: u64,
+) -> Result<PrefetchReport, Error> {
+    let upgrades = upgrades_by_name(handle, local)?;
+    let cache_directories = handle.borrow().cache_directories.clone();
+    let mut report = PrefetchReport::default();
+    let dest_dir = match cache_directories.first() {
+        Some(dir) => dir,
+        None => return Ok(report),
+    };
+    for upgrade in upgrades {
+        let available = upgrade.available;
+        let name = available.name().to_owned();
+        let (ignored, cached) = {
+            let handle_ref = handle.borrow();
+            let ignored = handle_ref
+                .ignore_reason(available.package.as_ref())
+                .is_some();
+            let cached = available
+                .find_archive(cache_directories.iter().map(PathBuf::as_path), &handle_ref)
+                .is_some();
+            (ignored, cached)
+        };
+        if ignored {
+            continue;
+        }
+        if cached {
+            report.cached.push(name);
+            continue;
+        }
+        let size = available.compressed_size();
+        if report.downloaded_bytes + size > limit_bytes {
+            report.deferred.push(name);
+            continue;
+        }
+        log::info!("prefetching {} {}", name, available.version());
+        match available
+            .database
+            .prefetch_package(&available.package, dest_dir)
+        {
+            Ok(_) => {
+                report.downloaded_bytes += size;
+                report.downloaded.push(name);
+            }
+            Err(e) => report.failed.push((name, e.to_string())),
+        }
+    }
+    Ok(report)
 }
 
 pub fn remove_pkg(name: String) {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{fs, io::Write, path::Path};
+
+    use libflate::gzip::Encoder;
+
+    use super::*;
+    use crate::{
+        db::{DuplicatePolicy, EntryProblem, Provenance, SyncDatabaseOptions},
+        question::NonInteractive,
+        Alpm, AlpmBuilder,
+    };
+
+    /// A package for `alpm_with`: its name, version, and `desc` sections like
+    /// `("DEPENDS", &["foo>=1"])`. The architecture is `any` unless an `ARCH` section is given.
+    pub(crate) type TestPackage<'a> = (&'a str, &'a str, &'a [(&'a str, &'a [&'a str])]);
+
+    /// The `desc` file of an installed or available package.
+    pub(crate) fn desc((name, version, sections): &TestPackage, installed: bool) -> String {
+        let mut desc = format!(
+            "%NAME%\n{}\n\n%VERSION%\n{}\n\n%DESC%\ntest\n\n%BUILDDATE%\n0\n\n\
+             %PACKAGER%\nme\n\n",
+            name, version
+        );
+        if installed {
+            desc.push_str("%INSTALLDATE%\n0\n\n%VALIDATION%\nnone\n\n%SIZE%\n1\n\n");
+        } else {
+            desc.push_str(&format!(
+                "%FILENAME%\n{}-{}-any.pkg.tar.zst\n\n%CSIZE%\n1\n\n%ISIZE%\n1\n\n\
+                 %MD5SUM%\n\n%SHA256SUM%\n\n%PGPSIG%\n\n",
+                name, version
+            ));
+        }
+        if sections.iter().all(|(key, _)| *key != "ARCH") {
+            desc.push_str("%ARCH%\nany\n\n");
+        }
+        for (key, values) in sections.iter() {
+            desc.push_str(&format!("%{}%\n{}\n\n", key, values.join("\n")));
+        }
+        desc
+    }
+
+    /// A read-only x86_64 instance in `root`, changed by `configure`, with the `installed`
+    /// packages in its local database and the given sync databases, in order of priority.
+    pub(crate) fn alpm_with(
+        root: &Path,
+        configure: impl FnOnce(AlpmBuilder) -> AlpmBuilder,
+        installed: &[TestPackage],
+        sync: &[(&str, &[TestPackage])],
+    ) -> Alpm {
+        let db_path = root.join("db");
+        let local = db_path.join("local");
+        fs::create_dir_all(&local).unwrap();
+        fs::create_dir_all(db_path.join("sync")).unwrap();
+        fs::write(local.join("ALPM_DB_VERSION"), "9\n").unwrap();
+        for package in installed {
+            let entry = local.join(format!("{}-{}", package.0, package.1));
+            fs::create_dir(&entry).unwrap();
+            fs::write(entry.join("desc"), desc(package, true)).unwrap();
+            fs::write(entry.join("files"), "%FILES%\nusr/\n\n").unwrap();
+            let mut mtree = Encoder::new(fs::File::create(entry.join("mtree")).unwrap()).unwrap();
+            mtree.write_all(b"#mtree\n").unwrap();
+            mtree.finish().into_result().unwrap();
+        }
+        let builder = Alpm::new()
+            .with_root_path(root)
+            .with_database_path(&db_path)
+            .with_architecture("x86_64")
+            .with_read_only(true);
+        let alpm = configure(builder).build().unwrap();
+        for (name, packages) in sync {
+            let file = fs::File::create(db_path.join("sync").join(format!("{}.db", name))).unwrap();
+            let mut archive = tar::Builder::new(Encoder::new(file).unwrap());
+            for package in packages.iter() {
+                let desc = desc(package, false);
+                let mut header = tar::Header::new_gnu();
+                header.set_size(desc.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                let path = format!("{}-{}/desc", package.0, package.1);
+                archive
+                    .append_data(&mut header, path, desc.as_bytes())
+                    .unwrap();
+            }
+            archive
+                .into_inner()
+                .unwrap()
+                .finish()
+                .into_result()
+                .unwrap();
+            alpm.register_sync_database(*name, SyncDatabaseOptions::new())
+                .unwrap();
+        }
+        alpm
+    }
+
+    /// The names of the packages a plan installs, in order.
+    pub(crate) fn install_names(plan: &MutationPlan) -> Vec<&str> {
+        plan.installs()
+            .iter()
+            .map(|planned| planned.package.name())
+            .collect()
+    }
+
+    /// A system upgrade leaves out ignored packages and groups, replaces packages that aren't
+    /// ignored, and passes over replacements for other architectures.
+    #[test]
+    fn sysupgrade_ignores_and_replaces() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| {
+                builder
+                    .with_ignore_group("ba*")
+                    .with_ignore_package("kept")
+                    .with_questions(NonInteractive::new().with_replace(true))
+            },
+            &[
+                ("foo", "1-1", &[]),
+                ("bar", "1-1", &[("GROUPS", &["base"])]),
+                ("old", "1-1", &[]),
+                ("kept", "1-1", &[]),
+                ("legacy", "1-1", &[]),
+            ],
+            &[(
+                "core",
+                &[
+                    ("foo", "2-1", &[]),
+                    ("bar", "2-1", &[("GROUPS", &["base"])]),
+                    ("new", "1-1", &[("REPLACES", &["old"])]),
+                    ("keeper", "1-1", &[("REPLACES", &["kept"])]),
+                    (
+                        "modern",
+                        "1-1",
+                        &[("ARCH", &["aarch64"]), ("REPLACES", &["legacy"])],
+                    ),
+                ],
+            )],
+        );
+        let mut plan = alpm.plan();
+        plan.sysupgrade().unwrap();
+        let mut installs = install_names(&plan);
+        installs.sort();
+        assert_eq!(installs, vec!["foo", "new"]);
+        let replaced: Vec<_> = plan
+            .replacements()
+            .iter()
+            .map(|r| (r.replaced.name(), r.replacement.name()))
+            .collect();
+        assert_eq!(replaced, vec![("old", "new")]);
+        let skipped: Vec<_> = plan
+            .skipped()
+            .iter()
+            .map(|s| (s.name.as_str(), s.reason.clone()))
+            .collect();
+        assert_eq!(skipped, vec![("bar", IgnoreReason::Group("base".into()))]);
+
+        let local = alpm.local_database();
+        let is_ignored = |name: &str| alpm.is_ignored(&*local.find(name).unwrap().unwrap());
+        assert_eq!(is_ignored("foo"), None);
+        assert_eq!(is_ignored("kept"), Some(IgnoreReason::Package));
+        assert_eq!(is_ignored("bar"), Some(IgnoreReason::Group("base".into())));
+    }
+
+    /// `LocalDatabase::gc` removes the entries it is allowed to, and leaves the rest.
+    #[test]
+    fn gc_broken_entries() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| builder.with_read_only(false),
+            &[("foo", "1-1", &[]), ("bar", "1-1", &[])],
+            &[],
+        );
+        let local_path = root.path().join("db/local");
+        fs::create_dir(local_path.join("empty-1-1")).unwrap();
+        // An entry whose desc file was copied from another package.
+        let stale = local_path.join("qux-1-1");
+        fs::create_dir(&stale).unwrap();
+        fs::copy(local_path.join("bar-1-1/desc"), stale.join("desc")).unwrap();
+
+        let local = alpm.local_database();
+        let mut seen = Vec::new();
+        let removed = local
+            .gc(|broken| {
+                seen.push(broken.problem.clone());
+                broken.problem == EntryProblem::MissingDesc
+            })
+            .unwrap();
+        assert_eq!(seen.len(), 2);
+        assert!(seen.contains(&EntryProblem::Mismatch {
+            name: "bar".into(),
+            version: "1-1".into(),
+        }));
+        let removed: Vec<_> = removed.iter().map(|broken| broken.path.clone()).collect();
+        assert_eq!(removed, vec![local_path.join("empty-1-1")]);
+        assert!(stale.exists());
+        assert!(local.find("foo").unwrap().is_some());
+    }
+
+    /// Stale duplicate entries are removed, including ones whose versions are equal but written
+    /// differently, which stop the database loading.
+    #[test]
+    fn find_latest_duplicate() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| builder.with_duplicate_policy(DuplicatePolicy::Allow),
+            &[("foo", "2-1", &[]), ("foo", "10-1", &[])],
+            &[("core", &[("foo", "11-1", &[])])],
+        );
+        let local = alpm.local_database();
+        assert_eq!(local.find("foo").unwrap().unwrap().version(), "10-1");
+        assert_eq!(local.version_of("foo").unwrap(), Some("10-1".to_owned()));
+        let mut plan = alpm.plan();
+        plan.sysupgrade().unwrap();
+        let upgrades = plan.installs()[0].upgrades.as_ref().unwrap();
+        assert_eq!(upgrades.version(), "10-1");
+    }
+
+    #[test]
+    fn remove_stale_duplicates() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| {
+                builder
+                    .with_read_only(false)
+                    .with_duplicate_policy(DuplicatePolicy::Allow)
+            },
+            &[
+                ("foo", "1-1", &[]),
+                ("foo", "2-1", &[]),
+                ("bar", "1-1", &[]),
+            ],
+            &[],
+        );
+        let local = alpm.local_database();
+        let removed = local.remove_stale_duplicates().unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed["foo"], vec!["1-1"]);
+        assert!(local.duplicates().is_empty());
+        assert!(!root.path().join("db/local/foo-1-1").exists());
+
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| {
+                builder
+                    .with_read_only(false)
+                    .with_duplicate_policy(DuplicatePolicy::RemoveStale)
+            },
+            &[
+                ("foo", "1-1", &[]),
+                ("foo", "0:1-1", &[]),
+                ("foo", "0.9-1", &[]),
+            ],
+            &[],
+        );
+        let local = alpm.local_database();
+        assert!(local.duplicates().is_empty());
+        let remaining = fs::read_dir(root.path().join("db/local"))
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().is_dir())
+            .count();
+        assert_eq!(remaining, 1);
+        assert!(!root.path().join("db/local/foo-0.9-1").exists());
+    }
+
+    #[test]
+    fn open_missing_snapshot() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(root.path(), |builder| builder, &[("foo", "1-1", &[])], &[]);
+        let local = alpm.local_database();
+        assert!(local.open_snapshot(root.path().join("missing")).is_err());
+        let snapshot = local.open_snapshot(root.path().join("db/local")).unwrap();
+        assert!(snapshot.contains("foo").unwrap());
+        assert_eq!(snapshot.version_of("foo").unwrap(), Some("1-1".to_owned()));
+        assert!(!snapshot.contains("fo").unwrap());
+    }
+
+    #[test]
+    fn install_target_reports_broken_databases() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| builder,
+            &[],
+            &[("core", &[("foo", "1-1", &[])])],
+        );
+        fs::write(root.path().join("db/sync/core.db"), "not an archive").unwrap();
+        let mut plan = alpm.plan();
+        let target = Target::parse("foo").unwrap();
+        let err = plan
+            .install_target(&target, InstallReason::Explicit)
+            .unwrap_err();
+        if let ErrorKind::TargetNotFound(_) = err.kind {
+            panic!("a database that can't be loaded was treated as not having the package");
+        }
+    }
+
+    #[test]
+    fn record_provenance() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| builder.with_read_only(false),
+            &[("foo", "1-1", &[("XDATA", &["pkgtype=pkg"])])],
+            &[],
+        );
+        let local = alpm.local_database();
+        let package = local.find("foo").unwrap().unwrap();
+        assert_eq!(package.provenance(), None);
+        let provenance = Provenance {
+            repository: Some("core".into()),
+            sha256: Some("abc".into()),
+            ..Provenance::default()
+        };
+        package.record_provenance(&provenance).unwrap();
+        let reloaded = local
+            .open_snapshot(root.path().join("db/local"))
+            .unwrap()
+            .find("foo")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reloaded.provenance(), Some(provenance.clone()));
+        assert_eq!(reloaded.xdata()[0], "pkgtype=pkg");
+        assert_eq!(reloaded.version(), "1-1");
+
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(root.path(), |builder| builder, &[("foo", "1-1", &[])], &[]);
+        let package = alpm.local_database().find("foo").unwrap().unwrap();
+        assert!(package.record_provenance(&provenance).is_err());
+    }
+
+    #[test]
+    fn optional_dependencies_from_broken_databases() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| builder,
+            &[],
+            &[
+                ("core", &[("libfoo", "2-1", &[])]),
+                (
+                    "extra",
+                    &[
+                        ("app", "1-1", &[("OPTDEPENDS", &["libfoo: for foo"])]),
+                        ("libfoo", "1-1", &[]),
+                    ],
+                ),
+            ],
+        );
+        fs::write(root.path().join("db/sync/core.db"), "not an archive").unwrap();
+        let mut plan = alpm.plan();
+        let target = Target::parse("extra/app").unwrap();
+        plan.install_target(&target, InstallReason::Explicit)
+            .unwrap();
+        assert!(plan.choose_optional_dependencies(|_| true).is_err());
+        assert_eq!(install_names(&plan), vec!["app"]);
+    }
+
+    #[test]
+    fn hold_covers_indirect_dependencies() {
+        let root = tempfile::tempdir().unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| builder.with_hold_package("app"),
+            &[
+                ("app", "1-1", &[("DEPENDS", &["lib>=2"])]),
+                ("lib", "2-1", &[("DEPENDS", &["sh"])]),
+                ("bash", "5-1", &[("PROVIDES", &["sh"])]),
+                ("other", "1-1", &[]),
+            ],
+            &[],
+        );
+        let local = alpm.local_database();
+        let mut plan = alpm.plan();
+        for name in &["app", "lib", "bash"] {
+            let package = local.find(name).unwrap().unwrap();
+            assert!(plan.remove(package).is_err(), "{} should be held", name);
+        }
+        plan.remove(local.find("other").unwrap().unwrap()).unwrap();
+        plan.override_hold(true);
+        plan.remove(local.find("bash").unwrap().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn parse_optional_dependency() {
+        let dep = OptionalDependency::parse("foo", "python>=3.7: for the python bindings");
+        assert_eq!(dep.package, "foo");
+        assert_eq!(dep.name, "python>=3.7");
+        assert_eq!(dep.package_name(), "python");
+        assert_eq!(dep.description.as_ref().unwrap(), "for the python bindings");
+        let dep = OptionalDependency::parse("foo", "bar");
+        assert_eq!(dep.package_name(), "bar");
+        assert_eq!(dep.description, None);
+    }
+
+    #[test]
+    fn wrap_list_to_width() {
+        let items: Vec<String> = vec!["bar-3.4-1".into(), "foo-1.2-1".into(), "qux-10-2".into()];
+        assert_eq!(
+            wrap_list("Packages (3)", &items, 0),
+            "Packages (3) bar-3.4-1  foo-1.2-1  qux-10-2"
+        );
+        assert_eq!(
+            wrap_list("Packages (3)", &items, 35),
+            "Packages (3) bar-3.4-1  foo-1.2-1\n             qux-10-2"
+        );
+        assert_eq!(wrap_list("Packages (0)", &[], 80), "Packages (0)");
+    }
+
+    #[test]
+    fn mib() {
+        assert_eq!(format_mib(0), "0.00 MiB");
+        assert_eq!(format_mib(1536 * 1024), "1.50 MiB");
+        assert_eq!(format_mib(-512 * 1024), "-0.50 MiB");
+    }
+}
@@ -0,0 +1,635 @@
+//! Finding everything a set of targets needs, and the order to install it in.
+//!
+//! The resolver starts from the packages the targets name, and follows their `depends` entries.
+//! A dependency is satisfied by a package already in the plan, then by an installed package that
+//! isn't being removed or replaced, then by a package from the sync databases: first one with the
+//! dependency's name, then one that provides it (asking `Questions::choose_provider` if there are
+//! several). Conflicts are checked once every package is known.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+use super::{strip_version, MutationPlan, PlannedInstall};
+use crate::{
+    db::{Database, InstallReason, LocalDatabase, LocalPackage, Located, SyncPackage},
+    dependency::Depend,
+    error::{Error, ErrorKind},
+    package::Package,
+    target::Target,
+    Handle, Warning,
+};
+
+/// Builds a `MutationPlan` from targets, adding their dependencies and ordering the installs so
+/// each package comes after what it depends on.
+///
+/// Made with `Alpm::resolver`.
+#[derive(Debug)]
+pub struct Resolver {
+    handle: Weak<RefCell<Handle>>,
+    local: LocalDatabase,
+    /// The targets to install, as the user gave them.
+    targets: Vec<Target>,
+}
+
+impl Resolver {
+    /// A resolver with no targets.
+    pub(crate) fn new(handle: Weak<RefCell<Handle>>, local: LocalDatabase) -> Resolver {
+        Resolver {
+            handle,
+            local,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Add a target to install explicitly (see `MutationPlan::install_target`).
+    pub fn with_target(mut self, target: Target) -> Self {
+        self.targets.push(target);
+        self
+    }
+
+    /// Plan to install the targets and everything they need.
+    pub fn resolve(&self) -> Result<MutationPlan, Error> {
+        let mut plan = MutationPlan::new(self.handle.clone(), self.local.clone());
+        for target in &self.targets {
+            plan.install_target(target, InstallReason::Explicit)?;
+        }
+        self.complete(&mut plan)?;
+        Ok(plan)
+    }
+
+    /// Add the dependencies of the packages a plan installs, check for conflicts, and put the
+    /// installs in order.
+    ///
+    /// This can be used on a plan made some other way, for example by `MutationPlan::sysupgrade`.
+    /// Installed packages that conflict with planned ones are removed if
+    /// `Questions::remove_conflict` agrees, and then dependencies they satisfied are resolved
+    /// again. Installed packages that depend on an upgraded package are not checked against its
+    /// new version.
+    pub fn complete(&self, plan: &mut MutationPlan) -> Result<(), Error> {
+        let handle = self.handle.upgrade().ok_or(ErrorKind::UseAfterDrop)?;
+        let mut installed = Vec::new();
+        self.local
+            .packages(|package: Rc<LocalPackage>| -> Result<(), Error> {
+                installed.push(package);
+                Ok(())
+            })?;
+        let mut providers = None;
+        // Each pass that removes a conflicting package may leave a dependency it satisfied
+        // unsatisfied, and each removes at least one installed package, so this ends.
+        loop {
+            add_dependencies(plan, &installed, &handle, &mut providers)?;
+            if !check_conflicts(plan, &installed, &handle)? {
+                break;
+            }
+        }
+        order_installs(plan, &handle);
+        Ok(())
+    }
+}
+
+/// Add the packages that satisfy the dependencies of the planned installs, until every
+/// dependency is satisfied.
+fn add_dependencies(
+    plan: &mut MutationPlan,
+    installed: &[Rc<LocalPackage>],
+    handle: &Rc<RefCell<Handle>>,
+    providers: &mut Option<Providers>,
+) -> Result<(), Error> {
+    let mut next = 0;
+    while next < plan.installs.len() {
+        let package = plan.installs[next].package.clone();
+        next += 1;
+        for depend in package.depends_iter() {
+            if satisfied(plan, installed, handle, &depend) {
+                continue;
+            }
+            let mut found = find_by_name(handle, &depend)?;
+            if found.is_none() {
+                if providers.is_none() {
+                    *providers = Some(Providers::new(handle)?);
+                }
+                if let Some(providers) = providers {
+                    found = providers.choose(handle, &depend)?;
+                }
+            }
+            let found = found.ok_or_else(|| ErrorKind::UnsatisfiedDependency {
+                package: package.name().to_owned(),
+                dependency: depend.to_string(),
+            })?;
+            log::debug!("{} needs {} for {}", package.name(), found.name(), depend);
+            let reason = installed
+                .iter()
+                .find(|installed| installed.name() == found.name())
+                .and_then(|installed| installed.reason())
+                .unwrap_or(InstallReason::Depend);
+            plan.install(&found.database, found.package, reason)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fail on conflicts between planned packages, and remove installed packages that conflict
+/// with planned ones if allowed to.
+///
+/// Returns whether any installed packages were removed.
+fn check_conflicts(
+    plan: &mut MutationPlan,
+    installed: &[Rc<LocalPackage>],
+    handle: &Rc<RefCell<Handle>>,
+) -> Result<bool, Error> {
+    let conflict = |package: &str, conflict: &str| -> Error {
+        ErrorKind::PackageConflict {
+            package: package.to_owned(),
+            conflict: conflict.to_owned(),
+        }
+        .into()
+    };
+    for (idx, planned) in plan.installs.iter().enumerate() {
+        for (other_idx, other) in plan.installs.iter().enumerate() {
+            if idx != other_idx && conflicts(&*planned.package, &*other.package) {
+                return Err(conflict(planned.package.name(), other.package.name()));
+            }
+        }
+    }
+    let questions = handle.borrow().questions.clone();
+    let mut removals = Vec::new();
+    for package in installed {
+        let kept = plan
+            .installs
+            .iter()
+            .all(|p| p.package.name() != package.name())
+            && plan.removals.iter().all(|p| p.name() != package.name());
+        if !kept {
+            continue;
+        }
+        let planned = plan.installs.iter().find(|planned| {
+            conflicts(&*planned.package, &**package) || conflicts(&**package, &*planned.package)
+        });
+        if let Some(planned) = planned {
+            let database = handle
+                .borrow()
+                .ordered_sync_databases()
+                .into_iter()
+                .find(|db| db.name() == planned.database)
+                .ok_or_else(|| ErrorKind::DatabaseNotFound(planned.database.clone()))?;
+            let installing = Located::new(database, planned.package.clone());
+            if !questions.remove_conflict(package, &installing) {
+                return Err(conflict(planned.package.name(), package.name()));
+            }
+            removals.push(package.clone());
+        }
+    }
+    let removed = !removals.is_empty();
+    for package in removals {
+        log::info!("removing conflicting package {}", package.name());
+        plan.remove(package)?;
+    }
+    Ok(removed)
+}
+
+/// The first package named by the dependency that satisfies it, by database priority.
+///
+/// Databases that can't be loaded are errors, so a dependency is never taken from a
+/// lower-priority database in their place.
+fn find_by_name(
+    handle: &Rc<RefCell<Handle>>,
+    depend: &Depend,
+) -> Result<Option<Located<Rc<SyncPackage>>>, Error> {
+    let handle = handle.borrow();
+    for db in handle.sync_databases_for(depend.name) {
+        if !db.contains(depend.name)? {
+            continue;
+        }
+        let package = db.package_latest(depend.name)?;
+        if depend.satisfied_by(&**package) && !handle.skip_architecture(&**package) {
+            return Ok(Some(Located::new(db, package)));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether the dependency is satisfied by a planned install, or by an installed package that is
+/// staying.
+fn satisfied(
+    plan: &MutationPlan,
+    installed: &[Rc<LocalPackage>],
+    handle: &Rc<RefCell<Handle>>,
+    depend: &Depend,
+) -> bool {
+    if handle
+        .borrow()
+        .packages_assume_installed
+        .contains(depend.name)
+    {
+        return true;
+    }
+    if plan
+        .installs
+        .iter()
+        .any(|planned| depend.satisfied_by(&*planned.package))
+    {
+        return true;
+    }
+    installed.iter().any(|package| {
+        depend.satisfied_by(&**package)
+            && plan
+                .installs
+                .iter()
+                .all(|p| p.package.name() != package.name())
+            && plan.removals.iter().all(|p| p.name() != package.name())
+    })
+}
+
+/// Whether `package` lists a conflict that `other` matches, directly or through what it provides.
+///
+/// A package never conflicts with another version of itself.
+fn conflicts(package: &impl Package, other: &impl Package) -> bool {
+    package.name() != other.name()
+        && package
+            .conflicts_iter()
+            .any(|conflict| conflict.satisfied_by(other))
+}
+
+/// The sync packages that provide each name, for finding virtual dependencies.
+///
+/// Built the first time a dependency isn't found by name, as it means looking at every package.
+struct Providers {
+    by_name: HashMap<String, Vec<Located<Rc<SyncPackage>>>>,
+}
+
+impl Providers {
+    fn new(handle: &Rc<RefCell<Handle>>) -> Result<Providers, Error> {
+        let databases = handle.borrow().ordered_sync_databases();
+        let mut by_name: HashMap<String, Vec<Located<Rc<SyncPackage>>>> = HashMap::new();
+        for db in databases {
+            db.packages(|package: Rc<SyncPackage>| -> Result<(), Error> {
+                let handle = handle.borrow();
+                if !handle.may_use(package.name(), db.name()) || handle.skip_architecture(&*package)
+                {
+                    return Ok(());
+                }
+                for provision in package.provides() {
+                    let found = by_name
+                        .entry(strip_version(provision).to_owned())
+                        .or_default();
+                    // Like packages, earlier databases take precedence.
+                    if found.iter().all(|p| p.name() != package.name()) {
+                        found.push(Located::new(db.clone(), package.clone()));
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(Providers { by_name })
+    }
+
+    /// The provider of the dependency to install, or `None` if there isn't one or none was
+    /// chosen.
+    fn choose(
+        &self,
+        handle: &Rc<RefCell<Handle>>,
+        depend: &Depend,
+    ) -> Result<Option<Located<Rc<SyncPackage>>>, Error> {
+        let providers: Vec<_> = self
+            .by_name
+            .get(depend.name)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+            .iter()
+            .filter(|provider| depend.satisfied_by(&*provider.package))
+            .cloned()
+            .collect();
+        let chosen = match providers.len() {
+            0 => None,
+            1 => Some(0),
+            _ => {
+                let questions = handle.borrow().questions.clone();
+                questions.choose_provider(&depend.to_string(), &providers)
+            }
+        };
+        Ok(chosen.and_then(|idx| providers.into_iter().nth(idx)))
+    }
+}
+
+/// For each planned install, the other planned installs that satisfy its dependencies.
+fn install_dependencies(installs: &[PlannedInstall]) -> Vec<Vec<usize>> {
+    installs
+        .iter()
+        .enumerate()
+        .map(|(idx, planned)| {
+            let mut needs: Vec<usize> = Vec::new();
+            for depend in planned.package.depends_iter() {
+                for (other_idx, other) in installs.iter().enumerate() {
+                    if other_idx != idx
+                        && !needs.contains(&other_idx)
+                        && depend.satisfied_by(&*other.package)
+                    {
+                        needs.push(other_idx);
+                    }
+                }
+            }
+            needs
+        })
+        .collect()
+}
+
+/// Sort the plan's installs so each comes after the installs it depends on, warning about cycles.
+fn order_installs(plan: &mut MutationPlan, handle: &Rc<RefCell<Handle>>) {
+    let (order, cycles) = dependency_order(&install_dependencies(&plan.installs));
+    for cycle in cycles {
+        let mut packages: Vec<String> = cycle
+            .into_iter()
+            .map(|idx| plan.installs[idx].package.name().to_owned())
+            .collect();
+        packages.sort();
+        handle.borrow().warn(Warning::DependencyCycle { packages });
+    }
+    let mut installs: Vec<Option<PlannedInstall>> = plan.installs.drain(..).map(Some).collect();
+    plan.installs = order
+        .into_iter()
+        .filter_map(|idx| installs[idx].take())
+        .collect();
+}
+
+/// The indices of the planned installs in batches, where no install depends on another in its
+/// batch, and each comes in a later batch than the installs it depends on.
+pub(super) fn extraction_batches(installs: &[PlannedInstall]) -> Vec<Vec<usize>> {
+    batches(&install_dependencies(installs))
+}
+
+/// Put the nodes of a graph into batches, each node in the batch after the last one holding a node
+/// it needs, where `needs[n]` lists the nodes that node `n` needs.
+///
+/// Within a batch, nodes are in the order of `dependency_order`, which also decides which need is
+/// ignored to break a cycle.
+fn batches(needs: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let (order, _) = dependency_order(needs);
+    let mut batch_of: Vec<Option<usize>> = vec![None; needs.len()];
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    for node in order {
+        let batch = needs[node]
+            .iter()
+            .filter_map(|&needed| batch_of[needed])
+            .map(|batch| batch + 1)
+            .max()
+            .unwrap_or(0);
+        batch_of[node] = Some(batch);
+        if batch == batches.len() {
+            batches.push(Vec::new());
+        }
+        batches[batch].push(node);
+    }
+    batches
+}
+
+/// An order for the nodes of a graph in which each comes after the nodes it needs, where
+/// `needs[n]` lists the nodes that node `n` needs.
+///
+/// Nodes stay in their original order where the graph allows it. Cycles can't be ordered: their
+/// nodes are put in the order they are first reached, and each cycle is also returned.
+fn dependency_order(needs: &[Vec<usize>]) -> (Vec<usize>, Vec<Vec<usize>>) {
+    #[derive(Copy, Clone, Eq, PartialEq)]
+    enum State {
+        New,
+        Visiting,
+        Done,
+    }
+
+    fn visit(
+        node: usize,
+        needs: &[Vec<usize>],
+        state: &mut [State],
+        path: &mut Vec<usize>,
+        order: &mut Vec<usize>,
+        cycles: &mut Vec<Vec<usize>>,
+    ) {
+        state[node] = State::Visiting;
+        path.push(node);
+        for &needed in &needs[node] {
+            match state[needed] {
+                State::New => visit(needed, needs, state, path, order, cycles),
+                State::Visiting => {
+                    let start = path.iter().position(|&n| n == needed).unwrap_or(0);
+                    cycles.push(path[start..].to_vec());
+                }
+                State::Done => (),
+            }
+        }
+        path.pop();
+        state[node] = State::Done;
+        order.push(node);
+    }
+
+    let mut state = vec![State::New; needs.len()];
+    let (mut order, mut cycles) = (Vec::new(), Vec::new());
+    for node in 0..needs.len() {
+        if state[node] == State::New {
+            visit(
+                node,
+                needs,
+                &mut state,
+                &mut Vec::new(),
+                &mut order,
+                &mut cycles,
+            );
+        }
+    }
+    (order, cycles)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        mutation::tests::{alpm_with, desc, install_names, TestPackage},
+        question::NonInteractive,
+    };
+
+    #[test]
+    fn orders_dependencies_first() {
+        let (order, cycles) = dependency_order(&[vec![2], vec![], vec![1], vec![]]);
+        assert_eq!(order, [1, 2, 0, 3]);
+        assert!(cycles.is_empty());
+
+        let (order, cycles) = dependency_order(&[vec![1], vec![2], vec![0], vec![0]]);
+        assert_eq!(order, [2, 1, 0, 3]);
+        assert_eq!(cycles, [vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn batches_independent_nodes() {
+        assert_eq!(
+            batches(&[vec![2], vec![], vec![1], vec![]]),
+            [vec![1, 3], vec![2], vec![0]]
+        );
+        assert_eq!(
+            batches(&[vec![], vec![0], vec![0], vec![1, 2]]),
+            [vec![0], vec![1, 2], vec![3]]
+        );
+        assert_eq!(
+            batches(&[vec![1], vec![2], vec![0], vec![0]]),
+            [vec![2], vec![1], vec![0], vec![3]]
+        );
+        assert!(batches(&[]).is_empty());
+    }
+
+    /// A planned install from `core` of the given package.
+    fn planned(package: &TestPackage) -> PlannedInstall {
+        let (name, version, _) = *package;
+        let desc = desc(package, false);
+        let package =
+            SyncPackage::from_parts(&desc, name, version, &Weak::<RefCell<Handle>>::new()).unwrap();
+        PlannedInstall {
+            database: "core".to_owned(),
+            package: Rc::new(package),
+            reason: InstallReason::Explicit,
+            upgrades: None,
+        }
+    }
+
+    /// Resolve the targets against `core` and `extra`, with the given packages installed.
+    fn resolve(
+        root: &std::path::Path,
+        remove_conflicts: bool,
+        installed: &[TestPackage],
+        targets: &[&str],
+    ) -> Result<MutationPlan, Error> {
+        let core: &[TestPackage] = &[
+            ("app", "1-1", &[("DEPENDS", &["libfoo>=2", "sh"])]),
+            ("libfoo", "2-1", &[]),
+            ("bash", "5-1", &[("PROVIDES", &["sh"])]),
+            ("needy", "1-1", &[("DEPENDS", &["missing"])]),
+            (
+                "rival",
+                "1-1",
+                &[("CONFLICTS", &["libfoo-git"]), ("DEPENDS", &["libfoo"])],
+            ),
+        ];
+        let extra: &[TestPackage] = &[("zsh", "5-1", &[("PROVIDES", &["sh"])])];
+        let alpm = alpm_with(
+            root,
+            |builder| {
+                builder
+                    .with_questions(NonInteractive::new().with_remove_conflicts(remove_conflicts))
+            },
+            installed,
+            &[("core", core), ("extra", extra)],
+        );
+        let mut resolver = alpm.resolver();
+        for target in targets {
+            resolver = resolver.with_target(Target::parse(target)?);
+        }
+        resolver.resolve()
+    }
+
+    #[test]
+    fn resolve_by_name_and_provider() {
+        let root = tempfile::tempdir().unwrap();
+        let plan = resolve(root.path(), false, &[], &["app"]).unwrap();
+        // bash is the first provider of sh, by database priority.
+        assert_eq!(install_names(&plan), ["libfoo", "bash", "app"]);
+        assert_eq!(plan.installs()[2].reason, InstallReason::Explicit);
+        assert_eq!(plan.installs()[0].reason, InstallReason::Depend);
+
+        // Installed packages that are staying satisfy dependencies.
+        let root = tempfile::tempdir().unwrap();
+        let installed: &[TestPackage] = &[
+            ("libfoo", "2-1", &[]),
+            ("zsh", "5-1", &[("PROVIDES", &["sh"])]),
+        ];
+        let plan = resolve(root.path(), false, installed, &["app"]).unwrap();
+        assert_eq!(install_names(&plan), ["app"]);
+
+        // Too old to satisfy `libfoo>=2`.
+        let root = tempfile::tempdir().unwrap();
+        let plan = resolve(root.path(), false, &[("libfoo", "1-1", &[])], &["app"]).unwrap();
+        assert_eq!(install_names(&plan), ["libfoo", "bash", "app"]);
+    }
+
+    #[test]
+    fn resolve_unsatisfied() {
+        let root = tempfile::tempdir().unwrap();
+        match resolve(root.path(), false, &[], &["needy"]).map_err(|e| e.kind) {
+            Err(ErrorKind::UnsatisfiedDependency {
+                package,
+                dependency,
+            }) => assert_eq!(
+                (package.as_str(), dependency.as_str()),
+                ("needy", "missing")
+            ),
+            other => panic!(
+                "expected an unsatisfied dependency, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+
+    #[test]
+    fn resolve_conflicts() {
+        let installed: &[TestPackage] = &[("libfoo-git", "3-1", &[("PROVIDES", &["libfoo=3"])])];
+        let root = tempfile::tempdir().unwrap();
+        match resolve(root.path(), false, installed, &["rival"]).map_err(|e| e.kind) {
+            Err(ErrorKind::PackageConflict { package, conflict }) => {
+                assert_eq!(
+                    (package.as_str(), conflict.as_str()),
+                    ("rival", "libfoo-git")
+                )
+            }
+            other => panic!("expected a conflict, got {:?}", other.map(|_| ())),
+        }
+
+        // Once libfoo-git is removed, libfoo is needed in its place.
+        let root = tempfile::tempdir().unwrap();
+        let plan = resolve(root.path(), true, installed, &["rival"]).unwrap();
+        assert_eq!(install_names(&plan), ["libfoo", "rival"]);
+        let removed: Vec<&str> = plan.removals().iter().map(|p| p.name()).collect();
+        assert_eq!(removed, ["libfoo-git"]);
+    }
+
+    #[test]
+    fn resolve_broken_database() {
+        let root = tempfile::tempdir().unwrap();
+        let core: &[TestPackage] = &[("libfoo", "2-1", &[])];
+        let extra: &[TestPackage] = &[
+            ("app", "1-1", &[("DEPENDS", &["libfoo"])]),
+            ("libfoo", "1-1", &[]),
+        ];
+        let alpm = alpm_with(
+            root.path(),
+            |builder| builder,
+            &[],
+            &[("core", core), ("extra", extra)],
+        );
+        fs::write(root.path().join("db/sync/core.db"), "not an archive").unwrap();
+        let result = alpm
+            .resolver()
+            .with_target(Target::parse("extra/app").unwrap())
+            .resolve();
+        match result.map_err(|e| e.kind) {
+            Err(ErrorKind::UnsatisfiedDependency { .. }) | Ok(_) => {
+                panic!("a database that can't be loaded was passed over")
+            }
+            Err(_) => (),
+        }
+    }
+
+    #[test]
+    fn batches_of_packages() {
+        let installs = vec![
+            planned(&("app", "1-1", &[("DEPENDS", &["libfoo>=2", "sh"])])),
+            planned(&("libfoo", "2-1", &[])),
+            planned(&("bash", "5-1", &[("PROVIDES", &["sh"])])),
+            planned(&("tool", "1-1", &[("DEPENDS", &["libbar>=1"])])),
+            planned(&("libbar", "0.9-1", &[])),
+        ];
+        assert_eq!(install_dependencies(&installs)[0], [1, 2]);
+        // libbar is too old to satisfy tool, so doesn't have to come first.
+        assert!(install_dependencies(&installs)[3].is_empty());
+        assert_eq!(extraction_batches(&installs), [vec![1, 2, 3, 4], vec![0]]);
+    }
+}
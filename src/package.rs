@@ -1,4 +1,7 @@
-use crate::version::Version;
+use crate::{
+    dependency::{Depend, DependIter},
+    version::Version,
+};
 use std::borrow::Cow;
 
 /// Information that is available on all packages - regardless of their location.
@@ -27,6 +30,13 @@ pub trait Package {
     /// The computer architecture this package is compiled for.
     fn arch(&self) -> &str;
 
+    /// Whether the package can be installed on a system with the given architectures: it is
+    /// built for one of them, or for `any`.
+    fn is_arch_compatible(&self, architectures: &[String]) -> bool {
+        let arch = self.arch();
+        arch == "any" || architectures.iter().any(|allowed| allowed == arch)
+    }
+
     /// The date and time that this package was built.
     fn build_date(&self) -> &str;
 
@@ -45,6 +55,17 @@ pub trait Package {
     /// Which packages this package optionally depends on.
     fn optional_depends(&self) -> &[String];
 
+    /// The optional dependencies of this package, each with the reason for it if there is one,
+    /// for showing when asking whether to install them.
+    ///
+    /// Entries that are not valid dependencies are skipped.
+    fn optional_depends_detailed(&self) -> Vec<(Depend<'_>, Option<String>)> {
+        self.optional_depends()
+            .iter()
+            .filter_map(|entry| Depend::parse_optional(entry))
+            .collect()
+    }
+
     /// Which packages this package depends on during build.
     fn make_depends(&self) -> &[String];
 
@@ -56,6 +77,39 @@ pub trait Package {
 
     /// Which virtual packages this package provides.
     fn provides(&self) -> &[String];
+
+    /// The values of any field of the package's `desc` file, as written, including fields this
+    /// crate doesn't know about.
+    ///
+    /// Keys are matched ignoring ASCII case, without the `%`s (e.g. `"ISIZE"`). Repeated keys and
+    /// aliases are handled as configured (see `AlpmBuilder::with_duplicate_key_policy` and
+    /// `AlpmBuilder::with_desc_alias`). The file is parsed on each call.
+    ///
+    /// The default returns `None`, for packages that don't come from a desc file.
+    fn raw_field(&self, _key: &str) -> Option<Vec<String>> {
+        None
+    }
+
+    /// The dependencies of this package, parsed as they are iterated without copying them.
+    ///
+    /// Entries that are not valid dependencies are skipped.
+    fn depends_iter(&self) -> DependIter<'_> {
+        DependIter::new(self.depends())
+    }
+
+    /// What this package provides, parsed as it is iterated without copying it.
+    ///
+    /// Entries that are not valid are skipped.
+    fn provides_iter(&self) -> DependIter<'_> {
+        DependIter::new(self.provides())
+    }
+
+    /// What this package conflicts with, parsed as it is iterated without copying it.
+    ///
+    /// Entries that are not valid are skipped.
+    fn conflicts_iter(&self) -> DependIter<'_> {
+        DependIter::new(self.conflicts())
+    }
 }
 
 /// Keys for hashtable of packages.
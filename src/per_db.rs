@@ -0,0 +1,121 @@
+//! Results of operations over several databases.
+
+use crate::error::Error;
+
+/// What each database gave for an operation over several databases, and the errors from those
+/// that failed.
+///
+/// One broken database doesn't fail the whole operation: its error is kept here, and the results
+/// of the others are still available. Databases are in the order they were used, which is the
+/// order of preference for sync databases.
+#[derive(Debug)]
+pub struct PerDb<T> {
+    successes: Vec<(String, T)>,
+    failures: Vec<(String, Error)>,
+}
+
+impl<T> PerDb<T> {
+    /// No results yet.
+    pub(crate) fn new() -> PerDb<T> {
+        PerDb {
+            successes: Vec::new(),
+            failures: Vec::new(),
+        }
+    }
+
+    /// Record the result of the operation on a database.
+    pub(crate) fn record(&mut self, database: &str, result: Result<T, Error>) {
+        match result {
+            Ok(value) => self.successes.push((database.to_owned(), value)),
+            Err(e) => {
+                log::debug!("database {} failed: {}", database, e);
+                self.failures.push((database.to_owned(), e))
+            }
+        }
+    }
+
+    /// The result for a database that succeeded, to add to.
+    pub(crate) fn get_mut(&mut self, database: &str) -> Option<&mut T> {
+        self.successes
+            .iter_mut()
+            .find(|(name, _)| name == database)
+            .map(|(_, value)| value)
+    }
+
+    /// The result for each database that succeeded, with its name.
+    pub fn successes(&self) -> &[(String, T)] {
+        &self.successes
+    }
+
+    /// The error for each database that failed, with its name.
+    pub fn failures(&self) -> &[(String, Error)] {
+        &self.failures
+    }
+
+    /// The result for a database, if it succeeded.
+    pub fn get(&self, database: &str) -> Option<&T> {
+        self.successes
+            .iter()
+            .find(|(name, _)| name == database)
+            .map(|(_, value)| value)
+    }
+
+    /// Whether every database succeeded.
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The names of the databases that succeeded.
+    pub fn databases(&self) -> impl Iterator<Item = &str> {
+        self.successes.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// The successes and failures.
+    pub fn into_parts(self) -> (Vec<(String, T)>, Vec<(String, Error)>) {
+        (self.successes, self.failures)
+    }
+
+    /// The results of every database, or the first error if any failed.
+    pub fn into_result(self) -> Result<Vec<(String, T)>, Error> {
+        match self.failures.into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(self.successes),
+        }
+    }
+}
+
+impl<T: IntoIterator> PerDb<T> {
+    /// The items from every database that succeeded, in order, ignoring failures.
+    pub fn into_flat(self) -> Vec<T::Item> {
+        self.successes
+            .into_iter()
+            .flat_map(|(_, value)| value)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    #[test]
+    fn per_db() {
+        let mut results = PerDb::new();
+        results.record("core", Ok(vec![1, 2]));
+        results.record("broken", Err(ErrorKind::UseAfterDrop.into()));
+        results.record("extra", Ok(vec![3]));
+        results.get_mut("extra").unwrap().push(4);
+        assert!(!results.is_complete());
+        assert_eq!(results.get("extra"), Some(&vec![3, 4]));
+        assert_eq!(results.get("broken"), None);
+        assert_eq!(results.databases().collect::<Vec<_>>(), ["core", "extra"]);
+        assert_eq!(results.failures()[0].0, "broken");
+        assert_eq!(results.into_flat(), [1, 2, 3, 4]);
+
+        let mut failed: PerDb<()> = PerDb::new();
+        failed.record("core", Ok(()));
+        failed.record("broken", Err(ErrorKind::UseAfterDrop.into()));
+        assert!(failed.into_result().is_err());
+    }
+}
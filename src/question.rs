@@ -0,0 +1,162 @@
+//! Questions the library asks while planning and running transactions.
+//!
+//! Pacman asks the user which provider of a virtual package to install, whether to import a
+//! missing signing key, and so on. Here the answers come from a `Questions` implementation given
+//! to `AlpmBuilder::with_questions`. The default, `NonInteractive`, answers everything the same
+//! way every time, so CI and other automation get deterministic transactions.
+
+use std::{fmt, path::Path, rc::Rc};
+
+use crate::{
+    db::{LocalPackage, Located, SyncPackage},
+    mutation::OptionalDependency,
+};
+
+/// Answers the questions asked during planning and running transactions.
+pub trait Questions: fmt::Debug {
+    /// Choose which of several packages providing `dependency` to install.
+    ///
+    /// The providers are in database order. Returning `None` means none of them, and the target
+    /// is not found.
+    fn choose_provider(
+        &self,
+        dependency: &str,
+        providers: &[Located<Rc<SyncPackage>>],
+    ) -> Option<usize>;
+
+    /// Whether to import a signing key that is missing from the keyring.
+    ///
+    /// The default is not to import it.
+    fn import_key(&self, _fingerprint: &str, _uid: &str) -> bool {
+        false
+    }
+
+    /// Whether a package may overwrite a file that is not owned by any package.
+    ///
+    /// The default is not to overwrite it.
+    fn overwrite_file(&self, _path: &Path, _package: &str) -> bool {
+        false
+    }
+
+    /// Whether to replace an installed package with one that lists it in `replaces`, during
+    /// `MutationPlan::sysupgrade`.
+    ///
+    /// The default is to keep the installed package.
+    fn replace_package(
+        &self,
+        _installed: &LocalPackage,
+        _replacement: &Located<Rc<SyncPackage>>,
+    ) -> bool {
+        false
+    }
+
+    /// Whether to install an optional dependency, for
+    /// `MutationPlan::answer_optional_dependencies`.
+    fn install_optional_dependency(&self, dependency: &OptionalDependency) -> bool;
+
+    /// Whether to remove an installed package that conflicts with one being installed, while
+    /// resolving dependencies with `mutation::Resolver`.
+    ///
+    /// Returning `false` means the packages can't be installed, and is the default, so
+    /// implementations written before this was asked keep conflicting packages.
+    fn remove_conflict(
+        &self,
+        _installed: &LocalPackage,
+        _installing: &Located<Rc<SyncPackage>>,
+    ) -> bool {
+        false
+    }
+}
+
+/// Preconfigured answers, for running without anyone to ask.
+///
+/// The first provider is always chosen, and keys are never imported. By default files are never
+/// overwritten, packages are never replaced, conflicting packages are never removed and optional
+/// dependencies are never installed.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct NonInteractive {
+    overwrite: bool,
+    replace: bool,
+    remove_conflicts: bool,
+    optional_dependencies: bool,
+}
+
+impl NonInteractive {
+    /// The default answers.
+    pub fn new() -> NonInteractive {
+        NonInteractive::default()
+    }
+
+    /// Whether to always, or never, overwrite files that are not owned by any package.
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Whether to always, or never, replace installed packages with packages that list them in
+    /// `replaces`.
+    pub fn with_replace(mut self, replace: bool) -> Self {
+        self.replace = replace;
+        self
+    }
+
+    /// Whether to always, or never, remove installed packages that conflict with packages being
+    /// installed.
+    pub fn with_remove_conflicts(mut self, remove: bool) -> Self {
+        self.remove_conflicts = remove;
+        self
+    }
+
+    /// Whether to install all, or no, optional dependencies.
+    pub fn with_optional_dependencies(mut self, optional_dependencies: bool) -> Self {
+        self.optional_dependencies = optional_dependencies;
+        self
+    }
+}
+
+impl Questions for NonInteractive {
+    fn choose_provider(
+        &self,
+        dependency: &str,
+        providers: &[Located<Rc<SyncPackage>>],
+    ) -> Option<usize> {
+        log::debug!(
+            "choosing the first of {} providers of {}",
+            providers.len(),
+            dependency
+        );
+        if providers.is_empty() {
+            None
+        } else {
+            Some(0)
+        }
+    }
+
+    fn import_key(&self, _fingerprint: &str, _uid: &str) -> bool {
+        false
+    }
+
+    fn overwrite_file(&self, _path: &Path, _package: &str) -> bool {
+        self.overwrite
+    }
+
+    fn replace_package(
+        &self,
+        _installed: &LocalPackage,
+        _replacement: &Located<Rc<SyncPackage>>,
+    ) -> bool {
+        self.replace
+    }
+
+    fn install_optional_dependency(&self, _dependency: &OptionalDependency) -> bool {
+        self.optional_dependencies
+    }
+
+    fn remove_conflict(
+        &self,
+        _installed: &LocalPackage,
+        _installing: &Located<Rc<SyncPackage>>,
+    ) -> bool {
+        self.remove_conflicts
+    }
+}
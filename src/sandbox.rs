@@ -0,0 +1,105 @@
+//! Restricting what the current process can do, before handling untrusted archives.
+//!
+//! Package archives are extracted as root, so a malicious archive (or a bug in the code reading
+//! it) could write anywhere. With the `sandbox` feature, extraction happens in the `alpm-extract`
+//! helper program, which uses Landlock to limit writes to the root path, and a seccomp filter to
+//! stop it running programs, using the network or changing mounts.
+
+use std::path::Path;
+
+use crate::error::{Error, ErrorKind};
+
+/// Restrict the current process to writing beneath `root`, and deny dangerous syscalls.
+///
+/// This can't be undone, so it should only be called in the extraction helper.
+#[cfg(feature = "sandbox")]
+pub(crate) fn restrict(root: &Path) -> Result<(), Error> {
+    restrict_writes(root)?;
+    deny_syscalls()?;
+    log::debug!("sandboxed to \"{}\"", root.display());
+    Ok(())
+}
+
+/// Without the `sandbox` feature there is no sandbox to enter.
+#[cfg(not(feature = "sandbox"))]
+pub(crate) fn restrict(_root: &Path) -> Result<(), Error> {
+    Err(ErrorKind::SandboxUnavailable.into())
+}
+
+/// Use Landlock to only allow writing beneath `root`.
+#[cfg(feature = "sandbox")]
+fn restrict_writes(root: &Path) -> Result<(), Error> {
+    use landlock::{
+        Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+
+    let abi = ABI::V1;
+    let status = Ruleset::new()
+        .handle_access(AccessFs::from_write(abi))
+        .map_err(failed)?
+        .create()
+        .map_err(failed)?
+        .add_rule(PathBeneath::new(
+            PathFd::new(root).map_err(failed)?,
+            AccessFs::from_write(abi),
+        ))
+        .map_err(failed)?
+        .restrict_self()
+        .map_err(failed)?;
+    match status.ruleset {
+        // Better to fail than to give a false sense of security.
+        RulesetStatus::NotEnforced => Err(ErrorKind::SandboxUnavailable.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Syscalls that extraction never needs, and that would be useful to an attacker.
+#[cfg(feature = "sandbox")]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+];
+
+/// Install a seccomp filter that makes `DENIED_SYSCALLS` fail with `EPERM`.
+#[cfg(feature = "sandbox")]
+fn deny_syscalls() -> Result<(), Error> {
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+    use std::{collections::BTreeMap, convert::TryInto};
+
+    // An empty list of rules matches every call of the syscall.
+    let rules = DENIED_SYSCALLS
+        .iter()
+        .map(|&syscall| (syscall as i64, Vec::new()))
+        .collect::<BTreeMap<_, _>>();
+    let arch: TargetArch = std::env::consts::ARCH.try_into().map_err(failed)?;
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        arch,
+    )
+    .map_err(failed)?;
+    let program: BpfProgram = filter.try_into().map_err(failed)?;
+    seccompiler::apply_filter(&program).map_err(failed)?;
+    Ok(())
+}
+
+/// The sandbox could not be set up, because of `e`.
+#[cfg(feature = "sandbox")]
+fn failed(e: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::from(ErrorKind::SandboxUnavailable).with_source(e)
+}
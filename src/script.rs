@@ -0,0 +1,115 @@
+//! Running commands for install scriptlets and hooks inside the managed root.
+//!
+//! How the commands are confined is up to a `ScriptRunner`. The default, `ChrootRunner`, does
+//! what pacman does: it changes root into the managed root (unless that is `/`) and runs the
+//! command there, which needs root privileges. Embedders can provide their own runner, for
+//! example one using bubblewrap or systemd-nspawn to build images as an ordinary user.
+
+use std::{
+    ffi::CString,
+    fmt,
+    io::{self, Write},
+    os::unix::{ffi::OsStrExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
+
+/// A command to run inside the managed root.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ScriptCommand {
+    /// The root the command must see as `/`.
+    pub root: PathBuf,
+    /// The program and its arguments. The program is a path inside the root.
+    pub args: Vec<String>,
+    /// What to write to the command's standard input, if anything.
+    pub stdin: Option<Vec<u8>>,
+}
+
+impl ScriptCommand {
+    /// A command running a program with arguments.
+    pub fn new(root: impl Into<PathBuf>, args: Vec<String>) -> ScriptCommand {
+        ScriptCommand {
+            root: root.into(),
+            args,
+            stdin: None,
+        }
+    }
+
+    /// A command running a shell script with `/bin/sh -c`, as install scriptlets are.
+    pub fn shell(root: impl Into<PathBuf>, script: impl Into<String>) -> ScriptCommand {
+        ScriptCommand::new(root, vec!["/bin/sh".into(), "-c".into(), script.into()])
+    }
+
+    /// Give the command something on standard input.
+    pub fn with_stdin(mut self, stdin: Vec<u8>) -> ScriptCommand {
+        self.stdin = Some(stdin);
+        self
+    }
+}
+
+impl fmt::Display for ScriptCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.args.join(" "))
+    }
+}
+
+/// Something that can run commands inside the managed root.
+///
+/// Implementations decide how the command is confined to the root. They should wait for it to
+/// finish, and return how it exited.
+pub trait ScriptRunner: fmt::Debug {
+    /// Run a command, returning its exit status.
+    fn run(&self, command: &ScriptCommand) -> io::Result<ExitStatus>;
+}
+
+/// The default runner, which changes root into the managed root like pacman.
+///
+/// When the root is `/` the command is run directly. Otherwise changing root needs root
+/// privileges.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ChrootRunner;
+
+impl ScriptRunner for ChrootRunner {
+    fn run(&self, command: &ScriptCommand) -> io::Result<ExitStatus> {
+        let (program, args) = command
+            .args
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+        let mut cmd = Command::new(program);
+        cmd.args(args);
+        if command.root != Path::new("/") {
+            // Nothing may be allocated between fork and exec, so the paths are prepared here.
+            let root = CString::new(command.root.as_os_str().as_bytes())?;
+            let slash = CString::new("/")?;
+            unsafe {
+                cmd.pre_exec(move || {
+                    nix::unistd::chroot(root.as_c_str()).map_err(nix_to_io)?;
+                    nix::unistd::chdir(slash.as_c_str()).map_err(nix_to_io)?;
+                    Ok(())
+                });
+            }
+        }
+        if command.stdin.is_some() {
+            cmd.stdin(Stdio::piped());
+        }
+        log::debug!("running \"{}\" in \"{}\"", command, command.root.display());
+        let mut child = cmd.spawn()?;
+        if let (Some(input), Some(mut stdin)) = (&command.stdin, child.stdin.take()) {
+            match stdin.write_all(input) {
+                Ok(()) => (),
+                // The command doesn't have to read everything.
+                Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+                Err(e) => return Err(e),
+            }
+        }
+        child.wait()
+    }
+}
+
+/// Turn an error from a system call into an `io::Error`.
+fn nix_to_io(e: nix::Error) -> io::Error {
+    match e.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
@@ -0,0 +1,286 @@
+//! An index of the names, descriptions and provisions of sync packages, so searching doesn't
+//! have to scan every package.
+//!
+//! Each package gets an entry holding its searchable text in lower case, and each trigram (three
+//! consecutive characters) of that text lists the entries that contain it. A query only needs to
+//! check the entries that have all of its trigrams. The entries are saved under the database path
+//! together with a fingerprint of the sync databases, so the index is only rebuilt when one of
+//! them changes.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    db::{Database, SyncDatabase},
+    error::Error,
+    package::Package,
+};
+
+/// The name of the index file, in the database directory.
+pub(crate) const INDEX_FILE: &str = "search.idx";
+
+/// Separates the fields of an entry, both in memory and on disk.
+const SEPARATOR: char = '\t';
+
+/// A package, as far as searching is concerned.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Entry {
+    /// The position of the package's database in the list the index was built from.
+    database: usize,
+    /// The package name.
+    name: String,
+    /// The name, description and provisions, in lower case, separated by `SEPARATOR`.
+    text: String,
+}
+
+/// The search index for a list of sync databases.
+pub(crate) struct SearchIndex {
+    /// Describes the state of the databases the index was built from.
+    fingerprint: String,
+    /// The entries, in database order and then by name.
+    entries: Vec<Entry>,
+    /// The entries containing each trigram, in ascending order.
+    trigrams: HashMap<[char; 3], Vec<usize>>,
+}
+
+impl SearchIndex {
+    /// Describe the databases (their order, and the size and modification time of their files),
+    /// so a changed database can be detected.
+    pub(crate) fn fingerprint_of(databases: &[SyncDatabase]) -> String {
+        databases
+            .iter()
+            .map(|db| {
+                let md = fs::metadata(db.path()).ok();
+                let len = md.as_ref().map_or(0, |md| md.len());
+                let modified = md
+                    .and_then(|md| md.modified().ok())
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                format!(
+                    "{}:{}:{}.{}",
+                    db.name(),
+                    len,
+                    modified.as_secs(),
+                    modified.subsec_nanos()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Build the index by reading every package of the databases.
+    ///
+    /// Databases that can't be loaded are left out (a warning is raised when loading them).
+    pub(crate) fn build(databases: &[SyncDatabase], fingerprint: String) -> SearchIndex {
+        let mut entries = Vec::new();
+        for (idx, db) in databases.iter().enumerate() {
+            let result = db.packages(|package| -> Result<(), Error> {
+                entries.push(Entry {
+                    database: idx,
+                    name: package.name().to_owned(),
+                    text: searchable_text(&*package),
+                });
+                Ok(())
+            });
+            if let Err(e) = result {
+                log::debug!("not indexing database {}: {}", db.name(), e);
+            }
+        }
+        SearchIndex::new(fingerprint, entries)
+    }
+
+    fn new(fingerprint: String, entries: Vec<Entry>) -> SearchIndex {
+        let mut trigrams: HashMap<[char; 3], Vec<usize>> = HashMap::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            for trigram in trigrams_of(&entry.text) {
+                let list = trigrams.entry(trigram).or_default();
+                // A trigram can appear more than once in the same entry.
+                if list.last() != Some(&idx) {
+                    list.push(idx);
+                }
+            }
+        }
+        SearchIndex {
+            fingerprint,
+            entries,
+            trigrams,
+        }
+    }
+
+    /// The fingerprint of the databases this index was built from.
+    pub(crate) fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Read an index saved with `save`.
+    pub(crate) fn load(path: &Path) -> io::Result<SearchIndex> {
+        let contents = fs::read_to_string(path)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed search index");
+        let mut lines = contents.lines();
+        let fingerprint = lines.next().ok_or_else(invalid)?.to_owned();
+        let mut entries = Vec::new();
+        for line in lines {
+            let mut fields = line.splitn(3, SEPARATOR);
+            let (database, name, text) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(database), Some(name), Some(text)) => (database, name, text),
+                _ => return Err(invalid()),
+            };
+            entries.push(Entry {
+                database: database.parse().map_err(|_| invalid())?,
+                name: name.to_owned(),
+                text: text.to_owned(),
+            });
+        }
+        Ok(SearchIndex::new(fingerprint, entries))
+    }
+
+    /// Write the index, so it can be loaded next time instead of reading every package.
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        let dir = path
+            .parent()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no parent directory"))?;
+        let mut out = tempfile::NamedTempFile::new_in(dir)?;
+        {
+            let mut writer = BufWriter::new(out.as_file_mut());
+            writeln!(writer, "{}", self.fingerprint)?;
+            for entry in &self.entries {
+                writeln!(
+                    writer,
+                    "{}{sep}{}{sep}{}",
+                    entry.database,
+                    entry.name,
+                    entry.text,
+                    sep = SEPARATOR
+                )?;
+            }
+            writer.flush()?;
+        }
+        out.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// Find packages whose name, description or provisions contain the query, ignoring case.
+    ///
+    /// Returns the position of each package's database, and its name, in database order and
+    /// then by name.
+    pub(crate) fn search(&self, query: &str) -> Vec<(usize, &str)> {
+        let query = query.to_lowercase();
+        let mut lists = Vec::new();
+        for trigram in trigrams_of(&query) {
+            match self.trigrams.get(&trigram) {
+                Some(list) => lists.push(list),
+                None => return Vec::new(),
+            }
+        }
+        let candidates: Box<dyn Iterator<Item = usize>> = match intersect(lists) {
+            Some(candidates) => Box::new(candidates.into_iter()),
+            // Queries shorter than a trigram have to check everything.
+            None => Box::new(0..self.entries.len()),
+        };
+        candidates
+            .map(|idx| &self.entries[idx])
+            .filter(|entry| entry.text.contains(&query))
+            .map(|entry| (entry.database, entry.name.as_str()))
+            .collect()
+    }
+}
+
+impl fmt::Debug for SearchIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SearchIndex")
+            .field("fingerprint", &self.fingerprint)
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+/// The text of a package that searches look in.
+fn searchable_text(package: &impl Package) -> String {
+    let clean = |field: &str| field.replace(|c| c == SEPARATOR || c == '\n', " ");
+    let mut fields = vec![clean(package.name()), clean(package.description())];
+    fields.extend(package.provides().iter().map(|provision| clean(provision)));
+    fields.join(&SEPARATOR.to_string()).to_lowercase()
+}
+
+/// Every trigram in some text.
+fn trigrams_of(text: &str) -> Vec<[char; 3]> {
+    let chars: Vec<char> = text.chars().collect();
+    chars
+        .windows(3)
+        .map(|window| [window[0], window[1], window[2]])
+        .collect()
+}
+
+/// The entries in all of the lists, or `None` if there are no lists.
+fn intersect(mut lists: Vec<&Vec<usize>>) -> Option<Vec<usize>> {
+    lists.sort_by_key(|list| list.len());
+    let (first, rest) = lists.split_first()?;
+    Some(
+        first
+            .iter()
+            .cloned()
+            .filter(|idx| rest.iter().all(|list| list.binary_search(idx).is_ok()))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(database: usize, name: &str, text: &str) -> Entry {
+        Entry {
+            database,
+            name: name.to_owned(),
+            text: text.to_owned(),
+        }
+    }
+
+    fn index() -> SearchIndex {
+        SearchIndex::new(
+            "core:1:1.0".into(),
+            vec![
+                entry(0, "bash", "bash\tthe gnu bourne again shell\tsh"),
+                entry(
+                    0,
+                    "zsh",
+                    "zsh\ta very advanced and programmable command interpreter",
+                ),
+                entry(
+                    1,
+                    "fish",
+                    "fish\tsmart and user friendly command line shell",
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn search() {
+        let index = index();
+        assert_eq!(index.search("SHELL"), vec![(0, "bash"), (1, "fish")]);
+        assert_eq!(index.search("command"), vec![(0, "zsh"), (1, "fish")]);
+        assert_eq!(
+            index.search("sh"),
+            vec![(0, "bash"), (0, "zsh"), (1, "fish")]
+        );
+        assert!(index.search("python").is_empty());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(INDEX_FILE);
+        let index = index();
+        index.save(&path).unwrap();
+        let loaded = SearchIndex::load(&path).unwrap();
+        assert_eq!(loaded.fingerprint(), index.fingerprint());
+        assert_eq!(loaded.entries, index.entries);
+        assert_eq!(loaded.search("shell"), index.search("shell"));
+    }
+}
@@ -0,0 +1,154 @@
+//! Summary numbers about an alpm installation, for dashboards and status displays.
+
+use std::{fs, io, path::Path, rc::Rc, time::SystemTime};
+
+use crate::{
+    db::{Database, InstallReason, LocalPackage},
+    error::Error,
+    package::Package,
+    Alpm,
+};
+
+/// Counts and sizes describing an installation, from `Alpm::statistics`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Statistics {
+    /// The number of installed packages.
+    pub installed: usize,
+    /// The number of packages installed explicitly.
+    ///
+    /// Packages without a recorded reason count as explicit, as in pacman.
+    pub explicit: usize,
+    /// The number of packages installed as dependencies of others.
+    pub dependencies: usize,
+    /// The sum of the installed sizes of all packages, as recorded in their metadata.
+    pub installed_size: u64,
+    /// The total size of the files in the cache directories, in bytes.
+    ///
+    /// Cache directories that can't be read are skipped.
+    pub cache_size: u64,
+    /// Numbers for each sync database, in order of preference.
+    pub sync_databases: Vec<DatabaseStatistics>,
+}
+
+/// Numbers about one sync database, as part of `Statistics`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DatabaseStatistics {
+    /// The name of the database.
+    pub name: String,
+    /// The number of packages in the database.
+    pub packages: usize,
+    /// The number of installed packages that this database has a package of the same name for.
+    pub installed: usize,
+    /// When the database was last synchronized (see `SyncDatabase::last_synced`).
+    pub last_synced: Option<SystemTime>,
+}
+
+/// Gather statistics, going through the installed packages once.
+///
+/// Sync databases are only asked whether they have a package of each name, so installed packages
+/// aren't looked up in them. This fails if a sync database can't be loaded.
+pub(crate) fn statistics(alpm: &Alpm) -> Result<Statistics, Error> {
+    let mut packages: Vec<Rc<LocalPackage>> = Vec::new();
+    alpm.local_database()
+        .packages(|package| -> Result<(), Error> {
+            packages.push(package);
+            Ok(())
+        })?;
+
+    let dependencies = packages
+        .iter()
+        .filter(|package| package.reason() == Some(InstallReason::Depend))
+        .count();
+    let installed_size = packages.iter().map(|package| package.size()).sum();
+
+    let handle = alpm.handle.borrow();
+    let mut sync_databases = Vec::new();
+    for db in handle.ordered_sync_databases() {
+        db.load()?;
+        let mut installed = 0;
+        for package in &packages {
+            if db.contains(package.name())? {
+                installed += 1;
+            }
+        }
+        sync_databases.push(DatabaseStatistics {
+            name: db.name().to_owned(),
+            packages: db.count(),
+            installed,
+            last_synced: db.last_synced(),
+        });
+    }
+    let cache_size = handle
+        .cache_directories
+        .iter()
+        .map(|dir| match directory_size(dir) {
+            Ok(size) => size,
+            Err(e) => {
+                log::debug!("skipping cache directory \"{}\": {}", dir.display(), e);
+                0
+            }
+        })
+        .sum();
+
+    Ok(Statistics {
+        installed: packages.len(),
+        explicit: packages.len() - dependencies,
+        dependencies,
+        installed_size,
+        cache_size,
+        sync_databases,
+    })
+}
+
+/// The total size of the files directly in a directory.
+fn directory_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let md = entry?.metadata()?;
+        if md.is_file() {
+            size += md.len();
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutation::tests::alpm_with;
+
+    #[test]
+    fn gather_statistics() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = root.path().join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(cache.join("foo-1-1-any.pkg.tar.zst"), b"abc").unwrap();
+        fs::write(cache.join("bar-1-1-any.pkg.tar.zst"), b"defg").unwrap();
+        let alpm = alpm_with(
+            root.path(),
+            |builder| builder.with_cache_directory(&cache),
+            &[
+                // No reason counts as explicit.
+                ("foo", "1-1", &[]),
+                ("bar", "1-1", &[("REASON", &["1"])]),
+                ("baz", "1-1", &[("REASON", &["0"])]),
+            ],
+            &[
+                ("core", &[("foo", "1-1", &[]), ("bar", "2-1", &[])]),
+                ("extra", &[("qux", "1-1", &[])]),
+            ],
+        );
+        let statistics = alpm.statistics().unwrap();
+        assert_eq!(statistics.installed, 3);
+        assert_eq!(statistics.explicit, 2);
+        assert_eq!(statistics.dependencies, 1);
+        assert_eq!(statistics.installed_size, 3);
+        assert_eq!(statistics.cache_size, 7);
+        let databases: Vec<_> = statistics
+            .sync_databases
+            .iter()
+            .map(|db| (db.name.as_str(), db.packages, db.installed))
+            .collect();
+        assert_eq!(databases, vec![("core", 2, 2), ("extra", 1, 0)]);
+    }
+}
@@ -0,0 +1,265 @@
+//! Parsing the package targets users type on the command line, like pacman does.
+
+use std::{cmp::Ordering, fmt, path::PathBuf, str::FromStr};
+
+use reqwest::Url;
+
+use crate::{
+    error::{Error, ErrorKind},
+    version::Version,
+};
+
+/// Extensions that mark a target as a package archive rather than a name.
+const ARCHIVE_MARKER: &str = ".pkg.tar";
+
+/// Something the user asked to install or remove.
+///
+/// The forms accepted are:
+///
+///  - `linux` - a package or group name,
+///  - `core/linux` - a name in a specific database,
+///  - `linux>=6.1` - a name with a version constraint (also `=`, `<`, `<=` and `>`),
+///  - `./linux-6.1-1-x86_64.pkg.tar.zst` - a package archive on disk,
+///  - `https://example.com/linux-6.1-1-x86_64.pkg.tar.zst` - a package archive to download.
+///
+/// Whether a name refers to a package or a group is only known once it is looked up, so both are
+/// parsed as `Target::Name`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Target {
+    /// A package or group, by name.
+    Name {
+        /// The database to look in, or `None` to use the first one that has the package.
+        database: Option<String>,
+        /// The package or group name.
+        name: String,
+        /// A version the package must satisfy.
+        constraint: Option<VersionConstraint>,
+    },
+    /// A package archive on disk.
+    File(PathBuf),
+    /// A package archive on a server.
+    Url(Url),
+}
+
+impl Target {
+    /// Parse a target, as typed by the user.
+    pub fn parse(input: &str) -> Result<Target, Error> {
+        let invalid = || Error::from(ErrorKind::InvalidTarget(input.to_owned()));
+        let input_trimmed = input.trim();
+        if input_trimmed.is_empty() {
+            return Err(invalid());
+        }
+        if input_trimmed.contains("://") {
+            return Url::parse(input_trimmed)
+                .map(Target::Url)
+                .map_err(|e| invalid().with_source(e));
+        }
+        if input_trimmed.starts_with('/')
+            || input_trimmed.starts_with('.')
+            || input_trimmed.contains(ARCHIVE_MARKER)
+        {
+            return Ok(Target::File(PathBuf::from(input_trimmed)));
+        }
+
+        let (database, rest) = match input_trimmed.find('/') {
+            Some(idx) => (Some(&input_trimmed[..idx]), &input_trimmed[idx + 1..]),
+            None => (None, input_trimmed),
+        };
+        let (name, constraint) = match rest.find(|c| c == '<' || c == '>' || c == '=') {
+            Some(idx) => (
+                &rest[..idx],
+                Some(VersionConstraint::parse(&rest[idx..]).ok_or_else(invalid)?),
+            ),
+            None => (rest, None),
+        };
+        if database == Some("") || name.is_empty() || name.contains('/') {
+            return Err(invalid());
+        }
+        Ok(Target::Name {
+            database: database.map(str::to_owned),
+            name: name.to_owned(),
+            constraint,
+        })
+    }
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Target::parse(s)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Name {
+                database,
+                name,
+                constraint,
+            } => {
+                if let Some(database) = database {
+                    write!(f, "{}/", database)?;
+                }
+                f.write_str(name)?;
+                if let Some(constraint) = constraint {
+                    write!(f, "{}", constraint)?;
+                }
+                Ok(())
+            }
+            Target::File(path) => write!(f, "{}", path.display()),
+            Target::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// How a version is compared in a `VersionConstraint`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Comparison {
+    /// `<`
+    Less,
+    /// `<=`
+    LessOrEqual,
+    /// `=`
+    Equal,
+    /// `>=`
+    GreaterOrEqual,
+    /// `>`
+    Greater,
+}
+
+impl Comparison {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Equal => "=",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::Greater => ">",
+        }
+    }
+}
+
+/// A requirement on the version of a package, like `>=6.1`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct VersionConstraint {
+    /// How to compare the version.
+    pub comparison: Comparison,
+    /// The version to compare against.
+    pub version: String,
+}
+
+impl VersionConstraint {
+    /// Parse a constraint like `>=6.1`, returning `None` if it is not valid.
+    pub fn parse(input: &str) -> Option<VersionConstraint> {
+        let (comparison, version) = parse_constraint(input)?;
+        Some(VersionConstraint {
+            comparison,
+            version: version.to_owned(),
+        })
+    }
+
+    /// Whether the given version satisfies the constraint.
+    ///
+    /// As in alpm, if the constraint has no release (`-1`), any release matches.
+    pub fn satisfied_by(&self, version: &str) -> bool {
+        satisfies(version, self.comparison, &self.version)
+    }
+}
+
+/// Split a constraint like `>=6.1` into its comparison and version, without copying.
+pub(crate) fn parse_constraint(input: &str) -> Option<(Comparison, &str)> {
+    let comparisons = [
+        Comparison::LessOrEqual,
+        Comparison::GreaterOrEqual,
+        Comparison::Less,
+        Comparison::Greater,
+        Comparison::Equal,
+    ];
+    let comparison = *comparisons
+        .iter()
+        .find(|comparison| input.starts_with(comparison.as_str()))?;
+    let version = &input[comparison.as_str().len()..];
+    if version.is_empty() || version.contains(|c| c == '<' || c == '>' || c == '=') {
+        return None;
+    }
+    Some((comparison, version))
+}
+
+/// Whether a version compares to the required one as asked (see `VersionConstraint`).
+pub(crate) fn satisfies(version: &str, comparison: Comparison, required: &str) -> bool {
+    let ordering = Version::parse(version).cmp(&Version::parse(required));
+    match comparison {
+        Comparison::Less => ordering == Ordering::Less,
+        Comparison::LessOrEqual => ordering != Ordering::Greater,
+        Comparison::Equal => ordering == Ordering::Equal,
+        Comparison::GreaterOrEqual => ordering != Ordering::Less,
+        Comparison::Greater => ordering == Ordering::Greater,
+    }
+}
+
+impl fmt::Display for VersionConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.comparison.as_str(), self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(database: Option<&str>, name: &str, constraint: Option<&str>) -> Target {
+        Target::Name {
+            database: database.map(str::to_owned),
+            name: name.to_owned(),
+            constraint: constraint.map(|c| VersionConstraint::parse(c).unwrap()),
+        }
+    }
+
+    #[test]
+    fn parse_targets() {
+        let cases = vec![
+            ("linux", name(None, "linux", None)),
+            ("base-devel", name(None, "base-devel", None)),
+            ("core/linux", name(Some("core"), "linux", None)),
+            ("linux>=6.1", name(None, "linux", Some(">=6.1"))),
+            (
+                "core/linux=6.1-1",
+                name(Some("core"), "linux", Some("=6.1-1")),
+            ),
+            (
+                "./foo-1-1-any.pkg.tar.zst",
+                Target::File("./foo-1-1-any.pkg.tar.zst".into()),
+            ),
+            ("/tmp/foo.pkg", Target::File("/tmp/foo.pkg".into())),
+            (
+                "https://example.com/foo-1-1-any.pkg.tar.zst",
+                Target::Url(Url::parse("https://example.com/foo-1-1-any.pkg.tar.zst").unwrap()),
+            ),
+        ];
+        for (input, expected) in cases {
+            let target = Target::parse(input).unwrap();
+            assert_eq!(target, expected);
+            assert_eq!(target.to_string(), input);
+        }
+        for bad in &["", "/", "core/", "/linux", "linux>=", "linux=>1", "a/b/c"] {
+            if let Ok(Target::Name { .. }) = Target::parse(bad) {
+                panic!("{:?} should not parse as a name", bad);
+            }
+        }
+    }
+
+    #[test]
+    fn constraints() {
+        let c = VersionConstraint::parse(">=6.1").unwrap();
+        assert!(c.satisfied_by("6.1-1"));
+        assert!(c.satisfied_by("6.2-1"));
+        assert!(!c.satisfied_by("6.0.9-3"));
+        let c = VersionConstraint::parse("=6.1").unwrap();
+        assert!(c.satisfied_by("6.1-2"));
+        let c = VersionConstraint::parse("<1:1.0").unwrap();
+        assert!(c.satisfied_by("2.0-1"));
+        assert!(VersionConstraint::parse("~1.0").is_none());
+    }
+}
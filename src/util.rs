@@ -1,7 +1,19 @@
-use std::{borrow::Cow, fmt, fs, io, path::Path};
+use std::{
+    borrow::Cow,
+    fmt, fs, io,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
 
+use fs2::FileExt;
 use reqwest::Url;
 
+use crate::error::{Error, ErrorKind};
+
+/// How long to wait between attempts to take a lock, when waiting with a timeout.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
 #[derive(Debug, Copy, Clone)]
 pub struct NotADirectory;
 
@@ -37,6 +49,102 @@ pub fn check_valid_directory(path: &Path) -> io::Result<()> {
     }
 }
 
+/// Check a directory is valid (see `check_valid_directory`), and that we can create files in it.
+pub fn check_writable_directory(path: &Path) -> io::Result<()> {
+    check_valid_directory(path)?;
+    tempfile::tempfile_in(path).map(|_| ())
+}
+
+/// Add a relative path to the end of a server's url.
+///
+/// `Url::join` can't be used, since a package filename with an epoch
+/// (`foo-1:2.0-1-any.pkg.tar.zst`) parses as an absolute url with the scheme `foo-1`. Returns
+/// `None` if `base` can't have a path.
+pub(crate) fn join_url(base: &Url, path: &str) -> Option<Url> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .ok()?
+        .pop_if_empty()
+        .extend(path.split('/'));
+    Some(url)
+}
+
+/// What to do when a database file is locked by another process.
+///
+/// This is used whenever a database file is written.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum LockMode {
+    /// Wait until the lock is released.
+    Block,
+    /// Wait up to the given time, then give up with `ErrorKind::DatabaseBusy`.
+    Timeout(Duration),
+    /// Give up straight away with `ErrorKind::DatabaseBusy`.
+    NoWait,
+}
+
+impl Default for LockMode {
+    fn default() -> Self {
+        LockMode::Block
+    }
+}
+
+/// Take an exclusive lock on a database file, waiting according to `mode`.
+pub(crate) fn lock_exclusive(file: &fs::File, path: &Path, mode: LockMode) -> Result<(), Error> {
+    lock(file, path, mode, false)
+}
+
+/// Take a shared lock on a database file, waiting according to `mode`.
+pub(crate) fn lock_shared(file: &fs::File, path: &Path, mode: LockMode) -> Result<(), Error> {
+    lock(file, path, mode, true)
+}
+
+/// Take a shared or exclusive lock, waiting according to `mode`.
+fn lock(file: &fs::File, path: &Path, mode: LockMode, shared: bool) -> Result<(), Error> {
+    let busy = || Error::from(ErrorKind::DatabaseBusy(path.to_owned()));
+    let access = if shared { "shared" } else { "exclusive" };
+    let start = Instant::now();
+    loop {
+        let attempt = if shared {
+            file.try_lock_shared()
+        } else {
+            file.try_lock_exclusive()
+        };
+        match attempt {
+            Ok(()) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e.into()),
+        }
+        match mode {
+            LockMode::Block => {
+                log::warn!(
+                    "database \"{}\" is in use, blocking on request for {} access",
+                    path.display(),
+                    access
+                );
+                let locked = if shared {
+                    file.lock_shared()
+                } else {
+                    file.lock_exclusive()
+                };
+                return locked.map_err(Error::from);
+            }
+            LockMode::Timeout(timeout) => {
+                let elapsed = start.elapsed();
+                if elapsed >= timeout {
+                    return Err(busy());
+                }
+                log::debug!(
+                    "database \"{}\" is in use, waiting for {} access",
+                    path.display(),
+                    access
+                );
+                thread::sleep(std::cmp::min(LOCK_RETRY_INTERVAL, timeout - elapsed));
+            }
+            LockMode::NoWait => return Err(busy()),
+        }
+    }
+}
+
 /// This structure only exists until `impl TryFrom<AsRef<str>> for Url` exists.
 pub enum UrlOrStr {
     /// A url
@@ -49,7 +157,11 @@ impl UrlOrStr {
     pub fn into_url(self) -> Result<Url, (String, impl std::error::Error + Send + Sync + 'static)> {
         match self {
             UrlOrStr::Url(url) => Ok(url),
-            UrlOrStr::Str(s) => s.parse().map_err(|e| (s, e)),
+            UrlOrStr::Str(s) => match Url::from_directory_path(&s) {
+                // An absolute path is a directory mirror.
+                Ok(url) => Ok(url),
+                Err(()) => s.parse().map_err(|e| (s, e)),
+            },
         }
     }
 }
@@ -80,3 +192,25 @@ impl fmt::Display for UrlOrStr {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_epoch_filename() {
+        let server: Url = "https://mirror.example/core/os/x86_64".parse().unwrap();
+        let url = join_url(&server, "foo-1:2.0-1-any.pkg.tar.zst").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://mirror.example/core/os/x86_64/foo-1:2.0-1-any.pkg.tar.zst"
+        );
+        let server: Url = "file:///srv/repo/".parse().unwrap();
+        let url = join_url(&server, "core.db.entries/foo-1:2.0-1/desc").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "file:///srv/repo/core.db.entries/foo-1:2.0-1/desc"
+        );
+        assert!(join_url(&"mailto:me@example.com".parse().unwrap(), "core.db").is_none());
+    }
+}
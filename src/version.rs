@@ -6,6 +6,9 @@ use std::{
     hash::{Hash, Hasher},
 };
 
+#[cfg(any(test, feature = "proptest-support"))]
+pub mod proptest_support;
+
 const DEFAULT_EPOCH: &'static str = "0";
 
 /// An alpm version.
@@ -126,11 +129,9 @@ impl Hash for Version<'_> {
     where
         H: Hasher,
     {
+        // The release isn't hashed, because a missing release is equal to any release.
         version_hash(&self.epoch, hasher);
         version_hash(&self.version, hasher);
-        if let Some(ref release) = self.release {
-            version_hash(release, hasher);
-        }
     }
 }
 
@@ -219,6 +220,8 @@ impl<'a> Iterator for BlocksIter<'a> {
 ///     (so "1a" and "1-a" are older than "1", "a1" and "a-1" are newer than "a").
 ///   - If the inputs have the same number of sections that are all equal, or one input has some
 ///     extra separator at the end, then they are equal.
+///   - Separator lengths are only compared when both inputs have a block after the separator, so
+///     "1-" and "1" are both newer than "1--a".
 fn version_cmp(left: &str, right: &str) -> Ordering {
     use self::{
         Block::{Alpha, Numeric, Separator},
@@ -259,17 +262,14 @@ fn version_cmp(left: &str, right: &str) -> Ordering {
                 Equal => continue,
                 gtlt => return gtlt,
             },
-            Both(Separator(left_len), Separator(right_len)) => {
-                // If we're at the end it's different to when we're in the middle.
-                if blocks_iter.peek().is_none() {
-                    return Equal;
-                } else {
-                    match left_len.cmp(&right_len) {
-                        Equal => continue,
-                        gtlt => return gtlt,
-                    }
-                }
-            }
+            Both(Separator(left_len), Separator(right_len)) => match blocks_iter.peek() {
+                // If either input ends here, the next block (or the end) decides.
+                Some(Both(_, _)) => match left_len.cmp(&right_len) {
+                    Equal => continue,
+                    gtlt => return gtlt,
+                },
+                _ => continue,
+            },
         }
     }
     // If we've fallen through then all blocks of the version matched.
@@ -311,10 +311,37 @@ fn discard_zeros(input: &[u8]) -> &[u8] {
 
 #[cfg(test)]
 mod tests {
+    use super::proptest_support::{check_laws, equivalent_versions, version, version_part};
     use itertools::Itertools;
+    use proptest::{prop_assert, prop_assert_eq, proptest};
     use std::cmp::Ordering::*;
     use std::collections::{BTreeSet, HashSet};
 
+    proptest! {
+        #[test]
+        fn laws(left in version(), right in version()) {
+            check_laws(&left, &right)?;
+        }
+
+        #[test]
+        fn equivalent_spellings((left, right) in equivalent_versions()) {
+            prop_assert_eq!(super::Version::parse(&left), super::Version::parse(&right));
+            check_laws(&left, &right)?;
+        }
+
+        #[test]
+        fn alpha_suffix_is_older(version in version_part(), suffix in "[._+]{1,2}[a-z]{1,5}") {
+            let suffixed = format!("{}{}", version, suffix);
+            prop_assert_eq!(super::version_cmp(&suffixed, &version), Less);
+        }
+
+        #[test]
+        fn numeric_suffix_is_newer(version in version_part(), suffix in "[._+]{0,2}[0-9]{1,3}") {
+            let suffixed = format!("{}{}", version, suffix);
+            prop_assert!(super::version_cmp(&suffixed, &version) != Less);
+        }
+    }
+
     #[test]
     fn version_cmp() {
         // TODO add more cases, or add fuzzing test.
@@ -340,6 +367,9 @@ mod tests {
             // Trailing separators have no effect.
             (&"1.2.4"[..], &"1.2.4---"[..], Equal),
             (&"1.2.4--"[..], &"1.2.4---"[..], Equal),
+            // Separator lengths don't matter when one side ends after the separator.
+            (&"1-"[..], &"1--a"[..], Greater),
+            (&"1.0."[..], &"1.0..1"[..], Less),
             // A random more complex example
             (&"123abc%%^%123abc"[..], &"123**$%abc123abc"[..], Less),
         ];
@@ -406,6 +436,7 @@ mod tests {
             Version::parse("1 "),
             Version::parse("1"),
             Version::parse("01"),
+            Version::parse("0:1"),
             Version::parse("a"),
         ] {
             set1.insert(val.clone());
@@ -0,0 +1,150 @@
+//! Proptest strategies for version strings, and the laws version comparison should follow.
+//!
+//! Enabled by the `proptest-support` feature, so resolvers and other code that orders versions
+//! can test against the same inputs as this crate.
+//!
+//! Version comparison is not transitive (like `vercmp`, "a" < "a1" < "a.b" < "a"), so a
+//! `BTreeSet` of arbitrary versions is not well-defined. The laws here are the ones that do hold.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use proptest::{prelude::*, prop_assert_eq, test_runner::TestCaseError};
+
+use super::Version;
+
+/// The separators generated between blocks.
+const SEPARATORS: &[char] = &['.', '_', '+'];
+
+/// A valid epoch, `digit+`.
+pub fn epoch() -> impl Strategy<Value = String> {
+    "[0-9]{1,2}"
+}
+
+/// A valid release, `digit+` optionally followed by `.digit+`.
+pub fn release() -> impl Strategy<Value = String> {
+    "[0-9]{1,3}(\\.[0-9]{1,2})?"
+}
+
+/// A valid version part (without epoch or release).
+///
+/// Blocks of digits (sometimes with leading zeros) and letters, joined by zero to two separators
+/// and sometimes followed by a trailing separator, so alpha blocks like "1.0rc1" and "1.0.alpha"
+/// are covered.
+pub fn version_part() -> impl Strategy<Value = String> {
+    (
+        "[0-9a-z]{1,4}",
+        prop::collection::vec(("[._+]{0,2}", "0{0,2}[0-9]{1,3}|[a-z]{1,5}"), 0..5),
+        "[._+]?",
+    )
+        .prop_map(|(first, rest, trailing)| {
+            let mut version = first;
+            for (separator, block) in rest {
+                version.push_str(&separator);
+                version.push_str(&block);
+            }
+            version.push_str(&trailing);
+            version
+        })
+}
+
+/// A valid version string, `[epoch:]version[-release]`.
+pub fn version() -> impl Strategy<Value = String> {
+    (
+        prop::option::of(epoch()),
+        version_part(),
+        prop::option::of(release()),
+    )
+        .prop_map(|(epoch, version, release)| {
+            let mut out = String::new();
+            if let Some(epoch) = epoch {
+                out.push_str(&epoch);
+                out.push(':');
+            }
+            out.push_str(&version);
+            if let Some(release) = release {
+                out.push('-');
+                out.push_str(&release);
+            }
+            out
+        })
+}
+
+/// Pairs of version strings that are written differently but compare equal.
+///
+/// The second is the first with an explicit epoch, extra leading zeros, different separators of
+/// the same length, an extra trailing separator, or no release.
+pub fn equivalent_versions() -> impl Strategy<Value = (String, String)> {
+    (
+        version(),
+        0..3usize,
+        0..3usize,
+        any::<bool>(),
+        any::<bool>(),
+    )
+        .prop_map(|(version, zeros, rotate, trailing, drop_release)| {
+            let respelled = respell(&version, zeros, rotate, trailing, drop_release);
+            (version, respelled)
+        })
+}
+
+/// Write `input` differently, without changing what it compares equal to.
+fn respell(input: &str, zeros: usize, rotate: usize, trailing: bool, drop_release: bool) -> String {
+    let parsed = Version::parse(input);
+    let mut out = respell_part(&parsed.epoch, zeros, rotate);
+    out.push(':');
+    out.push_str(&respell_part(&parsed.version, zeros, rotate));
+    if trailing {
+        out.push(SEPARATORS[rotate % SEPARATORS.len()]);
+    }
+    match parsed.release {
+        Some(ref release) if !drop_release => {
+            out.push('-');
+            out.push_str(&respell_part(release, zeros, rotate));
+        }
+        _ => (),
+    }
+    out
+}
+
+/// Add `zeros` leading zeros to each number, and swap each generated separator for another.
+fn respell_part(input: &str, zeros: usize, rotate: usize) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut in_number = false;
+    for ch in input.chars() {
+        if ch.is_ascii_digit() && !in_number {
+            out.extend((0..zeros).map(|_| '0'));
+        }
+        in_number = ch.is_ascii_digit();
+        match SEPARATORS.iter().position(|&sep| sep == ch) {
+            Some(idx) => out.push(SEPARATORS[(idx + rotate) % SEPARATORS.len()]),
+            None => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Check the laws relating `left` and `right`: comparison is reflexive and antisymmetric, agrees
+/// with equality, and equal versions hash equally.
+///
+/// Use it inside `proptest!` with `?`.
+pub fn check_laws(left: &str, right: &str) -> Result<(), TestCaseError> {
+    let left = Version::parse(left);
+    let right = Version::parse(right);
+    prop_assert_eq!(left.cmp(&left), std::cmp::Ordering::Equal);
+    prop_assert_eq!(left.cmp(&right), right.cmp(&left).reverse());
+    prop_assert_eq!(left == right, left.cmp(&right) == std::cmp::Ordering::Equal);
+    prop_assert_eq!(left.partial_cmp(&right), Some(left.cmp(&right)));
+    if left == right {
+        prop_assert_eq!(hash(&left), hash(&right));
+    }
+    Ok(())
+}
+
+fn hash(version: &Version) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    version.hash(&mut hasher);
+    hasher.finish()
+}